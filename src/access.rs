@@ -0,0 +1,165 @@
+// Which source addresses packets are accepted from, set with allowlist and denylist in the
+// config. Each is a comma-separated list of addresses and CIDR ranges.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// An address range like 192.168.1.0/24 or fd00::/8. A bare address is a range of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers reach dual-stack sockets as IPv4-mapped IPv6 addresses
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.into(), ip.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+// Whether the top prefix_len of the bits-wide addresses are the same
+fn prefix_matches(network: u128, ip: u128, bits: u8, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    let shift = u32::from(bits - prefix_len);
+    network >> shift == ip >> shift
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match text.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (text, None),
+        };
+        let network: IpAddr = address
+            .parse()
+            .map_err(|_| format!("{} is not an address or CIDR range", text))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix_len| *prefix_len <= max_len)
+                .ok_or_else(|| {
+                    format!("{} has a prefix length that isn't 0 to {}", text, max_len)
+                })?,
+            None => max_len,
+        };
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+// Parses a comma-separated list of ranges, ignoring blank entries
+pub fn parse_ranges(text: &str) -> Result<Vec<Cidr>, String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccessList {
+    // When not empty, only these ranges are accepted
+    allow: Vec<Cidr>,
+    // Never accepted, even when also allowed
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        Self { allow, deny }
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|range| range.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|range| range.contains(ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    fn ranges(text: &str) -> Vec<Cidr> {
+        parse_ranges(text).unwrap()
+    }
+
+    #[test]
+    fn ranges_match_by_prefix() {
+        let lan: Cidr = "192.168.1.0/24".parse().unwrap();
+        assert!(lan.contains(ip("192.168.1.0")));
+        assert!(lan.contains(ip("192.168.1.255")));
+        assert!(!lan.contains(ip("192.168.2.1")));
+        // The other family never matches
+        assert!(!lan.contains(ip("fd00::1")));
+        // Dual-stack sockets report IPv4 peers as mapped addresses
+        assert!(lan.contains(ip("::ffff:192.168.1.7")));
+
+        let single: Cidr = "10.0.0.5".parse().unwrap();
+        assert!(single.contains(ip("10.0.0.5")));
+        assert!(!single.contains(ip("10.0.0.6")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.9")));
+
+        let ula: Cidr = "fd00::/8".parse().unwrap();
+        assert!(ula.contains(ip("fd7a:115c:a1e0::1")));
+        assert!(!ula.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn bad_ranges_are_refused() {
+        assert!("192.168.1.0/33".parse::<Cidr>().is_err());
+        assert!("fd00::/129".parse::<Cidr>().is_err());
+        assert!("lan".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert_eq!(ranges(" 10.0.0.1 , ,10.0.1.0/24").len(), 2);
+    }
+
+    #[test]
+    fn an_allowlist_rejects_peers_outside_it() {
+        let access = AccessList::new(ranges("192.168.1.0/24, 100.64.0.0/10"), Vec::new());
+        assert!(access.permits(ip("192.168.1.20")));
+        assert!(access.permits(ip("100.90.1.2")));
+        assert!(!access.permits(ip("192.168.2.20")));
+        assert!(!access.permits(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn a_denylist_blocks_even_allowed_peers() {
+        let access = AccessList::new(Vec::new(), ranges("192.168.1.66"));
+        assert!(!access.permits(ip("192.168.1.66")));
+        assert!(access.permits(ip("192.168.1.67")));
+
+        let access = AccessList::new(ranges("192.168.1.0/24"), ranges("192.168.1.66"));
+        assert!(!access.permits(ip("192.168.1.66")));
+        assert!(access.permits(ip("192.168.1.20")));
+
+        // With neither list, everyone gets in
+        assert!(AccessList::default().permits(ip("203.0.113.9")));
+    }
+}
@@ -0,0 +1,104 @@
+// Auto-away: after a while without input we tell peers we're away, and that we're back on
+// the next keypress. A STATUS packet is MSG_TYPE_STATUS, name, node ID, then "away" or
+// "active".
+
+use std::time::{Duration, Instant};
+
+const STATUS_AWAY: &str = "away";
+const STATUS_ACTIVE: &str = "active";
+
+pub fn status_content(away: bool) -> &'static str {
+    if away {
+        STATUS_AWAY
+    } else {
+        STATUS_ACTIVE
+    }
+}
+
+// Some(true) for away, Some(false) for active, None for anything else
+pub fn parse_status(content: &str) -> Option<bool> {
+    match content {
+        STATUS_AWAY => Some(true),
+        STATUS_ACTIVE => Some(false),
+        _ => None,
+    }
+}
+
+// Decides when we've gone idle and when we're back
+#[derive(Clone)]
+pub struct IdleTracker {
+    last_input: Instant,
+    // None turns auto-away off
+    away_after: Option<Duration>,
+    away: bool,
+}
+
+impl IdleTracker {
+    pub fn new(away_after: Option<Duration>, now: Instant) -> Self {
+        Self {
+            last_input: now,
+            away_after,
+            away: false,
+        }
+    }
+
+    // Called for every keypress or paste
+    pub fn input(&mut self, now: Instant) {
+        self.last_input = now;
+    }
+
+    // Returns Some(true) when we've just gone away, Some(false) when we've just come back,
+    // and None when nothing changed
+    pub fn update(&mut self, now: Instant) -> Option<bool> {
+        let away_after = self.away_after?;
+        let idle = now.saturating_duration_since(self.last_input) >= away_after;
+        if idle == self.away {
+            return None;
+        }
+        self.away = idle;
+        Some(idle)
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.away
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn going_idle_and_coming_back() {
+        let start = Instant::now();
+        let mut idle = IdleTracker::new(Some(Duration::from_secs(300)), start);
+        assert_eq!(idle.update(start + Duration::from_secs(299)), None);
+        assert_eq!(idle.update(start + Duration::from_secs(300)), Some(true));
+        assert!(idle.is_away());
+        // Only the change is reported
+        assert_eq!(idle.update(start + Duration::from_secs(400)), None);
+
+        idle.input(start + Duration::from_secs(500));
+        assert_eq!(idle.update(start + Duration::from_secs(500)), Some(false));
+        assert!(!idle.is_away());
+        assert_eq!(idle.update(start + Duration::from_secs(799)), None);
+        assert_eq!(idle.update(start + Duration::from_secs(800)), Some(true));
+    }
+
+    #[test]
+    fn auto_away_can_be_turned_off() {
+        let start = Instant::now();
+        let mut idle = IdleTracker::new(None, start);
+        assert_eq!(idle.update(start + Duration::from_secs(86_400)), None);
+        assert!(!idle.is_away());
+    }
+
+    #[test]
+    fn statuses_round_trip() {
+        for away in [true, false] {
+            assert_eq!(parse_status(status_content(away)), Some(away));
+        }
+        assert_eq!(parse_status("busy"), None);
+        assert_eq!(parse_status("AWAY"), None);
+    }
+}
@@ -0,0 +1,109 @@
+// Spacing of discovery requests. They start frequent so a new client finds peers quickly,
+// double until the steady interval, and slow down further while peers are known. Losing
+// every peer starts over from the shortest interval. Each interval is jittered so clients
+// started at the same moment drift apart instead of flooding the network together.
+
+use crate::random::random_unit;
+use std::time::Duration;
+
+// Intervals vary by up to this fraction either way
+const JITTER: f64 = 0.2;
+
+#[derive(Clone, Debug)]
+pub struct DiscoveryBackoff {
+    min: Duration,
+    // Where backing off stops while we have no peers
+    steady: Duration,
+    // Where it stops once peers are known
+    max: Duration,
+    // The next interval before jitter
+    current: Duration,
+    had_peers: bool,
+}
+
+impl DiscoveryBackoff {
+    // The bounds are put in order, so a steady interval below the minimum is raised to it
+    pub fn new(min: Duration, steady: Duration, max: Duration) -> Self {
+        let steady = steady.max(min);
+        let max = max.max(steady);
+
+        Self {
+            min,
+            steady,
+            max,
+            current: min,
+            had_peers: false,
+        }
+    }
+
+    // True when the peer list has emptied since the last interval, so discovery should
+    // speed up without waiting out a long interval
+    pub fn lost_peers(&self, has_peers: bool) -> bool {
+        self.had_peers && !has_peers
+    }
+
+    pub fn next_interval(&mut self, has_peers: bool) -> Duration {
+        self.next_interval_with(has_peers, random_unit())
+    }
+
+    // Takes the random sample, in [0, 1), that picks the jitter
+    pub fn next_interval_with(&mut self, has_peers: bool, sample: f64) -> Duration {
+        if self.lost_peers(has_peers) {
+            self.current = self.min;
+        }
+        self.had_peers = has_peers;
+
+        let cap = if has_peers { self.max } else { self.steady };
+        let interval = self.current.min(cap);
+        self.current = interval.saturating_mul(2).min(cap);
+
+        let factor = 1.0 - JITTER + 2.0 * JITTER * sample.clamp(0.0, 1.0);
+        interval.mul_f64(factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn intervals_grow_to_the_bounds() {
+        let mut backoff = DiscoveryBackoff::new(secs(1), secs(8), secs(32));
+        let unjittered: Vec<Duration> = (0..6)
+            .map(|_| backoff.next_interval_with(false, 0.5))
+            .collect();
+        assert_eq!(
+            unjittered,
+            [secs(1), secs(2), secs(4), secs(8), secs(8), secs(8)]
+        );
+
+        // Known peers let it keep going to the maximum
+        let with_peers: Vec<Duration> = (0..4)
+            .map(|_| backoff.next_interval_with(true, 0.5))
+            .collect();
+        assert_eq!(with_peers, [secs(8), secs(16), secs(32), secs(32)]);
+
+        // Losing them starts over
+        assert!(backoff.lost_peers(false));
+        assert_eq!(backoff.next_interval_with(false, 0.5), secs(1));
+    }
+
+    #[test]
+    fn jitter_stays_within_its_fraction() {
+        let mut low = DiscoveryBackoff::new(secs(10), secs(10), secs(10));
+        let mut high = low.clone();
+        assert_eq!(low.next_interval_with(false, 0.0), secs(8));
+        assert!(high.next_interval_with(false, 0.999) < secs(12));
+        assert!(high.next_interval_with(false, 0.999) > secs(11));
+
+        let mut random = DiscoveryBackoff::new(secs(10), secs(10), secs(10));
+        for _ in 0..100 {
+            let interval = random.next_interval(false);
+            assert!(interval >= secs(8) && interval <= secs(12));
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::debug_logger::Level;
+use clap::Parser;
+use std::net::IpAddr;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[command(name = "reticulum", about = "Subnet Vox - P2P chat over UDP")]
+pub struct Args {
+    /// UDP port used for chat messages [default: 2223]
+    #[arg(long, value_name = "PORT")]
+    pub chat_port: Option<u16>,
+
+    /// UDP port used for peer discovery [default: 2224]
+    #[arg(long, value_name = "PORT")]
+    pub discovery_port: Option<u16>,
+
+    /// Bind every socket to this local address instead of all interfaces
+    #[arg(long, value_name = "ADDR")]
+    pub bind: Option<IpAddr>,
+
+    /// Also send and accept chat messages over TCP, for networks that drop UDP
+    #[arg(long)]
+    pub tcp: bool,
+
+    /// TCP port used for chat messages when --tcp is on [default: 2225]
+    #[arg(long, value_name = "PORT")]
+    pub tcp_port: Option<u16>,
+
+    /// Username to join with (prompted for when omitted)
+    #[arg(long)]
+    pub username: Option<String>,
+
+    /// Enable debug logging (also enabled by setting RETICULUM_DEBUG)
+    #[arg(long)]
+    pub debug: bool,
+
+    /// Least important log lines to write: error, warn, info or debug [default: warn]
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<Level>,
+
+    /// Skip the startup intro
+    #[arg(long)]
+    pub no_intro: bool,
+
+    /// Play the startup intro without its pauses
+    #[arg(long)]
+    pub fast_intro: bool,
+
+    /// Encrypt chat with a key derived from this passphrase. Only peers using the same
+    /// passphrase can read your messages, and you only see theirs.
+    #[arg(long, value_name = "PASSPHRASE")]
+    pub key: Option<String>,
+
+    /// Write log output to this file instead of stderr, which gets none while the
+    /// terminal UI is up
+    #[arg(long, value_name = "PATH")]
+    pub debug_log: Option<PathBuf>,
+
+    /// Read settings from this file instead of ~/.config/subnetvox/config.toml
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+
+    /// Append every sent and received message to this file as JSON lines
+    #[arg(long, value_name = "PATH")]
+    pub log: Option<PathBuf>,
+
+    /// Show the most recent messages from the --log file on startup
+    #[arg(long, requires = "log")]
+    pub replay: bool,
+
+    /// Run without the terminal UI: send each line read from stdin and print received
+    /// messages to stdout. Needs a username from --username or the config file.
+    #[arg(long)]
+    pub headless: bool,
+
+    /// Check ports, addresses and peer discovery, print a report of what works and what
+    /// is likely misconfigured, then exit
+    #[arg(long, conflicts_with = "headless")]
+    pub diagnose: bool,
+
+    /// Color theme: default, cyberpunk, mono or light
+    #[arg(long, value_name = "NAME")]
+    pub theme: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    fn parse(args: &[&str]) -> Result<Args, clap::Error> {
+        Args::try_parse_from(std::iter::once("reticulum").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn ports_and_username_are_optional() {
+        let args = parse(&[]).unwrap();
+        assert_eq!(args.chat_port, None);
+        assert_eq!(args.discovery_port, None);
+        assert_eq!(args.username, None);
+        assert!(!args.debug);
+    }
+
+    #[test]
+    fn ports_and_username_are_read() {
+        let args = parse(&[
+            "--chat-port",
+            "3000",
+            "--discovery-port",
+            "3001",
+            "--username",
+            "alice",
+            "--debug",
+        ])
+        .unwrap();
+        assert_eq!(args.chat_port, Some(3000));
+        assert_eq!(args.discovery_port, Some(3001));
+        assert_eq!(args.username.as_deref(), Some("alice"));
+        assert!(args.debug);
+    }
+
+    #[test]
+    fn bad_ports_are_rejected() {
+        assert!(parse(&["--chat-port", "70000"]).is_err());
+        assert!(parse(&["--discovery-port", "port"]).is_err());
+        assert!(parse(&["--chat-port"]).is_err());
+    }
+
+    #[test]
+    fn help_shows_the_default_ports() {
+        let help = Args::command().render_help().to_string();
+        assert!(help.contains("[default: 2223]"));
+        assert!(help.contains("[default: 2224]"));
+    }
+}
@@ -0,0 +1,368 @@
+// Binary wire format for chat messages, replacing the FIELD_SPLITTER-separated text packets.
+// A packet is CODEC_MAGIC, the codec version and a kind byte, followed by the kind's fields.
+// Numbers are big-endian and strings are UTF-8 behind a 4-byte length, so any content
+// survives without escaping.
+// The magic byte can never start valid UTF-8, so peers that only know the text format
+// read these packets as an unknown type and drop them. They are sent text packets instead
+// until they announce BINARY_PROTOCOL_VERSION, and their text packets are still understood.
+// A compressed packet wraps any other chat port packet, binary or text, and only goes to
+// peers that announce COMPRESSION_PROTOCOL_VERSION.
+
+use crate::compress::{compress, decompress};
+use crate::constants::{
+    COMPRESSION_THRESHOLD, MAX_MESSAGE_SIZE, MSG_TYPE_ACTION, MSG_TYPE_CHAT, MSG_TYPE_DM,
+};
+use crate::crypto::{decrypt_content, encrypt_content, sign_content, verify_content, Envelope};
+use crate::fragment::Fragment;
+use crate::message::Message;
+use std::fmt;
+
+const CODEC_MAGIC: u8 = 0xB1;
+const CODEC_VERSION: u8 = 1;
+
+const KIND_CHAT: u8 = 0;
+const KIND_ACTION: u8 = 1;
+const KIND_DIRECT: u8 = 2;
+const KIND_FRAGMENT: u8 = 3;
+const KIND_COMPRESSED: u8 = 4;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    // Doesn't start with CODEC_MAGIC, most likely a text packet
+    NotBinary,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    // Ends in the middle of a field
+    Truncated,
+    // Bytes left over after the last field
+    TrailingBytes,
+    InvalidUtf8,
+    // Compressed data that doesn't decompress, or wraps another compressed packet
+    BadCompression,
+    // Content sealed with a key other than ours
+    Undecryptable,
+    // Content whose authentication tag is missing or doesn't match the sender
+    Unauthenticated,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::NotBinary => write!(f, "not a binary packet"),
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported codec version {}", version)
+            }
+            DecodeError::UnknownKind(kind) => write!(f, "unknown packet kind {}", kind),
+            DecodeError::Truncated => write!(f, "packet ends in the middle of a field"),
+            DecodeError::TrailingBytes => write!(f, "unexpected bytes after the last field"),
+            DecodeError::InvalidUtf8 => write!(f, "text field is not valid UTF-8"),
+            DecodeError::BadCompression => write!(f, "compressed data is corrupt"),
+            DecodeError::Undecryptable => write!(f, "content doesn't decrypt with our key"),
+            DecodeError::Unauthenticated => {
+                write!(f, "missing or invalid authentication tag")
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+pub fn is_binary(packet: &[u8]) -> bool {
+    packet.first() == Some(&CODEC_MAGIC)
+}
+
+pub fn is_fragment(packet: &[u8]) -> bool {
+    is_binary(packet) && packet.get(2) == Some(&KIND_FRAGMENT)
+}
+
+pub fn is_compressed(packet: &[u8]) -> bool {
+    is_binary(packet) && packet.get(2) == Some(&KIND_COMPRESSED)
+}
+
+// The packet compressed, or None when it's too small to be worth it or doesn't shrink
+pub fn compress_packet(packet: &[u8]) -> Option<Vec<u8>> {
+    if packet.len() < COMPRESSION_THRESHOLD {
+        return None;
+    }
+    let mut compressed = vec![CODEC_MAGIC, CODEC_VERSION, KIND_COMPRESSED];
+    compressed.extend_from_slice(&compress(packet));
+    (compressed.len() < packet.len()).then_some(compressed)
+}
+
+// The packet a compressed one wraps, which can't be larger than a message may be
+pub fn decompress_packet(packet: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let mut reader = Reader::new(packet)?;
+    let kind = reader.u8()?;
+    if kind != KIND_COMPRESSED {
+        return Err(DecodeError::UnknownKind(kind));
+    }
+    let inner = decompress(reader.rest(), MAX_MESSAGE_SIZE).ok_or(DecodeError::BadCompression)?;
+    if is_compressed(&inner) {
+        return Err(DecodeError::BadCompression);
+    }
+    Ok(inner)
+}
+
+// Content is sealed and tagged with the shared key when encryption is on, like in the text
+// format
+pub fn encode(message: &Message) -> Vec<u8> {
+    let kind = if message.recipient().is_some() {
+        KIND_DIRECT
+    } else if message.is_action() {
+        KIND_ACTION
+    } else {
+        KIND_CHAT
+    };
+
+    let mut packet = vec![CODEC_MAGIC, CODEC_VERSION, kind];
+    packet.extend_from_slice(&message.id().to_be_bytes());
+    packet.extend_from_slice(&message.timestamp().to_be_bytes());
+    put_str(&mut packet, message.sender_name());
+    put_str(&mut packet, message.sender_ip());
+    if let Some(recipient) = message.recipient() {
+        put_str(&mut packet, recipient);
+    }
+    let envelope = Envelope {
+        msg_type: message.msg_type(),
+        id: message.id(),
+        node: message.sender_ip(),
+        name: message.sender_name(),
+        timestamp: message.timestamp(),
+        recipient: message.recipient(),
+    };
+    put_str(
+        &mut packet,
+        &sign_content(&envelope, &encrypt_content(message.content())),
+    );
+    packet
+}
+
+// The message as its sender encoded it, with the sender's node ID in place of the IP
+pub fn decode(packet: &[u8]) -> Result<Message, DecodeError> {
+    let mut reader = Reader::new(packet)?;
+    let kind = reader.u8()?;
+    if !matches!(kind, KIND_CHAT | KIND_ACTION | KIND_DIRECT) {
+        return Err(DecodeError::UnknownKind(kind));
+    }
+
+    let id = u64::from_be_bytes(reader.array()?);
+    let timestamp = i64::from_be_bytes(reader.array()?);
+    let sender_name = reader.string()?;
+    let sender_node = reader.string()?;
+    let recipient = match kind {
+        KIND_DIRECT => Some(reader.string()?),
+        _ => None,
+    };
+    let content = reader.string()?;
+    // The text format's type for the kind, which the tag covers in both formats
+    let msg_type = match kind {
+        KIND_DIRECT => MSG_TYPE_DM,
+        KIND_ACTION => MSG_TYPE_ACTION,
+        _ => MSG_TYPE_CHAT,
+    };
+    let envelope = Envelope {
+        msg_type,
+        id,
+        node: &sender_node,
+        name: &sender_name,
+        timestamp,
+        recipient: recipient.as_deref(),
+    };
+    let content = verify_content(&envelope, &content).ok_or(DecodeError::Unauthenticated)?;
+    let content = decrypt_content(content).ok_or(DecodeError::Undecryptable)?;
+    reader.finish()?;
+
+    let mut message = Message::new(content, sender_name, sender_node)
+        .with_id(id)
+        .with_timestamp(timestamp);
+    if let Some(recipient) = recipient {
+        message = message.with_recipient(recipient);
+    }
+    if kind == KIND_ACTION {
+        message = message.into_action();
+    }
+    Ok(message)
+}
+
+// One piece of an encoded packet too large for a single datagram
+pub fn encode_fragment(id: u64, seq: u16, total: u16, chunk: &[u8]) -> Vec<u8> {
+    let mut packet = vec![CODEC_MAGIC, CODEC_VERSION, KIND_FRAGMENT];
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&total.to_be_bytes());
+    packet.extend_from_slice(chunk);
+    packet
+}
+
+pub fn decode_fragment(packet: &[u8]) -> Result<Fragment, DecodeError> {
+    let mut reader = Reader::new(packet)?;
+    let kind = reader.u8()?;
+    if kind != KIND_FRAGMENT {
+        return Err(DecodeError::UnknownKind(kind));
+    }
+
+    Ok(Fragment {
+        id: u64::from_be_bytes(reader.array()?),
+        seq: usize::from(u16::from_be_bytes(reader.array()?)),
+        total: usize::from(u16::from_be_bytes(reader.array()?)),
+        // The chunk runs to the end of the packet
+        chunk: reader.rest().to_vec(),
+    })
+}
+
+fn put_str(packet: &mut Vec<u8>, field: &str) {
+    packet.extend_from_slice(&(field.len() as u32).to_be_bytes());
+    packet.extend_from_slice(field.as_bytes());
+}
+
+struct Reader<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    // Checks the header, leaving the reader at the kind byte
+    fn new(packet: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Reader { rest: packet };
+        if reader.u8().ok() != Some(CODEC_MAGIC) {
+            return Err(DecodeError::NotBinary);
+        }
+        let version = reader.u8()?;
+        if version != CODEC_VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        Ok(reader)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.rest.len() < len {
+            return Err(DecodeError::Truncated);
+        }
+        let (bytes, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        let mut array = [0u8; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn string(&mut self) -> Result<String, DecodeError> {
+        let len = u32::from_be_bytes(self.array()?) as usize;
+        let bytes = self.bytes(len)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        std::mem::take(&mut self.rest)
+    }
+
+    fn finish(&self) -> Result<(), DecodeError> {
+        if self.rest.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::Receiver;
+    use crate::random::random_u64;
+
+    // Characters the text format had trouble with, plus multi-byte ones
+    const ALPHABET: [char; 10] = ['a', 'Z', ' ', '~', '\\', '\0', '\n', 'é', '日', '🦀'];
+
+    fn random_text(max_chars: u64) -> String {
+        (0..random_u64() % (max_chars + 1))
+            .map(|_| ALPHABET[(random_u64() % ALPHABET.len() as u64) as usize])
+            .collect()
+    }
+
+    fn random_message() -> Message {
+        let mut message = Message::new(random_text(200), random_text(16), random_text(16))
+            .with_id(random_u64())
+            .with_timestamp(random_u64() as i64);
+        match random_u64() % 3 {
+            0 => message = message.with_recipient(random_text(16)),
+            1 => message = message.into_action(),
+            _ => {}
+        }
+        message
+    }
+
+    #[test]
+    fn arbitrary_messages_round_trip() {
+        for _ in 0..500 {
+            let message = random_message();
+            let decoded = decode(&encode(&message)).unwrap();
+            assert_eq!(decoded.id(), message.id());
+            assert_eq!(decoded.timestamp(), message.timestamp());
+            assert_eq!(decoded.content(), message.content());
+            assert_eq!(decoded.sender_name(), message.sender_name());
+            assert_eq!(decoded.sender_ip(), message.sender_ip());
+            assert_eq!(decoded.recipient(), message.recipient());
+            assert_eq!(decoded.is_action(), message.is_action());
+        }
+    }
+
+    #[test]
+    fn damaged_packets_are_rejected() {
+        let packet = encode(&Message::new(
+            "hello".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        ));
+        for end in 0..packet.len() {
+            assert!(decode(&packet[..end]).is_err());
+        }
+
+        let mut longer = packet.clone();
+        longer.push(0);
+        assert_eq!(decode(&longer).unwrap_err(), DecodeError::TrailingBytes);
+        assert_eq!(decode(b"CHAT~hi").unwrap_err(), DecodeError::NotBinary);
+        let mut future = packet.clone();
+        future[1] = CODEC_VERSION + 1;
+        assert_eq!(
+            decode(&future).unwrap_err(),
+            DecodeError::UnsupportedVersion(CODEC_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn fragments_round_trip() {
+        let packet = encode_fragment(42, 1, 3, b"\xB1chunk");
+        assert!(is_fragment(&packet));
+        let fragment = decode_fragment(&packet).unwrap();
+        assert_eq!((fragment.id, fragment.seq, fragment.total), (42, 1, 3));
+        assert_eq!(fragment.chunk, b"\xB1chunk");
+    }
+
+    #[test]
+    fn large_packets_are_compressed_and_small_ones_left_alone() {
+        let message = Message::new(
+            "the same words over and over ".repeat(100),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        let packet = message.encode_packet().into_bytes();
+        assert!(packet.len() >= COMPRESSION_THRESHOLD);
+
+        let compressed = compress_packet(&packet).unwrap();
+        assert!(is_compressed(&compressed));
+        assert!(compressed.len() < packet.len() / 4);
+        assert_eq!(decompress_packet(&compressed).unwrap(), packet);
+        let parsed = Receiver::parse_message(&compressed).unwrap();
+        assert_eq!(parsed.content, message.content());
+
+        let short = Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string());
+        assert_eq!(compress_packet(short.encode_packet().as_bytes()), None);
+    }
+}
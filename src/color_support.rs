@@ -0,0 +1,206 @@
+// How many colors the terminal can show, read from the environment at startup. Theme
+// colors beyond that are mapped to the nearest one it has, and with no color support at
+// all everything is drawn in the terminal's own colors.
+
+use crossterm::style::Color;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ColorSupport {
+    None,
+    // The 16 named colors
+    Basic,
+    Ansi256,
+    TrueColor,
+}
+
+// The 16 named colors with the RGB values terminals commonly use for them
+const BASIC_COLORS: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)),
+    (Color::DarkRed, (128, 0, 0)),
+    (Color::DarkGreen, (0, 128, 0)),
+    (Color::DarkYellow, (128, 128, 0)),
+    (Color::DarkBlue, (0, 0, 128)),
+    (Color::DarkMagenta, (128, 0, 128)),
+    (Color::DarkCyan, (0, 128, 128)),
+    (Color::Grey, (192, 192, 192)),
+    (Color::DarkGrey, (128, 128, 128)),
+    (Color::Red, (255, 0, 0)),
+    (Color::Green, (0, 255, 0)),
+    (Color::Yellow, (255, 255, 0)),
+    (Color::Blue, (0, 0, 255)),
+    (Color::Magenta, (255, 0, 255)),
+    (Color::Cyan, (0, 255, 255)),
+    (Color::White, (255, 255, 255)),
+];
+
+// Levels of the 6x6x6 color cube in the 256-color palette
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+pub fn detect() -> ColorSupport {
+    let var = |name| std::env::var(name).ok();
+    from_env(
+        var("NO_COLOR").as_deref(),
+        var("COLORTERM").as_deref(),
+        var("TERM").as_deref(),
+    )
+}
+
+// NO_COLOR turns color off whatever the terminal is (see no-color.org). COLORTERM is set
+// by terminals with 24-bit color, and TERM names the rest. Without TERM, Windows consoles
+// still have color, anything else is assumed to be a bare console.
+pub fn from_env(
+    no_color: Option<&str>,
+    colorterm: Option<&str>,
+    term: Option<&str>,
+) -> ColorSupport {
+    if no_color.is_some_and(|value| !value.is_empty()) {
+        return ColorSupport::None;
+    }
+    if let Some("truecolor" | "24bit") = colorterm.map(str::to_lowercase).as_deref() {
+        return ColorSupport::TrueColor;
+    }
+
+    let term = match term.map(str::to_lowercase) {
+        Some(term) if !term.is_empty() => term,
+        _ if cfg!(windows) => return ColorSupport::TrueColor,
+        _ => return ColorSupport::None,
+    };
+    if term == "dumb" || term.starts_with("vt") {
+        ColorSupport::None
+    } else if term.ends_with("-direct") || term.contains("truecolor") {
+        ColorSupport::TrueColor
+    } else if term.contains("256color") {
+        ColorSupport::Ansi256
+    } else {
+        ColorSupport::Basic
+    }
+}
+
+// The color to draw with at this level of support. Reset, the terminal's own color, is
+// used for everything when there's no color at all.
+pub fn downgrade(color: Color, support: ColorSupport) -> Color {
+    match (color, support) {
+        (_, ColorSupport::None) => Color::Reset,
+        (Color::Rgb { r, g, b }, ColorSupport::Ansi256) => Color::AnsiValue(nearest_ansi(r, g, b)),
+        (Color::Rgb { r, g, b }, ColorSupport::Basic) => nearest_basic((r, g, b)),
+        (Color::AnsiValue(value), ColorSupport::Basic) => nearest_basic(ansi_rgb(value)),
+        (color, _) => color,
+    }
+}
+
+// The closest entry in the color cube or the gray ramp of the 256-color palette
+fn nearest_ansi(r: u8, g: u8, b: u8) -> u8 {
+    let level = |value: u8| nearest_index(&CUBE_LEVELS, value) as u8;
+    let cube = 16 + 36 * level(r) + 6 * level(g) + level(b);
+
+    let average = ((u16::from(r) + u16::from(g) + u16::from(b)) / 3) as u8;
+    let gray = 232 + (average.saturating_sub(3) / 10).min(23);
+
+    if distance((r, g, b), ansi_rgb(gray)) < distance((r, g, b), ansi_rgb(cube)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+fn nearest_basic(rgb: (u8, u8, u8)) -> Color {
+    BASIC_COLORS
+        .iter()
+        .min_by_key(|(_, basic)| distance(rgb, *basic))
+        .map_or(Color::Reset, |(color, _)| *color)
+}
+
+// RGB of an entry in the 256-color palette
+fn ansi_rgb(value: u8) -> (u8, u8, u8) {
+    match value {
+        0..=15 => BASIC_COLORS[value as usize].1,
+        16..=231 => {
+            let index = value - 16;
+            (
+                CUBE_LEVELS[(index / 36) as usize],
+                CUBE_LEVELS[(index / 6 % 6) as usize],
+                CUBE_LEVELS[(index % 6) as usize],
+            )
+        }
+        _ => {
+            let level = 8 + (value - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+fn nearest_index(levels: &[u8], value: u8) -> usize {
+    (0..levels.len())
+        .min_by_key(|&i| levels[i].abs_diff(value))
+        .unwrap_or(0)
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let channel = |x: u8, y: u8| u32::from(x.abs_diff(y)).pow(2);
+    channel(a.0, b.0) + channel(a.1, b.1) + channel(a.2, b.2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environment_maps_to_a_level() {
+        assert_eq!(
+            from_env(None, Some("truecolor"), Some("xterm")),
+            ColorSupport::TrueColor
+        );
+        assert_eq!(from_env(None, Some("24BIT"), None), ColorSupport::TrueColor);
+        assert_eq!(
+            from_env(None, None, Some("xterm-direct")),
+            ColorSupport::TrueColor
+        );
+        assert_eq!(
+            from_env(None, None, Some("xterm-256color")),
+            ColorSupport::Ansi256
+        );
+        assert_eq!(
+            from_env(None, Some(""), Some("screen-256color")),
+            ColorSupport::Ansi256
+        );
+        assert_eq!(from_env(None, None, Some("xterm")), ColorSupport::Basic);
+        assert_eq!(from_env(None, None, Some("linux")), ColorSupport::Basic);
+        assert_eq!(from_env(None, None, Some("dumb")), ColorSupport::None);
+        assert_eq!(from_env(None, None, Some("vt100")), ColorSupport::None);
+        // NO_COLOR wins, unless it's empty
+        assert_eq!(
+            from_env(Some("1"), Some("truecolor"), Some("xterm")),
+            ColorSupport::None
+        );
+        assert_eq!(from_env(Some(""), None, Some("xterm")), ColorSupport::Basic);
+    }
+
+    #[test]
+    fn colors_are_downgraded_to_the_nearest_available() {
+        let red = Color::Rgb {
+            r: 250,
+            g: 10,
+            b: 5,
+        };
+        assert_eq!(downgrade(red, ColorSupport::TrueColor), red);
+        assert_eq!(downgrade(red, ColorSupport::Ansi256), Color::AnsiValue(196));
+        assert_eq!(downgrade(red, ColorSupport::Basic), Color::Red);
+        assert_eq!(downgrade(red, ColorSupport::None), Color::Reset);
+
+        let gray = Color::Rgb {
+            r: 128,
+            g: 128,
+            b: 128,
+        };
+        assert_eq!(
+            downgrade(gray, ColorSupport::Ansi256),
+            Color::AnsiValue(244)
+        );
+        assert_eq!(
+            downgrade(Color::AnsiValue(21), ColorSupport::Basic),
+            Color::Blue
+        );
+        // Named colors are already available everywhere there's color
+        assert_eq!(downgrade(Color::Cyan, ColorSupport::Basic), Color::Cyan);
+    }
+}
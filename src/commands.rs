@@ -0,0 +1,339 @@
+// Chat command parsing for input lines starting with '/'
+
+use std::net::{IpAddr, SocketAddr};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Quit,
+    Clear,
+    Users,
+    // Pings one peer, or every known peer when None
+    Ping(Option<String>),
+    Stats,
+    DirectMessage { target: String, text: String },
+    Action(String),
+    // An empty term clears the search
+    Search(String),
+    // Writes the messages on screen to a text file
+    Save(String),
+    // Hides a user's messages, None lists the muted users
+    Mute(Option<String>),
+    Unmute(String),
+    // Changes or removes one of our messages, picked by the start of its ID
+    Edit { id: String, text: String },
+    Delete(String),
+    // Reacts to anyone's message, picked by the start of its ID
+    React { id: String, emoji: String },
+    // Adds a peer by address, the port defaulting to the chat port
+    Connect { ip: IpAddr, port: Option<u16> },
+    // Binds fresh sockets and looks for peers again, after the network changed
+    Reconnect,
+    // Turns do-not-disturb on or off, None just reports whether it's on
+    DoNotDisturb(Option<bool>),
+    // Shows or hides the IP in front of remote messages, None just reports which
+    ShowIp(Option<bool>),
+    // A known command used with missing or bad arguments, with the usage to show
+    Invalid(String),
+    Unknown(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommandResult {
+    // The input was a command and has been fully handled locally
+    Handled,
+    // The user asked to leave the application
+    Quit,
+    // The input is a regular chat message and should be broadcast
+    NotCommand,
+}
+
+// Command usage with a short description, shown by /help
+pub const COMMAND_DESCRIPTIONS: [(&str, &str); 19] = [
+    ("/help", "show the commands and keys"),
+    ("/quit", "leave the chat and exit"),
+    ("/clear", "clear the message area"),
+    ("/users", "list known peers"),
+    (
+        "/ping [peer]",
+        "measure the round trip to a peer, or to all of them",
+    ),
+    ("/stats", "show message and traffic counters"),
+    ("/dm <peer> <message>", "send a private message to one peer"),
+    ("/me <action>", "describe what you're doing, e.g. /me waves"),
+    (
+        "/search [term]",
+        "find messages, repeat for older ones, no term to clear",
+    ),
+    ("/dnd [on|off]", "keep mentions from ringing the bell"),
+    (
+        "/showip [on|off]",
+        "show senders' IP addresses, or only their names",
+    ),
+    ("/save <path>", "write the conversation to a text file"),
+    (
+        "/mute [name]",
+        "hide a user's messages, or list muted users",
+    ),
+    ("/unmute <name>", "show a muted user's messages again"),
+    (
+        "/connect <ip>[:port]",
+        "add a peer discovery can't find and introduce yourself",
+    ),
+    (
+        "/reconnect",
+        "rebind the sockets and look for peers again after a network change",
+    ),
+    (
+        "/edit <id> <message>",
+        "change one of your messages, by the ID shown after it",
+    ),
+    ("/delete <id>", "remove one of your messages for everyone"),
+    (
+        "/react <id> <emoji>",
+        "react to a message, by the ID shown after it",
+    ),
+];
+
+// The argument of commands that turn something on or off. Some(None) when there's none,
+// which asks for the current setting, and None for anything but on and off.
+fn parse_switch(args: &str) -> Option<Option<bool>> {
+    match args.to_lowercase().as_str() {
+        "" => Some(None),
+        "on" => Some(Some(true)),
+        "off" => Some(Some(false)),
+        _ => None,
+    }
+}
+
+impl Command {
+    // Returns None when the input is not a command at all
+    pub fn parse(input: &str) -> Option<Command> {
+        let input = input.trim();
+        if !input.starts_with('/') {
+            return None;
+        }
+
+        let mut parts = input.splitn(2, char::is_whitespace);
+        let name = parts.next().unwrap_or_default();
+        let args = parts.next().unwrap_or_default().trim();
+
+        let command = match name.to_lowercase().as_str() {
+            "/help" => Command::Help,
+            "/quit" => Command::Quit,
+            "/clear" => Command::Clear,
+            "/users" => Command::Users,
+            "/ping" if args.is_empty() => Command::Ping(None),
+            "/ping" => Command::Ping(Some(args.to_string())),
+            "/stats" => Command::Stats,
+            "/dm" => Self::parse_direct_message(args),
+            "/me" if args.is_empty() => Command::Invalid("Usage: /me <action>".to_string()),
+            "/me" => Command::Action(args.to_string()),
+            "/search" => Command::Search(args.to_string()),
+            "/dnd" => match parse_switch(args) {
+                Some(on) => Command::DoNotDisturb(on),
+                None => Command::Invalid("Usage: /dnd [on|off]".to_string()),
+            },
+            "/showip" => match parse_switch(args) {
+                Some(on) => Command::ShowIp(on),
+                None => Command::Invalid("Usage: /showip [on|off]".to_string()),
+            },
+            "/save" if args.is_empty() => Command::Invalid("Usage: /save <path>".to_string()),
+            "/save" => Command::Save(args.to_string()),
+            "/mute" if args.is_empty() => Command::Mute(None),
+            "/mute" => Command::Mute(Some(args.to_string())),
+            "/unmute" if args.is_empty() => Command::Invalid("Usage: /unmute <name>".to_string()),
+            "/unmute" => Command::Unmute(args.to_string()),
+            "/connect" => Self::parse_connect(args),
+            "/reconnect" => Command::Reconnect,
+            "/edit" => Self::parse_edit(args),
+            "/delete" if args.is_empty() => Command::Invalid("Usage: /delete <id>".to_string()),
+            "/delete" => Command::Delete(args.to_string()),
+            "/react" => Self::parse_react(args),
+            _ => Command::Unknown(name.to_string()),
+        };
+
+        Some(command)
+    }
+
+    // Takes an IP address, optionally with a port. IPv6 addresses with a port need brackets,
+    // as in [fe80::1]:2223.
+    fn parse_connect(args: &str) -> Command {
+        if let Ok(addr) = args.parse::<SocketAddr>() {
+            if addr.port() != 0 {
+                return Command::Connect {
+                    ip: addr.ip(),
+                    port: Some(addr.port()),
+                };
+            }
+        } else if let Ok(ip) = args.parse::<IpAddr>() {
+            return Command::Connect { ip, port: None };
+        }
+        Command::Invalid("Usage: /connect <ip>[:port]".to_string())
+    }
+
+    fn parse_edit(args: &str) -> Command {
+        let mut parts = args.splitn(2, char::is_whitespace);
+        let id = parts.next().unwrap_or_default();
+        let text = parts.next().unwrap_or_default().trim();
+
+        if id.is_empty() || text.is_empty() {
+            return Command::Invalid("Usage: /edit <id> <message>".to_string());
+        }
+
+        Command::Edit {
+            id: id.to_string(),
+            text: text.to_string(),
+        }
+    }
+
+    fn parse_react(args: &str) -> Command {
+        let mut parts = args.split_whitespace();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(id), Some(emoji), None) => Command::React {
+                id: id.to_string(),
+                emoji: emoji.to_string(),
+            },
+            _ => Command::Invalid("Usage: /react <id> <emoji>".to_string()),
+        }
+    }
+
+    fn parse_direct_message(args: &str) -> Command {
+        let mut parts = args.splitn(2, char::is_whitespace);
+        let target = parts.next().unwrap_or_default();
+        let text = parts.next().unwrap_or_default().trim();
+
+        if target.is_empty() || text.is_empty() {
+            return Command::Invalid("Usage: /dm <peer> <message>".to_string());
+        }
+
+        Command::DirectMessage {
+            target: target.to_string(),
+            text: text.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Command {
+        Command::parse(input).unwrap()
+    }
+
+    #[test]
+    fn chat_text_is_not_a_command() {
+        assert_eq!(Command::parse("hello /help"), None);
+        assert_eq!(Command::parse(""), None);
+    }
+
+    #[test]
+    fn commands_without_arguments() {
+        assert_eq!(parse("/help"), Command::Help);
+        assert_eq!(parse("/quit"), Command::Quit);
+        assert_eq!(parse("/clear"), Command::Clear);
+        assert_eq!(parse("/users"), Command::Users);
+        // Case and surrounding whitespace don't matter, extra arguments are ignored
+        assert_eq!(parse("  /HELP  "), Command::Help);
+        assert_eq!(parse("/quit now"), Command::Quit);
+    }
+
+    #[test]
+    fn ping_takes_an_optional_peer() {
+        assert_eq!(parse("/ping"), Command::Ping(None));
+        assert_eq!(parse("/ping   "), Command::Ping(None));
+        assert_eq!(parse("/ping bob"), Command::Ping(Some("bob".to_string())));
+        assert_eq!(
+            parse("/ping  10.0.0.2 "),
+            Command::Ping(Some("10.0.0.2".to_string()))
+        );
+    }
+
+    #[test]
+    fn dnd_takes_an_optional_switch() {
+        assert_eq!(parse("/dnd"), Command::DoNotDisturb(None));
+        assert_eq!(parse("/dnd on"), Command::DoNotDisturb(Some(true)));
+        assert_eq!(parse("/dnd OFF"), Command::DoNotDisturb(Some(false)));
+        assert!(matches!(parse("/dnd maybe"), Command::Invalid(_)));
+    }
+
+    #[test]
+    fn showip_takes_an_optional_switch() {
+        assert_eq!(parse("/showip"), Command::ShowIp(None));
+        assert_eq!(parse("/showip on"), Command::ShowIp(Some(true)));
+        assert_eq!(parse("/showip off"), Command::ShowIp(Some(false)));
+        assert!(matches!(parse("/showip names"), Command::Invalid(_)));
+    }
+
+    #[test]
+    fn connect_takes_an_address_and_optional_port() {
+        let ip = |text: &str| text.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            parse("/connect 10.0.0.2"),
+            Command::Connect {
+                ip: ip("10.0.0.2"),
+                port: None
+            }
+        );
+        assert_eq!(
+            parse("/connect 10.0.0.2:4000"),
+            Command::Connect {
+                ip: ip("10.0.0.2"),
+                port: Some(4000)
+            }
+        );
+        assert_eq!(
+            parse("/connect fd7a::1"),
+            Command::Connect {
+                ip: ip("fd7a::1"),
+                port: None
+            }
+        );
+        assert_eq!(
+            parse("/connect [fd7a::1]:4000"),
+            Command::Connect {
+                ip: ip("fd7a::1"),
+                port: Some(4000)
+            }
+        );
+        for bad in [
+            "/connect",
+            "/connect bob",
+            "/connect 10.0.0.2:0",
+            "/connect 10.0.0.2:99999",
+        ] {
+            assert!(matches!(parse(bad), Command::Invalid(_)), "{} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn mute_and_unmute_take_a_name() {
+        assert_eq!(parse("/mute"), Command::Mute(None));
+        assert_eq!(parse("/mute eve"), Command::Mute(Some("eve".to_string())));
+        assert_eq!(parse("/unmute eve"), Command::Unmute("eve".to_string()));
+        assert!(matches!(parse("/unmute"), Command::Invalid(_)));
+    }
+
+    #[test]
+    fn unknown_commands_keep_their_name() {
+        assert_eq!(
+            parse("/frobnicate x"),
+            Command::Unknown("/frobnicate".to_string())
+        );
+        assert_eq!(parse("/"), Command::Unknown("/".to_string()));
+    }
+
+    // Every command /help lists is one parse knows
+    #[test]
+    fn described_commands_all_parse() {
+        for (usage, _) in COMMAND_DESCRIPTIONS {
+            let name = usage.split_whitespace().next().unwrap();
+            assert!(
+                !matches!(parse(name), Command::Unknown(_)),
+                "{} is unknown",
+                name
+            );
+        }
+    }
+}
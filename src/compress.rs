@@ -0,0 +1,59 @@
+// Raw DEFLATE (RFC 1951) for large packets, so long messages and history take fewer
+// fragments. Decompressing stops past a size limit, so a small packet can't expand into an
+// unbounded amount of memory.
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use std::io::{Read, Write};
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(input)
+        .and_then(|()| encoder.finish())
+        .expect("compressing into memory can't fail")
+}
+
+// None when the data is corrupt or would come out longer than max_len
+pub fn decompress(input: &[u8], max_len: usize) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    DeflateDecoder::new(input)
+        .take((max_len as u64).saturating_add(1))
+        .read_to_end(&mut output)
+        .ok()?;
+    (output.len() <= max_len).then_some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_comes_back_unchanged() {
+        let repetitive = "lunch at noon? ".repeat(200).into_bytes();
+        let compressed = compress(&repetitive);
+        assert!(compressed.len() < repetitive.len() / 10);
+        assert_eq!(decompress(&compressed, usize::MAX).unwrap(), repetitive);
+
+        // Long runs, data with nothing to match, and nothing at all
+        for input in [vec![7u8; 1000], (0..=255).collect(), Vec::new()] {
+            assert_eq!(decompress(&compress(&input), usize::MAX).unwrap(), input);
+        }
+    }
+
+    #[test]
+    fn corrupt_or_oversized_data_is_refused() {
+        let compressed = compress(&[b'a'; 100]);
+        assert_eq!(decompress(&compressed, 99), None);
+        assert_eq!(
+            decompress(&compressed, 100).map(|data| data.len()),
+            Some(100)
+        );
+        // A final block of the reserved type
+        assert_eq!(decompress(&[0x07], usize::MAX), None);
+        // Cut off before the end of the stream
+        let long = compress(&(0..=255).cycle().take(4096).collect::<Vec<u8>>());
+        assert_eq!(decompress(&long[..long.len() / 2], usize::MAX), None);
+    }
+}
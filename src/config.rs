@@ -0,0 +1,806 @@
+// Runtime settings loaded from ~/.config/subnetvox/config.toml, read with the toml crate.
+// Keys inside a table are named table.key, and the list settings take either a comma
+// separated string or an array of strings. Missing keys keep the defaults from
+// constants.rs, and command-line arguments override the file.
+
+use crate::access::{parse_ranges, Cidr};
+use crate::cli::Args;
+use crate::constants::{
+    AWAY_AFTER_SECS, BROADCAST_ADDR, CHAT_PORT, DEFAULT_TIME_FORMAT, DIM_AFTER_SECS,
+    DISCOVERY_INTERVAL_SECS, DISCOVERY_MAX_INTERVAL_SECS, DISCOVERY_MIN_INTERVAL_SECS,
+    DISCOVERY_PORT, DO_BULLSHIT_INTRO, EVENT_QUEUE_SIZE, HISTORY_BATCH_MAX, HISTORY_SYNC_COUNT,
+    INPUT_HISTORY_SIZE, MAX_CONTENT_CHARS, MAX_MESSAGES_PER_SEC, MAX_MESSAGE_LENGTH,
+    MAX_MESSAGE_LINES, MAX_NAME_WIDTH, MAX_RECV_BUFFER_SIZE, MAX_RETRANSMITS, MIN_RECV_BUFFER_SIZE,
+    MIN_SEND_INTERVAL_MS, MULTICAST_GROUP_V4, MULTICAST_GROUP_V6, RECV_BUFFER_SIZE,
+    RETRANSMIT_DELAY_MS, TAILSCALE_CGNAT_FIRST, TAILSCALE_CGNAT_LAST, TAILSCALE_SWEEP_FIRST,
+    TAILSCALE_SWEEP_LAST, TCP_PORT,
+};
+use crate::debug_logger::{Level, DEFAULT_LOG_LEVEL};
+use crate::keys::{parse_keys, Action, KeyBindings};
+use crate::theme::{Theme, DEFAULT_THEME};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+use std::str::FromStr;
+use toml::de::{DeTable, DeValue};
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub chat_port: u16,
+    pub discovery_port: u16,
+    // Bounds of the discovery backoff: the starting interval, the one settled on while
+    // alone, and the one settled on once peers are known
+    pub discovery_min_interval_secs: u64,
+    pub discovery_interval_secs: u64,
+    pub discovery_max_interval_secs: u64,
+    // Prompted for at startup when not set
+    pub username: Option<String>,
+    // Messages kept for scrollback, at least a screenful is always kept
+    pub max_message_lines: usize,
+    pub do_intro: bool,
+    // Shows the intro without its pauses
+    pub fast_intro: bool,
+    pub debug: bool,
+    // Least important log lines written, debug turns on every level
+    pub log_level: Level,
+    // Longest message that can be typed, in characters
+    pub max_message_length: usize,
+    // Bytes each listener receives a packet into
+    pub recv_buffer_size: usize,
+    // Columns a sender's name takes at most in the message list
+    pub max_name_width: usize,
+    // Ask the first peer found for its recent messages, and how many
+    pub history_sync: bool,
+    pub history_sync_count: usize,
+    // Least time between two of our broadcasts, 0 for no limit
+    pub min_send_interval_ms: u64,
+    // Times our messages are sent again to peers that haven't acked them, and the wait
+    // before the first time, doubled for each one after
+    pub max_retransmits: u32,
+    pub retransmit_delay_ms: u64,
+    // Messages accepted per second from each source IP
+    pub max_messages_per_sec: u32,
+    // Network events buffered for the UI before the oldest are dropped
+    pub event_queue_size: usize,
+    // Also find peers with mDNS, for networks that drop broadcasts
+    pub mdns: bool,
+    // Also send and accept chat messages over TCP, for networks that drop UDP between hosts.
+    // Discovery stays on UDP.
+    pub tcp: bool,
+    pub tcp_port: u16,
+    // Where broadcasts and discovery requests are sent on the local network
+    pub broadcast_address: Ipv4Addr,
+    // Address our sockets are bound to, for picking one interface on a host with several.
+    // None binds to every interface.
+    pub bind_address: Option<IpAddr>,
+    // Only packets from these ranges are accepted when any are given, and never from the
+    // denylist's (see access.rs)
+    pub allowlist: Vec<Cidr>,
+    pub denylist: Vec<Cidr>,
+    // Multicast groups joined and sent to on each address family
+    pub multicast_group_v4: Ipv4Addr,
+    pub multicast_group_v6: Ipv6Addr,
+    // Second octets of the 100.x.y.2 addresses broadcasts are swept across for Tailscale
+    // peers, inside the CGNAT range Tailscale uses
+    pub tailscale_sweep_first: u8,
+    pub tailscale_sweep_last: u8,
+    // Keep the input history between sessions. Off by default, it holds everything typed,
+    // direct messages included.
+    pub save_history: bool,
+    // Entered lines kept for recall with the arrow keys, and saved with save_history
+    pub history_size: usize,
+    // Keep the /mute list between sessions
+    pub save_muted: bool,
+    // Ring the bell and highlight messages that mention our username
+    pub notify_mentions: bool,
+    // Start with do-not-disturb on, which keeps mentions from ringing the bell
+    pub do_not_disturb: bool,
+    // Put the sender's IP in front of remote messages, only the name otherwise
+    pub show_ip: bool,
+    // Seconds without input before peers are told we're away, 0 turns it off
+    pub away_after_secs: u64,
+    // Messages older than this many seconds are dimmed, 0 for never
+    pub dim_after_secs: u64,
+    // strftime-style format of message timestamps
+    pub time_format: String,
+    // Show the date on the first message of each day
+    pub date_on_new_day: bool,
+    // Expand shortcodes like :smile: in our messages before they're sent
+    pub emoji_shortcodes: bool,
+    // Name of the color theme preset, unknown names fall back to the default theme
+    pub theme: String,
+    // Colors from the [theme] section, applied over the preset in file order
+    pub theme_colors: Vec<(String, String)>,
+    // Keys for the UI actions, the defaults with the [keys] section applied
+    pub key_bindings: KeyBindings,
+}
+
+// How the startup intro is played
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntroMode {
+    Off,
+    Fast,
+    Full,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            chat_port: CHAT_PORT,
+            discovery_port: DISCOVERY_PORT,
+            discovery_min_interval_secs: DISCOVERY_MIN_INTERVAL_SECS,
+            discovery_interval_secs: DISCOVERY_INTERVAL_SECS,
+            discovery_max_interval_secs: DISCOVERY_MAX_INTERVAL_SECS,
+            username: None,
+            max_message_lines: MAX_MESSAGE_LINES,
+            do_intro: DO_BULLSHIT_INTRO,
+            fast_intro: false,
+            debug: false,
+            log_level: DEFAULT_LOG_LEVEL,
+            max_message_length: MAX_MESSAGE_LENGTH,
+            recv_buffer_size: RECV_BUFFER_SIZE,
+            max_name_width: MAX_NAME_WIDTH,
+            history_sync: false,
+            history_sync_count: HISTORY_SYNC_COUNT,
+            min_send_interval_ms: MIN_SEND_INTERVAL_MS,
+            max_retransmits: MAX_RETRANSMITS,
+            retransmit_delay_ms: RETRANSMIT_DELAY_MS,
+            max_messages_per_sec: MAX_MESSAGES_PER_SEC,
+            event_queue_size: EVENT_QUEUE_SIZE,
+            mdns: false,
+            tcp: false,
+            tcp_port: TCP_PORT,
+            broadcast_address: BROADCAST_ADDR,
+            bind_address: None,
+            allowlist: Vec::new(),
+            denylist: Vec::new(),
+            multicast_group_v4: MULTICAST_GROUP_V4,
+            multicast_group_v6: MULTICAST_GROUP_V6,
+            tailscale_sweep_first: TAILSCALE_SWEEP_FIRST,
+            tailscale_sweep_last: TAILSCALE_SWEEP_LAST,
+            save_history: false,
+            history_size: INPUT_HISTORY_SIZE,
+            save_muted: false,
+            notify_mentions: true,
+            do_not_disturb: false,
+            show_ip: true,
+            away_after_secs: AWAY_AFTER_SECS,
+            dim_after_secs: DIM_AFTER_SECS,
+            time_format: DEFAULT_TIME_FORMAT.to_string(),
+            date_on_new_day: true,
+            emoji_shortcodes: true,
+            theme: DEFAULT_THEME.to_string(),
+            theme_colors: Vec::new(),
+            key_bindings: KeyBindings::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    // Floats, dates and tables, which no setting takes, as they were written
+    Other(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::String(value) => write!(f, "\"{}\"", value),
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Boolean(value) => write!(f, "{}", value),
+            Value::Array(values) => {
+                let values: Vec<String> = values.iter().map(Value::to_string).collect();
+                write!(f, "[{}]", values.join(", "))
+            }
+            Value::Other(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+// A problem with the config file, pointing at the line it's on
+#[derive(Debug)]
+pub struct ConfigError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error(line: usize, message: impl Into<String>) -> ConfigError {
+    ConfigError {
+        line,
+        message: message.into(),
+    }
+}
+
+// Line number of a byte offset into the file
+fn line_at(contents: &str, offset: usize) -> usize {
+    contents[..offset.min(contents.len())].matches('\n').count() + 1
+}
+
+fn convert(value: &DeValue, line: usize) -> Result<Value, ConfigError> {
+    Ok(match value {
+        DeValue::String(text) => Value::String(text.to_string()),
+        DeValue::Integer(number) => i64::from_str_radix(number.as_str(), number.radix())
+            .map(Value::Integer)
+            .map_err(|_| error(line, format!("number out of range: {}", number)))?,
+        DeValue::Boolean(value) => Value::Boolean(*value),
+        DeValue::Array(items) => Value::Array(
+            items
+                .iter()
+                .map(|item| convert(item.get_ref(), line))
+                .collect::<Result<_, _>>()?,
+        ),
+        DeValue::Float(number) => Value::Other(number.to_string()),
+        DeValue::Datetime(date) => Value::Other(date.to_string()),
+        DeValue::Table(_) => Value::Other("a table".to_string()),
+    })
+}
+
+// Adds the values of a table, and of the tables inside it, under their dotted keys
+fn flatten(
+    table: &DeTable,
+    prefix: &str,
+    contents: &str,
+    values: &mut HashMap<String, (usize, Value)>,
+) -> Result<(), ConfigError> {
+    for (key, value) in table {
+        let line = line_at(contents, key.span().start);
+        let key = if prefix.is_empty() {
+            key.get_ref().to_string()
+        } else {
+            format!("{}.{}", prefix, key.get_ref())
+        };
+
+        match value.get_ref() {
+            DeValue::Table(inner) => flatten(inner, &key, contents, values)?,
+            value => {
+                values.insert(key, (line, convert(value, line)?));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Reads every value in the file. Keys inside a table are returned as table.key.
+pub fn parse_values(contents: &str) -> Result<HashMap<String, (usize, Value)>, ConfigError> {
+    let table = DeTable::parse(contents).map_err(|e| {
+        let line = e.span().map_or(1, |span| line_at(contents, span.start));
+        error(line, e.message().trim())
+    })?;
+
+    let mut values = HashMap::new();
+    flatten(table.get_ref(), "", contents, &mut values)?;
+    Ok(values)
+}
+
+fn expect_string(key: &str, line: usize, value: Value) -> Result<String, ConfigError> {
+    match value {
+        Value::String(value) => Ok(value),
+        other => Err(error(
+            line,
+            format!("{} must be a string, got {}", key, other),
+        )),
+    }
+}
+
+fn expect_bool(key: &str, line: usize, value: Value) -> Result<bool, ConfigError> {
+    match value {
+        Value::Boolean(value) => Ok(value),
+        other => Err(error(
+            line,
+            format!("{} must be true or false, got {}", key, other),
+        )),
+    }
+}
+
+// Integers are range checked against the field's type
+fn expect_integer<T: TryFrom<i64>>(key: &str, line: usize, value: Value) -> Result<T, ConfigError> {
+    match value {
+        Value::Integer(number) => T::try_from(number)
+            .map_err(|_| error(line, format!("{} is out of range: {}", key, number))),
+        other => Err(error(
+            line,
+            format!("{} must be a number, got {}", key, other),
+        )),
+    }
+}
+
+// Port 0 would bind a random port that peers can't know about
+fn expect_port(key: &str, line: usize, value: Value) -> Result<u16, ConfigError> {
+    match expect_integer::<u16>(key, line, value)? {
+        0 => Err(error(line, format!("{} must be between 1 and 65535", key))),
+        port => Ok(port),
+    }
+}
+
+// Limits and sizes where zero would disable the feature outright
+fn expect_positive<T: TryFrom<i64> + Default + PartialEq>(
+    key: &str,
+    line: usize,
+    value: Value,
+) -> Result<T, ConfigError> {
+    let number: T = expect_integer(key, line, value)?;
+    if number == T::default() {
+        return Err(error(line, format!("{} must be at least 1", key)));
+    }
+    Ok(number)
+}
+
+fn expect_address<T: FromStr>(key: &str, line: usize, value: Value) -> Result<T, ConfigError> {
+    let text = expect_string(key, line, value)?;
+    text.parse()
+        .map_err(|_| error(line, format!("{} is not a valid address: {}", key, text)))
+}
+
+// A comma separated string, or an array of strings, given back as the former
+fn expect_list(key: &str, line: usize, value: Value) -> Result<String, ConfigError> {
+    let Value::Array(items) = value else {
+        return expect_string(key, line, value);
+    };
+    let items = items
+        .into_iter()
+        .map(|item| expect_string(key, line, item))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(items.join(", "))
+}
+
+// A list of addresses and CIDR ranges
+fn expect_ranges(key: &str, line: usize, value: Value) -> Result<Vec<Cidr>, ConfigError> {
+    let text = expect_list(key, line, value)?;
+    parse_ranges(&text).map_err(|message| error(line, format!("{}: {}", key, message)))
+}
+
+fn expect_level(key: &str, line: usize, value: Value) -> Result<Level, ConfigError> {
+    let text = expect_string(key, line, value)?;
+    text.parse()
+        .map_err(|message| error(line, format!("{}: {}", key, message)))
+}
+
+// Joining a unicast address as a group fails, and sending to one reaches a single host
+fn expect_multicast<T: FromStr + Into<IpAddr> + Copy>(
+    key: &str,
+    line: usize,
+    value: Value,
+) -> Result<T, ConfigError> {
+    let address: T = expect_address(key, line, value)?;
+    if !address.into().is_multicast() {
+        return Err(error(
+            line,
+            format!(
+                "{} must be a multicast address, got {}",
+                key,
+                address.into()
+            ),
+        ));
+    }
+    Ok(address)
+}
+
+// Second octet of a 100.x.y.z address inside Tailscale's 100.64.0.0/10
+fn expect_tailscale_octet(key: &str, line: usize, value: Value) -> Result<u8, ConfigError> {
+    let octet: i64 = expect_integer(key, line, value)?;
+    u8::try_from(octet)
+        .ok()
+        .filter(|octet| (TAILSCALE_CGNAT_FIRST..=TAILSCALE_CGNAT_LAST).contains(octet))
+        .ok_or_else(|| {
+            error(
+                line,
+                format!(
+                    "{} must be between {} and {}, Tailscale's 100.64.0.0/10 range, got {}",
+                    key, TAILSCALE_CGNAT_FIRST, TAILSCALE_CGNAT_LAST, octet
+                ),
+            )
+        })
+}
+
+impl Config {
+    // Also gives back a warning for each key that isn't a setting, which is skipped
+    pub fn parse(contents: &str) -> Result<(Config, Vec<String>), ConfigError> {
+        let mut config = Config::default();
+        let mut warnings = Vec::new();
+
+        // Apply in file order so errors point at the first bad line
+        let mut values: Vec<(String, (usize, Value))> =
+            parse_values(contents)?.into_iter().collect();
+        values.sort_by_key(|(_, (line, _))| *line);
+        // Line of whichever sweep bound came last, where a reversed range is reported
+        let mut sweep_line = 0;
+
+        for (key, (line, value)) in values {
+            match key.as_str() {
+                "chat_port" => config.chat_port = expect_port(&key, line, value)?,
+                "discovery_port" => config.discovery_port = expect_port(&key, line, value)?,
+                "discovery_min_interval_secs" => {
+                    config.discovery_min_interval_secs = expect_positive(&key, line, value)?
+                }
+                "discovery_interval_secs" => {
+                    config.discovery_interval_secs = expect_positive(&key, line, value)?
+                }
+                "discovery_max_interval_secs" => {
+                    config.discovery_max_interval_secs = expect_positive(&key, line, value)?
+                }
+                "username" => config.username = Some(expect_string(&key, line, value)?),
+                "max_message_lines" => {
+                    config.max_message_lines = expect_positive(&key, line, value)?
+                }
+                "do_intro" => config.do_intro = expect_bool(&key, line, value)?,
+                "fast_intro" => config.fast_intro = expect_bool(&key, line, value)?,
+                "debug" => config.debug = expect_bool(&key, line, value)?,
+                "log_level" => config.log_level = expect_level(&key, line, value)?,
+                "mdns" => config.mdns = expect_bool(&key, line, value)?,
+                "tcp" => config.tcp = expect_bool(&key, line, value)?,
+                "tcp_port" => config.tcp_port = expect_port(&key, line, value)?,
+                "broadcast_address" => {
+                    config.broadcast_address = expect_address(&key, line, value)?
+                }
+                "bind_address" => config.bind_address = Some(expect_address(&key, line, value)?),
+                "allowlist" => config.allowlist = expect_ranges(&key, line, value)?,
+                "denylist" => config.denylist = expect_ranges(&key, line, value)?,
+                "multicast_group_v4" => {
+                    config.multicast_group_v4 = expect_multicast(&key, line, value)?
+                }
+                "multicast_group_v6" => {
+                    config.multicast_group_v6 = expect_multicast(&key, line, value)?
+                }
+                "tailscale_sweep_first" => {
+                    config.tailscale_sweep_first = expect_tailscale_octet(&key, line, value)?;
+                    sweep_line = line;
+                }
+                "tailscale_sweep_last" => {
+                    config.tailscale_sweep_last = expect_tailscale_octet(&key, line, value)?;
+                    sweep_line = line;
+                }
+                "save_history" => config.save_history = expect_bool(&key, line, value)?,
+                "history_size" => config.history_size = expect_positive(&key, line, value)?,
+                "save_muted" => config.save_muted = expect_bool(&key, line, value)?,
+                "notify_mentions" => config.notify_mentions = expect_bool(&key, line, value)?,
+                "do_not_disturb" => config.do_not_disturb = expect_bool(&key, line, value)?,
+                "show_ip" => config.show_ip = expect_bool(&key, line, value)?,
+                "away_after_secs" => config.away_after_secs = expect_integer(&key, line, value)?,
+                "dim_after_secs" => config.dim_after_secs = expect_integer(&key, line, value)?,
+                "max_message_length" => {
+                    config.max_message_length = expect_positive(&key, line, value)?;
+                    if config.max_message_length > MAX_CONTENT_CHARS {
+                        return Err(error(
+                            line,
+                            format!(
+                                "{} must be at most {}, receivers cut longer messages",
+                                key, MAX_CONTENT_CHARS
+                            ),
+                        ));
+                    }
+                }
+                "recv_buffer_size" => {
+                    config.recv_buffer_size = expect_integer(&key, line, value)?;
+                    if !(MIN_RECV_BUFFER_SIZE..=MAX_RECV_BUFFER_SIZE)
+                        .contains(&config.recv_buffer_size)
+                    {
+                        return Err(error(
+                            line,
+                            format!(
+                                "{} must be {} to {}, fragments need at least the minimum",
+                                key, MIN_RECV_BUFFER_SIZE, MAX_RECV_BUFFER_SIZE
+                            ),
+                        ));
+                    }
+                }
+                "max_name_width" => config.max_name_width = expect_positive(&key, line, value)?,
+                "history_sync" => config.history_sync = expect_bool(&key, line, value)?,
+                "history_sync_count" => {
+                    config.history_sync_count = expect_positive(&key, line, value)?;
+                    if config.history_sync_count > HISTORY_BATCH_MAX {
+                        return Err(error(
+                            line,
+                            format!(
+                                "{} must be at most {}, peers send no more than that",
+                                key, HISTORY_BATCH_MAX
+                            ),
+                        ));
+                    }
+                }
+                "min_send_interval_ms" => {
+                    config.min_send_interval_ms = expect_integer(&key, line, value)?
+                }
+                "max_retransmits" => config.max_retransmits = expect_integer(&key, line, value)?,
+                "retransmit_delay_ms" => {
+                    config.retransmit_delay_ms = expect_positive(&key, line, value)?
+                }
+                "max_messages_per_sec" => {
+                    config.max_messages_per_sec = expect_positive(&key, line, value)?
+                }
+                "event_queue_size" => config.event_queue_size = expect_positive(&key, line, value)?,
+                "time_format" => config.time_format = expect_string(&key, line, value)?,
+                "date_on_new_day" => config.date_on_new_day = expect_bool(&key, line, value)?,
+                "emoji_shortcodes" => config.emoji_shortcodes = expect_bool(&key, line, value)?,
+                "theme" => config.theme = expect_string(&key, line, value)?,
+                _ if key.starts_with("theme.") => {
+                    let color = expect_list(&key, line, value)?;
+                    let name = &key["theme.".len()..];
+                    // Checked now so a bad color is reported with its line
+                    Theme::default()
+                        .set_color(name, &color)
+                        .map_err(|message| error(line, message))?;
+                    config.theme_colors.push((name.to_string(), color));
+                }
+                _ if key.starts_with("keys.") => {
+                    let keys = expect_list(&key, line, value)?;
+                    let action: Action = key["keys.".len()..]
+                        .parse()
+                        .map_err(|message| error(line, message))?;
+                    let keys = parse_keys(&keys).map_err(|message| error(line, message))?;
+                    config.key_bindings.bind(action, &keys);
+                }
+                _ => warnings.push(format!(
+                    "Ignoring unknown config key on line {}: {}",
+                    line, key
+                )),
+            }
+        }
+
+        if config.tailscale_sweep_first > config.tailscale_sweep_last {
+            return Err(error(
+                sweep_line,
+                format!(
+                    "tailscale_sweep_first ({}) is above tailscale_sweep_last ({})",
+                    config.tailscale_sweep_first, config.tailscale_sweep_last
+                ),
+            ));
+        }
+
+        Ok((config, warnings))
+    }
+
+    // Reads the config file. A missing file gives the defaults, an unreadable or invalid
+    // one is an error so a typo isn't silently ignored.
+    pub fn load(path: &Path) -> io::Result<(Config, Vec<String>)> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok((Config::default(), Vec::new()))
+            }
+            Err(e) => return Err(e),
+        };
+
+        Config::parse(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })
+    }
+
+    // Command-line arguments take precedence over the file
+    pub fn apply_args(&mut self, args: &Args) {
+        if let Some(port) = args.chat_port {
+            self.chat_port = port;
+        }
+        if let Some(port) = args.discovery_port {
+            self.discovery_port = port;
+        }
+        if let Some(address) = args.bind {
+            self.bind_address = Some(address);
+        }
+        self.tcp |= args.tcp;
+        if let Some(port) = args.tcp_port {
+            self.tcp_port = port;
+        }
+        if let Some(username) = &args.username {
+            self.username = Some(username.clone());
+        }
+        self.debug |= args.debug;
+        if let Some(level) = args.log_level {
+            self.log_level = level;
+        }
+        if args.no_intro {
+            self.do_intro = false;
+        }
+        self.fast_intro |= args.fast_intro;
+        if let Some(theme) = &args.theme {
+            self.theme = theme.clone();
+        }
+    }
+
+    // The theme preset with the config's color overrides applied
+    pub fn theme(&self) -> Theme {
+        let mut theme = Theme::named_or_default(&self.theme);
+        for (name, color) in &self.theme_colors {
+            // Already validated while parsing
+            let _ = theme.set_color(name, color);
+        }
+        theme
+    }
+
+    pub fn intro_mode(&self) -> IntroMode {
+        match (self.do_intro, self.fast_intro) {
+            (false, _) => IntroMode::Off,
+            (true, true) => IntroMode::Fast,
+            (true, false) => IntroMode::Full,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+    use crossterm::event::{KeyCode, KeyModifiers};
+
+    const SAMPLE: &str = r#"
+# Ports for the office network
+chat_port = 3000
+username = "alice # not a comment"   # a comment
+max_message_lines = 1_000
+debug = true
+"#;
+
+    fn parse(contents: &str) -> Config {
+        Config::parse(contents).unwrap().0
+    }
+
+    fn parse_error(contents: &str) -> ConfigError {
+        Config::parse(contents).unwrap_err()
+    }
+
+    #[test]
+    fn sample_config_is_read() {
+        let config = parse(SAMPLE);
+        assert_eq!(config.chat_port, 3000);
+        assert_eq!(config.username.as_deref(), Some("alice # not a comment"));
+        assert_eq!(config.max_message_lines, 1000);
+        assert!(config.debug);
+    }
+
+    #[test]
+    fn defaults_fill_the_gaps() {
+        let config = parse(SAMPLE);
+        let defaults = Config::default();
+        assert_eq!(config.discovery_port, DISCOVERY_PORT);
+        assert_eq!(config.discovery_port, defaults.discovery_port);
+        assert_eq!(config.max_message_length, defaults.max_message_length);
+        assert_eq!(config.recv_buffer_size, defaults.recv_buffer_size);
+        assert_eq!(config.time_format, defaults.time_format);
+        assert_eq!(config.do_intro, defaults.do_intro);
+
+        let empty = parse("");
+        assert_eq!(empty.chat_port, CHAT_PORT);
+        assert_eq!(empty.username, None);
+    }
+
+    #[test]
+    fn mistakes_point_at_their_line() {
+        let error = parse_error("chat_port = 3000\ndiscovery_port = \"x\"");
+        assert_eq!(error.line, 2);
+        assert!(error.message.contains("discovery_port"));
+
+        assert_eq!(parse_error("chat_port = 70000").line, 1);
+        assert_eq!(parse_error("\n\nchat_port 3000").line, 3);
+        assert_eq!(parse_error("debug = true\ndebug = false").line, 2);
+        assert_eq!(parse_error("username = \"alice").line, 1);
+    }
+
+    #[test]
+    fn network_addresses_are_checked() {
+        let config = parse("multicast_group_v4 = \"239.1.2.3\"\nmulticast_group_v6 = \"ff05::1\"");
+        assert_eq!(config.multicast_group_v4, Ipv4Addr::new(239, 1, 2, 3));
+        assert_eq!(
+            config.multicast_group_v6,
+            "ff05::1".parse::<Ipv6Addr>().unwrap()
+        );
+
+        // The Tailscale resolver isn't a multicast group
+        let error = parse_error("multicast_group_v4 = \"100.100.100.100\"");
+        assert_eq!(error.line, 1);
+        assert!(error.message.contains("must be a multicast address"));
+        assert!(error.message.contains("100.100.100.100"));
+        assert!(parse_error("multicast_group_v6 = \"fe80::1\"")
+            .message
+            .contains("multicast_group_v6"));
+
+        assert!(parse_error("tailscale_sweep_first = 10")
+            .message
+            .contains("100.64.0.0/10"));
+        let error = parse_error("tailscale_sweep_first = 100\ntailscale_sweep_last = 80");
+        assert!(error.message.contains("is above tailscale_sweep_last"));
+    }
+
+    #[test]
+    fn a_missing_file_gives_the_defaults() {
+        let path = std::env::temp_dir().join("reticulum-no-such-config.toml");
+        let (config, warnings) = Config::load(&path).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(config.chat_port, CHAT_PORT);
+    }
+
+    #[test]
+    fn arguments_override_the_file() {
+        let mut config = parse(SAMPLE);
+        let args = Args::try_parse_from(["reticulum", "--chat-port", "4000"]).unwrap();
+        config.apply_args(&args);
+        assert_eq!(config.chat_port, 4000);
+        assert_eq!(config.username.as_deref(), Some("alice # not a comment"));
+    }
+
+    #[test]
+    fn the_bind_address_comes_from_the_file_or_arguments() {
+        assert_eq!(Config::default().bind_address, None);
+
+        let mut config = parse("bind_address = \"192.168.1.20\"\n");
+        assert_eq!(config.bind_address, Some("192.168.1.20".parse().unwrap()));
+
+        let args = Args::try_parse_from(["reticulum", "--bind", "10.0.0.5"]).unwrap();
+        config.apply_args(&args);
+        assert_eq!(config.bind_address, Some("10.0.0.5".parse().unwrap()));
+
+        assert!(Config::parse("bind_address = \"lan\"\n").is_err());
+    }
+
+    #[test]
+    fn the_receive_buffer_size_is_bounded() {
+        let config = parse("recv_buffer_size = 16384\n");
+        assert_eq!(config.recv_buffer_size, 16384);
+
+        let too_small = format!("recv_buffer_size = {}\n", MIN_RECV_BUFFER_SIZE - 1);
+        assert!(Config::parse(&too_small).is_err());
+        let too_large = format!("recv_buffer_size = {}\n", MAX_RECV_BUFFER_SIZE + 1);
+        assert!(Config::parse(&too_large).is_err());
+    }
+
+    #[test]
+    fn toml_quoting_tables_and_arrays_work() {
+        let config = parse(
+            r#"
+username = 'literal \ name'
+time_format = """
+%H:%M"""
+allowlist = [
+    "192.168.1.0/24",
+    "100.64.0.0/10",
+]
+keys = { help = ["f1", "ctrl+h"] }
+
+[theme]
+name_palette = ["cyan", "green"]
+"#,
+        );
+        assert_eq!(config.username.as_deref(), Some("literal \\ name"));
+        assert_eq!(config.time_format, "%H:%M");
+        assert_eq!(config.allowlist.len(), 2);
+        assert_eq!(
+            config.theme_colors,
+            vec![("name_palette".to_string(), "cyan, green".to_string())]
+        );
+        assert_eq!(
+            config
+                .key_bindings
+                .action_for(KeyCode::Char('h'), KeyModifiers::CONTROL),
+            Some(Action::Help)
+        );
+    }
+
+    #[test]
+    fn unknown_keys_come_back_as_warnings() {
+        let (config, warnings) = Config::parse(
+            "chat_port = 3000
+colour = \"red\"",
+        )
+        .unwrap();
+        assert_eq!(config.chat_port, 3000);
+        assert_eq!(
+            warnings,
+            vec!["Ignoring unknown config key on line 2: colour".to_string()]
+        );
+        assert!(parse_error("chat_port = 1.5").message.contains("got 1.5"));
+    }
+}
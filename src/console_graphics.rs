@@ -1,9 +1,29 @@
+use crate::away::IdleTracker;
+use crate::color_support::{self, ColorSupport};
+use crate::config::Config;
 use crate::constants::{
-    COMMON_COMMANDS, LOGO_ASCII_ART, START_MESSAGE_LINE, STATUS_BAR_LINE, USER_INPUT_PROMPT,
-    USER_INPUT_PROMPT_LENGTH,
+    COMMON_COMMANDS, LOGO_ASCII_ART, MIN_TERMINAL_HEIGHT, MIN_TERMINAL_WIDTH, RESIZE_DEBOUNCE_MS,
+    START_MESSAGE_LINE, STATUS_BAR_LINE, USER_INPUT_PROMPT, USER_INPUT_PROMPT_LENGTH,
 };
+use crate::crypto::is_encryption_enabled;
+use crate::debug_logger::set_terminal_ui;
+use crate::help::{format_entry, help_sections, HELP_FOOTER};
+use crate::history::InputHistory;
+use crate::keys::{Action, Key, KeyBindings};
+use crate::line_edit;
 use crate::message::Message;
-use chrono::Local;
+use crate::mute::MutedUsers;
+use crate::networking::PeerList;
+use crate::peer_panel::{format_age, health_summary, panel_peers, split_width};
+use crate::reactions::Reactions;
+use crate::render::{
+    age_style, clamp_scroll_offset, visible_range, AgeStyle, MessageFormat, StyledLine,
+};
+use crate::text::{find_ignore_case, mentions, str_width, truncate_to_width};
+use crate::theme::Theme;
+use crate::timestamp::is_new_day;
+use crate::toast::Toast;
+use chrono::{DateTime, Local, TimeZone};
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyEvent},
@@ -11,20 +31,180 @@ use crossterm::{
     style::{self, Color, SetBackgroundColor, SetForegroundColor},
     terminal::{self, ClearType},
 };
+use std::collections::HashMap;
 use std::io::{stdout, Write};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+// Whether a peer confirmed receiving one of our messages
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Delivery {
+    // Messages from others, and system notices
+    NotTracked,
+    // Waiting in the send queue
+    Queued,
+    // Sent, waiting for an ack
+    Pending,
+    Delivered,
+    // No peer acknowledged the message in time
+    Undelivered,
+    // Couldn't be sent at all
+    Failed,
+}
+
+// A message as stored for display. Formatting happens when printing, so the raw fields
+// stay available for re-rendering.
+#[derive(Clone, Debug)]
+pub struct DisplayMessage {
+    pub id: u64,
+    pub timestamp: DateTime<Local>,
+    pub sender_name: String,
+    pub sender_ip: String,
+    pub content: String,
+    pub is_local: bool,
+    // Local notices such as command output
+    pub is_system: bool,
+    // Set for direct messages: who we sent it to, or the name it was addressed to
+    pub recipient: Option<String>,
+    // A /me message
+    pub is_action: bool,
+    // Someone else's message containing our username
+    pub is_mention: bool,
+    // The node ID a received message came from, the only one allowed to edit or delete it
+    pub sender_node: Option<String>,
+    // Changed with /edit since it was sent
+    pub edited: bool,
+    // Added with /react, by us or by peers
+    pub reactions: Reactions,
+    // The first message shown on its day, whose timestamp includes the date
+    pub starts_day: bool,
+    pub delivery: Delivery,
+}
+
+impl DisplayMessage {
+    pub fn from_message(message: &Message) -> Self {
+        let is_local = message.sender_ip() == "local";
+
+        Self {
+            id: message.id(),
+            timestamp: Local::now(),
+            sender_name: message.sender_name().to_string(),
+            sender_ip: message.sender_ip().to_string(),
+            content: message.content().to_string(),
+            is_local,
+            is_system: false,
+            recipient: message.recipient().map(str::to_string),
+            is_action: message.is_action(),
+            is_mention: false,
+            sender_node: message.sender_node().map(str::to_string),
+            edited: false,
+            reactions: Reactions::default(),
+            starts_day: false,
+            delivery: if is_local {
+                Delivery::Queued
+            } else {
+                Delivery::NotTracked
+            },
+        }
+    }
+
+    // A message from an earlier session's log: shown with its original time and no
+    // delivery status, since acks for it can no longer arrive
+    pub fn replayed(message: &Message) -> Self {
+        let timestamp = Local
+            .timestamp_millis_opt(message.timestamp())
+            .single()
+            .unwrap_or_else(Local::now);
+
+        Self {
+            timestamp,
+            delivery: Delivery::NotTracked,
+            ..Self::from_message(message)
+        }
+    }
+
+    pub fn system(text: &str) -> Self {
+        Self {
+            id: 0,
+            timestamp: Local::now(),
+            sender_name: String::new(),
+            sender_ip: String::new(),
+            content: text.to_string(),
+            is_local: true,
+            is_system: true,
+            recipient: None,
+            is_action: false,
+            is_mention: false,
+            sender_node: None,
+            edited: false,
+            reactions: Reactions::default(),
+            starts_day: false,
+            delivery: Delivery::NotTracked,
+        }
+    }
+}
 
 pub struct GraphicsEngine {
     height: usize,
     width: usize,
     previous_height: usize,
     previous_width: usize,
+    // Scrollback kept however small the terminal gets. A taller terminal keeps enough to
+    // fill the screen, see message_capacity.
     max_message_lines: usize,
-    message_lines: Vec<String>,
-    input_history: Vec<String>,
+    message_lines: Vec<DisplayMessage>,
+    input_history: InputHistory,
     history_position: usize,
     current_input: String,
+    // Byte position of the cursor within the input line
+    input_cursor: usize,
+    // Byte position of the first character shown when the input is too long for the line
+    input_scroll: usize,
+    // How many wrapped lines the view is scrolled up from the newest message
+    scroll_offset: usize,
+    // Who is typing, shown at the start of the status bar
+    typing_status: String,
+    // Longest line that can be typed, in characters, and the count shown in the status bar
+    // as the line nears it
+    max_message_length: usize,
+    length_counter: Option<String>,
+    // The last /search term, highlighted until cleared, and the message it jumped to
+    search_term: Option<String>,
+    search_match: Option<usize>,
+    // Set in headless mode, where messages are still kept but nothing is drawn
+    headless: bool,
+    // Detected at startup, the theme is downgraded to what the terminal can show
+    color_support: ColorSupport,
+    theme: Theme,
+    // Messages containing this name are highlighted, None when mentions are turned off
+    mention_name: Option<String>,
+    // Mentions are still highlighted but don't ring the bell
+    do_not_disturb: bool,
+    // Put the sender's IP in front of remote messages
+    show_ip: bool,
+    // Users whose messages aren't shown
+    muted: MutedUsers,
+    // When we last had input, to mark us away after a while without any
+    idle: IdleTracker,
+    // While a message waits for the send throttle
+    throttled: bool,
+    time_format: String,
+    date_on_new_day: bool,
+    // Longer sender names are cut short in the message list
+    max_name_width: usize,
+    // Listed in the peer panel, which Ctrl+P opens and closes
+    peer_list: Option<PeerList>,
+    peer_panel_open: bool,
+    // The help overlay covers the message area and the panel until a key is pressed
+    help_open: bool,
+    // Messages older than this are drawn dimmed
+    dim_after: Option<Duration>,
+    // How many messages were dimmed when last drawn, to redraw once another one ages
+    dimmed_count: usize,
+    // Keys for the actions in read_input
+    key_bindings: KeyBindings,
+    // While a toast is shown it takes the row above the status bar, moving the messages up
+    toast: Toast,
 }
 
 impl Clone for GraphicsEngine {
@@ -39,32 +219,226 @@ impl Clone for GraphicsEngine {
             input_history: self.input_history.clone(),
             history_position: self.history_position,
             current_input: self.current_input.clone(),
+            input_cursor: self.input_cursor,
+            input_scroll: self.input_scroll,
+            scroll_offset: self.scroll_offset,
+            typing_status: self.typing_status.clone(),
+            max_message_length: self.max_message_length,
+            length_counter: self.length_counter.clone(),
+            search_term: self.search_term.clone(),
+            search_match: self.search_match,
+            headless: self.headless,
+            color_support: self.color_support,
+            theme: self.theme.clone(),
+            mention_name: self.mention_name.clone(),
+            do_not_disturb: self.do_not_disturb,
+            show_ip: self.show_ip,
+            muted: self.muted.clone(),
+            idle: self.idle.clone(),
+            throttled: self.throttled,
+            time_format: self.time_format.clone(),
+            date_on_new_day: self.date_on_new_day,
+            max_name_width: self.max_name_width,
+            peer_list: self.peer_list.clone(),
+            peer_panel_open: self.peer_panel_open,
+            help_open: self.help_open,
+            dim_after: self.dim_after,
+            dimmed_count: self.dimmed_count,
+            key_bindings: self.key_bindings.clone(),
+            toast: self.toast.clone(),
         }
     }
 }
 
 impl GraphicsEngine {
-    pub fn new(max_message_lines: usize) -> Self {
+    pub fn new(config: &Config) -> Self {
         let (width, height) = terminal::size().unwrap_or((80, 24));
+        let color_support = color_support::detect();
 
         Self {
             height: height as usize,
             width: width as usize,
             previous_height: height as usize,
             previous_width: width as usize,
-            max_message_lines,
+            max_message_lines: config.max_message_lines,
             message_lines: Vec::new(),
-            input_history: Vec::with_capacity(50),
+            input_history: InputHistory::with_capacity(config.history_size),
             history_position: 0,
             current_input: String::new(),
+            input_cursor: 0,
+            input_scroll: 0,
+            scroll_offset: 0,
+            typing_status: String::new(),
+            max_message_length: config.max_message_length,
+            length_counter: None,
+            search_term: None,
+            search_match: None,
+            headless: false,
+            color_support,
+            theme: config.theme().downgraded(color_support),
+            mention_name: None,
+            do_not_disturb: config.do_not_disturb,
+            show_ip: config.show_ip,
+            muted: MutedUsers::new(),
+            idle: IdleTracker::new(
+                (config.away_after_secs > 0).then(|| Duration::from_secs(config.away_after_secs)),
+                Instant::now(),
+            ),
+            throttled: false,
+            time_format: config.time_format.clone(),
+            date_on_new_day: config.date_on_new_day,
+            max_name_width: config.max_name_width,
+            peer_list: None,
+            peer_panel_open: false,
+            help_open: false,
+            dim_after: (config.dim_after_secs > 0)
+                .then(|| Duration::from_secs(config.dim_after_secs)),
+            dimmed_count: 0,
+            key_bindings: config.key_bindings.clone(),
+            toast: Toast::default(),
         }
     }
 
     pub fn update_resolution(&mut self) {
         if let Ok((width, height)) = terminal::size() {
-            self.width = width as usize;
+            let width = width as usize;
+            if width != self.width {
+                self.rewrap(|engine| engine.width = width);
+            }
             self.height = height as usize;
+            self.trim_messages();
+        }
+    }
+
+    // Applies a change to the message area's width. Messages rewrap, so a scrolled-up view
+    // is kept on the same message instead of the same number of lines from the bottom.
+    fn rewrap(&mut self, change: impl FnOnce(&mut Self)) {
+        let anchor = match self.scroll_offset {
+            0 => None,
+            offset => Self::anchor_for_offset(&self.line_heights(), offset),
+        };
+        change(self);
+        if let Some(anchor) = anchor {
+            self.scroll_offset = Self::offset_for_anchor(&self.line_heights(), anchor);
+        }
+    }
+
+    // Columns of the message area and of the peer panel, which is 0 when not shown
+    fn layout(&self) -> (usize, usize) {
+        split_width(self.width, self.peer_panel_open && !self.help_open)
+    }
+
+    fn message_width(&self) -> usize {
+        self.layout().0
+    }
+
+    pub fn set_peer_list(&mut self, peers: PeerList) {
+        self.peer_list = Some(peers);
+    }
+
+    pub fn toggle_peer_panel(&mut self) {
+        self.rewrap(|engine| engine.peer_panel_open = !engine.peer_panel_open);
+    }
+
+    pub fn toggle_help(&mut self) {
+        self.rewrap(|engine| engine.help_open = !engine.help_open);
+    }
+
+    pub fn is_help_open(&self) -> bool {
+        self.help_open
+    }
+
+    // How many messages are kept: the configured scrollback, or a screenful if that's more
+    fn message_capacity(&self) -> usize {
+        self.max_message_lines
+            .max(self.height.saturating_sub(START_MESSAGE_LINE))
+    }
+
+    // Drops the oldest messages beyond the current capacity
+    fn trim_messages(&mut self) {
+        let excess = self
+            .message_lines
+            .len()
+            .saturating_sub(self.message_capacity());
+        self.message_lines.drain(..excess);
+        self.search_match = self
+            .search_match
+            .and_then(|index| index.checked_sub(excess));
+    }
+
+    // Indices of the messages whose content contains the term, oldest first
+    fn matching_messages(&self, term: &str) -> Vec<usize> {
+        self.message_lines
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| {
+                !message.is_system && !find_ignore_case(&message.content, term).is_empty()
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    // Highlights the term and scrolls to the newest message containing it. Searching for
+    // the same term again moves on to the next older match, wrapping around at the oldest.
+    // Returns which match is shown, counting from the newest, and how many there are.
+    pub fn search(&mut self, term: &str) -> Option<(usize, usize)> {
+        let matches = self.matching_messages(term);
+        let repeated = self
+            .search_term
+            .as_deref()
+            .is_some_and(|previous| previous.to_lowercase() == term.to_lowercase());
+        self.search_term = Some(term.to_string());
+
+        let position = match (repeated, self.search_match) {
+            (true, Some(current)) => matches
+                .iter()
+                .rposition(|index| *index < current)
+                .unwrap_or(matches.len().checked_sub(1)?),
+            _ => matches.len().checked_sub(1)?,
+        };
+        let index = matches[position];
+        self.search_match = Some(index);
+
+        // Put the match on the bottom row of the view
+        self.scroll_offset = Self::offset_for_anchor(&self.line_heights(), (index, usize::MAX));
+
+        Some((matches.len() - position, matches.len()))
+    }
+
+    pub fn clear_search(&mut self) {
+        self.search_term = None;
+        self.search_match = None;
+        self.scroll_offset = 0;
+    }
+
+    // Too small for the UI to be usable, see is_too_small. Only the notice asking for a
+    // bigger terminal is drawn until it grows again.
+    fn too_small(&self) -> bool {
+        is_too_small(self.width, self.height)
+    }
+
+    // Replaces the whole screen with a centered notice, cut to fit however small it gets
+    fn print_too_small_notice(&self) -> std::io::Result<()> {
+        let lines = too_small_notice(self.width, self.height);
+        let top = self.height.saturating_sub(lines.len()) / 2;
+
+        let mut stdout = stdout();
+        queue!(stdout, terminal::Clear(ClearType::All))?;
+        for (row, line) in lines.iter().enumerate() {
+            let line = truncate_to_width(line, self.width);
+            let column = self.width.saturating_sub(str_width(line)) / 2;
+            queue!(
+                stdout,
+                cursor::MoveTo(column as u16, (top + row) as u16),
+                style::Print(line)
+            )?;
         }
+        stdout.flush()
+    }
+
+    // Bottom row of the terminal, where input is typed
+    fn input_row(&self) -> u16 {
+        self.height.saturating_sub(1) as u16
     }
 
     // Make the compiler ignore this warning as we might need this function in the future
@@ -81,7 +455,7 @@ impl GraphicsEngine {
         )
     }
 
-    pub fn specific_line_print(&mut self, text: &str, line_height: usize) -> std::io::Result<()> {
+    fn styled_line_print(&mut self, line: &StyledLine, line_height: usize) -> std::io::Result<()> {
         self.update_resolution();
 
         if line_height >= self.height {
@@ -90,76 +464,591 @@ impl GraphicsEngine {
 
         let y_position = self.height.saturating_sub(line_height).saturating_sub(1);
 
+        // Message rows next to the peer panel are blanked by padding, clearing the whole
+        // line would erase the panel too
+        let (message_width, panel_width) = self.layout();
+        let pad_to =
+            (line_height >= self.message_start_line() && panel_width > 0).then_some(message_width);
+
         let mut stdout = stdout();
+        queue!(
+            stdout,
+            cursor::SavePosition,
+            cursor::MoveTo(0, y_position as u16)
+        )?;
+        if pad_to.is_none() {
+            queue!(stdout, terminal::Clear(ClearType::CurrentLine))?;
+        }
 
-        // Save cursor position
-        queue!(stdout, cursor::SavePosition)?;
+        for (text, color, highlighted) in line.segments() {
+            if highlighted {
+                queue!(
+                    stdout,
+                    SetBackgroundColor(self.theme.search_highlight_background),
+                    SetForegroundColor(self.theme.search_highlight_foreground)
+                )?;
+            } else if let Some(color) = color {
+                queue!(stdout, SetForegroundColor(color))?;
+            }
+            queue!(stdout, style::Print(text))?;
+            if highlighted || color.is_some() {
+                queue!(stdout, style::ResetColor)?;
+            }
+        }
+        if let Some(width) = pad_to {
+            let padding = width.saturating_sub(str_width(&line.text));
+            queue!(stdout, style::Print(" ".repeat(padding)))?;
+        }
 
-        // Move to the specific line
-        queue!(stdout, cursor::MoveTo(0, y_position as u16))?;
+        queue!(stdout, cursor::RestorePosition)?;
+        stdout.flush()
+    }
 
-        // Clear the line
-        queue!(stdout, terminal::Clear(ClearType::CurrentLine))?;
+    // Returns true if the message mentions us
+    pub fn add_message(&mut self, message: &Message) -> bool {
+        self.push_checked_for_mention(DisplayMessage::from_message(message))
+    }
 
-        // Print the text
-        queue!(stdout, style::Print(text))?;
+    pub fn add_replayed_message(&mut self, message: &Message) {
+        self.push_checked_for_mention(DisplayMessage::replayed(message));
+    }
 
-        // Restore cursor position
-        queue!(stdout, cursor::RestorePosition)?;
+    // Messages from muted users are dropped here, and never count as mentions
+    fn push_checked_for_mention(&mut self, mut message: DisplayMessage) -> bool {
+        if !message.is_local && self.muted.is_muted(&message.sender_name) {
+            return false;
+        }
+        message.is_mention = !message.is_local
+            && self
+                .mention_name
+                .as_deref()
+                .is_some_and(|name| mentions(&message.content, name));
+        let is_mention = message.is_mention;
+        self.push_message_line(message);
+        is_mention
+    }
 
-        stdout.flush()
+    pub fn set_mention_name(&mut self, name: &str) {
+        self.mention_name = (!name.is_empty()).then(|| name.to_string());
     }
 
-    pub fn add_message(&mut self, message: &Message) {
-        // Format sender info differently for local messages
-        let message_text = if message.sender_ip() == "local" {
-            let timestamp = chrono::Local::now().format("%H:%M:%S");
-            format!(
-                "[{}] YOU >>> {}: {}",
-                timestamp,
-                message.sender_name(),
-                message.content()
-            )
-        } else {
-            let timestamp = chrono::Local::now().format("%H:%M:%S");
-            format!(
-                "[{}] {} >>> {}: {}",
-                timestamp,
-                message.sender_ip(),
-                message.sender_name(),
-                message.content()
-            )
+    // Rings the bell for a mention unless do-not-disturb is on
+    pub fn notify_mention(&self) -> std::io::Result<()> {
+        self.notify_mention_on(&mut stdout())
+    }
+
+    fn notify_mention_on(&self, out: &mut impl Write) -> std::io::Result<()> {
+        if self.do_not_disturb {
+            return Ok(());
+        }
+        execute!(out, style::Print('\x07'))
+    }
+
+    pub fn do_not_disturb(&self) -> bool {
+        self.do_not_disturb
+    }
+
+    // Stops all drawing, for running without a terminal
+    pub fn set_headless(&mut self) {
+        self.headless = true;
+    }
+
+    pub fn set_do_not_disturb(&mut self, on: bool) {
+        self.do_not_disturb = on;
+    }
+
+    pub fn show_ip(&self) -> bool {
+        self.show_ip
+    }
+
+    // The prefixes change length, so messages rewrap
+    pub fn set_show_ip(&mut self, on: bool) {
+        self.rewrap(|engine| engine.show_ip = on);
+    }
+
+    // Names of known peers by IP, for naming messages that came without one
+    fn peer_names(&self) -> HashMap<String, String> {
+        let Some(peers) = self.peer_list.as_ref().filter(|_| !self.show_ip) else {
+            return HashMap::new();
+        };
+        peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, info)| (addr.ip().to_string(), info.name.clone()))
+            .collect()
+    }
+
+    // Shows or clears the status bar note that a message is waiting to go out
+    pub fn set_throttled(&mut self, on: bool) -> std::io::Result<()> {
+        self.throttled = on;
+        self.print_status_bar()
+    }
+
+    // Checks whether we've gone away or come back since the last call, see IdleTracker.
+    // The status bar shows it either way.
+    pub fn update_away(&mut self) -> Option<bool> {
+        let change = self.idle.update(Instant::now());
+        if change.is_some() {
+            let _ = self.print_status_bar();
+        }
+        change
+    }
+
+    // Local notices such as command output, never sent over the network
+    pub fn add_system_message(&mut self, text: &str) {
+        self.push_message_line(DisplayMessage::system(text));
+    }
+
+    // Updates the delivery state of one of our messages, returning true if it was found
+    pub fn set_delivery(&mut self, id: u64, delivery: Delivery) -> bool {
+        match self
+            .message_lines
+            .iter_mut()
+            .find(|message| message.is_local && !message.is_system && message.id == id)
+        {
+            // An ack can arrive before the sender task gets to mark the message sent, which
+            // mustn't take the tick away again
+            Some(message)
+                if delivery == Delivery::Pending && message.delivery != Delivery::Queued =>
+            {
+                false
+            }
+            Some(message) => {
+                message.delivery = delivery;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn muted(&self) -> &MutedUsers {
+        &self.muted
+    }
+
+    pub fn muted_mut(&mut self) -> &mut MutedUsers {
+        &mut self.muted
+    }
+
+    // Replaces the muted users, as with ones saved by an earlier session
+    pub fn set_muted(&mut self, muted: MutedUsers) {
+        self.muted = muted;
+    }
+
+    pub fn input_history(&self) -> &InputHistory {
+        &self.input_history
+    }
+
+    // Replaces the history, as with one saved by an earlier session
+    pub fn set_input_history(&mut self, history: InputHistory) {
+        self.history_position = history.len();
+        self.input_history = history;
+    }
+
+    // Everything in the scrollback, oldest first
+    pub fn messages(&self) -> &[DisplayMessage] {
+        &self.message_lines
+    }
+
+    // Slots an earlier message a peer sent back into the scrollback by its time. Returns
+    // false if it's already there or its sender is muted.
+    pub fn merge_history(&mut self, message: &Message) -> bool {
+        let mut message = DisplayMessage::replayed(message);
+        if self.muted.is_muted(&message.sender_name)
+            || self
+                .message_lines
+                .iter()
+                .any(|shown| shown.id == message.id)
+        {
+            return false;
+        }
+
+        let index = self
+            .message_lines
+            .partition_point(|shown| shown.timestamp <= message.timestamp);
+        message.starts_day = self.date_on_new_day
+            && index.checked_sub(1).is_some_and(|previous| {
+                is_new_day(&self.message_lines[previous].timestamp, &message.timestamp)
+            });
+        self.message_lines.insert(index, message);
+        self.search_match = self.search_match.map(|current| {
+            if current >= index {
+                current + 1
+            } else {
+                current
+            }
+        });
+        self.trim_messages();
+        true
+    }
+
+    // Our own messages whose hex ID starts with the prefix
+    pub fn own_messages_matching(&self, prefix: &str) -> Vec<&DisplayMessage> {
+        self.messages_matching(prefix)
+            .into_iter()
+            .filter(|message| message.is_local)
+            .collect()
+    }
+
+    // Messages from anyone whose ID starts with the prefix, for /react
+    pub fn messages_matching(&self, prefix: &str) -> Vec<&DisplayMessage> {
+        let prefix = prefix.trim_start_matches('#').to_lowercase();
+        self.message_lines
+            .iter()
+            .filter(|message| {
+                !message.is_system
+                    && message.id != 0
+                    && format!("{:016x}", message.id).starts_with(&prefix)
+            })
+            .collect()
+    }
+
+    // Adds a reaction to a message, returning true if the message is shown and the
+    // reaction is new. Reactions to messages we don't have are dropped.
+    pub fn react(&mut self, id: u64, emoji: &str, reactor: &str) -> bool {
+        self.message_lines
+            .iter_mut()
+            .find(|message| message.id == id && !message.is_system)
+            .is_some_and(|message| message.reactions.add(emoji, reactor))
+    }
+
+    // Where the message with this ID is, if it's ours when from_node is None or was sent
+    // from that node otherwise. Nobody gets to change someone else's message.
+    fn owned_message_index(&self, id: u64, from_node: Option<&str>) -> Option<usize> {
+        self.message_lines.iter().position(|message| {
+            message.id == id
+                && !message.is_system
+                && match from_node {
+                    None => message.is_local,
+                    Some(node) => !message.is_local && message.sender_node.as_deref() == Some(node),
+                }
+        })
+    }
+
+    // Replaces a message's content, returning true if it was found and could be edited
+    pub fn edit_message(&mut self, id: u64, from_node: Option<&str>, content: &str) -> bool {
+        let Some(index) = self.owned_message_index(id, from_node) else {
+            return false;
+        };
+        let mention_name = self.mention_name.clone();
+        let message = &mut self.message_lines[index];
+        message.content = content.to_string();
+        message.edited = true;
+        message.is_mention =
+            !message.is_local && mention_name.is_some_and(|name| mentions(&message.content, &name));
+        true
+    }
+
+    // Removes a message, returning true if it was found and could be deleted. A scrolled-up
+    // view stays on the same messages, and never ends up past the oldest one.
+    pub fn delete_message(&mut self, id: u64, from_node: Option<&str>) -> bool {
+        let Some(index) = self.owned_message_index(id, from_node) else {
+            return false;
         };
+        let line_heights = self.line_heights();
+        let below: usize = line_heights.iter().skip(index + 1).sum();
+        if self.scroll_offset > below {
+            self.scroll_offset -= line_heights[index].min(self.scroll_offset - below);
+        }
+        self.message_lines.remove(index);
+        self.scroll_offset = clamp_scroll_offset(
+            self.scroll_offset,
+            self.physical_lines().len(),
+            self.visible_message_rows(),
+        );
+        self.search_match = match self.search_match {
+            Some(current) if current == index => None,
+            Some(current) if current > index => Some(current - 1),
+            other => other,
+        };
+        true
+    }
 
-        self.message_lines.push(message_text);
+    // Empties the scrollback for /clear. Nothing is left to be scrolled to or to have
+    // matched a search, so the view goes back to the bottom.
+    pub fn clear_messages(&mut self) {
+        self.message_lines.clear();
+        self.scroll_offset = 0;
+        self.search_match = None;
+    }
 
-        if self.message_lines.len() > self.max_message_lines {
-            self.message_lines.remove(0);
+    fn push_message_line(&mut self, mut message: DisplayMessage) {
+        message.starts_day = self.date_on_new_day
+            && self
+                .message_lines
+                .last()
+                .is_some_and(|last| is_new_day(&last.timestamp, &message.timestamp));
+
+        // Keep a scrolled-up view anchored instead of jumping to the new message
+        if self.scroll_offset > 0 {
+            let width = self.message_width();
+            self.scroll_offset += self
+                .message_format()
+                .wrapped_message(&message, width, None)
+                .len();
         }
+
+        self.message_lines.push(message);
+        self.trim_messages();
     }
 
     pub fn print_all_messages(&mut self, reserve_space: bool) -> std::io::Result<()> {
+        if self.headless {
+            return Ok(());
+        }
         self.update_resolution();
+        if self.too_small() {
+            return self.print_too_small_notice();
+        }
 
         if reserve_space {
-            for _ in 0..self.max_message_lines + START_MESSAGE_LINE - 1 {
+            for _ in 0..self.visible_message_rows() + self.message_start_line() - 1 {
                 println!();
             }
         }
+        if self.help_open {
+            self.print_help_overlay()?;
+            return self.print_toast();
+        }
+
+        // Wrap every message to the terminal width, then show the window selected by the
+        // scroll offset (the newest lines when not scrolled)
+        let visible_rows = self.visible_message_rows();
+        let physical_lines = self.physical_lines();
+        self.scroll_offset =
+            clamp_scroll_offset(self.scroll_offset, physical_lines.len(), visible_rows);
+        let shown =
+            &physical_lines[visible_range(physical_lines.len(), self.scroll_offset, visible_rows)];
+
+        let blank = StyledLine::default();
+        for i in 0..visible_rows {
+            // Rows above the oldest shown line are blanked so stale text doesn't linger
+            let line = shown
+                .len()
+                .checked_sub(i + 1)
+                .map_or(&blank, |idx| &shown[idx]);
+            self.styled_line_print(line, self.message_start_line() + i)?;
+        }
+
+        self.print_toast()?;
+        self.print_peer_panel()
+    }
+
+    // Shows a notice above the status bar for the duration, replacing any shown before.
+    // The messages move up a row to make room, and back down once it clears.
+    pub fn show_toast(&mut self, text: &str, duration: Duration) -> std::io::Result<()> {
+        let was_shown = self.toast.is_shown();
+        self.toast.show(text, duration, Instant::now());
+        if self.headless {
+            return Ok(());
+        }
+        if was_shown {
+            self.print_toast()
+        } else {
+            self.print_all_messages(false)
+        }
+    }
 
-        let current_buffer_vector_size = self.message_lines.len();
-        let messages_copy = self.message_lines.clone();
+    // Clears a toast whose time is up, repainting the messages into its row
+    fn expire_toast(&mut self) -> std::io::Result<()> {
+        if self.toast.expire(Instant::now()) {
+            self.print_all_messages(false)?;
+        }
+        Ok(())
+    }
+
+    fn print_toast(&mut self) -> std::io::Result<()> {
+        if self.headless || self.too_small() {
+            return Ok(());
+        }
+        let Some(text) = self.toast.text(Instant::now()) else {
+            return Ok(());
+        };
+        let mut line = StyledLine::default();
+        line.push_colored(
+            truncate_to_width(text, self.width),
+            self.theme.system_message,
+        );
+        self.styled_line_print(&line, START_MESSAGE_LINE)
+    }
 
-        for i in 0..current_buffer_vector_size {
-            let message_idx = current_buffer_vector_size - i - 1;
-            if message_idx < messages_copy.len() {
-                self.specific_line_print(&messages_copy[message_idx], START_MESSAGE_LINE + i)?;
+    // Fills the message rows with the commands and keys, from the top. Lines past the bottom
+    // of a short terminal are left out, the footer always stays.
+    fn print_help_overlay(&mut self) -> std::io::Result<()> {
+        let mut lines = Vec::new();
+        for (heading, entries) in help_sections(&self.key_bindings) {
+            let mut line = StyledLine::default();
+            line.push_colored(heading, self.theme.completion);
+            lines.push(line);
+            for (name, description) in entries {
+                let mut line = StyledLine::default();
+                line.push(&format_entry(&name, &description));
+                lines.push(line);
             }
+            lines.push(StyledLine::default());
         }
 
+        let visible_rows = self.visible_message_rows();
+        lines.truncate(visible_rows.saturating_sub(1));
+        let mut footer = StyledLine::default();
+        footer.push_colored(HELP_FOOTER, self.theme.system_message);
+
+        let blank = StyledLine::default();
+        for i in 0..visible_rows {
+            let line = match i {
+                0 => &footer,
+                _ => lines.get(visible_rows - 1 - i).unwrap_or(&blank),
+            };
+            let text = truncate_to_width(&line.text, self.width).len();
+            self.styled_line_print(&line.slice(0..text), self.message_start_line() + i)?;
+        }
         Ok(())
     }
 
+    // Draws the peer panel down the right of the message rows, when it's shown
+    pub fn print_peer_panel(&mut self) -> std::io::Result<()> {
+        if self.headless {
+            return Ok(());
+        }
+        let (message_width, panel_width) = self.layout();
+        if panel_width == 0 || self.too_small() {
+            return Ok(());
+        }
+
+        let peers = self.peer_list.as_ref().map(panel_peers).unwrap_or_default();
+        let mut rows = vec![(format!("Peers ({})", peers.len()), None)];
+        for peer in &peers {
+            let name = if peer.away {
+                format!("{} (away)", peer.name)
+            } else {
+                peer.name.clone()
+            };
+            rows.push((name, Some(self.theme.name_color(&peer.name))));
+            rows.push((
+                format!("  {}, {}", peer.ip, format_age(peer.last_seen.elapsed())),
+                Some(self.theme.system_message),
+            ));
+        }
+
+        // Past the separator and a space on each side
+        let text_width = panel_width.saturating_sub(3);
+        let blank = (String::new(), None);
+        let mut stdout = stdout();
+        queue!(stdout, cursor::SavePosition)?;
+        for row in 0..self.visible_message_rows() {
+            let (text, color) = rows.get(row).unwrap_or(&blank);
+            let text = truncate_to_width(text, text_width);
+            let padding = text_width.saturating_sub(str_width(text));
+            queue!(
+                stdout,
+                cursor::MoveTo(message_width as u16, row as u16),
+                SetForegroundColor(self.theme.system_message),
+                style::Print("│ "),
+                SetForegroundColor(color.unwrap_or(Color::Reset)),
+                style::Print(text),
+                style::ResetColor,
+                style::Print(" ".repeat(padding + 1))
+            )?;
+        }
+        queue!(stdout, cursor::RestorePosition)?;
+        stdout.flush()
+    }
+
+    // Wraps by the plain text only, so colors never affect the width accounting
+    fn physical_lines(&self) -> Vec<StyledLine> {
+        let format = self.message_format();
+        let width = self.message_width();
+        self.message_lines
+            .iter()
+            .flat_map(|message| format.wrapped_message(message, width, self.search_term.as_deref()))
+            .collect()
+    }
+
+    // How many wrapped lines each message takes at the current width, oldest first
+    fn line_heights(&self) -> Vec<usize> {
+        let format = self.message_format();
+        let width = self.message_width();
+        self.message_lines
+            .iter()
+            .map(|message| format.wrapped_message(message, width, None).len())
+            .collect()
+    }
+
+    fn message_format(&self) -> MessageFormat<'_> {
+        MessageFormat {
+            theme: &self.theme,
+            time_format: &self.time_format,
+            max_name_width: self.max_name_width,
+            dim_after: self.dim_after,
+            now: Local::now(),
+            show_ip: self.show_ip,
+            peer_names: self.peer_names(),
+        }
+    }
+
+    // The message shown on the bottom row when scrolled up by the offset, and which of its
+    // wrapped lines that is. None when the offset is past the oldest message.
+    fn anchor_for_offset(line_heights: &[usize], offset: usize) -> Option<(usize, usize)> {
+        let mut remaining = offset;
+        for (index, &height) in line_heights.iter().enumerate().rev() {
+            if remaining < height {
+                return Some((index, height - 1 - remaining));
+            }
+            remaining -= height;
+        }
+        None
+    }
+
+    // The scroll offset that puts the anchored line on the bottom row. A line past the end
+    // of the message, which can happen once it wraps to fewer lines, means its last line.
+    fn offset_for_anchor(line_heights: &[usize], (index, line): (usize, usize)) -> usize {
+        let height = line_heights.get(index).copied().unwrap_or(0);
+        let below: usize = line_heights.iter().skip(index + 1).sum();
+        below + height.saturating_sub(1).saturating_sub(line)
+    }
+
+    // Following is when the newest messages are in view, and new ones scroll the view to
+    // stay there. Scrolling up stops it, until scrolling back down or jumping to the end.
+    pub fn is_following(&self) -> bool {
+        self.scroll_offset == 0
+    }
+
+    fn scroll_up(&mut self, lines: usize) -> std::io::Result<()> {
+        let total_lines = self.physical_lines().len();
+        self.scroll_offset = clamp_scroll_offset(
+            self.scroll_offset.saturating_add(lines),
+            total_lines,
+            self.visible_message_rows(),
+        );
+        self.print_all_messages(false)?;
+        self.print_status_bar()
+    }
+
+    fn scroll_down(&mut self, lines: usize) -> std::io::Result<()> {
+        self.scroll_offset = self.scroll_offset.saturating_sub(lines);
+        self.print_all_messages(false)?;
+        self.print_status_bar()
+    }
+
+    fn jump_to_newest(&mut self) -> std::io::Result<()> {
+        self.scroll_down(self.scroll_offset)
+    }
+
+    // Number of terminal rows available for messages between the top and the status bar,
+    // or the toast when one is shown
+    fn visible_message_rows(&self) -> usize {
+        self.height.saturating_sub(self.message_start_line())
+    }
+
+    // Rows from the bottom of the screen to the lowest message row
+    fn message_start_line(&self) -> usize {
+        if self.toast.is_shown() {
+            START_MESSAGE_LINE + 1
+        } else {
+            START_MESSAGE_LINE
+        }
+    }
+
     pub fn clear_console() -> std::io::Result<()> {
         execute!(
             stdout(),
@@ -169,28 +1058,30 @@ impl GraphicsEngine {
     }
 
     pub fn console_format_keeper(graphics_engine: Arc<Mutex<GraphicsEngine>>) {
-        loop {
-            let mut should_clear = false;
-            let mut should_print = false;
+        // When the size last changed, cleared once the screen has been redrawn
+        let mut resized_at: Option<Instant> = None;
+        // The panel shows how long ago each peer was seen, which goes stale by the second
+        let mut panel_drawn_at = Instant::now();
 
+        loop {
             {
                 let mut engine = graphics_engine.lock().unwrap();
                 engine.update_resolution();
 
                 if engine.previous_height != engine.height || engine.previous_width != engine.width
                 {
-                    should_clear = true;
                     engine.previous_height = engine.height;
                     engine.previous_width = engine.width;
-                    should_print = true;
+                    resized_at = Some(Instant::now());
                 }
             }
 
-            if should_clear {
+            // Dragging a window edge resizes many times a second, so wait for it to settle
+            let settled = resized_at
+                .is_some_and(|at| at.elapsed() >= Duration::from_millis(RESIZE_DEBOUNCE_MS));
+            if settled {
+                resized_at = None;
                 let _ = Self::clear_console();
-            }
-
-            if should_print {
                 let mut engine = graphics_engine.lock().unwrap();
                 let _ = engine.print_all_messages(true);
                 let _ = engine.print_status_bar();
@@ -200,14 +1091,50 @@ impl GraphicsEngine {
             // Update status bar every second
             let mut engine = graphics_engine.lock().unwrap();
             let _ = engine.print_status_bar();
+            let _ = engine.expire_toast();
+            if panel_drawn_at.elapsed() >= Duration::from_secs(1) {
+                let _ = engine.print_peer_panel();
+                panel_drawn_at = Instant::now();
+            }
             drop(engine);
 
             std::thread::sleep(Duration::from_millis(100));
         }
     }
 
+    // Returns true once more messages have aged past dim_after since the last call, and
+    // the message area needs repainting
+    pub fn refresh_dimming(&mut self) -> bool {
+        let now = Local::now();
+        let dimmed = self
+            .message_lines
+            .iter()
+            .filter(|message| {
+                age_style(&message.timestamp, &now, self.dim_after) == AgeStyle::Dimmed
+            })
+            .count();
+        let changed = dimmed != self.dimmed_count;
+        self.dimmed_count = dimmed;
+        changed
+    }
+
+    // Returns true if the text changed and the status bar needs repainting
+    pub fn set_typing_status(&mut self, status: String) -> bool {
+        if self.typing_status == status {
+            return false;
+        }
+        self.typing_status = status;
+        true
+    }
+
     pub fn print_status_bar(&mut self) -> std::io::Result<()> {
+        if self.headless {
+            return Ok(());
+        }
         self.update_resolution();
+        if self.too_small() {
+            return Ok(());
+        }
 
         // Get current time
         let now = Local::now();
@@ -216,17 +1143,55 @@ impl GraphicsEngine {
 
         // Calculate spaces for centering and padding
         let terminal_info = format!("{}x{}", self.width, self.height);
-        let help_text = "Ctrl+L: Clear | ↑↓: History";
+        let first_key = |action| {
+            self.key_bindings
+                .keys_for(action)
+                .first()
+                .map_or_else(|| "unbound".to_string(), |key| key.to_string())
+        };
+        let help_text = format!(
+            "{}: Help | {}: Peers | {}/{}: Scroll",
+            first_key(Action::Help),
+            first_key(Action::TogglePeers),
+            first_key(Action::PageUp),
+            first_key(Action::PageDown)
+        );
 
         // Create a more readable status line with distinct sections
-        let status = format!(
-            " 🕒 {} | 📅 {} | 📺 {} | ⌨️  {} ",
-            time_str, date_str, terminal_info, help_text
-        );
+        let mut status = format!(" 🕒 {} | 📅 {} ", time_str, date_str);
+        if let Some(peers) = &self.peer_list {
+            status.push_str(&format!(
+                "| {} ",
+                health_summary(&panel_peers(peers), Instant::now())
+            ));
+        }
+        status.push_str(&format!("| 📺 {} | ⌨️  {} ", terminal_info, help_text));
+        if is_encryption_enabled() {
+            status.push_str("| 🔒 Encrypted ");
+        }
+        if self.do_not_disturb {
+            status.push_str("| 🔕 DND ");
+        }
+        if self.idle.is_away() {
+            status.push_str("| 💤 Away ");
+        }
+        if self.throttled {
+            status.push_str("| ⏳ Sending ");
+        }
+        // Up front so a narrow terminal cuts the help text instead
+        if !self.is_following() {
+            status = format!(" -- MORE -- |{}", status);
+        }
+        if let Some(counter) = &self.length_counter {
+            status = format!(" 📏 {} |{}", counter, status);
+        }
+        if !self.typing_status.is_empty() {
+            status = format!(" ✏️  {} |{}", self.typing_status, status);
+        }
 
-        // Truncate if needed
-        let status_display = if status.len() > self.width {
-            status[..self.width].to_string()
+        // Truncate by display columns, not bytes, so multibyte glyphs are never split
+        let status_display = if str_width(&status) > self.width {
+            truncate_to_width(&status, self.width).to_string()
         } else {
             status
         };
@@ -239,14 +1204,14 @@ impl GraphicsEngine {
         // Move to status bar line
         queue!(
             stdout,
-            cursor::MoveTo(0, (self.height - STATUS_BAR_LINE - 1) as u16)
+            cursor::MoveTo(0, self.height.saturating_sub(STATUS_BAR_LINE + 1) as u16)
         )?;
 
         // Set colors and print status with improved visibility
         queue!(
             stdout,
-            SetBackgroundColor(Color::DarkBlue),
-            SetForegroundColor(Color::White),
+            SetBackgroundColor(self.theme.status_bar_background),
+            SetForegroundColor(self.theme.status_bar_foreground),
             style::SetAttribute(style::Attribute::Bold),
             terminal::Clear(ClearType::CurrentLine),
             style::Print(status_display),
@@ -262,11 +1227,15 @@ impl GraphicsEngine {
     }
 
     pub fn print_input_prompt(&mut self) -> std::io::Result<()> {
-        self.specific_line_print(USER_INPUT_PROMPT, 0)?;
-        let height = self.height;
+        let mut prompt = StyledLine::default();
+        prompt.push_colored(USER_INPUT_PROMPT, self.theme.prompt);
+        if self.headless || self.too_small() {
+            return Ok(());
+        }
+        self.styled_line_print(&prompt, 0)?;
         execute!(
             stdout(),
-            cursor::MoveTo(USER_INPUT_PROMPT_LENGTH as u16, (height - 1) as u16)
+            cursor::MoveTo(USER_INPUT_PROMPT_LENGTH as u16, self.input_row())
         )
     }
 
@@ -276,8 +1245,14 @@ impl GraphicsEngine {
     }
 
     pub fn setup_terminal() -> std::io::Result<()> {
+        // Log lines on stderr would draw over the UI from here on
+        set_terminal_ui(true);
         terminal::enable_raw_mode()?;
-        execute!(stdout(), terminal::EnterAlternateScreen)?;
+        execute!(
+            stdout(),
+            terminal::EnterAlternateScreen,
+            event::EnableBracketedPaste
+        )?;
         Ok(())
     }
 
@@ -288,199 +1263,153 @@ impl GraphicsEngine {
 
         // Disable raw mode and leave alternate screen
         terminal::disable_raw_mode()?;
-        execute!(stdout(), terminal::LeaveAlternateScreen)?;
+        execute!(
+            stdout(),
+            event::DisableBracketedPaste,
+            terminal::LeaveAlternateScreen
+        )?;
 
         // Flush stdout to ensure all terminal commands are processed
         stdout().flush()?;
+        set_terminal_ui(false);
 
         Ok(())
     }
 
     pub fn read_input(&mut self, input: &mut String) -> std::io::Result<(bool, bool)> {
+        // The line is replaced after each message, which can leave the cursor past its end
+        self.input_cursor = line_edit::clamp_cursor(input, self.input_cursor);
+        self.update_length_counter(input)?;
+
         if event::poll(Duration::from_millis(100))? {
+            let event = event::read()?;
+            if matches!(event, Event::Key(_) | Event::Paste(_)) {
+                self.idle.input(Instant::now());
+            }
+
+            // A paste arrives in one piece and is inserted as if typed
+            if let Event::Paste(text) = &event {
+                self.insert_input(input, &flatten_paste(text))?;
+            }
+
             if let Event::Key(KeyEvent {
                 code, modifiers, ..
-            }) = event::read()?
+            }) = event
             {
+                // Any key closes the help overlay and does nothing else
+                if self.help_open {
+                    self.toggle_help();
+                    let _ = self.print_all_messages(false);
+                    return Ok((false, false));
+                }
+
+                if let Some(action) = self.key_bindings.action_for(code, modifiers) {
+                    return self.run_action(action, code, modifiers, input);
+                }
+
                 match code {
                     KeyCode::Enter => {
-                        if !input.is_empty()
-                            && (self.input_history.is_empty()
-                                || self.input_history.last().unwrap() != input)
-                        {
-                            self.input_history.push(input.clone());
-                            if self.input_history.len() > 50 {
-                                self.input_history.remove(0);
-                            }
-                        }
+                        self.input_history.push(input);
                         self.history_position = self.input_history.len();
                         self.current_input.clear();
+                        self.input_cursor = 0;
+                        self.input_scroll = 0;
                         return Ok((true, false));
                     }
-                    KeyCode::Char('q') if modifiers == event::KeyModifiers::CONTROL => {
-                        // Ctrl+Q exits the application immediately
-                        println!("\nExiting application via Ctrl+Q...");
-                        stdout().flush()?;
-                        return Ok((false, true));
+                    KeyCode::Char(c) => {
+                        self.insert_input(input, c.encode_utf8(&mut [0; 4]))?;
                     }
-                    KeyCode::Char('c') if modifiers == event::KeyModifiers::CONTROL => {
-                        // Ctrl+C also exits the application immediately
-                        println!("\nExiting application via Ctrl+C...");
-                        stdout().flush()?;
-                        return Ok((false, true));
+                    KeyCode::Backspace => {
+                        line_edit::delete_before(input, &mut self.input_cursor);
+                        self.redraw_input(input)?;
                     }
-                    KeyCode::Char('l') if modifiers == event::KeyModifiers::CONTROL => {
-                        // Ctrl+L clears the screen
-                        let _ = Self::clear_console();
-                        let _ = self.print_all_messages(true);
-                        let _ = self.print_status_bar();
-                        let _ = self.print_input_prompt();
-                        print!("{}", input);
-                        stdout().flush()?;
+                    KeyCode::Delete => {
+                        line_edit::delete_at(input, self.input_cursor);
+                        self.redraw_input(input)?;
                     }
-                    KeyCode::Char(c) => {
-                        input.push(c);
-                        print!("{}", c);
-                        stdout().flush()?;
+                    KeyCode::Left => {
+                        self.input_cursor = line_edit::previous_boundary(input, self.input_cursor);
+                        self.move_to_input_cursor(input)?;
                     }
-                    KeyCode::Backspace => {
-                        if !input.is_empty() {
-                            input.pop();
-                            execute!(
-                                stdout(),
-                                cursor::MoveLeft(1),
-                                terminal::Clear(ClearType::UntilNewLine)
-                            )?;
-                        }
+                    KeyCode::Right => {
+                        self.input_cursor = line_edit::next_boundary(input, self.input_cursor);
+                        self.move_to_input_cursor(input)?;
                     }
-                    KeyCode::Tab => {
-                        // Tab completion for commands
-                        if input.starts_with('/') {
-                            let matching_commands: Vec<&str> = COMMON_COMMANDS
-                                .iter()
-                                .filter(|&cmd| cmd.starts_with(input.as_str()))
-                                .cloned()
-                                .collect();
-
-                            match matching_commands.len() {
-                                1 => {
-                                    // Exact match, complete the command
-                                    input.clear();
-                                    input.push_str(matching_commands[0]);
-
-                                    // Clear line and print the completed command
-                                    execute!(
-                                        stdout(),
-                                        cursor::MoveTo(
-                                            USER_INPUT_PROMPT_LENGTH as u16,
-                                            (self.height - 1) as u16
-                                        ),
-                                        terminal::Clear(ClearType::UntilNewLine),
-                                        style::Print(input)
-                                    )?;
-                                }
-                                n if n > 1 => {
-                                    // Multiple matches - show options above the input line
-                                    let mut stdout = stdout();
-
-                                    // Save cursor position
-                                    queue!(stdout, cursor::SavePosition)?;
-
-                                    // Move to the line above input
-                                    queue!(stdout, cursor::MoveTo(0, (self.height - 2) as u16))?;
-
-                                    // Print matches
-                                    let matches_str = matching_commands.join("  ");
-                                    queue!(
-                                        stdout,
-                                        terminal::Clear(ClearType::CurrentLine),
-                                        SetForegroundColor(Color::Yellow),
-                                        style::Print(matches_str),
-                                        SetForegroundColor(Color::Reset)
-                                    )?;
-
-                                    // Restore cursor position
-                                    queue!(stdout, cursor::RestorePosition)?;
-                                    stdout.flush()?;
-
-                                    // Find common prefix if any
-                                    if let Some(common_prefix) =
-                                        Self::find_common_prefix(&matching_commands)
-                                    {
-                                        if common_prefix.len() > input.len() {
-                                            input.clear();
-                                            input.push_str(&common_prefix);
-
-                                            // Update the input line
-                                            execute!(
-                                                stdout,
-                                                cursor::MoveTo(
-                                                    USER_INPUT_PROMPT_LENGTH as u16,
-                                                    (self.height - 1) as u16
-                                                ),
-                                                terminal::Clear(ClearType::UntilNewLine),
-                                                style::Print(input)
-                                            )?;
-                                        }
-                                    }
-                                }
-                                _ => {}
-                            }
-                        }
+                    KeyCode::Home => {
+                        self.input_cursor = 0;
+                        self.move_to_input_cursor(input)?;
                     }
-                    KeyCode::Up => {
-                        if !self.input_history.is_empty() {
-                            if self.history_position == self.input_history.len() {
-                                self.current_input = input.clone();
+                    // Pressed again at the end of the input, End goes back to following
+                    // the newest messages, like in a pager
+                    KeyCode::End if self.input_cursor == input.len() => {
+                        self.jump_to_newest()?;
+                    }
+                    KeyCode::End => {
+                        self.input_cursor = input.len();
+                        self.move_to_input_cursor(input)?;
+                    }
+                    KeyCode::Tab if input.starts_with('/') => {
+                        // Tab completion for commands
+                        let matching_commands: Vec<&str> = COMMON_COMMANDS
+                            .iter()
+                            .filter(|&cmd| cmd.starts_with(input.as_str()))
+                            .cloned()
+                            .collect();
+
+                        match matching_commands.len() {
+                            1 => {
+                                // Exact match, complete the command
+                                input.clear();
+                                input.push_str(matching_commands[0]);
+
+                                // Clear line and print the completed command
+                                self.input_cursor = input.len();
+                                self.redraw_input(input)?;
                             }
+                            n if n > 1 => {
+                                // Multiple matches - show options above the input line
+                                let mut stdout = stdout();
 
-                            if self.history_position > 0 {
-                                self.history_position -= 1;
-                                input.clear();
-                                input.push_str(&self.input_history[self.history_position]);
-
-                                // Clear current line and print new input
-                                execute!(
-                                    stdout(),
-                                    cursor::MoveTo(
-                                        USER_INPUT_PROMPT_LENGTH as u16,
-                                        (self.height - 1) as u16
-                                    ),
-                                    terminal::Clear(ClearType::UntilNewLine),
-                                    style::Print(input)
+                                // Save cursor position
+                                queue!(stdout, cursor::SavePosition)?;
+
+                                // Move to the line above input
+                                queue!(
+                                    stdout,
+                                    cursor::MoveTo(0, self.height.saturating_sub(2) as u16)
+                                )?;
+
+                                // Print matches
+                                let matches_str = matching_commands.join("  ");
+                                queue!(
+                                    stdout,
+                                    terminal::Clear(ClearType::CurrentLine),
+                                    SetForegroundColor(self.theme.completion),
+                                    style::Print(matches_str),
+                                    SetForegroundColor(Color::Reset)
                                 )?;
-                            }
-                        }
-                    }
-                    KeyCode::Down => {
-                        if self.history_position < self.input_history.len() {
-                            self.history_position += 1;
-                            input.clear();
-
-                            if self.history_position == self.input_history.len() {
-                                input.push_str(&self.current_input);
-                            } else {
-                                input.push_str(&self.input_history[self.history_position]);
-                            }
 
-                            // Clear current line and print new input
-                            execute!(
-                                stdout(),
-                                cursor::MoveTo(
-                                    USER_INPUT_PROMPT_LENGTH as u16,
-                                    (self.height - 1) as u16
-                                ),
-                                terminal::Clear(ClearType::UntilNewLine),
-                                style::Print(input)
-                            )?;
+                                // Restore cursor position
+                                queue!(stdout, cursor::RestorePosition)?;
+                                stdout.flush()?;
+
+                                // Find common prefix if any
+                                if let Some(common_prefix) =
+                                    Self::find_common_prefix(&matching_commands)
+                                {
+                                    if common_prefix.len() > input.len() {
+                                        input.clear();
+                                        input.push_str(&common_prefix);
+
+                                        // Update the input line
+                                        self.input_cursor = input.len();
+                                        self.redraw_input(input)?;
+                                    }
+                                }
+                            }
+                            _ => {}
                         }
                     }
-                    KeyCode::Esc => {
-                        // Escape key exits the application
-                        println!("\nExiting application via Escape key...");
-                        stdout().flush()?;
-                        return Ok((false, true));
-                    }
                     _ => {}
                 }
             }
@@ -488,6 +1417,201 @@ impl GraphicsEngine {
         Ok((false, false))
     }
 
+    // Does what a bound key is for, returning the same as read_input
+    fn run_action(
+        &mut self,
+        action: Action,
+        code: KeyCode,
+        modifiers: event::KeyModifiers,
+        input: &mut String,
+    ) -> std::io::Result<(bool, bool)> {
+        match action {
+            Action::Quit => {
+                println!("\nExiting application via {}...", Key::new(code, modifiers));
+                stdout().flush()?;
+                return Ok((false, true));
+            }
+            Action::Help => {
+                self.toggle_help();
+                let _ = self.print_all_messages(false);
+            }
+            Action::TogglePeers => {
+                self.toggle_peer_panel();
+                let _ = self.print_all_messages(false);
+                let _ = self.print_input_prompt();
+                self.redraw_input(input)?;
+            }
+            Action::Redraw => {
+                // Repaints the screen, keeping the messages. /clear is the one that
+                // empties them.
+                let _ = Self::clear_console();
+                let _ = self.print_all_messages(true);
+                let _ = self.print_status_bar();
+                let _ = self.print_input_prompt();
+                self.redraw_input(input)?;
+            }
+            Action::PageUp => {
+                let page = self.visible_message_rows().saturating_sub(1).max(1);
+                self.scroll_up(page)?;
+            }
+            Action::PageDown => {
+                let page = self.visible_message_rows().saturating_sub(1).max(1);
+                self.scroll_down(page)?;
+            }
+            Action::ScrollUp => self.scroll_up(1)?,
+            Action::ScrollDown => self.scroll_down(1)?,
+            Action::HistoryUp if !self.input_history.is_empty() => {
+                if self.history_position == self.input_history.len() {
+                    self.current_input = input.clone();
+                }
+
+                if self.history_position > 0 {
+                    self.history_position -= 1;
+                    input.clear();
+                    input.push_str(
+                        self.input_history
+                            .get(self.history_position)
+                            .unwrap_or_default(),
+                    );
+
+                    // Clear current line and print new input
+                    self.input_cursor = input.len();
+                    self.redraw_input(input)?;
+                }
+            }
+            Action::HistoryDown if self.history_position < self.input_history.len() => {
+                self.history_position += 1;
+                input.clear();
+
+                if self.history_position == self.input_history.len() {
+                    input.push_str(&self.current_input);
+                } else {
+                    input.push_str(
+                        self.input_history
+                            .get(self.history_position)
+                            .unwrap_or_default(),
+                    );
+                }
+
+                // Clear current line and print new input
+                self.input_cursor = input.len();
+                self.redraw_input(input)?;
+            }
+            Action::HistoryUp | Action::HistoryDown => {}
+        }
+        Ok((false, false))
+    }
+
+    // Inserts typed or pasted text at the cursor. Appending to a line that still fits only
+    // needs the new text printed, anything else redraws the line.
+    // Text that would take the line past the length limit is cut off there.
+    fn insert_input(&mut self, input: &mut String, text: &str) -> std::io::Result<()> {
+        let text = line_edit::fitting_prefix(input, text, self.max_message_length);
+        if text.is_empty() {
+            return Ok(());
+        }
+        let at_end = self.input_cursor == input.len();
+        line_edit::insert(input, &mut self.input_cursor, text);
+
+        if self.too_small() {
+            return Ok(());
+        }
+        if at_end && str_width(input) < self.input_width() {
+            print!("{}", text);
+            stdout().flush()
+        } else {
+            self.redraw_input(input)
+        }
+    }
+
+    pub fn max_message_length(&self) -> usize {
+        self.max_message_length
+    }
+
+    // Shows, updates or hides the character count as the line changes
+    fn update_length_counter(&mut self, input: &str) -> std::io::Result<()> {
+        let counter = line_edit::length_counter(input, self.max_message_length);
+        if counter == self.length_counter {
+            return Ok(());
+        }
+        self.length_counter = counter;
+        self.print_status_bar()
+    }
+
+    // Columns right of the prompt
+    fn input_width(&self) -> usize {
+        self.width.saturating_sub(USER_INPUT_PROMPT_LENGTH)
+    }
+
+    fn input_window(&self, input: &str) -> line_edit::InputWindow {
+        line_edit::input_window(
+            input,
+            self.input_cursor,
+            self.input_width(),
+            self.input_scroll,
+        )
+    }
+
+    // Reprints the part of the input line in view and puts the cursor back where it belongs
+    fn redraw_input(&mut self, input: &str) -> std::io::Result<()> {
+        let window = self.input_window(input);
+        self.input_scroll = window.start;
+        if self.too_small() {
+            return Ok(());
+        }
+
+        let mut stdout = stdout();
+        queue!(
+            stdout,
+            cursor::MoveTo(USER_INPUT_PROMPT_LENGTH as u16, self.input_row()),
+            terminal::Clear(ClearType::UntilNewLine)
+        )?;
+        if window.more_left || window.more_right {
+            let left = if window.more_left { "<" } else { " " };
+            let right_column = self.width.saturating_sub(1);
+            queue!(
+                stdout,
+                SetForegroundColor(self.theme.completion),
+                style::Print(left),
+                SetForegroundColor(Color::Reset),
+                style::Print(&input[window.start..window.end])
+            )?;
+            if window.more_right {
+                queue!(
+                    stdout,
+                    cursor::MoveTo(right_column as u16, self.input_row()),
+                    SetForegroundColor(self.theme.completion),
+                    style::Print(">"),
+                    SetForegroundColor(Color::Reset)
+                )?;
+            }
+        } else {
+            queue!(stdout, style::Print(input))?;
+        }
+        stdout.flush()?;
+        self.place_input_cursor(&window)
+    }
+
+    // Moves the cursor within the input line, scrolling the line when it leaves the view
+    fn move_to_input_cursor(&mut self, input: &str) -> std::io::Result<()> {
+        let window = self.input_window(input);
+        if window.start != self.input_scroll {
+            return self.redraw_input(input);
+        }
+        self.place_input_cursor(&window)
+    }
+
+    fn place_input_cursor(&self, window: &line_edit::InputWindow) -> std::io::Result<()> {
+        if self.too_small() {
+            return Ok(());
+        }
+        let column = USER_INPUT_PROMPT_LENGTH + window.cursor_column;
+        execute!(
+            stdout(),
+            cursor::MoveTo(column.min(u16::MAX as usize) as u16, self.input_row())
+        )
+    }
+
     // Helper function to find the common prefix among strings
     fn find_common_prefix(strings: &[&str]) -> Option<String> {
         if strings.is_empty() {
@@ -516,3 +1640,553 @@ impl GraphicsEngine {
         }
     }
 }
+
+// The UI needs a row each for the input line, the status bar and a few messages, and room
+// for the prompt and some of what's typed after it
+pub fn is_too_small(width: usize, height: usize) -> bool {
+    width < MIN_TERMINAL_WIDTH || height < MIN_TERMINAL_HEIGHT
+}
+
+// Lines of the notice shown instead of the UI, fewer when there aren't rows for them all
+fn too_small_notice(width: usize, height: usize) -> Vec<String> {
+    let mut lines = vec![
+        "Terminal too small".to_string(),
+        format!(
+            "Resize to at least {}x{}",
+            MIN_TERMINAL_WIDTH, MIN_TERMINAL_HEIGHT
+        ),
+        format!("Now {}x{}", width, height),
+    ];
+    lines.truncate(height);
+    lines
+}
+
+// Pasted text goes on the single input line, so line breaks and tabs become spaces and
+// other control characters are dropped
+fn flatten_paste(text: &str) -> String {
+    let text = text.trim_end_matches(['\r', '\n']).replace("\r\n", "\n");
+    text.chars()
+        .filter_map(|c| match c {
+            '\n' | '\r' | '\t' => Some(' '),
+            c if c.is_control() => None,
+            c => Some(c),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::PEER_PANEL_WIDTH;
+
+    fn engine() -> GraphicsEngine {
+        let mut engine = GraphicsEngine::new(&Config::default());
+        engine.set_headless();
+        engine
+    }
+
+    fn message(content: &str, name: &str, ip: &str) -> Message {
+        Message::new(content.to_string(), name.to_string(), ip.to_string())
+    }
+
+    #[test]
+    fn muted_senders_are_not_shown() {
+        let mut engine = engine();
+        engine.muted_mut().mute("Eve");
+
+        engine.add_message(&message("hello", "bob", "10.0.0.2"));
+        engine.add_message(&message("buy now", "eve", "10.0.0.3"));
+        engine.add_message(&message("hi", "eve", "local"));
+
+        let lines = engine.physical_lines();
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(texts.len(), 2);
+        assert!(texts[0].contains("bob: hello"));
+        // Our own messages always show, even under a muted name
+        assert!(texts[1].contains("YOU >>> eve: hi"));
+        assert!(!texts.iter().any(|text| text.contains("buy now")));
+    }
+
+    #[test]
+    fn muted_senders_never_mention_us() {
+        let mut engine = engine();
+        engine.set_mention_name("alice");
+        assert!(engine.add_message(&message("hey alice", "bob", "10.0.0.2")));
+
+        engine.muted_mut().mute("bob");
+        assert!(!engine.add_message(&message("hey alice", "bob", "10.0.0.2")));
+        assert_eq!(engine.messages().len(), 1);
+    }
+
+    #[test]
+    fn stored_messages_keep_their_fields() {
+        let mut engine = engine();
+        let sent = message("hi bob", "alice", "10.0.0.1")
+            .with_recipient("bob".to_string())
+            .with_sender_node("node-a".to_string());
+        engine.add_message(&sent);
+
+        // A clone keeps everything stored so far
+        let copy = engine.clone();
+        let stored = &copy.messages()[0];
+        assert_eq!(stored.id, sent.id());
+        assert_eq!(stored.sender_name, "alice");
+        assert_eq!(stored.sender_ip, "10.0.0.1");
+        assert_eq!(stored.content, "hi bob");
+        assert_eq!(stored.recipient.as_deref(), Some("bob"));
+        assert_eq!(stored.sender_node.as_deref(), Some("node-a"));
+        assert!(!stored.is_local && !stored.is_action && !stored.is_system);
+
+        let local = DisplayMessage::from_message(&message("waves", "alice", "local").into_action());
+        assert!(local.is_local && local.is_action);
+        assert_eq!(local.delivery, Delivery::Queued);
+    }
+
+    // Only the layout math, drawing would go to the terminal running the tests
+    #[test]
+    fn the_size_floor_is_inclusive() {
+        assert!(!is_too_small(MIN_TERMINAL_WIDTH, MIN_TERMINAL_HEIGHT));
+        assert!(!is_too_small(200, 60));
+        assert!(is_too_small(MIN_TERMINAL_WIDTH - 1, 60));
+        assert!(is_too_small(200, MIN_TERMINAL_HEIGHT - 1));
+
+        assert_eq!(
+            too_small_notice(12, 3),
+            [
+                "Terminal too small".to_string(),
+                format!(
+                    "Resize to at least {}x{}",
+                    MIN_TERMINAL_WIDTH, MIN_TERMINAL_HEIGHT
+                ),
+                "Now 12x3".to_string(),
+            ]
+        );
+        assert_eq!(too_small_notice(80, 1), ["Terminal too small"]);
+        assert!(too_small_notice(80, 0).is_empty());
+    }
+
+    #[test]
+    fn a_one_row_terminal_does_not_panic() {
+        let mut engine = GraphicsEngine::new(&Config::default());
+        for (width, height) in [(80, 1), (1, 1), (0, 0), (3, 80)] {
+            engine.width = width;
+            engine.height = height;
+            engine.add_message(&message(&"wide 日本 text ".repeat(20), "bob", "10.0.0.2"));
+            engine
+                .toast
+                .show("bob joined", Duration::from_secs(5), Instant::now());
+
+            assert!(engine.too_small());
+            assert!(too_small_notice(width, height).len() <= height);
+            assert!(engine.visible_message_rows() <= height);
+            assert!((engine.input_row() as usize) < height.max(1));
+            let lines = engine.physical_lines();
+            assert!(lines
+                .iter()
+                .all(|line| str_width(&line.text) <= engine.message_width().max(2)));
+            let input = "typing 日本 ".repeat(10);
+            let window = engine.input_window(&input);
+            assert!(str_width(&input[window.start..window.end]) <= engine.input_width().max(2));
+        }
+    }
+
+    #[test]
+    fn resizing_changes_capacity_and_keeps_the_newest() {
+        let mut engine = GraphicsEngine::new(&Config {
+            max_message_lines: 5,
+            ..Config::default()
+        });
+        engine.set_headless();
+        engine.height = 20 + START_MESSAGE_LINE;
+        assert_eq!(engine.message_capacity(), 20);
+
+        for n in 0..20 {
+            engine.add_message(&message(&format!("m{}", n), "bob", "10.0.0.2"));
+        }
+        assert_eq!(engine.messages().len(), 20);
+
+        // A shorter terminal falls back to the configured scrollback
+        engine.height = 3;
+        engine.trim_messages();
+        assert_eq!(engine.message_capacity(), 5);
+        let kept: Vec<&str> = engine
+            .messages()
+            .iter()
+            .map(|message| message.content.as_str())
+            .collect();
+        assert_eq!(kept, vec!["m15", "m16", "m17", "m18", "m19"]);
+    }
+
+    #[test]
+    fn anchors_map_to_offsets_at_any_width() {
+        // Three messages wrapped at a narrow width, then at a wide one
+        let narrow = [3, 1, 4];
+        let wide = [1, 1, 2];
+
+        // Four lines up is the second message, its only line
+        let anchor = GraphicsEngine::anchor_for_offset(&narrow, 4).unwrap();
+        assert_eq!(anchor, (1, 0));
+        assert_eq!(GraphicsEngine::offset_for_anchor(&wide, anchor), 2);
+
+        // The second line of the first message, which only has one line once wide
+        let anchor = GraphicsEngine::anchor_for_offset(&narrow, 6).unwrap();
+        assert_eq!(anchor, (0, 1));
+        assert_eq!(GraphicsEngine::offset_for_anchor(&narrow, anchor), 6);
+        assert_eq!(GraphicsEngine::offset_for_anchor(&wide, anchor), 3);
+
+        assert_eq!(GraphicsEngine::anchor_for_offset(&narrow, 8), None);
+    }
+
+    #[test]
+    fn resizing_keeps_a_scrolled_view_on_the_same_message() {
+        let mut engine = engine();
+        engine.width = 40;
+        for n in 0..10 {
+            let content = format!("m{} {}", n, "word ".repeat(20));
+            engine.add_message(&message(&content, "bob", "10.0.0.2"));
+        }
+        engine.scroll_offset = 10;
+        let (index, _) =
+            GraphicsEngine::anchor_for_offset(&engine.line_heights(), engine.scroll_offset)
+                .unwrap();
+
+        engine.rewrap(|engine| engine.width = 120);
+        let anchor =
+            GraphicsEngine::anchor_for_offset(&engine.line_heights(), engine.scroll_offset);
+        assert_eq!(anchor.map(|(at, _)| at), Some(index));
+        assert!(!engine.is_following());
+
+        // At the bottom it keeps following
+        engine.scroll_offset = 0;
+        engine.rewrap(|engine| engine.width = 40);
+        assert!(engine.is_following());
+    }
+
+    #[test]
+    fn do_not_disturb_silences_mentions_but_still_finds_them() {
+        let mut engine = engine();
+        engine.set_mention_name("alice");
+        let mut out = Vec::new();
+        assert!(engine.add_message(&message("hey alice", "bob", "10.0.0.2")));
+        engine.notify_mention_on(&mut out).unwrap();
+        assert_eq!(out, b"\x07");
+
+        engine.set_do_not_disturb(true);
+        let mut out = Vec::new();
+        assert!(engine.add_message(&message("alice?", "bob", "10.0.0.2")));
+        engine.notify_mention_on(&mut out).unwrap();
+        assert!(out.is_empty());
+        // The message itself is still shown
+        assert_eq!(engine.messages().len(), 2);
+    }
+
+    #[test]
+    fn clearing_empties_the_buffer_and_resets_scrolling() {
+        let mut engine = engine();
+        for n in 0..30 {
+            engine.add_message(&message(&format!("m{}", n), "bob", "10.0.0.2"));
+        }
+        engine.scroll_offset = 5;
+        assert!(!engine.is_following());
+
+        engine.clear_messages();
+        assert!(engine.messages().is_empty());
+        assert!(engine.physical_lines().is_empty());
+        assert!(engine.is_following());
+
+        // New messages show up as usual afterwards
+        engine.add_message(&message("after", "bob", "10.0.0.2"));
+        assert_eq!(engine.messages().len(), 1);
+    }
+
+    #[test]
+    fn pastes_are_flattened_onto_one_line() {
+        assert_eq!(flatten_paste("one\ntwo\r\nthree\n"), "one two three");
+        assert_eq!(flatten_paste("a\tb\x07c"), "a bc");
+        assert_eq!(
+            flatten_paste("https://example.com/ü"),
+            "https://example.com/ü"
+        );
+    }
+
+    #[test]
+    fn a_paste_is_inserted_at_the_cursor() {
+        let mut engine = engine();
+        // Too small to draw, so nothing reaches the terminal
+        engine.width = 0;
+        engine.height = 0;
+
+        let mut input = "see  now".to_string();
+        engine.input_cursor = 4;
+        engine
+            .insert_input(&mut input, &flatten_paste("https://a.example\n"))
+            .unwrap();
+        assert_eq!(input, "see https://a.example now");
+        assert_eq!(engine.input_cursor, "see https://a.example".len());
+    }
+
+    #[test]
+    fn a_paste_is_cut_to_the_length_limit() {
+        let mut engine = engine();
+        engine.width = 0;
+        engine.height = 0;
+        engine.max_message_length = 5;
+
+        let mut input = "ab".to_string();
+        engine.input_cursor = 2;
+        engine.insert_input(&mut input, "cdefgh").unwrap();
+        assert_eq!(input, "abcde");
+    }
+
+    #[test]
+    fn repeated_searches_cycle_from_newest_to_oldest() {
+        let mut engine = engine();
+        engine.width = 80;
+        engine.height = 24;
+        for content in ["Lunch?", "no", "lunch at noon", "ok", "LUNCH now"] {
+            engine.add_message(&message(content, "bob", "10.0.0.2"));
+        }
+        assert_eq!(engine.matching_messages("lunch"), vec![0, 2, 4]);
+
+        assert_eq!(engine.search("lunch"), Some((1, 3)));
+        assert_eq!(engine.search_match, Some(4));
+        assert_eq!(engine.search("LUNCH"), Some((2, 3)));
+        assert_eq!(engine.search_match, Some(2));
+        assert_eq!(engine.search("lunch"), Some((3, 3)));
+        assert_eq!(engine.search("lunch"), Some((1, 3)));
+
+        assert_eq!(engine.search("dinner"), None);
+        engine.clear_search();
+        assert_eq!(engine.search_match, None);
+    }
+
+    #[test]
+    fn our_own_messages_never_mention_us() {
+        let mut engine = engine();
+        engine.set_mention_name("alice");
+        assert!(!engine.add_message(&message("I'm alice", "alice", "local")));
+        assert!(engine.add_message(&message("ask Alice", "bob", "10.0.0.2")));
+        assert!(!engine.add_message(&message("malice", "bob", "10.0.0.2")));
+    }
+
+    #[test]
+    fn edits_and_deletes_change_the_stored_message() {
+        let mut engine = engine();
+        let ours = message("helo", "alice", "local");
+        let theirs = message("hi", "bob", "10.0.0.2").with_sender_node("node-b".to_string());
+        engine.add_message(&ours);
+        engine.add_message(&theirs);
+
+        assert!(engine.edit_message(ours.id(), None, "hello"));
+        assert_eq!(engine.messages()[0].content, "hello");
+        assert!(engine.messages()[0].edited);
+
+        assert!(engine.edit_message(theirs.id(), Some("node-b"), "hi all"));
+        assert_eq!(engine.messages()[1].content, "hi all");
+
+        assert!(engine.delete_message(theirs.id(), Some("node-b")));
+        assert_eq!(engine.messages().len(), 1);
+        assert!(engine.delete_message(ours.id(), None));
+        assert!(engine.messages().is_empty());
+    }
+
+    #[test]
+    fn other_peoples_messages_cannot_be_changed() {
+        let mut engine = engine();
+        let ours = message("mine", "alice", "local");
+        let theirs = message("bob's", "bob", "10.0.0.2").with_sender_node("node-b".to_string());
+        engine.add_message(&ours);
+        engine.add_message(&theirs);
+
+        // Not from us, and not from the node that sent it
+        assert!(!engine.edit_message(theirs.id(), None, "changed"));
+        assert!(!engine.edit_message(theirs.id(), Some("node-e"), "changed"));
+        assert!(!engine.edit_message(ours.id(), Some("node-e"), "changed"));
+        assert!(!engine.delete_message(theirs.id(), Some("node-e")));
+        assert!(!engine.delete_message(ours.id(), Some("node-b")));
+        // Nor one we never had
+        assert!(!engine.edit_message(ours.id() ^ 1, None, "changed"));
+
+        let contents: Vec<&str> = engine
+            .messages()
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(contents, ["mine", "bob's"]);
+        assert!(!engine.messages().iter().any(|m| m.edited));
+    }
+
+    #[test]
+    fn history_is_merged_in_time_order_without_duplicates() {
+        let mut engine = engine();
+        let at = |content: &str, timestamp: i64| {
+            message(content, "bob", "10.0.0.2").with_timestamp(timestamp)
+        };
+        let first = at("first", 1_000);
+        let third = at("third", 3_000);
+        // Replayed so they keep their own times, live ones are stamped on arrival
+        engine.add_replayed_message(&first);
+        engine.add_replayed_message(&third);
+
+        let second = at("second", 2_000);
+        assert!(engine.merge_history(&second));
+        // Already shown, whether live or from an earlier batch
+        assert!(!engine.merge_history(&third));
+        assert!(!engine.merge_history(&second));
+
+        let contents: Vec<&str> = engine
+            .messages()
+            .iter()
+            .map(|m| m.content.as_str())
+            .collect();
+        assert_eq!(contents, ["first", "second", "third"]);
+    }
+
+    #[test]
+    fn the_help_overlay_toggles_and_covers_the_peer_panel() {
+        let mut engine = engine();
+        engine.width = 120;
+        engine.height = 24;
+        engine.toggle_peer_panel();
+        assert_eq!(engine.layout(), (120 - PEER_PANEL_WIDTH, PEER_PANEL_WIDTH));
+        assert!(!engine.is_help_open());
+
+        engine.toggle_help();
+        assert!(engine.is_help_open());
+        // The overlay takes the whole width
+        assert_eq!(engine.layout(), (120, 0));
+
+        // Closing it brings back the view it covered
+        engine.toggle_help();
+        assert!(!engine.is_help_open());
+        assert_eq!(engine.layout(), (120 - PEER_PANEL_WIDTH, PEER_PANEL_WIDTH));
+    }
+
+    #[test]
+    fn reactions_to_unknown_messages_are_dropped() {
+        let mut engine = engine();
+        let shown = message("lunch?", "bob", "10.0.0.2");
+        engine.add_message(&shown);
+
+        assert!(engine.react(shown.id(), "👍", "node-a"));
+        assert!(!engine.react(shown.id(), "👍", "node-a"));
+        assert!(!engine.react(shown.id() ^ 1, "👍", "node-a"));
+        assert_eq!(engine.messages()[0].reactions.counts(), vec![("👍", 1)]);
+    }
+
+    #[test]
+    fn a_conversation_renders_to_plain_lines() {
+        let mut engine = engine();
+        engine.width = 40;
+        engine.time_format = "%H:%M".to_string();
+        engine.muted_mut().mute("eve");
+        let noon = Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 0).unwrap();
+        // Pinned IDs too, since the short ID is shown after each message
+        let at_noon = |id: u64, content: &str, name: &str, ip: &str| {
+            message(content, name, ip)
+                .with_id(id << 40)
+                .with_timestamp(noon.timestamp_millis())
+        };
+
+        engine.add_replayed_message(&at_noon(0xa11ce1, "hi all", "alice", "local"));
+        engine.add_replayed_message(&at_noon(0xb0b001, "hello", "bob", "10.0.0.2"));
+        engine.add_replayed_message(&at_noon(0xeee001, "buy now", "eve", "10.0.0.3"));
+        engine.add_replayed_message(&at_noon(
+            0xb0b002,
+            "the quick brown fox jumps over the lazy dog",
+            "bob",
+            "10.0.0.2",
+        ));
+
+        let lines = engine.physical_lines();
+        let texts: Vec<&str> = lines.iter().map(|line| line.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "[12:30] YOU >>> alice: hi all #a11ce1",
+                "[12:30] 10.0.0.2 >>> bob: hello #b0b001",
+                // Eve is muted, so her message is left out
+                "[12:30] 10.0.0.2 >>> bob: the quick",
+                "brown fox jumps over the lazy dog",
+                "#b0b002",
+            ]
+        );
+    }
+
+    #[test]
+    fn scrolling_up_stops_following_until_the_end() {
+        let mut engine = engine();
+        engine.width = 80;
+        engine.height = 12;
+        for n in 0..40 {
+            engine.add_message(&message(&format!("m{}", n), "bob", "10.0.0.2"));
+        }
+        assert!(engine.is_following());
+
+        engine.scroll_up(3).unwrap();
+        assert!(!engine.is_following());
+        // New messages keep the view where it was
+        engine.add_message(&message("new", "bob", "10.0.0.2"));
+        assert_eq!(engine.scroll_offset, 4);
+
+        engine.scroll_down(1).unwrap();
+        assert!(!engine.is_following());
+        engine.scroll_down(10).unwrap();
+        assert!(engine.is_following());
+
+        engine.scroll_up(5).unwrap();
+        engine.jump_to_newest().unwrap();
+        assert!(engine.is_following());
+        // Back at the bottom, new messages scroll the view again
+        engine.add_message(&message("newer", "bob", "10.0.0.2"));
+        assert_eq!(engine.scroll_offset, 0);
+    }
+
+    #[test]
+    fn deleting_while_scrolled_up_keeps_the_view_in_range() {
+        let mut engine = engine();
+        engine.width = 80;
+        engine.height = 12;
+        let visible = engine.visible_message_rows();
+        for n in 0..visible + 2 {
+            engine.add_message(&message(&format!("m{}", n), "alice", "local"));
+        }
+        let ids: Vec<u64> = engine.message_lines.iter().map(|line| line.id).collect();
+
+        // Scrolled all the way up, two lines of history are below the view
+        engine.scroll_up(100).unwrap();
+        assert_eq!(engine.scroll_offset, 2);
+        assert!(engine.delete_message(ids[0], None));
+        assert_eq!(engine.scroll_offset, 1);
+
+        // A message below the view takes its lines with it
+        assert!(engine.delete_message(ids[ids.len() - 1], None));
+        assert_eq!(engine.scroll_offset, 0);
+        assert!(engine.is_following());
+    }
+
+    #[test]
+    fn toggling_ips_repaints_the_prefixes() {
+        let mut engine = engine();
+        engine.width = 80;
+        let peers: PeerList = Arc::new(Mutex::new(HashMap::from([(
+            "10.0.0.2:2223".parse().unwrap(),
+            crate::networking::PeerInfo::new("bob"),
+        )])));
+        engine.set_peer_list(peers);
+        // From an older peer that doesn't send its name with messages
+        let unnamed = message("hi", "", "10.0.0.2").with_id(0);
+        engine.add_message(&unnamed);
+        let text = |engine: &GraphicsEngine| engine.physical_lines()[0].text.clone();
+
+        engine.set_show_ip(true);
+        assert!(text(&engine).ends_with("] 10.0.0.2 >>> : hi"));
+        engine.set_show_ip(false);
+        assert!(text(&engine).ends_with("] bob: hi"));
+
+        // Peers we've never heard a name from fall back to the IP
+        engine.clear_messages();
+        engine.add_message(&message("hey", "", "10.0.0.9").with_id(0));
+        assert!(text(&engine).ends_with("] 10.0.0.9: hey"));
+    }
+}
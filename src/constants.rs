@@ -1,24 +1,227 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
 pub const CHAT_PORT: u16 = 2223;
 pub const DISCOVERY_PORT: u16 = 2224;
+// Chat messages are also accepted over TCP on this port when the tcp setting is on
+pub const TCP_PORT: u16 = 2225;
+// Longest we wait to connect to a peer over TCP before falling back to UDP
+pub const TCP_CONNECT_TIMEOUT_MS: u64 = 1000;
+// Incoming TCP connections idle for this long are closed
+pub const TCP_IDLE_TIMEOUT_SECS: u64 = 30;
+// Number of messages kept in the scrollback
+pub const MAX_MESSAGE_LINES: usize = 64;
+// Default size of the receive buffers, set with recv_buffer_size. It can't be set below the
+// minimum or above the largest UDP payload. Longer packets are dropped as truncated.
 pub const RECV_BUFFER_SIZE: usize = 8192;
+pub const MIN_RECV_BUFFER_SIZE: usize = 4096;
+pub const MAX_RECV_BUFFER_SIZE: usize = 65507;
+// Packets longer than this are sent as fragments of at most this many bytes. Escaping can
+// double a chunk, which still leaves room in MIN_RECV_BUFFER_SIZE.
+pub const FRAGMENT_SIZE: usize = 1024;
+// Largest encoded message we send or reassemble
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// Packets at least this long are compressed for peers that understand it
+pub const COMPRESSION_THRESHOLD: usize = 512;
+// Incomplete fragmented messages kept at once, and how long they may wait for the rest
+pub const MAX_FRAGMENT_SETS: usize = 32;
+pub const FRAGMENT_TIMEOUT_SECS: u64 = 10;
+
+// Peers not heard from within this window are dropped from the peer list
+pub const PEER_EXPIRY_SECS: u64 = 60;
+pub const PEER_REAPER_INTERVAL_SECS: u64 = 10;
+// How often we announce we're still here, well inside PEER_EXPIRY_SECS so a few lost
+// heartbeats don't drop us from peer lists
+pub const HEARTBEAT_INTERVAL_SECS: u64 = 5;
+// The status bar shows the network as stale once no peer has been heard from for a few
+// heartbeats
+pub const PEER_STALE_SECS: u64 = 15;
+// Discovery requests start this often, back off to DISCOVERY_INTERVAL_SECS while we have no
+// peers and to DISCOVERY_MAX_INTERVAL_SECS once we do
+pub const DISCOVERY_MIN_INTERVAL_SECS: u64 = 1;
+pub const DISCOVERY_INTERVAL_SECS: u64 = 15;
+pub const DISCOVERY_MAX_INTERVAL_SECS: u64 = 60;
+
+// A listener that fails is restarted after a delay starting here and doubling up to the max
+pub const LISTENER_RESTART_MIN_SECS: u64 = 1;
+pub const LISTENER_RESTART_MAX_SECS: u64 = 60;
+// A listening socket failing this many receives in a row is replaced by a fresh one
+pub const MAX_CONSECUTIVE_RECV_ERRORS: u32 = 10;
+
+// Longest we wait on the leave announcement while quitting
+pub const LEAVE_ANNOUNCE_TIMEOUT_MS: u64 = 500;
+// Longest we wait on messages still in the send queue while quitting
+pub const SEND_QUEUE_DRAIN_MS: u64 = 2000;
+// Longest quitting may take before we restore the terminal and exit anyway. A little more
+// than the two waits above together.
+pub const SHUTDOWN_TIMEOUT_MS: u64 = 4000;
+
+// Setting this environment variable enables debug logging like --debug
+pub const DEBUG_ENV_VAR: &str = "RETICULUM_DEBUG";
+
+// Our messages are sent again to peers that haven't acked them this many times, first
+// after RETRANSMIT_DELAY_MS and then doubling the wait each time. Messages no peer acked by
+// the end are marked undelivered.
+pub const MAX_RETRANSMITS: u32 = 3;
+pub const RETRANSMIT_DELAY_MS: u64 = 1000;
+// Least time between two of our broadcasts, faster ones wait their turn
+pub const MIN_SEND_INTERVAL_MS: u64 = 200;
+// Messages asked for when joining with history_sync on, and the most we ever send back
+pub const HISTORY_SYNC_COUNT: usize = 20;
+pub const HISTORY_BATCH_MAX: usize = 50;
+// How long to wait for a peer to ask for history, and how often one requester is answered
+pub const HISTORY_SYNC_WAIT_SECS: u64 = 30;
+pub const HISTORY_REQUEST_INTERVAL_SECS: u64 = 60;
+// How long --diagnose listens for peers answering its discovery request
+pub const DIAGNOSE_WAIT_SECS: u64 = 3;
+// Longest /ping waits for a peer's pong
+pub const PING_TIMEOUT_SECS: u64 = 5;
+
+// Stands in for the sender's address on messages a peer sent back as history
+pub const HISTORY_SENDER: &str = "history";
+
+// Messages accepted per second from one source IP, beyond which its messages are dropped
+pub const MAX_MESSAGES_PER_SEC: u32 = 20;
+// Network events waiting for the UI. When it falls behind the oldest are dropped.
+pub const EVENT_QUEUE_SIZE: usize = 100;
+
+// While typing, the start notification is repeated at most this often
+pub const TYPING_RESEND_SECS: u64 = 2;
+// A peer stops showing as typing when no start has arrived for this long
+pub const TYPING_EXPIRY_SECS: u64 = 5;
+
+// Received messages are held this long so ones arriving slightly out of order are shown in
+// the order they were sent
+pub const REORDER_WINDOW_MS: u64 = 300;
+
+// Number of recently seen message IDs remembered for duplicate suppression
+pub const DEDUP_WINDOW: usize = 1000;
+// Copies of a message without an ID, from a legacy peer, are dropped for this long after
+// the first one arrives
+pub const LEGACY_DEDUP_WINDOW_MS: u64 = 2000;
 
 // Used for local network discovery via broadcast
-pub const BROADCAST_ADDR: &str = "255.255.255.255";
-// Multicast address for Tailscale discovery
-pub const TAILSCALE_MULTICAST: &str = "100.100.100.100";
+pub const BROADCAST_ADDR: Ipv4Addr = Ipv4Addr::BROADCAST;
+// Multicast groups joined for discovery and chat on each address family
+pub const MULTICAST_GROUP_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 22, 23);
+pub const MULTICAST_GROUP_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x2223);
+// Tailscale hands out addresses from 100.64.0.0/10, whose second octet spans this range
+pub const TAILSCALE_CGNAT_FIRST: u8 = 64;
+pub const TAILSCALE_CGNAT_LAST: u8 = 127;
+// and IPv6 addresses from fd7a:115c:a1e0::/48
+pub const TAILSCALE_ULA_PREFIX: [u16; 3] = [0xfd7a, 0x115c, 0xa1e0];
+// Broadcasts are also sent to 100.x.y.2 for every x in this range, a sweep for Tailscale
+// peers. The config can narrow it to the part of the range a tailnet uses.
+pub const TAILSCALE_SWEEP_FIRST: u8 = TAILSCALE_CGNAT_FIRST;
+pub const TAILSCALE_SWEEP_LAST: u8 = TAILSCALE_CGNAT_LAST;
+
+// mDNS discovery, used alongside broadcast discovery when enabled in the config
+pub const MDNS_SERVICE_TYPE: &str = "_subnetvox._udp.local.";
+
+// Version of the wire format, sent with discovery packets. Bump it whenever a change would
+// confuse older peers. Peers that don't send a version are treated as the legacy one.
+pub const PROTOCOL_VERSION: u32 = 5;
+pub const LEGACY_PROTOCOL_VERSION: u32 = 1;
+// First version that understands binary chat packets (see codec.rs)
+pub const BINARY_PROTOCOL_VERSION: u32 = 3;
+// First version that understands compressed packets
+pub const COMPRESSION_PROTOCOL_VERSION: u32 = 4;
+// First version that tags chat content when a passphrase is set. Older peers with the same
+// passphrase can't read our messages, and we drop theirs.
+pub const AUTH_PROTOCOL_VERSION: u32 = 5;
 
 // Special message types for discovery
 pub const MSG_TYPE_DISCOVERY: &str = "DISCOVER";
 pub const MSG_TYPE_DISCOVERY_RESPONSE: &str = "DISCOVER_RESPONSE";
 pub const MSG_TYPE_CHAT: &str = "CHAT";
-pub const FIELD_SPLITTER: &str = "~";
-pub const OUTBOUND_MESSAGE_REPORTED_IP: &str = "000.000.000.000";
+pub const MSG_TYPE_DM: &str = "DM";
+// A /me message, shown as "* name does something". Same fields as MSG_TYPE_CHAT.
+pub const MSG_TYPE_ACTION: &str = "ACTION";
+// Sent back to a message's sender to confirm it arrived
+pub const MSG_TYPE_ACK: &str = "ACK";
+pub const MSG_TYPE_FRAGMENT: &str = "FRAG";
+// Sent periodically on the discovery port so peers know we're alive while idle
+pub const MSG_TYPE_HEARTBEAT: &str = "HEARTBEAT";
+// Sent on the discovery port when we quit, so peers drop us without waiting for expiry
+pub const MSG_TYPE_LEAVE: &str = "LEAVE";
+// Tells peers we started or stopped composing a message
+pub const MSG_TYPE_TYPING: &str = "TYPING";
+// Sent by /ping, answered with a pong echoing the ping's timestamp (see ping.rs)
+pub const MSG_TYPE_PING: &str = "PING";
+pub const MSG_TYPE_PONG: &str = "PONG";
+// Tells peers we went away after a while without input, or came back (see away.rs)
+pub const MSG_TYPE_STATUS: &str = "STATUS";
+// Replaces the content of one of the sender's earlier messages. Same fields as
+// MSG_TYPE_CHAT, with the ID of the message being edited and the time of the edit.
+pub const MSG_TYPE_EDIT: &str = "EDIT";
+// Removes one of the sender's earlier messages, whose ID is the content
+pub const MSG_TYPE_DELETE: &str = "DELETE";
+// Reacts to anyone's earlier message with an emoji. Same fields as MSG_TYPE_EDIT, with the
+// emoji as the content.
+pub const MSG_TYPE_REACTION: &str = "REACTION";
+// Longest reaction accepted, in characters. Enough for emoji joined into one, like families
+// and flags.
+pub const MAX_REACTION_CHARS: usize = 16;
+// Who our own reactions are from. Peers' reactions are kept by their node ID.
+pub const LOCAL_REACTOR: &str = "local";
+// Asks a peer for its recent messages when joining, answered with one HISTORY_RESP per
+// message (see history_sync.rs)
+pub const MSG_TYPE_HISTORY_REQ: &str = "HISTORY_REQ";
+pub const MSG_TYPE_HISTORY_RESP: &str = "HISTORY_RESP";
+// Hex digits of a message ID shown next to our messages, for /edit and /delete
+pub const SHORT_ID_DIGITS: usize = 6;
+// Separates the fields of every text packet. The ASCII unit separator, a control character
+// nobody types, so it hardly ever needs escaping. Every encoder and parser goes through this
+// constant. Peers only understand each other when they agree on it, discovery packets are
+// split on it too, so there's no way to negotiate it.
+pub const FIELD_SPLITTER: &str = "\u{1f}";
+// What peers from before the unit separator split on. Their packets are still read, a packet
+// without a single FIELD_SPLITTER in it is split on this instead.
+pub const LEGACY_FIELD_SPLITTER: &str = "~";
+// Prefix used to escape field splitters (and itself) inside field values
+pub const FIELD_ESCAPE: char = '\\';
+// Longer usernames and message content from peers are cut off when received
+pub const MAX_USERNAME_CHARS: usize = 32;
+pub const MAX_CONTENT_CHARS: usize = 8192;
+// Shown for peers whose username we haven't learned yet
+pub const UNKNOWN_PEER_NAME: &str = "Unknown";
+
+// Longest message that can be typed or sent, in characters. Receivers cut content at
+// MAX_CONTENT_CHARS, so the limit can't be set above that.
+pub const MAX_MESSAGE_LENGTH: usize = 2000;
+// Sender names are cut to this many columns in the message list, so a long one can't push
+// the message itself off the screen
+pub const MAX_NAME_WIDTH: usize = 20;
+// The input's character count is shown once it reaches this share of the limit
+pub const LENGTH_COUNTER_PERCENT: usize = 90;
+
+// Seconds without input before we're marked away, 0 turns auto-away off
+pub const AWAY_AFTER_SECS: u64 = 300;
+
+// Seconds before a message is drawn dimmed, 0 keeps every message bright
+pub const DIM_AFTER_SECS: u64 = 1800;
+
+// How long a toast stays above the status bar, like a failed send or someone joining
+pub const TOAST_DURATION_MS: u64 = 4000;
+
+// Entered lines kept for recall with the arrow keys, unless history_size says otherwise
+pub const INPUT_HISTORY_SIZE: usize = 50;
 
 // UI style stuff
 pub const USER_INPUT_PROMPT: &str = "BROADCAST >>> ";
 pub const USER_INPUT_PROMPT_LENGTH: usize = 14;
 pub const START_MESSAGE_LINE: usize = 2;
 pub const STATUS_BAR_LINE: usize = 1;
+// Below this size the UI is replaced by a notice asking for a bigger terminal
+pub const MIN_TERMINAL_WIDTH: usize = 20;
+pub const MIN_TERMINAL_HEIGHT: usize = 5;
+// Columns taken by the peer panel, its separator included, and the fewest the message area
+// is left with. Narrower terminals don't show the panel.
+pub const PEER_PANEL_WIDTH: usize = 30;
+pub const MIN_MESSAGE_AREA_WIDTH: usize = 40;
+// Message timestamps when the config doesn't set time_format, or sets an invalid one
+pub const DEFAULT_TIME_FORMAT: &str = "%H:%M:%S";
+// The screen is redrawn once the terminal size has held still this long
+pub const RESIZE_DEBOUNCE_MS: u64 = 200;
 
 pub const LOGO_ASCII_ART: &str = " _______ _     _ ______  __   _ _______ _______       _    _  _____  _     _\n |______ |     | |_____] | \\  | |______    |           \\  /  |     |  \\___/ \n ______| |_____| |_____] |  \\_| |______    |    _____   \\/   |_____| _/   \\_";
 
@@ -27,4 +230,24 @@ pub const ONLINE_ASCII_ART: &str = "  _____  __   _        _____ __   _ _______\
 pub const DO_BULLSHIT_INTRO: bool = true;
 
 // Common chat commands for tab completion
-pub const COMMON_COMMANDS: [&str; 5] = ["/help", "/quit", "/clear", "/users", "/ping"];
+pub const COMMON_COMMANDS: [&str; 19] = [
+    "/help",
+    "/quit",
+    "/clear",
+    "/users",
+    "/ping",
+    "/stats",
+    "/dm",
+    "/me",
+    "/search",
+    "/dnd",
+    "/showip",
+    "/save",
+    "/mute",
+    "/unmute",
+    "/connect",
+    "/reconnect",
+    "/edit",
+    "/delete",
+    "/react",
+];
@@ -0,0 +1,425 @@
+// Optional end-to-end encryption of message content with a shared passphrase.
+// The key is derived with PBKDF2-HMAC-SHA256 and content is sealed with ChaCha20-Poly1305
+// (RFC 8439) under a random nonce from the OS.
+// With a passphrase, messages also carry an HMAC-SHA256 tag over the packet type, message ID,
+// sender's node ID, name, timestamp, recipient and sealed content, so nobody without the
+// passphrase can pass a message off as someone else's, turn a chat message into an edit of
+// another one, or replay it under a new ID.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::OnceLock;
+
+// Marks encrypted content on the wire: prefix, then hex of nonce || ciphertext || tag
+const ENCRYPTED_PREFIX: &str = "ENC1:";
+// Every peer derives the key from the passphrase alone, so the salt can't differ between
+// users and a guess tried against one chat is tried against all of them. The iterations make
+// each guess costly instead, at the price of a delay when starting with a passphrase: about
+// a third of a second in a release build.
+const KDF_SALT: &[u8] = b"subnetvox-chat-key";
+const KDF_ITERATIONS: u32 = 200_000;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+// Marks an authentication tag ahead of the content: prefix, then hex of the HMAC
+const AUTH_PREFIX: &str = "MAC1:";
+const AUTH_TAG_HEX_LEN: usize = 64;
+// Derives the tag key from the encryption key, so the two never share a key
+const AUTH_KEY_LABEL: &[u8] = b"subnetvox-message-auth";
+
+static CIPHER: OnceLock<Cipher> = OnceLock::new();
+
+// Turns on encryption for the rest of the session. Only the first call has any effect.
+pub fn set_passphrase(passphrase: &str) {
+    let _ = CIPHER.set(Cipher::from_passphrase(passphrase));
+}
+
+pub fn is_encryption_enabled() -> bool {
+    CIPHER.get().is_some()
+}
+
+// Content as it goes on the wire: sealed when a passphrase is set, unchanged otherwise
+pub fn encrypt_content(content: &str) -> String {
+    match CIPHER.get() {
+        Some(cipher) => cipher.encrypt(content),
+        None => content.to_string(),
+    }
+}
+
+// Content as received from the wire. Gives None for content we can't trust: anything that
+// fails to decrypt when a passphrase is set, and encrypted content when none is.
+pub fn decrypt_content(content: &str) -> Option<String> {
+    match CIPHER.get() {
+        Some(cipher) => cipher.decrypt(content),
+        None if content.starts_with(ENCRYPTED_PREFIX) => None,
+        None => Some(content.to_string()),
+    }
+}
+
+// Everything about a message its authentication tag vouches for besides the content
+pub struct Envelope<'a> {
+    pub msg_type: &'a str,
+    pub id: u64,
+    pub node: &'a str,
+    pub name: &'a str,
+    pub timestamp: i64,
+    pub recipient: Option<&'a str>,
+}
+
+// Puts the authentication tag in front of content from encrypt_content when a passphrase
+// is set, leaves it unchanged otherwise
+pub fn sign_content(envelope: &Envelope, content: &str) -> String {
+    match CIPHER.get() {
+        Some(cipher) => cipher.sign(envelope, content),
+        None => content.to_string(),
+    }
+}
+
+// Checks and strips the tag, giving the content for decrypt_content. None when a passphrase
+// is set and the tag is missing or doesn't match, and for tagged content when none is.
+pub fn verify_content<'a>(envelope: &Envelope, content: &'a str) -> Option<&'a str> {
+    match CIPHER.get() {
+        Some(cipher) => cipher.verify(envelope, content),
+        None if content.starts_with(AUTH_PREFIX) => None,
+        None => Some(content),
+    }
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub struct Cipher {
+    key: [u8; 32],
+    auth_key: [u8; 32],
+}
+
+impl Cipher {
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        Self::with_iterations(passphrase, KDF_ITERATIONS)
+    }
+
+    // Like from_passphrase with a cheaper key derivation, which only tests want
+    pub fn with_iterations(passphrase: &str, iterations: u32) -> Self {
+        Self::from_key(derive_key(passphrase.as_bytes(), KDF_SALT, iterations))
+    }
+
+    fn from_key(key: [u8; 32]) -> Self {
+        Self {
+            key,
+            auth_key: auth_mac(&key, AUTH_KEY_LABEL)
+                .finalize()
+                .into_bytes()
+                .into(),
+        }
+    }
+
+    // Strings are length prefixed and the recipient is marked present or not, so no two
+    // envelopes give the same bytes
+    fn auth_tag(&self, envelope: &Envelope, content: &str) -> HmacSha256 {
+        let mut data = Vec::new();
+        let recipient = envelope.recipient.unwrap_or_default();
+        data.push(u8::from(envelope.recipient.is_some()));
+        for field in [
+            envelope.msg_type,
+            envelope.node,
+            envelope.name,
+            recipient,
+            content,
+        ] {
+            data.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            data.extend_from_slice(field.as_bytes());
+        }
+        data.extend_from_slice(&envelope.id.to_be_bytes());
+        data.extend_from_slice(&envelope.timestamp.to_be_bytes());
+        auth_mac(&self.auth_key, &data)
+    }
+
+    pub fn sign(&self, envelope: &Envelope, content: &str) -> String {
+        let tag = self.auth_tag(envelope, content).finalize().into_bytes();
+        format!("{}{}{}", AUTH_PREFIX, to_hex(&tag), content)
+    }
+
+    // The comparison takes as long whichever byte differs, so timing gives nothing away
+    pub fn verify<'a>(&self, envelope: &Envelope, signed: &'a str) -> Option<&'a str> {
+        let rest = signed.strip_prefix(AUTH_PREFIX)?;
+        let tag = from_hex(rest.get(..AUTH_TAG_HEX_LEN)?)?;
+        let content = &rest[AUTH_TAG_HEX_LEN..];
+        self.auth_tag(envelope, content)
+            .verify_slice(&tag)
+            .ok()
+            .map(|()| content)
+    }
+
+    fn aead(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(&self.key))
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead()
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("a chat message is far below ChaCha20's length limit");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        format!("{}{}", ENCRYPTED_PREFIX, to_hex(&sealed))
+    }
+
+    pub fn decrypt(&self, encoded: &str) -> Option<String> {
+        let sealed = from_hex(encoded.strip_prefix(ENCRYPTED_PREFIX)?)?;
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+
+        let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+        let plaintext = self.aead().decrypt(Nonce::from_slice(nonce), sealed).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+// PBKDF2-HMAC-SHA256 (RFC 8018) producing a single 32 byte block
+fn derive_key(password: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2_hmac::<Sha256>(password, salt, iterations, &mut key);
+    key
+}
+
+// An HMAC-SHA256 over the data, ready to finalize or verify
+fn auth_mac(key: &[u8], data: &[u8]) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data);
+    mac
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Deriving a key from a passphrase is slow on purpose, most tests skip it
+    fn cipher() -> Cipher {
+        Cipher::from_key([7; 32])
+    }
+
+    fn hex(text: &str) -> Vec<u8> {
+        from_hex(&text.replace(' ', "")).unwrap()
+    }
+
+    fn envelope<'a>() -> Envelope<'a> {
+        Envelope {
+            msg_type: "CHAT",
+            id: 42,
+            node: "node-a",
+            name: "alice",
+            timestamp: 1_700_000_000_000,
+            recipient: None,
+        }
+    }
+
+    #[test]
+    fn signed_content_verifies() {
+        let cipher = cipher();
+        let signed = cipher.sign(&envelope(), "hello");
+        assert!(signed.starts_with(AUTH_PREFIX));
+        assert_eq!(
+            signed.len(),
+            AUTH_PREFIX.len() + AUTH_TAG_HEX_LEN + "hello".len()
+        );
+        assert_eq!(cipher.verify(&envelope(), &signed), Some("hello"));
+    }
+
+    #[test]
+    fn signing_is_deterministic() {
+        let cipher = cipher();
+        assert_eq!(
+            cipher.sign(&envelope(), "hello"),
+            cipher.sign(&envelope(), "hello")
+        );
+    }
+
+    #[test]
+    fn tampered_content_is_rejected() {
+        let cipher = cipher();
+        let signed = cipher.sign(&envelope(), "hello");
+        let tampered = signed.replace("hello", "hellO");
+        assert_eq!(cipher.verify(&envelope(), &tampered), None);
+    }
+
+    #[test]
+    fn tampered_tag_is_rejected() {
+        let cipher = cipher();
+        let signed = cipher.sign(&envelope(), "hello");
+        let flipped = if signed.as_bytes()[AUTH_PREFIX.len()] == b'0' {
+            "1"
+        } else {
+            "0"
+        };
+        let mut tampered = signed.clone();
+        tampered.replace_range(AUTH_PREFIX.len()..AUTH_PREFIX.len() + 1, flipped);
+        assert_eq!(cipher.verify(&envelope(), &tampered), None);
+    }
+
+    #[test]
+    fn every_envelope_field_is_covered() {
+        let cipher = cipher();
+        let signed = cipher.sign(&envelope(), "hello");
+        let changed = [
+            Envelope {
+                msg_type: "EDIT",
+                ..envelope()
+            },
+            Envelope {
+                id: 43,
+                ..envelope()
+            },
+            Envelope {
+                node: "node-b",
+                ..envelope()
+            },
+            Envelope {
+                name: "mallory",
+                ..envelope()
+            },
+            Envelope {
+                timestamp: 1_700_000_000_001,
+                ..envelope()
+            },
+            Envelope {
+                recipient: Some("bob"),
+                ..envelope()
+            },
+            Envelope {
+                recipient: Some(""),
+                ..envelope()
+            },
+        ];
+        for other in &changed {
+            assert_eq!(cipher.verify(other, &signed), None);
+        }
+    }
+
+    #[test]
+    fn recipient_is_covered() {
+        let cipher = cipher();
+        let to_bob = Envelope {
+            msg_type: "DM",
+            recipient: Some("bob"),
+            ..envelope()
+        };
+        let signed = cipher.sign(&to_bob, "psst");
+        assert_eq!(cipher.verify(&to_bob, &signed), Some("psst"));
+        let to_carol = Envelope {
+            recipient: Some("carol"),
+            ..to_bob
+        };
+        assert_eq!(cipher.verify(&to_carol, &signed), None);
+    }
+
+    #[test]
+    fn other_key_is_rejected() {
+        let signed = cipher().sign(&envelope(), "hello");
+        let other = Cipher::from_key([8; 32]);
+        assert_eq!(other.verify(&envelope(), &signed), None);
+    }
+
+    #[test]
+    fn untagged_and_short_content_is_rejected() {
+        let cipher = cipher();
+        assert_eq!(cipher.verify(&envelope(), "hello"), None);
+        assert_eq!(cipher.verify(&envelope(), "MAC1:abcd"), None);
+        assert_eq!(cipher.verify(&envelope(), "MAC1:"), None);
+    }
+
+    // Widely published PBKDF2-HMAC-SHA256 vectors, with iteration counts low enough to stay
+    // quick in a debug build
+    #[test]
+    fn keys_are_derived_with_pbkdf2() {
+        assert_eq!(
+            derive_key(b"password", b"salt", 1).to_vec(),
+            hex("120fb6cf fcf8b32c 43e72252 56c4f837 a86548c9 2ccc3548 0805987c b70be17b")
+        );
+        assert_eq!(
+            derive_key(b"password", b"salt", 2).to_vec(),
+            hex("ae4d0c95 af6b46d3 2d0adff9 28f06dd0 2a303f8e f3c251df d6e2d85a 95474c43")
+        );
+    }
+
+    // Sealed elsewhere with the RFC 8439 section 2.8.2 key and nonce, so content from peers
+    // on other builds still opens
+    #[test]
+    fn content_sealed_elsewhere_opens() {
+        let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+        let sealed = "ENC1:070000004041424344454647f71e85316eadbcb32f6c82bbf8a12e7f25bfc750a7";
+        assert_eq!(
+            Cipher::from_key(key).decrypt(sealed).as_deref(),
+            Some("hello")
+        );
+    }
+
+    #[test]
+    fn encryption_round_trips() {
+        let cipher = cipher();
+        for plaintext in ["", "hello", "a ~ b ~ c", "héllo 👋", &"x".repeat(1000)] {
+            let sealed = cipher.encrypt(plaintext);
+            assert!(sealed.starts_with(ENCRYPTED_PREFIX));
+            assert_eq!(cipher.decrypt(&sealed).as_deref(), Some(plaintext));
+        }
+    }
+
+    #[test]
+    fn each_encryption_uses_a_fresh_nonce() {
+        let cipher = cipher();
+        assert_ne!(cipher.encrypt("hello"), cipher.encrypt("hello"));
+    }
+
+    #[test]
+    fn wrong_key_is_rejected() {
+        let sealed = cipher().encrypt("hello");
+        assert_eq!(Cipher::from_key([8; 32]).decrypt(&sealed), None);
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let cipher = cipher();
+        let sealed = cipher.encrypt("hello");
+        let last = sealed.len() - 1;
+        let flipped = if sealed.ends_with('0') { "1" } else { "0" };
+        let mut tampered = sealed.clone();
+        tampered.replace_range(last.., flipped);
+        assert_eq!(cipher.decrypt(&tampered), None);
+        assert_eq!(cipher.decrypt(&sealed[..sealed.len() - 2]), None);
+        assert_eq!(cipher.decrypt("ENC1:abcd"), None);
+        assert_eq!(cipher.decrypt("hello"), None);
+    }
+
+    #[test]
+    fn passphrases_derive_their_own_keys() {
+        let cipher = Cipher::with_iterations("secret", 2);
+        let expected = derive_key(b"secret", KDF_SALT, 2);
+        assert_eq!(cipher.key, expected);
+        assert_ne!(cipher.auth_key, cipher.key);
+        assert_ne!(Cipher::with_iterations("Secret", 2).key, expected);
+
+        let sealed = cipher.encrypt("hello");
+        assert_eq!(cipher.decrypt(&sealed).as_deref(), Some("hello"));
+        assert_eq!(
+            Cipher::from_key(expected).decrypt(&sealed).as_deref(),
+            Some("hello")
+        );
+    }
+}
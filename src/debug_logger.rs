@@ -1,26 +1,164 @@
-// Simple debug logger for Reticulum
+// Leveled logger for Reticulum. Lines go to the file set with --debug-log, or to stderr
+// when there's none. While the terminal UI is up stderr would draw over it, so lines
+// without a file to go to are dropped then.
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::Mutex;
 
-static DEBUG_ENABLED: AtomicBool = AtomicBool::new(false);
+// Most important first, so a level lets through everything before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+    fn label(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+}
 
-pub fn enable_debug() {
-    DEBUG_ENABLED.store(true, Ordering::SeqCst);
-    debug_log("Debug logging enabled");
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text.trim().to_lowercase().as_str() {
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            _ => Err(format!(
+                "unknown log level {:?}, expected error, warn, info or debug",
+                text
+            )),
+        }
+    }
 }
 
-#[allow(dead_code)]
-pub fn disable_debug() {
-    debug_log("Debug logging disabled");
-    DEBUG_ENABLED.store(false, Ordering::SeqCst);
+pub const DEFAULT_LOG_LEVEL: Level = Level::Warn;
+
+static MAX_LEVEL: AtomicU8 = AtomicU8::new(DEFAULT_LOG_LEVEL as u8);
+static TERMINAL_UI: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // When set, log lines are appended here instead of stderr
+    static ref LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
 }
 
-pub fn is_debug_enabled() -> bool {
-    DEBUG_ENABLED.load(Ordering::SeqCst)
+pub fn set_level(level: Level) {
+    MAX_LEVEL.store(level as u8, Ordering::SeqCst);
+    log(Level::Debug, "Debug logging enabled");
+}
+
+// Set while the terminal UI owns the screen
+pub fn set_terminal_ui(active: bool) {
+    TERMINAL_UI.store(active, Ordering::SeqCst);
+}
+
+// Sends log output to a file so it never draws over the terminal UI
+pub fn set_log_file(path: &Path) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+    Ok(())
+}
+
+// The line written for a message at the level, None when the most detailed level let
+// through is a less detailed one
+fn log_line(level: Level, max_level: u8, message: &str) -> Option<String> {
+    (level as u8 <= max_level).then(|| {
+        format!(
+            "[{}] [{}] {}\n",
+            chrono::Local::now().format("%H:%M:%S%.3f"),
+            level.label(),
+            message
+        )
+    })
+}
+
+pub fn log(level: Level, message: &str) {
+    let Some(line) = log_line(level, MAX_LEVEL.load(Ordering::SeqCst), message) else {
+        return;
+    };
+
+    let mut log_file = LOG_FILE.lock().unwrap();
+    match log_file.as_mut() {
+        Some(file) => {
+            let _ = file.write_all(line.as_bytes());
+        }
+        None if !TERMINAL_UI.load(Ordering::SeqCst) => {
+            let _ = io::stderr().write_all(line.as_bytes());
+        }
+        None => {}
+    }
 }
 
 pub fn debug_log(message: &str) {
-    if is_debug_enabled() {
-        println!("[DEBUG] {}", message);
+    log(Level::Debug, message);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_lines_are_dropped_by_default() {
+        assert!(log_line(Level::Debug, DEFAULT_LOG_LEVEL as u8, "hidden").is_none());
+        let line = log_line(Level::Debug, Level::Debug as u8, "shown").unwrap();
+        assert!(line.ends_with("[DEBUG] shown\n"));
+    }
+
+    #[test]
+    fn each_level_lets_through_only_what_matters_more() {
+        let levels = [Level::Error, Level::Warn, Level::Info, Level::Debug];
+        for max_level in levels {
+            for level in levels {
+                let line = log_line(level, max_level as u8, "message");
+                assert_eq!(
+                    line.is_some(),
+                    level <= max_level,
+                    "{:?} at {:?}",
+                    level,
+                    max_level
+                );
+            }
+        }
+        let line = log_line(Level::Warn, Level::Info as u8, "socket closed").unwrap();
+        assert!(line.ends_with("[WARN] socket closed\n"));
+    }
+
+    #[test]
+    fn levels_are_read_from_their_names() {
+        assert_eq!("error".parse(), Ok(Level::Error));
+        assert_eq!(" Warn ".parse(), Ok(Level::Warn));
+        assert_eq!("INFO".parse(), Ok(Level::Info));
+        assert_eq!("debug".parse(), Ok(Level::Debug));
+        assert!("verbose".parse::<Level>().is_err());
     }
-}
\ No newline at end of file
+
+    // No other test sets a log file or a level, so nothing at debug level gets written
+    #[test]
+    fn debug_log_is_a_no_op_when_disabled() {
+        let path = std::env::temp_dir().join(format!("reticulum-log-{}", std::process::id()));
+        set_log_file(&path).unwrap();
+        debug_log("should not appear");
+        log(Level::Error, "should appear");
+        *LOG_FILE.lock().unwrap() = None;
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert!(!written.contains("should not appear"));
+        assert!(written.contains("[ERROR] should appear"));
+    }
+}
@@ -0,0 +1,131 @@
+// Bounded memory of recently seen message IDs, used to drop duplicate packets. Messages
+// from legacy peers carry no ID, so their copies are recognised by content for a short while
+// instead.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, Instant};
+
+pub struct RecentIds {
+    seen: HashSet<u64>,
+    order: VecDeque<u64>,
+    capacity: usize,
+}
+
+impl RecentIds {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            seen: HashSet::with_capacity(capacity),
+            order: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    // Records the ID, returning false if it was already seen within the window
+    pub fn insert(&mut self, id: u64) -> bool {
+        if !self.seen.insert(id) {
+            return false;
+        }
+
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+
+        true
+    }
+}
+
+// Messages without an ID seen within the window, by a hash of who sent them and what they
+// said. Copies arriving over several paths land well within the window. The same text sent
+// again later is a new message, since there's no ID to tell it apart.
+pub struct RecentContent {
+    // When each hash was first seen
+    seen: HashMap<u64, Instant>,
+    window: Duration,
+}
+
+impl RecentContent {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            seen: HashMap::new(),
+            window,
+        }
+    }
+
+    fn hash(source: &str, name: &str, content: &str) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        (source, name, content).hash(&mut hasher);
+        hasher.finish()
+    }
+
+    // Records the message, returning false if the same one was already seen within the
+    // window. The window counts from the first copy, so a steady stream of copies can't
+    // hold it open.
+    pub fn insert(&mut self, source: &str, name: &str, content: &str, now: Instant) -> bool {
+        let window = self.window;
+        self.seen
+            .retain(|_, first_seen| now.saturating_duration_since(*first_seen) < window);
+
+        match self.seen.entry(Self::hash(source, name, content)) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                entry.insert(now);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeated_ids_are_rejected() {
+        let mut ids = RecentIds::new(10);
+        assert!(ids.insert(1));
+        assert!(ids.insert(2));
+        assert!(!ids.insert(1));
+        assert!(!ids.insert(2));
+    }
+
+    #[test]
+    fn oldest_ids_are_forgotten_past_capacity() {
+        let mut ids = RecentIds::new(3);
+        for id in 1..=4 {
+            assert!(ids.insert(id));
+        }
+        // 1 fell out of the window, 2 to 4 are still remembered
+        assert!(ids.insert(1));
+        assert!(!ids.insert(4));
+    }
+
+    #[test]
+    fn id_less_copies_within_the_window_are_rejected() {
+        let mut recent = RecentContent::new(Duration::from_secs(2));
+        let start = Instant::now();
+        assert!(recent.insert("10.0.0.2", "bob", "hi", start));
+        assert!(!recent.insert("10.0.0.2", "bob", "hi", start + Duration::from_millis(300)));
+        // Copies don't hold the window open
+        assert!(!recent.insert("10.0.0.2", "bob", "hi", start + Duration::from_millis(1900)));
+        assert!(recent.insert("10.0.0.2", "bob", "hi", start + Duration::from_secs(2)));
+
+        // The same text sent again later is a new message
+        assert!(recent.insert("10.0.0.2", "bob", "hi", start + Duration::from_secs(12)));
+    }
+
+    #[test]
+    fn other_senders_and_sources_are_told_apart() {
+        let mut recent = RecentContent::new(Duration::from_secs(2));
+        let now = Instant::now();
+        assert!(recent.insert("10.0.0.2", "bob", "hi", now));
+        assert!(recent.insert("10.0.0.3", "bob", "hi", now));
+        assert!(recent.insert("10.0.0.2", "carol", "hi", now));
+        assert!(recent.insert("10.0.0.2", "bob", "hi!", now));
+    }
+}
@@ -0,0 +1,225 @@
+// Tracks sent messages until the peers they went to acknowledge them. Peers that haven't
+// acked are sent the message again after a delay that doubles with each try. Once the tries
+// run out the message is given up on, and counts as failed if nobody acked it at all.
+
+use crate::message::Message;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+struct PendingMessage {
+    message: Message,
+    // Peers that haven't acked yet, one address per host
+    waiting_on: Vec<SocketAddr>,
+    acked: bool,
+    retries: u32,
+    next_try: Instant,
+}
+
+// What's due when the tracker is polled
+#[derive(Default)]
+pub struct DeliveryUpdate {
+    // Messages to send again, with the peers to send them to
+    pub retransmit: Vec<(Message, Vec<SocketAddr>)>,
+    // Messages given up on without a single ack
+    pub failed: Vec<u64>,
+}
+
+pub struct DeliveryTracker {
+    pending: HashMap<u64, PendingMessage>,
+    max_retries: u32,
+    // Before the first retry, doubled for each one after it
+    first_delay: Duration,
+}
+
+impl DeliveryTracker {
+    pub fn new(max_retries: u32, first_delay: Duration) -> Self {
+        Self {
+            pending: HashMap::new(),
+            max_retries,
+            first_delay,
+        }
+    }
+
+    // With no peers to wait on, the message is still given up on if no ack arrives in time
+    pub fn track(&mut self, message: Message, peers: Vec<SocketAddr>, now: Instant) {
+        self.pending.insert(
+            message.id(),
+            PendingMessage {
+                message,
+                waiting_on: peers,
+                acked: false,
+                retries: 0,
+                next_try: now + self.first_delay,
+            },
+        );
+    }
+
+    // Returns true the first time an ack arrives for a pending message. Acks from other
+    // peers only stop it being sent to them again, and acks for unknown IDs return false.
+    pub fn acknowledge(&mut self, id: u64, from: IpAddr) -> bool {
+        let Some(pending) = self.pending.get_mut(&id) else {
+            return false;
+        };
+
+        let first = !pending.acked;
+        pending.acked = true;
+        pending.waiting_on.retain(|peer| peer.ip() != from);
+        if pending.waiting_on.is_empty() {
+            self.pending.remove(&id);
+        }
+        first
+    }
+
+    // Stops waiting on a message that never made it onto the network
+    pub fn forget(&mut self, id: u64) {
+        self.pending.remove(&id);
+    }
+
+    pub fn poll(&mut self, now: Instant) -> DeliveryUpdate {
+        let mut update = DeliveryUpdate::default();
+        let mut given_up = Vec::new();
+
+        for (id, pending) in self.pending.iter_mut() {
+            if pending.next_try > now {
+                continue;
+            }
+            if pending.retries >= self.max_retries {
+                given_up.push(*id);
+                if !pending.acked {
+                    update.failed.push(*id);
+                }
+                continue;
+            }
+
+            pending.retries += 1;
+            pending.next_try = now + self.first_delay * 2u32.saturating_pow(pending.retries);
+            if !pending.waiting_on.is_empty() {
+                update
+                    .retransmit
+                    .push((pending.message.clone(), pending.waiting_on.clone()));
+            }
+        }
+
+        for id in given_up {
+            self.pending.remove(&id);
+        }
+        update
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 2223)
+    }
+
+    fn sent() -> Message {
+        Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string())
+    }
+
+    #[test]
+    fn the_first_ack_marks_delivery() {
+        let mut tracker = DeliveryTracker::new(3, Duration::from_secs(1));
+        let message = sent();
+        let id = message.id();
+        tracker.track(
+            message,
+            vec![peer("10.0.0.2"), peer("10.0.0.3")],
+            Instant::now(),
+        );
+
+        assert!(tracker.acknowledge(id, "10.0.0.2".parse().unwrap()));
+        // Later acks, even repeats from the same peer, don't mark it again
+        assert!(!tracker.acknowledge(id, "10.0.0.3".parse().unwrap()));
+        assert!(!tracker.acknowledge(id, "10.0.0.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn acks_must_match_a_pending_id() {
+        let mut tracker = DeliveryTracker::new(3, Duration::from_secs(1));
+        let message = sent();
+        let id = message.id();
+        tracker.track(message, vec![peer("10.0.0.2")], Instant::now());
+
+        assert!(!tracker.acknowledge(id.wrapping_add(1), "10.0.0.2".parse().unwrap()));
+        tracker.forget(id);
+        assert!(!tracker.acknowledge(id, "10.0.0.2".parse().unwrap()));
+    }
+
+    // Acks come from the peer's chat socket, not the port the message went to
+    #[test]
+    fn acks_match_peers_by_ip() {
+        let mut tracker = DeliveryTracker::new(3, Duration::from_secs(1));
+        let message = sent();
+        let id = message.id();
+        let start = Instant::now();
+        tracker.track(message, vec![peer("10.0.0.2"), peer("10.0.0.3")], start);
+
+        tracker.acknowledge(id, "10.0.0.2".parse().unwrap());
+        let update = tracker.poll(start + Duration::from_secs(1));
+        assert_eq!(update.retransmit.len(), 1);
+        assert_eq!(update.retransmit[0].1, vec![peer("10.0.0.3")]);
+    }
+
+    // Polls once a second for a minute, counting retransmissions and failures
+    fn run(tracker: &mut DeliveryTracker, start: Instant) -> (usize, Vec<u64>) {
+        let mut retransmits = 0;
+        let mut failed = Vec::new();
+        for second in 1..=60 {
+            let update = tracker.poll(start + Duration::from_secs(second));
+            retransmits += update.retransmit.len();
+            failed.extend(update.failed);
+        }
+        (retransmits, failed)
+    }
+
+    #[test]
+    fn acked_messages_are_not_sent_again() {
+        let mut tracker = DeliveryTracker::new(3, Duration::from_secs(1));
+        let message = sent();
+        let id = message.id();
+        let start = Instant::now();
+        tracker.track(message, vec![peer("10.0.0.2"), peer("10.0.0.3")], start);
+        tracker.acknowledge(id, "10.0.0.2".parse().unwrap());
+        tracker.acknowledge(id, "10.0.0.3".parse().unwrap());
+
+        assert_eq!(run(&mut tracker, start), (0, vec![]));
+    }
+
+    #[test]
+    fn unacked_messages_are_retried_then_failed() {
+        let mut tracker = DeliveryTracker::new(3, Duration::from_secs(1));
+        let message = sent();
+        let id = message.id();
+        let start = Instant::now();
+        tracker.track(message, vec![peer("10.0.0.2")], start);
+
+        // Retries at 1s, 3s and 7s, each twice as long after the one before
+        assert!(tracker.poll(start).retransmit.is_empty());
+        for (second, due) in [(1, true), (2, false), (3, true), (6, false), (7, true)] {
+            let update = tracker.poll(start + Duration::from_secs(second));
+            assert_eq!(update.retransmit.len(), usize::from(due), "at {}s", second);
+            assert!(update.failed.is_empty());
+        }
+        assert_eq!(
+            run(&mut tracker, start + Duration::from_secs(7)),
+            (0, vec![id])
+        );
+    }
+
+    #[test]
+    fn partly_acked_messages_are_given_up_on_without_failing() {
+        let mut tracker = DeliveryTracker::new(2, Duration::from_secs(1));
+        let message = sent();
+        let id = message.id();
+        let start = Instant::now();
+        tracker.track(message, vec![peer("10.0.0.2"), peer("10.0.0.3")], start);
+        tracker.acknowledge(id, "10.0.0.2".parse().unwrap());
+
+        assert_eq!(run(&mut tracker, start), (2, vec![]));
+        assert!(!tracker.acknowledge(id, "10.0.0.3".parse().unwrap()));
+    }
+}
@@ -0,0 +1,431 @@
+// The --diagnose report: a one-shot check of what peer discovery depends on, run instead of
+// the chat. It binds the ports, looks at which local addresses traffic leaves from, sends a
+// discovery request and waits briefly for answers, then prints what it found along with
+// hints for the usual culprits such as firewalls and mismatched ports.
+
+use crate::config::Config;
+use crate::constants::{
+    DIAGNOSE_WAIT_SECS, MSG_TYPE_DISCOVERY, MSG_TYPE_DISCOVERY_RESPONSE, MSG_TYPE_HEARTBEAT,
+    TAILSCALE_CGNAT_FIRST, TAILSCALE_CGNAT_LAST, TAILSCALE_ULA_PREFIX,
+};
+use crate::networking::{self, Broadcaster, Receiver};
+use crate::udp::DualSocket;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use tokio::time::{self, Duration, Instant};
+
+// Public addresses that are only used to ask the OS which local address it would send
+// from. Connecting a UDP socket sends nothing.
+const ROUTE_PROBE_V4: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8)), 53);
+const ROUTE_PROBE_V6: SocketAddr = SocketAddr::new(
+    IpAddr::V6(Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888)),
+    53,
+);
+// Tailscale's own resolver, only routable while Tailscale is up
+const ROUTE_PROBE_TAILSCALE: SocketAddr =
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, 100, 100, 100)), 53);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Warn,
+    Fail,
+}
+
+pub struct Check {
+    pub name: String,
+    pub outcome: Outcome,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, outcome: Outcome, detail: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            outcome,
+            detail: detail.into(),
+        }
+    }
+}
+
+// What the checks found, before it's turned into the report
+pub struct Findings {
+    pub discovery_port: u16,
+    pub chat_port: u16,
+    // Error from binding each port, None when it worked
+    pub discovery_bind: Option<String>,
+    pub chat_bind: Option<String>,
+    // Only checked with --tcp
+    pub tcp: Option<(u16, Option<String>)>,
+    pub local_v4: Option<IpAddr>,
+    pub local_v6: Option<IpAddr>,
+    pub tailscale: Option<IpAddr>,
+    pub broadcast_address: Ipv4Addr,
+    // Name and address of everyone who answered the discovery request, None when it
+    // couldn't be sent
+    pub answers: Option<Vec<(String, IpAddr)>>,
+}
+
+// Runs every check. Takes about DIAGNOSE_WAIT_SECS while waiting for answers.
+pub async fn run(config: &Config) -> Findings {
+    let discovery_socket = Receiver::bind_discovery(config);
+    let chat_bind = Receiver::bind_chat(config).err().map(|e| e.to_string());
+    let tcp = config.tcp.then(|| {
+        let error = Receiver::bind_tcp(config.tcp_port, config.bind_address)
+            .err()
+            .map(|e| e.to_string());
+        (config.tcp_port, error)
+    });
+
+    let (discovery_bind, answers) = match discovery_socket {
+        Ok(socket) => (None, Some(probe(config, &socket).await)),
+        Err(e) => (Some(e.to_string()), None),
+    };
+
+    let local = local_addresses(route_source);
+
+    Findings {
+        discovery_port: config.discovery_port,
+        chat_port: config.chat_port,
+        discovery_bind,
+        chat_bind,
+        tcp,
+        local_v4: local.v4,
+        local_v6: local.v6,
+        tailscale: local.tailscale,
+        broadcast_address: config.broadcast_address,
+        answers,
+    }
+}
+
+// Sends a discovery request from the discovery port and collects whoever answers or
+// announces themselves until the wait is over
+async fn probe(config: &Config, socket: &DualSocket) -> Vec<(String, IpAddr)> {
+    let node_id = networking::new_node_id();
+    let username = config
+        .username
+        .clone()
+        .unwrap_or_else(|| "diagnose".to_string());
+    let broadcaster = Broadcaster::new(config, username, node_id.clone());
+    broadcaster.send_discovery_from(socket).await;
+
+    let mut answers: Vec<(String, IpAddr)> = Vec::new();
+    let mut buf = vec![0u8; 2048];
+    let deadline = Instant::now() + Duration::from_secs(DIAGNOSE_WAIT_SECS);
+    while let Ok(received) = time::timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        let Ok((size, src)) = received else {
+            continue;
+        };
+        let Ok(parsed) = Receiver::parse_discovery(&buf[..size]) else {
+            continue;
+        };
+        let announces = matches!(
+            parsed.msg_type.as_str(),
+            MSG_TYPE_DISCOVERY | MSG_TYPE_DISCOVERY_RESPONSE | MSG_TYPE_HEARTBEAT
+        );
+        if announces
+            && parsed.sender_node != node_id
+            && !answers.iter().any(|(_, ip)| *ip == src.ip())
+        {
+            answers.push((parsed.sender_name, src.ip()));
+        }
+    }
+
+    answers
+}
+
+struct LocalAddresses {
+    v4: Option<IpAddr>,
+    v6: Option<IpAddr>,
+    tailscale: Option<IpAddr>,
+}
+
+// The addresses traffic leaves from, found by asking route_source which local address
+// would reach each probe. Without Tailscale its resolver's probe leaves from the LAN
+// address, if it has a route at all, which isn't taken for a Tailscale one.
+fn local_addresses(route_source: impl Fn(SocketAddr) -> Option<IpAddr>) -> LocalAddresses {
+    LocalAddresses {
+        v4: route_source(ROUTE_PROBE_V4),
+        v6: route_source(ROUTE_PROBE_V6),
+        tailscale: route_source(ROUTE_PROBE_TAILSCALE).filter(is_tailscale),
+    }
+}
+
+// The local address the OS would send to target from, if it has a route there at all
+fn route_source(target: SocketAddr) -> Option<IpAddr> {
+    let any = match target {
+        SocketAddr::V4(_) => SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0),
+        SocketAddr::V6(_) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0),
+    };
+    let socket = UdpSocket::bind(any).ok()?;
+    socket.connect(target).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified()).then_some(ip)
+}
+
+fn is_tailscale(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [first, second, ..] = v4.octets();
+            first == 100 && (TAILSCALE_CGNAT_FIRST..=TAILSCALE_CGNAT_LAST).contains(&second)
+        }
+        IpAddr::V6(v6) => v6.segments()[..3] == TAILSCALE_ULA_PREFIX,
+    }
+}
+
+fn bind_check(name: String, error: &Option<String>) -> Check {
+    match error {
+        None => Check::new(name, Outcome::Pass, "listening"),
+        Some(e) => Check::new(name, Outcome::Fail, e.clone()),
+    }
+}
+
+pub fn checks(findings: &Findings) -> Vec<Check> {
+    let mut checks = vec![
+        bind_check(
+            format!("Discovery port {}/udp", findings.discovery_port),
+            &findings.discovery_bind,
+        ),
+        bind_check(
+            format!("Chat port {}/udp", findings.chat_port),
+            &findings.chat_bind,
+        ),
+    ];
+    if let Some((port, error)) = &findings.tcp {
+        checks.push(bind_check(format!("Chat port {}/tcp", port), error));
+    }
+
+    checks.push(match findings.local_v4 {
+        Some(ip) => Check::new("IPv4 address", Outcome::Pass, ip.to_string()),
+        None => Check::new("IPv4 address", Outcome::Warn, "none, no IPv4 network is up"),
+    });
+    checks.push(match findings.local_v6 {
+        Some(ip) => Check::new("IPv6 address", Outcome::Pass, ip.to_string()),
+        None => Check::new("IPv6 address", Outcome::Warn, "none"),
+    });
+    checks.push(match findings.tailscale {
+        Some(ip) => Check::new("Tailscale", Outcome::Pass, format!("up as {}", ip)),
+        None => Check::new("Tailscale", Outcome::Warn, "not connected"),
+    });
+
+    checks.push(match &findings.answers {
+        None => Check::new(
+            "Discovery",
+            Outcome::Fail,
+            "not tried, the discovery port isn't available",
+        ),
+        Some(answers) if answers.is_empty() => Check::new(
+            "Discovery",
+            Outcome::Warn,
+            format!("no peers answered within {}s", DIAGNOSE_WAIT_SECS),
+        ),
+        Some(answers) => {
+            let peers: Vec<String> = answers
+                .iter()
+                .map(|(name, ip)| format!("{} ({})", name, ip))
+                .collect();
+            Check::new(
+                "Discovery",
+                Outcome::Pass,
+                format!("{} answered: {}", answers.len(), peers.join(", ")),
+            )
+        }
+    });
+
+    checks
+}
+
+// Likely reasons discovery isn't working, given what the checks found
+pub fn hints(findings: &Findings) -> Vec<String> {
+    let mut hints = Vec::new();
+
+    if findings.discovery_bind.is_some() || findings.chat_bind.is_some() {
+        hints.push(
+            "A port couldn't be bound. Close the other program using it or pick free ones \
+             with --chat-port and --discovery-port."
+                .to_string(),
+        );
+    }
+    if findings.local_v4.is_none() && findings.local_v6.is_none() {
+        hints.push("No network is up. Connect to Wi-Fi or Ethernet and try again.".to_string());
+    }
+    if let (Some(IpAddr::V4(local)), broadcast) = (findings.local_v4, findings.broadcast_address) {
+        if !broadcast.is_broadcast() && local.octets()[..2] != broadcast.octets()[..2] {
+            hints.push(format!(
+                "broadcast_address {} doesn't look like it's on the same network as {}. \
+                 Remove it from the config to use 255.255.255.255.",
+                broadcast, local
+            ));
+        }
+    }
+    if findings.answers.as_ref().is_some_and(Vec::is_empty) {
+        hints.push(format!(
+            "Nobody answered. Check that a peer is running, that both sides use discovery \
+             port {} and chat port {}, and that no firewall drops UDP on them. Networks \
+             that block broadcasts, such as guest Wi-Fi, need /connect <ip> instead.",
+            findings.discovery_port, findings.chat_port
+        ));
+        if findings.tailscale.is_none() {
+            hints.push(
+                "Peers on other networks are only reachable over Tailscale, which isn't \
+                 connected."
+                    .to_string(),
+            );
+        }
+    }
+
+    hints
+}
+
+// Plain-text report, one line per check followed by the hints
+pub fn format_report(checks: &[Check], hints: &[String]) -> String {
+    let mut report = String::from("Subnet Vox connectivity check\n\n");
+    for check in checks {
+        let label = match check.outcome {
+            Outcome::Pass => "ok",
+            Outcome::Warn => "warn",
+            Outcome::Fail => "FAIL",
+        };
+        report.push_str(&format!(
+            "[{:>4}] {}: {}\n",
+            label, check.name, check.detail
+        ));
+    }
+
+    if hints.is_empty() {
+        report.push_str("\nNo problems found.\n");
+    } else {
+        report.push_str("\nHints:\n");
+        for hint in hints {
+            report.push_str(&format!("- {}\n", hint));
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn findings(local_v4: Option<IpAddr>, tailscale: Option<IpAddr>) -> Findings {
+        Findings {
+            discovery_port: 2224,
+            chat_port: 2223,
+            discovery_bind: None,
+            chat_bind: None,
+            tcp: None,
+            local_v4,
+            local_v6: None,
+            tailscale,
+            broadcast_address: Ipv4Addr::BROADCAST,
+            answers: Some(Vec::new()),
+        }
+    }
+
+    // Routes of a host with the given interfaces, each reaching the probes it's listed with
+    fn routes<'a>(
+        interfaces: &'a [(&'a str, &'a [SocketAddr])],
+    ) -> impl Fn(SocketAddr) -> Option<IpAddr> + 'a {
+        move |target| {
+            interfaces
+                .iter()
+                .find(|(_, reaches)| reaches.contains(&target))
+                .map(|(address, _)| address.parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn local_addresses_come_from_the_routes() {
+        let lan: &[SocketAddr] = &[ROUTE_PROBE_V4];
+        let tailnet: &[SocketAddr] = &[ROUTE_PROBE_TAILSCALE];
+        let local = local_addresses(routes(&[
+            ("192.168.1.20", lan),
+            ("fd7a:115c:a1e0::5", &[ROUTE_PROBE_V6]),
+            ("100.90.1.2", tailnet),
+        ]));
+        assert_eq!(local.v4, Some("192.168.1.20".parse().unwrap()));
+        assert_eq!(local.v6, Some("fd7a:115c:a1e0::5".parse().unwrap()));
+        assert_eq!(local.tailscale, Some("100.90.1.2".parse().unwrap()));
+    }
+
+    #[test]
+    fn a_lan_route_to_the_tailscale_resolver_isnt_tailscale() {
+        // Without Tailscale, the default route takes the resolver's probe too
+        let everything: &[SocketAddr] = &[ROUTE_PROBE_V4, ROUTE_PROBE_TAILSCALE];
+        let local = local_addresses(routes(&[("192.168.1.20", everything)]));
+        assert_eq!(local.v4, Some("192.168.1.20".parse().unwrap()));
+        assert_eq!(local.v6, None);
+        assert_eq!(local.tailscale, None);
+
+        let offline = local_addresses(routes(&[]));
+        assert!(offline.v4.is_none() && offline.v6.is_none() && offline.tailscale.is_none());
+    }
+
+    #[test]
+    fn tailscale_addresses_are_in_the_cgnat_range() {
+        let is = |text: &str| is_tailscale(&text.parse().unwrap());
+        assert!(is("100.64.0.1"));
+        assert!(is("100.101.102.103"));
+        assert!(is("100.127.255.254"));
+        assert!(!is("100.128.0.1"));
+        assert!(!is("100.63.0.1"));
+        assert!(!is("192.168.1.2"));
+        assert!(is("fd7a:115c:a1e0::1"));
+        assert!(is("fd7a:115c:a1e0:ab12:4843:cd96:6258:b240"));
+        assert!(!is("fd7a:115c:a1e1::1"));
+        assert!(!is("2001:db8::1"));
+    }
+
+    #[test]
+    fn detected_addresses_show_in_the_report() {
+        let lan = "192.168.1.20".parse().unwrap();
+        let tailnet = "100.90.1.2".parse().unwrap();
+        let checks = checks(&findings(Some(lan), Some(tailnet)));
+        let detail = |name: &str| {
+            checks
+                .iter()
+                .find(|check| check.name == name)
+                .map(|check| check.detail.clone())
+                .unwrap()
+        };
+        assert_eq!(detail("IPv4 address"), "192.168.1.20");
+        assert_eq!(detail("Tailscale"), "up as 100.90.1.2");
+
+        let offline = findings(None, None);
+        let hints = hints(&offline);
+        assert!(hints
+            .iter()
+            .any(|hint| hint.starts_with("No network is up")));
+        assert!(hints.iter().any(|hint| hint.contains("Tailscale")));
+    }
+
+    #[test]
+    fn a_healthy_network_reports_no_problems() {
+        let mut healthy = findings(Some("192.168.1.20".parse().unwrap()), None);
+        healthy.answers = Some(vec![("bob".to_string(), "192.168.1.30".parse().unwrap())]);
+        let report = format_report(&checks(&healthy), &hints(&healthy));
+
+        assert!(report.starts_with("Subnet Vox connectivity check\n\n"));
+        assert!(report.contains("[  ok] Discovery port 2224/udp: listening\n"));
+        assert!(report.contains("[  ok] Discovery: 1 answered: bob (192.168.1.30)\n"));
+        assert!(report.contains("[warn] Tailscale: not connected\n"));
+        assert!(report.ends_with("\nNo problems found.\n"));
+    }
+
+    #[test]
+    fn failures_are_reported_with_hints() {
+        let mut broken = findings(Some("192.168.1.20".parse().unwrap()), None);
+        broken.chat_bind = Some("address in use".to_string());
+        broken.discovery_bind = Some("permission denied".to_string());
+        broken.answers = None;
+        broken.broadcast_address = Ipv4Addr::new(10, 0, 0, 255);
+        let report = format_report(&checks(&broken), &hints(&broken));
+
+        assert!(report.contains("[FAIL] Chat port 2223/udp: address in use\n"));
+        assert!(report.contains("[FAIL] Discovery port 2224/udp: permission denied\n"));
+        assert!(report.contains("[FAIL] Discovery: not tried"));
+        assert!(report.contains("\nHints:\n- A port couldn't be bound."));
+        assert!(report.contains("- broadcast_address 10.0.0.255 doesn't look like"));
+        assert!(!report.contains("No problems found"));
+    }
+}
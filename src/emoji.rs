@@ -0,0 +1,146 @@
+// Emoji shortcodes like :smile:, expanded in our messages before they're sent so everyone
+// sees the emoji. Every emoji here is a single character two columns wide, so the width
+// measured in text.rs matches what terminals draw.
+
+use std::borrow::Cow;
+
+// Shortcode names and their emoji, sorted by name for the lookup
+const SHORTCODES: &[(&str, &str)] = &[
+    ("+1", "👍"),
+    ("-1", "👎"),
+    ("100", "💯"),
+    ("angry", "😠"),
+    ("beer", "🍺"),
+    ("bug", "🐛"),
+    ("cake", "🍰"),
+    ("cat", "🐱"),
+    ("check", "✅"),
+    ("clap", "👏"),
+    ("coffee", "☕"),
+    ("cool", "😎"),
+    ("cry", "😢"),
+    ("dog", "🐶"),
+    ("eyes", "👀"),
+    ("fire", "🔥"),
+    ("frown", "🙁"),
+    ("gift", "🎁"),
+    ("grin", "😁"),
+    ("heart", "💖"),
+    ("hourglass", "⌛"),
+    ("hug", "🤗"),
+    ("joy", "😂"),
+    ("laughing", "😆"),
+    ("lock", "🔒"),
+    ("muscle", "💪"),
+    ("ok_hand", "👌"),
+    ("party", "🎉"),
+    ("pizza", "🍕"),
+    ("poop", "💩"),
+    ("pray", "🙏"),
+    ("question", "❓"),
+    ("rocket", "🚀"),
+    ("rofl", "🤣"),
+    ("sad", "😞"),
+    ("scream", "😱"),
+    ("see_no_evil", "🙈"),
+    ("shrug", "🤷"),
+    ("skull", "💀"),
+    ("sleeping", "😴"),
+    ("slightly_smiling_face", "🙂"),
+    ("smile", "😄"),
+    ("smiley", "😃"),
+    ("smirk", "😏"),
+    ("sparkles", "✨"),
+    ("star", "⭐"),
+    ("sunglasses", "😎"),
+    ("sweat_smile", "😅"),
+    ("tada", "🎉"),
+    ("thinking", "🤔"),
+    ("thumbsdown", "👎"),
+    ("thumbsup", "👍"),
+    ("unamused", "😒"),
+    ("wave", "👋"),
+    ("wink", "😉"),
+    ("x", "❌"),
+    ("zap", "⚡"),
+];
+
+pub fn lookup(name: &str) -> Option<&'static str> {
+    SHORTCODES
+        .binary_search_by(|(code, _)| (*code).cmp(name))
+        .ok()
+        .map(|index| SHORTCODES[index].1)
+}
+
+fn is_shortcode_char(c: char) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '_' | '+' | '-')
+}
+
+// Replaces the known shortcodes in the text. Anything else between colons, like :notacode:
+// or a time such as 12:30:45, is left as it was.
+pub fn expand_shortcodes(text: &str) -> Cow<'_, str> {
+    if !text.contains(':') {
+        return Cow::Borrowed(text);
+    }
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find(':') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let emoji = after
+            .find(':')
+            .map(|end| &after[..end])
+            .filter(|name| name.chars().all(is_shortcode_char))
+            .and_then(|name| lookup(name).map(|emoji| (name, emoji)));
+
+        match emoji {
+            Some((name, emoji)) => {
+                expanded.push_str(emoji);
+                rest = &after[name.len() + 1..];
+            }
+            // The closing colon may open the next shortcode
+            None => {
+                expanded.push(':');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    Cow::Owned(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::text::str_width;
+
+    #[test]
+    fn known_shortcodes_are_expanded() {
+        assert_eq!(expand_shortcodes("hi :wave:"), "hi 👋");
+        assert_eq!(expand_shortcodes(":+1::100:"), "👍💯");
+        assert_eq!(expand_shortcodes("ok:thumbsup:ok"), "ok👍ok");
+    }
+
+    #[test]
+    fn anything_else_is_left_alone() {
+        assert_eq!(expand_shortcodes("a :notacode: b"), "a :notacode: b");
+        assert_eq!(expand_shortcodes("at 12:30:45"), "at 12:30:45");
+        assert_eq!(expand_shortcodes(":Wave: :wave"), ":Wave: :wave");
+        // The colon closing an unknown name can still open a real one
+        assert_eq!(expand_shortcodes("x:y:wave:"), "x:y👋");
+        assert!(matches!(expand_shortcodes("no colons"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn the_table_is_sorted_and_two_columns_wide() {
+        for pair in SHORTCODES.windows(2) {
+            assert!(pair[0].0 < pair[1].0, "{} is out of order", pair[1].0);
+        }
+        for (name, emoji) in SHORTCODES {
+            assert_eq!(emoji.chars().count(), 1, "{}", name);
+            assert_eq!(str_width(emoji), 2, "{}", name);
+            assert_eq!(lookup(name), Some(*emoji));
+        }
+    }
+}
@@ -0,0 +1,259 @@
+// Splitting of packets too large for a single datagram, and reassembly on the other side.
+// A text fragment is MSG_TYPE_FRAGMENT, message ID, sequence number, fragment count, chunk.
+// Binary packets are split into codec fragments carrying the same values.
+
+use crate::codec;
+use crate::constants::{FRAGMENT_SIZE, MAX_FRAGMENT_SETS, MAX_MESSAGE_SIZE, MSG_TYPE_FRAGMENT};
+use crate::message::{join_fields, split_fields};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// A fragment as received, before reassembly
+pub struct Fragment {
+    pub id: u64,
+    pub seq: usize,
+    pub total: usize,
+    pub chunk: Vec<u8>,
+}
+
+// Whether the datagram is a fragment in either format, well-formed or not
+pub fn is_fragment(packet: &[u8]) -> bool {
+    codec::is_fragment(packet) || packet.starts_with(MSG_TYPE_FRAGMENT.as_bytes())
+}
+
+impl Fragment {
+    // Returns None when the packet isn't a well-formed fragment
+    pub fn parse(packet: &[u8]) -> Option<Fragment> {
+        if codec::is_binary(packet) {
+            return codec::decode_fragment(packet).ok();
+        }
+
+        let parts = split_fields(std::str::from_utf8(packet).ok()?);
+        if parts.len() != 5 || parts[0] != MSG_TYPE_FRAGMENT {
+            return None;
+        }
+
+        Some(Fragment {
+            id: parts[1].parse().ok()?,
+            seq: parts[2].parse().ok()?,
+            total: parts[3].parse().ok()?,
+            chunk: parts[4].clone().into_bytes(),
+        })
+    }
+}
+
+// Splits chunks at character boundaries so each one stays valid UTF-8
+fn split_chunks(packet: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = packet;
+
+    while !rest.is_empty() {
+        let mut end = size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A chunk size smaller than one character still has to make progress
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+
+    chunks
+}
+
+// Datagrams to send for an encoded packet: the packet itself when it's small enough,
+// otherwise its fragments. Packets over MAX_MESSAGE_SIZE give None.
+pub fn fragment_packet(packet: &str, id: u64) -> Option<Vec<String>> {
+    if packet.len() > MAX_MESSAGE_SIZE {
+        return None;
+    }
+    if packet.len() <= FRAGMENT_SIZE {
+        return Some(vec![packet.to_string()]);
+    }
+
+    let chunks = split_chunks(packet, FRAGMENT_SIZE);
+    let id = id.to_string();
+    let total = chunks.len().to_string();
+
+    Some(
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(seq, chunk)| {
+                join_fields(&[MSG_TYPE_FRAGMENT, &id, &seq.to_string(), &total, chunk])
+            })
+            .collect(),
+    )
+}
+
+// Datagrams to send for a binary packet, split like fragment_packet does for text ones
+pub fn fragment_bytes(packet: &[u8], id: u64) -> Option<Vec<Vec<u8>>> {
+    if packet.len() > MAX_MESSAGE_SIZE {
+        return None;
+    }
+    if packet.len() <= FRAGMENT_SIZE {
+        return Some(vec![packet.to_vec()]);
+    }
+
+    let chunks: Vec<&[u8]> = packet.chunks(FRAGMENT_SIZE).collect();
+    // MAX_MESSAGE_SIZE keeps the count well inside the codec's 16 bits
+    let total = chunks.len() as u16;
+
+    Some(
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(seq, chunk)| codec::encode_fragment(id, seq as u16, total, chunk))
+            .collect(),
+    )
+}
+
+struct FragmentSet {
+    chunks: Vec<Option<Vec<u8>>>,
+    received: usize,
+    size: usize,
+    started: Instant,
+}
+
+// Collects fragments per message ID until every chunk has arrived
+pub struct Reassembler {
+    sets: HashMap<u64, FragmentSet>,
+}
+
+impl Reassembler {
+    pub fn new() -> Self {
+        Self {
+            sets: HashMap::new(),
+        }
+    }
+
+    // Adds a fragment, returning the whole packet once its last missing fragment arrives.
+    // Fragments that don't fit the set they claim to belong to are dropped.
+    pub fn insert(&mut self, fragment: Fragment) -> Option<Vec<u8>> {
+        let max_fragments = MAX_MESSAGE_SIZE.div_ceil(FRAGMENT_SIZE);
+        if fragment.total == 0 || fragment.total > max_fragments || fragment.seq >= fragment.total {
+            return None;
+        }
+
+        // Make room by dropping the oldest incomplete message
+        if !self.sets.contains_key(&fragment.id) && self.sets.len() >= MAX_FRAGMENT_SETS {
+            if let Some(oldest) = self
+                .sets
+                .iter()
+                .min_by_key(|(_, set)| set.started)
+                .map(|(id, _)| *id)
+            {
+                self.sets.remove(&oldest);
+            }
+        }
+
+        let set = self.sets.entry(fragment.id).or_insert_with(|| FragmentSet {
+            chunks: vec![None; fragment.total],
+            received: 0,
+            size: 0,
+            started: Instant::now(),
+        });
+
+        if set.chunks.len() != fragment.total {
+            return None;
+        }
+
+        let slot = &mut set.chunks[fragment.seq];
+        if slot.is_none() {
+            set.size += fragment.chunk.len();
+            set.received += 1;
+            *slot = Some(fragment.chunk);
+        }
+
+        if set.size > MAX_MESSAGE_SIZE {
+            self.sets.remove(&fragment.id);
+            return None;
+        }
+
+        if set.received < set.chunks.len() {
+            return None;
+        }
+
+        let set = self.sets.remove(&fragment.id)?;
+        Some(set.chunks.into_iter().flatten().flatten().collect())
+    }
+
+    // Discards messages still missing fragments after max_age, returning how many
+    pub fn expire(&mut self, max_age: Duration) -> usize {
+        let before = self.sets.len();
+        self.sets.retain(|_, set| set.started.elapsed() <= max_age);
+        before - self.sets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 20 KB of text with multibyte characters, so chunk edges land inside some of them
+    fn large_packet() -> String {
+        "héllo wörld ✓ ".repeat(20 * 1024 / 18)
+    }
+
+    fn reassemble(datagrams: &[Vec<u8>]) -> Option<Vec<u8>> {
+        let mut reassembler = Reassembler::new();
+        let mut whole = None;
+        for datagram in datagrams {
+            assert!(is_fragment(datagram));
+            let fragment = Fragment::parse(datagram).unwrap();
+            assert!(whole.is_none(), "complete before the last fragment");
+            whole = reassembler.insert(fragment);
+        }
+        whole
+    }
+
+    #[test]
+    fn large_text_packets_round_trip() {
+        let packet = large_packet();
+        assert!(packet.len() > 20 * 1000);
+        let mut datagrams: Vec<Vec<u8>> = fragment_packet(&packet, 42)
+            .unwrap()
+            .into_iter()
+            .map(String::into_bytes)
+            .collect();
+        assert_eq!(datagrams.len(), packet.len().div_ceil(FRAGMENT_SIZE));
+
+        // Fragments can arrive in any order
+        datagrams.reverse();
+        assert_eq!(reassemble(&datagrams).unwrap(), packet.as_bytes());
+    }
+
+    #[test]
+    fn large_binary_packets_round_trip() {
+        let packet: Vec<u8> = (0..20 * 1024).map(|i| (i % 251) as u8).collect();
+        let datagrams = fragment_bytes(&packet, 42).unwrap();
+        assert_eq!(datagrams.len(), 20);
+        assert_eq!(reassemble(&datagrams).unwrap(), packet);
+    }
+
+    #[test]
+    fn small_packets_are_sent_whole() {
+        assert_eq!(fragment_packet("CHAT~hi", 1).unwrap(), vec!["CHAT~hi"]);
+        assert_eq!(fragment_bytes(b"hi", 1).unwrap(), vec![b"hi".to_vec()]);
+        assert!(fragment_packet(&"x".repeat(MAX_MESSAGE_SIZE + 1), 1).is_none());
+    }
+
+    #[test]
+    fn fragments_that_dont_fit_their_set_are_dropped() {
+        let mut reassembler = Reassembler::new();
+        let fragment = |seq, total| Fragment {
+            id: 7,
+            seq,
+            total,
+            chunk: b"x".to_vec(),
+        };
+        assert!(reassembler.insert(fragment(0, 0)).is_none());
+        assert!(reassembler.insert(fragment(2, 2)).is_none());
+        assert!(reassembler.insert(fragment(0, 2)).is_none());
+        // A different count than the set started with
+        assert!(reassembler.insert(fragment(1, 3)).is_none());
+        assert_eq!(reassembler.insert(fragment(1, 2)).unwrap(), b"xx");
+    }
+}
@@ -0,0 +1,224 @@
+// Headless mode, for scripts, bots and tests: no terminal UI. Each line read from stdin is
+// handled like one typed on the input line, and received messages are printed to stdout one
+// per line, formatted like a /save transcript. Notices go to stderr so stdout only ever
+// holds messages.
+
+use crate::commands::CommandResult;
+use crate::console_graphics::DisplayMessage;
+use crate::constants::PING_TIMEOUT_SECS;
+use crate::debug_logger::debug_log;
+use crate::networking::NetworkEvent;
+use crate::ping;
+use crate::transcript::format_line;
+use crate::user_interface::UserInterface;
+use tokio::io::{self, AsyncBufRead, AsyncBufReadExt, BufReader};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task;
+use tokio::time::{self, Duration};
+
+// Headless mode is used when asked for, and also when stdin or stdout isn't a terminal, as
+// when piped, since the terminal UI can't run there
+pub fn is_needed(requested: bool, stdin_is_tty: bool, stdout_is_tty: bool) -> bool {
+    requested || !stdin_is_tty || !stdout_is_tty
+}
+
+// Runs until stdin is closed or a /quit line is read
+pub async fn run(ui: &UserInterface) -> std::io::Result<()> {
+    run_with(ui, BufReader::new(io::stdin())).await
+}
+
+// Same as run, with lines read from input instead of stdin
+pub async fn run_with<R: AsyncBufRead + Unpin>(
+    ui: &UserInterface,
+    input: R,
+) -> std::io::Result<()> {
+    let ui_clone = ui.clone();
+    let printer = task::spawn(async move { print_received(&ui_clone).await });
+
+    let mut lines = input.lines();
+    while let Some(line) = lines.next_line().await? {
+        match ui.handle_command(&line).await {
+            CommandResult::Handled => {}
+            CommandResult::Quit => break,
+            CommandResult::NotCommand => ui.send_chat(line).await,
+        }
+    }
+    // Everything read has to go out before leaving, however long the throttle makes that
+    ui.send_queue.wait_until_empty().await;
+
+    printer.abort();
+    Ok(())
+}
+
+async fn print_received(ui: &UserInterface) {
+    let Some(mut events) = ui.receiver.lock().unwrap().take_events() else {
+        eprintln!("Network events are already being consumed");
+        return;
+    };
+
+    // Nothing shows delivery here, but pending acks and pings still need to expire
+    let mut expiry = time::interval(Duration::from_secs(1));
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(NetworkEvent::Message(message)) => {
+                    let muted = ui
+                        .graphics_engine
+                        .lock()
+                        .unwrap()
+                        .muted()
+                        .is_muted(message.sender_name());
+                    if muted {
+                        continue;
+                    }
+                    println!("{}", format_line(&DisplayMessage::from_message(&message)));
+                    ui.log_message(&message);
+                }
+                // Lines already printed can't be changed
+                Ok(NetworkEvent::Typing)
+                | Ok(NetworkEvent::Edited { .. })
+                | Ok(NetworkEvent::Deleted { .. })
+                | Ok(NetworkEvent::Reacted { .. })
+                | Ok(NetworkEvent::History(_)) => {}
+                Ok(NetworkEvent::HistoryRequest { from, count }) => {
+                    ui.answer_history_request(from, count).await;
+                }
+                Ok(NetworkEvent::PeerStatus { name, away }) => {
+                    let status = if away { "away" } else { "back" };
+                    ui.show_system_message(&format!("{} is {}", name, status));
+                }
+                Ok(NetworkEvent::PeerJoined(name)) => {
+                    ui.show_toast(&format!("{} joined the chat", name));
+                }
+                Ok(NetworkEvent::PeerLeft(addr, name)) => {
+                    ui.broadcaster.forget_peer(addr, &name);
+                    ui.show_toast(&format!("{} left the chat", name));
+                }
+                Ok(NetworkEvent::Ack { id, from }) => {
+                    ui.broadcaster.confirm_delivery(id, from);
+                }
+                Ok(NetworkEvent::Pong { from, sent }) => {
+                    if let Some(name) = ui.broadcaster.answer_ping(from, sent) {
+                        let now = chrono::Utc::now().timestamp_millis();
+                        ui.show_system_message(&format!(
+                            "Pong from {} ({}): {} ms",
+                            name,
+                            from,
+                            ping::round_trip(sent, now).as_millis()
+                        ));
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    debug_log(&format!("Output fell behind, dropped {} events", skipped));
+                }
+                Err(RecvError::Closed) => return,
+            },
+            _ = expiry.tick() => {
+                ui.broadcaster.retransmit_pending();
+                for name in ui.broadcaster.expire_pings(Duration::from_secs(PING_TIMEOUT_SECS)) {
+                    ui.show_system_message(&format!(
+                        "No pong from {} within {}s",
+                        name, PING_TIMEOUT_SECS
+                    ));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::console_graphics::GraphicsEngine;
+    use crate::message::Message;
+    use crate::networking::{Broadcaster, Receiver};
+    use crate::transport::MemoryNetwork;
+    use std::net::SocketAddr;
+    use tokio::sync::broadcast::Receiver as EventReceiver;
+
+    fn addr(ip: &str, port: u16) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), port)
+    }
+
+    async fn next_message(events: &mut EventReceiver<NetworkEvent>) -> Option<Message> {
+        loop {
+            match time::timeout(Duration::from_millis(300), events.recv()).await {
+                Ok(Ok(NetworkEvent::Message(message))) => return Some(message),
+                Ok(Ok(_)) => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    #[test]
+    fn headless_when_asked_or_piped() {
+        assert!(!is_needed(false, true, true));
+        assert!(is_needed(true, true, true));
+        assert!(is_needed(false, false, true));
+        assert!(is_needed(false, true, false));
+        // Piped both ways, as under a script or a service manager
+        assert!(is_needed(false, false, false));
+        assert!(is_needed(true, false, false));
+    }
+
+    // Alice runs headless on 10.0.0.1 with her input piped in, bob listens on 10.0.0.2
+    fn alice_and_bob() -> (UserInterface, EventReceiver<NetworkEvent>) {
+        let config = Config::default();
+        let network = MemoryNetwork::default();
+
+        let bob = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let events = bob.take_events().unwrap();
+        let socket = network.bind(addr("10.0.0.2", config.chat_port));
+        tokio::spawn(async move { bob.listen_for_messages(socket).await });
+
+        let mut graphics_engine = GraphicsEngine::new(&config);
+        graphics_engine.set_headless();
+        let mut ui = UserInterface::new(
+            Receiver::new(&config, "alice".to_string(), "node-a".to_string()),
+            Broadcaster::new(&config, "alice".to_string(), "node-a".to_string()),
+            graphics_engine,
+        );
+        ui.username = "alice".to_string();
+        ui.headless = true;
+
+        let outgoing = ui.send_queue.take_receiver().unwrap();
+        let sender = ui.clone();
+        let transport = network.bind(addr("10.0.0.1", config.chat_port));
+        tokio::spawn(async move {
+            sender
+                .send_queued_over(outgoing, || Ok(transport.clone()))
+                .await
+        });
+        (ui, events)
+    }
+
+    #[tokio::test]
+    async fn piped_lines_are_broadcast() {
+        let (ui, mut events) = alice_and_bob();
+        let input: &[u8] = b"hello from a script\n/quit\nnever read\n";
+        run_with(&ui, input).await.unwrap();
+
+        let received = next_message(&mut events).await.unwrap();
+        assert_eq!(received.content(), "hello from a script");
+        assert_eq!(received.sender_name(), "alice");
+        assert_eq!(received.sender_node(), Some("node-a"));
+        // Lines after /quit are never sent
+        assert!(next_message(&mut events).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn piped_commands_are_run_not_sent() {
+        let (ui, mut events) = alice_and_bob();
+        let input: &[u8] = b"/mute eve\n\n   \n/dnd on\nafter the commands\n";
+        run_with(&ui, input).await.unwrap();
+
+        let received = next_message(&mut events).await.unwrap();
+        assert_eq!(received.content(), "after the commands");
+        assert!(next_message(&mut events).await.is_none());
+        let engine = ui.graphics_engine.lock().unwrap();
+        assert!(engine.muted().is_muted("eve"));
+        assert!(engine.do_not_disturb());
+    }
+}
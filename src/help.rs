@@ -0,0 +1,43 @@
+// Contents of the help overlay opened with F1 or /help
+
+use crate::commands::COMMAND_DESCRIPTIONS;
+use crate::keys::{KeyBindings, ACTIONS};
+
+// Keys handled by the input line, with what they do. These can't be rebound.
+pub const EDITING_KEYS: [(&str, &str); 5] = [
+    ("Enter", "send the message or run the command"),
+    ("Tab", "complete a command"),
+    ("←/→, Home/End", "move through the input"),
+    ("Backspace/Delete", "delete before or at the cursor"),
+    (
+        "End, at the end of input",
+        "jump back to the newest messages",
+    ),
+];
+
+// Shown at the bottom of the overlay
+pub const HELP_FOOTER: &str = "Press any key to close";
+
+// Sections of the overlay, each a heading and its entries. The keys are the ones bound in
+// the config, actions without a key are left out.
+pub fn help_sections(bindings: &KeyBindings) -> Vec<(&'static str, Vec<(String, String)>)> {
+    let owned = |entries: &[(&str, &str)]| -> Vec<(String, String)> {
+        entries
+            .iter()
+            .map(|(name, description)| (name.to_string(), description.to_string()))
+            .collect()
+    };
+
+    let mut keys = owned(&EDITING_KEYS);
+    for action in ACTIONS {
+        if !bindings.keys_for(action).is_empty() {
+            keys.push((bindings.describe(action), action.description().to_string()));
+        }
+    }
+    vec![("Commands", owned(&COMMAND_DESCRIPTIONS)), ("Keys", keys)]
+}
+
+// An entry with its name padded so the descriptions line up
+pub fn format_entry(name: &str, description: &str) -> String {
+    format!("  {:<22} {}", name, description)
+}
@@ -0,0 +1,164 @@
+// Lines entered on the input line, recalled with the up and down arrows. Blank lines aren't
+// kept, and entering a line that's already in the history moves it to the newest spot
+// instead of adding a copy. Optionally saved between sessions, one line per entry.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone)]
+pub struct InputHistory {
+    // Oldest first
+    entries: VecDeque<String>,
+    capacity: usize,
+}
+
+impl InputHistory {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity: capacity.max(1),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+
+    // Records an entered line, returning false for one that's only whitespace. The oldest
+    // entry is dropped once the history is full.
+    pub fn push(&mut self, line: &str) -> bool {
+        if line.trim().is_empty() {
+            return false;
+        }
+
+        if let Some(index) = self.entries.iter().position(|entry| entry == line) {
+            self.entries.remove(index);
+        }
+        self.entries.push_back(line.to_string());
+        if self.entries.len() > self.capacity {
+            self.entries.pop_front();
+        }
+        true
+    }
+
+    // Reads a saved history, keeping the newest entries when the file has more than fit. A
+    // missing file gives an empty one. Lines that aren't valid UTF-8 or hold control
+    // characters, as left by a cut-off write or an edit gone wrong, are skipped.
+    pub fn load(path: &Path, capacity: usize) -> io::Result<Self> {
+        let mut history = Self::with_capacity(capacity);
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(e),
+        };
+
+        for line in contents.split(|byte| *byte == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            match std::str::from_utf8(line) {
+                Ok(line) if !line.chars().any(char::is_control) => {
+                    history.push(line);
+                }
+                _ => {}
+            }
+        }
+        Ok(history)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = String::new();
+        for entry in &self.entries {
+            contents.push_str(entry);
+            contents.push('\n');
+        }
+        fs::write(path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries(history: &InputHistory) -> Vec<&str> {
+        (0..history.len()).filter_map(|i| history.get(i)).collect()
+    }
+
+    #[test]
+    fn blank_lines_and_repeats_are_not_added() {
+        let mut history = InputHistory::with_capacity(10);
+        assert!(history.push("hello"));
+        assert!(!history.push(""));
+        assert!(!history.push("  \t "));
+        assert!(history.push("/users"));
+        assert!(history.push("bye"));
+        // An earlier entry, not just the last one, moves to the newest spot
+        assert!(history.push("hello"));
+        assert_eq!(entries(&history), ["/users", "bye", "hello"]);
+    }
+
+    #[test]
+    fn the_oldest_entries_are_evicted_at_capacity() {
+        let mut history = InputHistory::with_capacity(3);
+        for line in ["a", "b", "c", "d", "e"] {
+            history.push(line);
+        }
+        assert_eq!(entries(&history), ["c", "d", "e"]);
+        assert!(InputHistory::with_capacity(0).push("kept"));
+    }
+
+    #[test]
+    fn saved_history_loads_back() {
+        let path = std::env::temp_dir().join(format!("reticulum-history-{}", std::process::id()));
+        let mut history = InputHistory::with_capacity(10);
+        for line in ["first", "second", "third"] {
+            history.push(line);
+        }
+        history.save(&path).unwrap();
+        // Damaged lines are skipped, and only the newest that fit are kept
+        let mut contents = fs::read(&path).unwrap();
+        contents.extend_from_slice(b"bad\x1b[2J\n\xff\xfe\nfourth\r\n");
+        fs::write(&path, contents).unwrap();
+
+        let loaded = InputHistory::load(&path, 3).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(entries(&loaded), ["second", "third", "fourth"]);
+        assert!(InputHistory::load(&path, 3).unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_carries_over_between_sessions() {
+        let dir = std::env::temp_dir().join(format!("reticulum-sessions-{}", std::process::id()));
+        // Saving makes the config directory when it isn't there yet
+        let path = dir.join("subnetvox").join("history");
+
+        let mut first = InputHistory::with_capacity(4);
+        for line in ["/users", "hi", "/away"] {
+            first.push(line);
+        }
+        first.save(&path).unwrap();
+
+        let mut second = InputHistory::load(&path, 4).unwrap();
+        for line in ["/users", "lunch?", "bye"] {
+            second.push(line);
+        }
+        second.save(&path).unwrap();
+
+        // A smaller cap in the next session keeps only the newest
+        let third = InputHistory::load(&path, 3).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(entries(&second), ["/away", "/users", "lunch?", "bye"]);
+        assert_eq!(entries(&third), ["/users", "lunch?", "bye"]);
+    }
+}
@@ -0,0 +1,129 @@
+// Catching up on the conversation when joining. Once a peer turns up we ask it for its most
+// recent messages, and peers answer such requests from their scrollback.
+// A HISTORY_REQ packet's content is how many messages are wanted. Each message sent back is
+// its own HISTORY_RESP packet, laid out like MSG_TYPE_CHAT with the original sender's name
+// and the answering peer's node ID.
+
+use crate::console_graphics::DisplayMessage;
+use crate::message::Message;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// Answers each requester at most once per interval, so asking repeatedly can't make us
+// flood the network with our scrollback
+pub struct RequestLimiter {
+    interval: Duration,
+    answered: HashMap<IpAddr, Instant>,
+}
+
+impl RequestLimiter {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            answered: HashMap::new(),
+        }
+    }
+
+    // Returns true if a request from the source should be answered, and counts it
+    pub fn allow(&mut self, source: IpAddr, now: Instant) -> bool {
+        self.answered
+            .retain(|_, answered| now.saturating_duration_since(*answered) < self.interval);
+        if self.answered.contains_key(&source) {
+            return false;
+        }
+        self.answered.insert(source, now);
+        true
+    }
+}
+
+// The newest count messages worth passing on, oldest first, ready to send. Notices, direct
+// messages and actions are left out, the answer has no way to mark the latter two.
+pub fn batch(messages: &[DisplayMessage], count: usize, node_id: &str) -> Vec<Message> {
+    let mut batch: Vec<Message> = messages
+        .iter()
+        .rev()
+        .filter(|message| !message.is_system && !message.is_action && message.recipient.is_none())
+        .take(count)
+        .map(|message| {
+            Message::new(
+                message.content.clone(),
+                message.sender_name.clone(),
+                node_id.to_string(),
+            )
+            .with_id(message.id)
+            .with_timestamp(message.timestamp.timestamp_millis())
+        })
+        .collect();
+    batch.reverse();
+    batch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn received(content: &str) -> DisplayMessage {
+        DisplayMessage::from_message(&Message::new(
+            content.to_string(),
+            "bob".to_string(),
+            "10.0.0.2".to_string(),
+        ))
+    }
+
+    fn contents(batch: &[Message]) -> Vec<&str> {
+        batch.iter().map(Message::content).collect()
+    }
+
+    #[test]
+    fn a_requester_is_answered_once_per_interval() {
+        let mut limiter = RequestLimiter::new(Duration::from_secs(30));
+        let alice = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let bob = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+        let start = Instant::now();
+
+        assert!(limiter.allow(alice, start));
+        assert!(!limiter.allow(alice, start + Duration::from_secs(29)));
+        // Others are counted separately
+        assert!(limiter.allow(bob, start + Duration::from_secs(29)));
+        assert!(limiter.allow(alice, start + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn batches_hold_the_newest_messages_oldest_first() {
+        let messages: Vec<_> = ["one", "two", "three"].into_iter().map(received).collect();
+
+        let batch = batch(&messages, 2, "a1b2");
+        assert_eq!(contents(&batch), ["two", "three"]);
+        assert_eq!(batch[1].id(), messages[2].id);
+        // Sent on as coming from us
+        assert_eq!(batch[1].sender_ip(), "a1b2");
+        assert_eq!(batch[1].sender_name(), "bob");
+    }
+
+    #[test]
+    fn batches_leave_out_notices_direct_messages_and_actions() {
+        let direct = DisplayMessage::from_message(
+            &Message::new("psst".to_string(), "alice".to_string(), "local".to_string())
+                .with_recipient("bob".to_string()),
+        );
+        let action = DisplayMessage::from_message(
+            &Message::new(
+                "waves".to_string(),
+                "bob".to_string(),
+                "10.0.0.2".to_string(),
+            )
+            .into_action(),
+        );
+        let messages = [
+            received("hello"),
+            DisplayMessage::system("bob joined"),
+            direct,
+            action,
+            received("bye"),
+        ];
+
+        assert_eq!(contents(&batch(&messages, 10, "a1b2")), ["hello", "bye"]);
+    }
+}
@@ -0,0 +1,363 @@
+// Configurable key bindings for the terminal UI. Each action can be bound to any number of
+// keys in the [keys] section of the config, like `quit = "ctrl+q, ctrl+c"`. Keys not bound
+// to an action keep editing the input line, plain letters can't be bound so typing works.
+
+use crossterm::event::{KeyCode, KeyModifiers};
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    Redraw,
+    Help,
+    TogglePeers,
+    HistoryUp,
+    HistoryDown,
+    ScrollUp,
+    ScrollDown,
+    PageUp,
+    PageDown,
+}
+
+// In the order they're listed in the help overlay
+pub const ACTIONS: [Action; 10] = [
+    Action::Help,
+    Action::HistoryUp,
+    Action::HistoryDown,
+    Action::ScrollUp,
+    Action::ScrollDown,
+    Action::PageUp,
+    Action::PageDown,
+    Action::TogglePeers,
+    Action::Redraw,
+    Action::Quit,
+];
+
+impl Action {
+    // Name used for the action in the [keys] config section
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::Redraw => "redraw",
+            Action::Help => "help",
+            Action::TogglePeers => "peers",
+            Action::HistoryUp => "history_up",
+            Action::HistoryDown => "history_down",
+            Action::ScrollUp => "scroll_up",
+            Action::ScrollDown => "scroll_down",
+            Action::PageUp => "page_up",
+            Action::PageDown => "page_down",
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Action::Quit => "leave the chat and exit",
+            Action::Redraw => "redraw the screen",
+            Action::Help => "show or hide this help",
+            Action::TogglePeers => "show or hide the peer panel",
+            Action::HistoryUp => "go to earlier input",
+            Action::HistoryDown => "go to later input",
+            Action::ScrollUp => "scroll up by a line",
+            Action::ScrollDown => "scroll down by a line",
+            Action::PageUp => "scroll up by a page",
+            Action::PageDown => "scroll down by a page",
+        }
+    }
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        ACTIONS
+            .iter()
+            .copied()
+            .find(|action| action.name() == text)
+            .ok_or_else(|| {
+                let names: Vec<&str> = ACTIONS.iter().map(|action| action.name()).collect();
+                format!(
+                    "unknown key action {:?}, expected one of {}",
+                    text,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+// A key together with the modifiers held for it, like Ctrl+Q
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Key {
+    pub fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        // Letters are matched without case, Shift is part of the letter typed
+        match code {
+            KeyCode::Char(c) => Self {
+                code: KeyCode::Char(c.to_ascii_lowercase()),
+                modifiers: modifiers - KeyModifiers::SHIFT,
+            },
+            _ => Self { code, modifiers },
+        }
+    }
+}
+
+fn parse_code(name: &str) -> Option<KeyCode> {
+    let code = match name {
+        "esc" | "escape" => KeyCode::Esc,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "pageup" | "pgup" => KeyCode::PageUp,
+        "pagedown" | "pgdn" => KeyCode::PageDown,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "tab" => KeyCode::Tab,
+        "enter" => KeyCode::Enter,
+        "backspace" => KeyCode::Backspace,
+        "delete" | "del" => KeyCode::Delete,
+        "insert" | "ins" => KeyCode::Insert,
+        _ => {
+            if let Some(number) = name.strip_prefix('f') {
+                return number
+                    .parse()
+                    .ok()
+                    .filter(|n| (1..=12).contains(n))
+                    .map(KeyCode::F);
+            }
+            let mut chars = name.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                _ => return None,
+            }
+        }
+    };
+    Some(code)
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    // Names like "ctrl+q", "shift+up", "f1" or "esc", without case
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let lowered = text.trim().to_lowercase();
+        let mut parts: Vec<&str> = lowered.split('+').map(str::trim).collect();
+        // A trailing "+" is the plus key itself, as in "ctrl++"
+        if lowered.ends_with("++") {
+            parts.truncate(parts.len() - 2);
+            parts.push("+");
+        }
+        let name = parts.pop().unwrap_or_default();
+
+        let mut modifiers = KeyModifiers::NONE;
+        for modifier in parts {
+            modifiers |= match modifier {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                _ => return Err(format!("unknown modifier {:?} in key {:?}", modifier, text)),
+            };
+        }
+
+        let code = parse_code(name).ok_or_else(|| format!("unknown key {:?}", text))?;
+        let key = Key::new(code, modifiers);
+        if let KeyCode::Char(_) = key.code {
+            if !key
+                .modifiers
+                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+            {
+                return Err(format!(
+                    "key {:?} would be typed into the input, add ctrl+ or alt+",
+                    text
+                ));
+            }
+        }
+        Ok(key)
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (modifier, label) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(label)?;
+            }
+        }
+        match self.code {
+            KeyCode::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Esc => f.write_str("Esc"),
+            KeyCode::Up => f.write_str("↑"),
+            KeyCode::Down => f.write_str("↓"),
+            KeyCode::Left => f.write_str("←"),
+            KeyCode::Right => f.write_str("→"),
+            KeyCode::PageUp => f.write_str("PgUp"),
+            KeyCode::PageDown => f.write_str("PgDn"),
+            KeyCode::Home => f.write_str("Home"),
+            KeyCode::End => f.write_str("End"),
+            KeyCode::Tab => f.write_str("Tab"),
+            KeyCode::Enter => f.write_str("Enter"),
+            KeyCode::Backspace => f.write_str("Backspace"),
+            KeyCode::Delete => f.write_str("Delete"),
+            KeyCode::Insert => f.write_str("Insert"),
+            other => write!(f, "{:?}", other),
+        }
+    }
+}
+
+// Parses a comma separated list of keys, an empty list leaves the action unbound
+pub fn parse_keys(text: &str) -> Result<Vec<Key>, String> {
+    text.split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Clone, Debug)]
+pub struct KeyBindings {
+    bindings: Vec<(Key, Action)>,
+}
+
+impl Default for KeyBindings {
+    // Esc isn't bound, quitting on it is easy to hit by accident. `quit = "ctrl+q, esc"`
+    // in the [keys] section brings it back.
+    fn default() -> Self {
+        let defaults = [
+            (Action::Quit, "ctrl+q"),
+            (Action::Quit, "ctrl+c"),
+            (Action::Redraw, "ctrl+l"),
+            (Action::Help, "f1"),
+            (Action::TogglePeers, "ctrl+p"),
+            (Action::HistoryUp, "up"),
+            (Action::HistoryDown, "down"),
+            (Action::ScrollUp, "shift+up"),
+            (Action::ScrollDown, "shift+down"),
+            (Action::PageUp, "pageup"),
+            (Action::PageDown, "pagedown"),
+        ];
+        Self {
+            bindings: defaults
+                .iter()
+                .map(|(action, key)| (key.parse().unwrap(), *action))
+                .collect(),
+        }
+    }
+}
+
+impl KeyBindings {
+    // Replaces the keys of an action. Keys taken from other actions move to this one.
+    pub fn bind(&mut self, action: Action, keys: &[Key]) {
+        self.bindings
+            .retain(|(key, bound)| *bound != action && !keys.contains(key));
+        self.bindings.extend(keys.iter().map(|key| (*key, action)));
+    }
+
+    pub fn action_for(&self, code: KeyCode, modifiers: KeyModifiers) -> Option<Action> {
+        let pressed = Key::new(code, modifiers);
+        self.bindings
+            .iter()
+            .find(|(key, _)| *key == pressed)
+            .map(|(_, action)| *action)
+    }
+
+    pub fn keys_for(&self, action: Action) -> Vec<Key> {
+        self.bindings
+            .iter()
+            .filter(|(_, bound)| *bound == action)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    // Keys of an action for display, like "Ctrl+Q, Ctrl+C"
+    pub fn describe(&self, action: Action) -> String {
+        let keys: Vec<String> = self
+            .keys_for(action)
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        if keys.is_empty() {
+            "unbound".to_string()
+        } else {
+            keys.join(", ")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(text: &str) -> Vec<Key> {
+        parse_keys(text).unwrap()
+    }
+
+    #[test]
+    fn esc_does_nothing_unless_bound_to_quit() {
+        let mut bindings = KeyBindings::default();
+        assert_eq!(bindings.action_for(KeyCode::Esc, KeyModifiers::NONE), None);
+        assert_eq!(
+            bindings.action_for(KeyCode::Char('q'), KeyModifiers::CONTROL),
+            Some(Action::Quit)
+        );
+
+        bindings.bind(Action::Quit, &keys("ctrl+q, esc"));
+        assert_eq!(
+            bindings.action_for(KeyCode::Esc, KeyModifiers::NONE),
+            Some(Action::Quit)
+        );
+        assert_eq!(bindings.describe(Action::Quit), "Ctrl+Q, Esc");
+    }
+
+    #[test]
+    fn custom_bindings_replace_the_defaults() {
+        let mut bindings = KeyBindings::default();
+        bindings.bind(Action::Redraw, &keys("ctrl+r"));
+        assert_eq!(
+            bindings.action_for(KeyCode::Char('r'), KeyModifiers::CONTROL),
+            Some(Action::Redraw)
+        );
+        assert_eq!(
+            bindings.action_for(KeyCode::Char('l'), KeyModifiers::CONTROL),
+            None
+        );
+
+        // A key taken from another action moves over
+        bindings.bind(Action::Help, &keys("ctrl+p"));
+        assert_eq!(
+            bindings.action_for(KeyCode::Char('p'), KeyModifiers::CONTROL),
+            Some(Action::Help)
+        );
+        assert_eq!(bindings.describe(Action::TogglePeers), "unbound");
+
+        // Letters match whatever the case
+        assert_eq!(
+            bindings.action_for(
+                KeyCode::Char('R'),
+                KeyModifiers::CONTROL | KeyModifiers::SHIFT
+            ),
+            Some(Action::Redraw)
+        );
+    }
+
+    #[test]
+    fn key_names_are_checked() {
+        assert_eq!(keys("F1, shift+pgup").len(), 2);
+        assert_eq!(keys("").len(), 0);
+        assert!("ctrl+".parse::<Key>().is_err());
+        assert!("hyper+q".parse::<Key>().is_err());
+        assert!("f13".parse::<Key>().is_err());
+        // Plain letters would be typed into the input instead
+        assert!("q".parse::<Key>().is_err());
+        assert_eq!("ctrl++".parse::<Key>().unwrap().to_string(), "Ctrl++");
+    }
+}
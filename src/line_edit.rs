@@ -0,0 +1,288 @@
+// Editing operations on the input line. The cursor is a byte index into the text that is
+// always kept on a character boundary, and moves and deletes whole graphemes so an emoji
+// with a skin tone or a letter with an accent goes in one keypress.
+
+use crate::constants::LENGTH_COUNTER_PERCENT;
+use crate::text::{str_width, truncate_to_width};
+use unicode_segmentation::UnicodeSegmentation;
+
+// Moves a cursor that may be stale back inside the text and onto a character boundary
+pub fn clamp_cursor(text: &str, cursor: usize) -> usize {
+    let mut cursor = cursor.min(text.len());
+    while !text.is_char_boundary(cursor) {
+        cursor -= 1;
+    }
+    cursor
+}
+
+// Start of the grapheme before the cursor
+pub fn previous_boundary(text: &str, cursor: usize) -> usize {
+    text[..cursor]
+        .grapheme_indices(true)
+        .next_back()
+        .map_or(0, |(start, _)| start)
+}
+
+// End of the grapheme after the cursor
+pub fn next_boundary(text: &str, cursor: usize) -> usize {
+    text[cursor..]
+        .graphemes(true)
+        .next()
+        .map_or(text.len(), |grapheme| cursor + grapheme.len())
+}
+
+// Inserts at the cursor and moves the cursor past the inserted text
+pub fn insert(text: &mut String, cursor: &mut usize, inserted: &str) {
+    text.insert_str(*cursor, inserted);
+    *cursor += inserted.len();
+}
+
+// Backspace: removes the grapheme before the cursor, if there is one
+pub fn delete_before(text: &mut String, cursor: &mut usize) {
+    let start = previous_boundary(text, *cursor);
+    text.replace_range(start..*cursor, "");
+    *cursor = start;
+}
+
+// Delete: removes the grapheme under the cursor, if there is one
+pub fn delete_at(text: &mut String, cursor: usize) {
+    let end = next_boundary(text, cursor);
+    text.replace_range(cursor..end, "");
+}
+
+// The part of `inserted` that fits on a line already holding `text` without going over
+// `max_chars`. The rest is refused.
+pub fn fitting_prefix<'a>(text: &str, inserted: &'a str, max_chars: usize) -> &'a str {
+    let room = max_chars.saturating_sub(text.chars().count());
+    match inserted.char_indices().nth(room) {
+        Some((index, _)) => &inserted[..index],
+        None => inserted,
+    }
+}
+
+// "count/limit" once the line is close to the limit, counted in characters rather than
+// bytes or columns
+pub fn length_counter(text: &str, max_chars: usize) -> Option<String> {
+    let count = text.chars().count();
+    if count * 100 < max_chars * LENGTH_COUNTER_PERCENT {
+        return None;
+    }
+    Some(format!("{}/{}", count, max_chars))
+}
+
+// The part of the input line that fits on screen
+pub struct InputWindow {
+    // Byte range of the text that is shown
+    pub start: usize,
+    pub end: usize,
+    // Column of the cursor from the start of the input area
+    pub cursor_column: usize,
+    // Whether there's text cut off at either side. The area's first and last columns are
+    // kept for these indicators whenever the text doesn't fit.
+    pub more_left: bool,
+    pub more_right: bool,
+}
+
+// Picks what to show of a line too long for its `width` columns. The window scrolls as
+// little as it can from where it started last time, `previous_start`, to keep the cursor
+// in view.
+pub fn input_window(text: &str, cursor: usize, width: usize, previous_start: usize) -> InputWindow {
+    // The cursor needs a column of its own after the text
+    if str_width(text) < width {
+        return InputWindow {
+            start: 0,
+            end: text.len(),
+            cursor_column: str_width(&text[..cursor]),
+            more_left: false,
+            more_right: false,
+        };
+    }
+
+    let area = width.saturating_sub(2);
+    let mut start = clamp_cursor(text, previous_start).min(cursor);
+    while start < cursor && str_width(&text[start..cursor]) >= area {
+        start = next_boundary(text, start);
+    }
+    // Bring back text from the left rather than leave the end of the area empty, as after
+    // deleting at the end
+    while start > 0 {
+        let previous = previous_boundary(text, start);
+        if str_width(&text[previous..]) >= area || str_width(&text[previous..cursor]) >= area {
+            break;
+        }
+        start = previous;
+    }
+
+    let end = start + truncate_to_width(&text[start..], area).len();
+
+    InputWindow {
+        start,
+        end,
+        cursor_column: 1 + str_width(&text[start..cursor]),
+        more_left: start > 0,
+        more_right: end < text.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserts_in_the_middle() {
+        let mut text = "helo".to_string();
+        let mut cursor = 3;
+        insert(&mut text, &mut cursor, "l");
+        assert_eq!(text, "hello");
+        assert_eq!(cursor, 4);
+    }
+
+    #[test]
+    fn deletes_before_and_at_the_cursor() {
+        let mut text = "héllo".to_string();
+        let mut cursor = "hé".len();
+        delete_before(&mut text, &mut cursor);
+        assert_eq!(text, "hllo");
+        assert_eq!(cursor, 1);
+
+        delete_at(&mut text, cursor);
+        assert_eq!(text, "hlo");
+
+        // Nothing to delete at either end
+        let mut cursor = 0;
+        delete_before(&mut text, &mut cursor);
+        delete_at(&mut text, 3);
+        assert_eq!(text, "hlo");
+    }
+
+    #[test]
+    fn backspace_removes_a_whole_emoji_with_its_skin_tone() {
+        let mut text = "hi 👍🏽".to_string();
+        let mut cursor = text.len();
+        assert_eq!(str_width(&text[..cursor]), 5);
+
+        delete_before(&mut text, &mut cursor);
+        assert_eq!(text, "hi ");
+        assert_eq!(cursor, 3);
+        assert_eq!(str_width(&text[..cursor]), 3);
+
+        // A letter with a combining accent goes in one keypress too
+        let mut text = "cafe\u{301}".to_string();
+        let mut cursor = text.len();
+        delete_before(&mut text, &mut cursor);
+        assert_eq!(text, "caf");
+        assert_eq!(cursor, 3);
+    }
+
+    #[test]
+    fn joined_emoji_flags_and_jamo_move_as_one() {
+        // A family joined by zero width joiners, two flags and a Hangul syllable in jamo
+        let family = "👨\u{200D}👩\u{200D}👧";
+        let syllable = "\u{1100}\u{1161}\u{11A8}";
+        let text = format!("{}🇺🇸🇩🇪{}", family, syllable);
+
+        let mut cursor = 0;
+        let mut stops = Vec::new();
+        while cursor < text.len() {
+            cursor = next_boundary(&text, cursor);
+            stops.push(cursor);
+        }
+        let flags = family.len() + "🇺🇸".len();
+        assert_eq!(
+            stops,
+            vec![family.len(), flags, flags + "🇩🇪".len(), text.len()]
+        );
+        assert_eq!(
+            previous_boundary(&text, text.len()),
+            text.len() - syllable.len()
+        );
+        assert_eq!(previous_boundary(&text, family.len()), 0);
+    }
+
+    #[test]
+    fn stale_cursors_are_clamped_onto_a_boundary() {
+        assert_eq!(clamp_cursor("abc", 10), 3);
+        // Byte 2 is inside "é"
+        assert_eq!(clamp_cursor("aé", 2), 1);
+        assert_eq!(clamp_cursor("", 5), 0);
+    }
+
+    #[test]
+    fn cursor_moves_by_whole_characters() {
+        let text = "aéb";
+        assert_eq!(next_boundary(text, 1), 3);
+        assert_eq!(previous_boundary(text, 3), 1);
+        assert_eq!(next_boundary(text, text.len()), text.len());
+        assert_eq!(previous_boundary(text, 0), 0);
+    }
+
+    #[test]
+    fn input_past_the_limit_is_refused() {
+        assert_eq!(fitting_prefix("hello", " world", 8), " wo");
+        assert_eq!(fitting_prefix("hello", "!", 5), "");
+        assert_eq!(fitting_prefix("", "short", 10), "short");
+        // Counted in characters, and never cut inside one
+        assert_eq!(fitting_prefix("日本", "語テキスト", 4), "語テ");
+        assert_eq!(fitting_prefix("é", "🦀🦀🦀", 3), "🦀🦀");
+    }
+
+    #[test]
+    fn the_counter_shows_near_the_limit() {
+        assert_eq!(length_counter(&"a".repeat(89), 100), None);
+        assert_eq!(
+            length_counter(&"a".repeat(90), 100).as_deref(),
+            Some("90/100")
+        );
+        // Multibyte characters count once each
+        let text = "é".repeat(95);
+        assert_eq!(text.len(), 190);
+        assert_eq!(length_counter(&text, 100).as_deref(), Some("95/100"));
+        assert_eq!(length_counter(&"日".repeat(8), 10).as_deref(), None);
+        assert_eq!(length_counter(&"日".repeat(9), 10).as_deref(), Some("9/10"));
+    }
+
+    fn visible(text: &str, window: &InputWindow) -> String {
+        text[window.start..window.end].to_string()
+    }
+
+    #[test]
+    fn short_lines_are_shown_whole() {
+        let window = input_window("hi", 1, 10, 0);
+        assert_eq!((window.start, window.end, window.cursor_column), (0, 2, 1));
+        assert!(!window.more_left && !window.more_right);
+    }
+
+    #[test]
+    fn long_lines_scroll_to_keep_the_cursor_in_view() {
+        let text = "abcdefghijklmnopqrst";
+
+        // Typing at the end shows the tail, with room for the cursor
+        let window = input_window(text, text.len(), 10, 0);
+        assert_eq!(visible(text, &window), "nopqrst");
+        assert_eq!(window.cursor_column, 8);
+        assert!(window.more_left && !window.more_right);
+
+        // Going home scrolls all the way back
+        let window = input_window(text, 0, 10, window.start);
+        assert_eq!(visible(text, &window), "abcdefgh");
+        assert_eq!(window.cursor_column, 1);
+        assert!(!window.more_left && window.more_right);
+
+        // Moving inside the window doesn't scroll it
+        let window = input_window(text, 5, 10, 2);
+        assert_eq!(window.start, 2);
+        assert_eq!(window.cursor_column, 4);
+    }
+
+    #[test]
+    fn wide_characters_are_windowed_by_column() {
+        let text = "日本語テキスト";
+        let window = input_window(text, text.len(), 8, 0);
+        assert_eq!(visible(text, &window), "スト");
+        assert_eq!(window.cursor_column, 5);
+
+        let window = input_window(text, 0, 8, window.start);
+        assert_eq!(visible(text, &window), "日本語");
+        assert!(window.more_right);
+    }
+}
@@ -1,97 +1,433 @@
+mod access;
+mod away;
+mod backoff;
+mod cli;
+mod codec;
+mod color_support;
+mod commands;
+mod compress;
+mod config;
 mod console_graphics;
 mod constants;
+mod crypto;
 mod debug_logger;
+mod dedup;
+mod delivery;
+mod diagnose;
+mod emoji;
+mod fragment;
+mod headless;
+mod help;
+mod history;
+mod history_sync;
+mod keys;
+mod line_edit;
+mod mdns;
 mod message;
+mod mute;
 mod networking;
+mod peer_panel;
+mod ping;
+mod protocol;
+mod random;
+mod rate_limit;
+mod reactions;
+mod render;
+mod reorder;
+mod send_queue;
+mod session_log;
+mod shutdown;
+mod stats;
+mod storage;
+mod supervisor;
+mod tcp;
+mod text;
+mod theme;
+mod throttle;
+mod timestamp;
+mod toast;
+mod transcript;
+mod transport;
+mod typing;
+mod udp;
 mod user_interface;
-
-use console_graphics::GraphicsEngine;
-use constants::{CHAT_PORT, DISCOVERY_PORT};
-use debug_logger::enable_debug;
-use message::Message;
-use networking::{Broadcaster, Receiver};
+mod watchdog;
+
+use clap::Parser;
+use cli::Args;
+use commands::CommandResult;
+use config::{Config, IntroMode};
+use console_graphics::{Delivery, GraphicsEngine};
+use constants::{
+    DEBUG_ENV_VAR, DEFAULT_TIME_FORMAT, LEAVE_ANNOUNCE_TIMEOUT_MS, PEER_EXPIRY_SECS,
+    PEER_REAPER_INTERVAL_SECS, PING_TIMEOUT_SECS, REORDER_WINDOW_MS, SEND_QUEUE_DRAIN_MS,
+    SHUTDOWN_TIMEOUT_MS,
+};
+use crossterm::tty::IsTty;
+use debug_logger::{debug_log, log, set_level, set_log_file, Level};
+use networking::{merge_peer, Broadcaster, NetworkEvent, Receiver};
+use reorder::ReorderBuffer;
+use session_log::SessionLog;
 use std::io::Write;
 use std::sync::Arc;
+use std::time::Instant;
+use supervisor::supervise;
 use tokio::signal;
+use tokio::sync::broadcast::error::RecvError;
 use tokio::task;
 use tokio::time;
 use user_interface::UserInterface;
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    let args = Args::parse();
+
+    // Settings come from the config file, with command-line arguments taking precedence
+    let config_path = args.config.clone().or_else(storage::config_path);
+    let (mut config, warnings) = match &config_path {
+        Some(path) => Config::load(path).unwrap_or_else(|e| {
+            eprintln!("Invalid config file {}", e);
+            std::process::exit(1);
+        }),
+        None => (Config::default(), Vec::new()),
+    };
+    // Printed before the UI starts, so they're visible
+    for warning in warnings {
+        eprintln!("{}", warning);
+    }
+    config.apply_args(&args);
+    if theme::Theme::named(&config.theme).is_none() {
+        eprintln!(
+            "Unknown theme {}, using the default. Available themes: {}",
+            config.theme,
+            theme::THEME_NAMES.join(", ")
+        );
+    }
+    if !timestamp::is_valid_format(&config.time_format) {
+        eprintln!(
+            "Invalid time_format {:?}, using the default {:?}",
+            config.time_format, DEFAULT_TIME_FORMAT
+        );
+    }
+    let chat_port = config.chat_port;
+    let discovery_port = config.discovery_port;
+    // Without the terminal UI stdout only carries received messages, so the banners,
+    // prompts and intro are all skipped
+    let headless = headless::is_needed(
+        args.headless,
+        std::io::stdin().is_tty(),
+        std::io::stdout().is_tty(),
+    );
+    if headless && !args.headless {
+        eprintln!("Not running in a terminal, falling back to headless mode");
+    }
+    if headless && config.username.is_none() {
+        eprintln!("Headless mode needs a username, from --username or the config file");
+        std::process::exit(1);
+    }
+    if let Some(error) = config.username.as_deref().and_then(message::username_error) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+
+    // Report on what discovery depends on and exit, without joining the chat
+    if args.diagnose {
+        let findings = diagnose::run(&config).await;
+        let checks = diagnose::checks(&findings);
+        print!(
+            "{}",
+            diagnose::format_report(&checks, &diagnose::hints(&findings))
+        );
+        let failed = checks
+            .iter()
+            .any(|check| check.outcome == diagnose::Outcome::Fail);
+        std::process::exit(i32::from(failed));
+    }
+
     // Setup terminal cleanup on exit
-    let _cleanup_guard = CleanupGuard {};
-    println!("Subnet Vox - P2P Chat (Tailscale Enhanced)");
-    println!("Press Ctrl+Q or Ctrl+C to exit");
-    println!("Special Features: Tailscale Multicast & Direct Communication");
+    let _cleanup_guard = (!headless).then(|| CleanupGuard {});
+    if !headless {
+        println!("Subnet Vox - P2P Chat (Tailscale Enhanced)");
+        println!("Press Ctrl+Q or Ctrl+C to exit");
+        println!("Special Features: Tailscale Multicast & Direct Communication");
+    }
 
-    // Enable debug logging
-    enable_debug();
+    // Log at the configured level, with --debug or RETICULUM_DEBUG turning on everything.
+    // Only a log file gets lines while the terminal UI is up.
+    if let Some(path) = &args.debug_log {
+        if let Err(e) = set_log_file(path) {
+            eprintln!("Failed to open debug log {}: {}", path.display(), e);
+        }
+    }
+    if config.debug || std::env::var_os(DEBUG_ENV_VAR).is_some() {
+        set_level(Level::Debug);
+    } else {
+        set_level(config.log_level);
+    }
 
-    // Create graphics engine
-    let graphics_engine = GraphicsEngine::new(64);
+    // Opt-in encryption with a shared passphrase, plaintext otherwise
+    if let Some(passphrase) = &args.key {
+        crypto::set_passphrase(passphrase);
+        eprintln!("Encryption enabled: only peers with the same key can read your messages");
+    }
 
-    // Prompt for username
-    let mut username = String::new();
+    // Create graphics engine
+    let mut graphics_engine = GraphicsEngine::new(&config);
+    if headless {
+        graphics_engine.set_headless();
+    } else {
+        // Print logo first
+        GraphicsEngine::print_logo()?;
+        println!("\n\n========================================\n");
+    }
 
-    // Print logo first
-    GraphicsEngine::print_logo()?;
-    println!("\n\n========================================\n");
-    print!("your username: ");
-    std::io::stdout().flush()?;
-    std::io::stdin().read_line(&mut username)?;
-    let username = username.trim().to_string();
+    // Use the username from the command line, or prompt for one
+    let username = match config.username.clone() {
+        Some(username) => username.trim().to_string(),
+        None => loop {
+            let mut username = String::new();
+            print!("your username: ");
+            std::io::stdout().flush()?;
+            // Asking again at end of input would never stop
+            if std::io::stdin().read_line(&mut username)? == 0 {
+                eprintln!("\nNo username entered");
+                std::process::exit(1);
+            }
+            let username = username.trim().to_string();
+            match message::username_error(&username) {
+                Some(error) => println!("{}", error),
+                None => break username,
+            }
+        },
+    };
 
-    println!("\n\nwelcome. joining the subnet...");
+    if !headless {
+        println!("\n\nwelcome. joining the subnet...");
+    }
 
     // Create the networking components
-    let receiver = Receiver::new(CHAT_PORT, username.clone());
-    let broadcaster = Broadcaster::new(CHAT_PORT, username.clone());
+    let node_id = networking::new_node_id();
+    let receiver = Receiver::new(&config, username.clone(), node_id.clone());
+    let mut broadcaster = Broadcaster::new(&config, username.clone(), node_id);
+
+    // Restore peers from the previous session so we don't start from scratch
+    if let Some(path) = storage::peer_cache_path() {
+        broadcaster = broadcaster.with_peers(Broadcaster::load_peers(&path));
+    }
 
     // Create user interface
     let mut user_interface =
         UserInterface::new(receiver.clone(), broadcaster.clone(), graphics_engine);
+    {
+        let mut engine = user_interface.graphics_engine.lock().unwrap();
+        if config.notify_mentions {
+            engine.set_mention_name(&username);
+        }
+        engine.set_peer_list(receiver.get_peers());
+    }
     user_interface.username = username;
+    user_interface.headless = headless;
+    user_interface.emoji_shortcodes = config.emoji_shortcodes;
+
+    // Bring back the lines entered in earlier sessions
+    if config.save_history {
+        if let Some(path) = storage::history_path() {
+            match history::InputHistory::load(&path, config.history_size) {
+                Ok(history) => user_interface
+                    .graphics_engine
+                    .lock()
+                    .unwrap()
+                    .set_input_history(history),
+                Err(e) => eprintln!("Failed to load input history {}: {}", path.display(), e),
+            }
+            user_interface.history_path = Some(path);
+        }
+    }
 
-    // Load cyberpunk intro
-    show_intro(CHAT_PORT, DISCOVERY_PORT).await;
+    // And the users muted in earlier sessions
+    if config.save_muted {
+        if let Some(path) = storage::muted_path() {
+            match mute::MutedUsers::load(&path) {
+                Ok(muted) => user_interface
+                    .graphics_engine
+                    .lock()
+                    .unwrap()
+                    .set_muted(muted),
+                Err(e) => eprintln!("Failed to load muted users {}: {}", path.display(), e),
+            }
+            user_interface.muted_path = Some(path);
+        }
+    }
 
-    // Set up terminal UI
-    GraphicsEngine::setup_terminal()?;
-    {
-        let mut engine = user_interface.graphics_engine.lock().unwrap();
-        let _ = engine.print_all_messages(true);
-        let _ = engine.print_status_bar();
-        let _ = engine.print_input_prompt();
+    // Keep a record of the session's messages, picking up where the last one left off
+    if let Some(path) = &args.log {
+        if args.replay {
+            match session_log::load_recent(path, config.max_message_lines) {
+                Ok(messages) => {
+                    let mut engine = user_interface.graphics_engine.lock().unwrap();
+                    for message in &messages {
+                        engine.add_replayed_message(message);
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("Failed to replay session log {}: {}", path.display(), e),
+            }
+        }
+
+        match SessionLog::open(path) {
+            Ok(log) => user_interface.session_log = Some(log),
+            Err(e) => eprintln!("Failed to open session log {}: {}", path.display(), e),
+        }
     }
 
-    // Start the format keeper thread for terminal
-    let graphics_engine_clone = user_interface.graphics_engine.clone();
-    task::spawn_blocking(move || {
-        GraphicsEngine::console_format_keeper(graphics_engine_clone);
-    });
+    // Claim our ports before the UI takes over the terminal, so a failure is readable.
+    // Peers send to the configured ports, so there's no silent fallback to another one.
+    let listeners = Receiver::bind_discovery(&config)
+        .and_then(|discovery| Ok((discovery, Receiver::bind_chat(&config)?)))
+        .and_then(|(discovery, chat)| {
+            let tcp = if config.tcp {
+                Some(Receiver::bind_tcp(config.tcp_port, config.bind_address)?)
+            } else {
+                None
+            };
+            Ok((discovery, chat, tcp))
+        });
+    let (discovery_socket, chat_socket, tcp_listener) = match listeners {
+        Ok(sockets) => sockets,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    log(
+        Level::Info,
+        &format!(
+            "Listening for discovery on port {} and chat on port {}",
+            config.discovery_port, config.chat_port
+        ),
+    );
+
+    if !headless {
+        // Load cyberpunk intro
+        show_intro(chat_port, discovery_port, config.intro_mode()).await;
+
+        // Set up terminal UI
+        GraphicsEngine::setup_terminal()?;
+        {
+            let mut engine = user_interface.graphics_engine.lock().unwrap();
+            let _ = engine.print_all_messages(true);
+            let _ = engine.print_status_bar();
+            let _ = engine.print_input_prompt();
+        }
 
-    // Start the discovery listener
+        // Start the format keeper thread for terminal
+        let graphics_engine_clone = user_interface.graphics_engine.clone();
+        task::spawn_blocking(move || {
+            GraphicsEngine::console_format_keeper(graphics_engine_clone);
+        });
+    }
+
+    // The listeners are restarted when they fail or on /reconnect. The sockets bound above
+    // are used for the first run, later runs bind fresh ones.
     let receiver_clone = receiver.clone();
+    let ui_clone = user_interface.clone();
+    let restarts = user_interface.restarts.clone();
+    let config_clone = config.clone();
+    let mut socket = Some(discovery_socket);
     task::spawn(async move {
-        if let Err(e) = receiver_clone.listen_for_discovery(DISCOVERY_PORT).await {
-            eprintln!("Discovery listener error: {}", e);
-        }
+        let start = || {
+            let receiver = receiver_clone.clone();
+            let socket = socket
+                .take()
+                .map_or_else(|| Receiver::bind_discovery(&config_clone), Ok);
+            async move { receiver.listen_for_discovery(socket?).await }
+        };
+        supervise("Discovery listener", start, &restarts, |message| {
+            ui_clone.show_system_message(message)
+        })
+        .await;
     });
 
-    // Start the message listener
-    let mut receiver_clone2 = receiver.clone();
+    let receiver_clone = receiver.clone();
+    let ui_clone = user_interface.clone();
+    let restarts = user_interface.restarts.clone();
+    let config_clone = config.clone();
+    let mut socket = Some(chat_socket);
     task::spawn(async move {
-        if let Err(e) = receiver_clone2.listen_for_messages(CHAT_PORT).await {
-            eprintln!("Message listener error: {}", e);
-        }
+        let start = || {
+            let receiver = receiver_clone.clone();
+            let socket = socket
+                .take()
+                .map_or_else(|| Receiver::bind_chat(&config_clone), Ok);
+            async move { receiver.listen_for_messages(socket?).await }
+        };
+        supervise("Message listener", start, &restarts, |message| {
+            ui_clone.show_system_message(message)
+        })
+        .await;
     });
 
+    // Optional TCP listener alongside the UDP one
+    if let Some(tcp_listener) = tcp_listener {
+        let receiver_clone = receiver.clone();
+        let ui_clone = user_interface.clone();
+        let restarts = user_interface.restarts.clone();
+        let tcp_port = config.tcp_port;
+        let bind_address = config.bind_address;
+        let mut listener = Some(tcp_listener);
+        task::spawn(async move {
+            let start = || {
+                let receiver = receiver_clone.clone();
+                let listener = listener
+                    .take()
+                    .map_or_else(|| Receiver::bind_tcp(tcp_port, bind_address), Ok);
+                async move { receiver.listen_for_tcp(listener?).await }
+            };
+            supervise("TCP listener", start, &restarts, |message| {
+                ui_clone.show_system_message(message)
+            })
+            .await;
+        });
+    }
+
+    // Optional mDNS discovery alongside the broadcast one
+    if config.mdns {
+        let receiver_clone = receiver.clone();
+        let ui_clone = user_interface.clone();
+        let restarts = user_interface.restarts.clone();
+        task::spawn(async move {
+            let start = || {
+                let receiver = receiver_clone.clone();
+                async move { receiver.mdns_service().await }
+            };
+            supervise("mDNS discovery", start, &restarts, |message| {
+                ui_clone.show_system_message(message)
+            })
+            .await;
+        });
+    }
+
+    // Our messages go out from here, so a slow send never holds up the input line
+    if let Some(outgoing) = user_interface.send_queue.take_receiver() {
+        let ui_clone = user_interface.clone();
+        task::spawn(async move { ui_clone.send_queued(outgoing).await });
+    }
+
+    // Catch up on the conversation from the first peer found. Headless mode only prints
+    // messages as they arrive, so it has no use for earlier ones.
+    if config.history_sync && !headless {
+        let ui_clone = user_interface.clone();
+        let count = config.history_sync_count;
+        task::spawn(async move { ui_clone.request_history(count).await });
+    }
+
     // Start discovery service (periodically broadcasts presence)
     let broadcaster_clone = broadcaster.clone();
+    let restarts = user_interface.restarts.clone();
     task::spawn(async move {
-        if let Err(e) = Broadcaster::discovery_service(Arc::new(broadcaster_clone)).await {
-            eprintln!("Discovery service error: {}", e);
+        if let Err(e) = Broadcaster::discovery_service(Arc::new(broadcaster_clone), restarts).await
+        {
+            log(Level::Error, &format!("Discovery service error: {}", e));
         }
     });
 
@@ -114,88 +450,210 @@ async fn main() -> std::io::Result<()> {
 
             {
                 let mut broadcaster_peers_lock = broadcaster_peers.lock().unwrap();
-                for peer in receiver_peers_clone {
-                    broadcaster_peers_lock.insert(peer);
+                for (peer, info) in receiver_peers_clone {
+                    // Keeps whichever side heard from the peer most recently
+                    merge_peer(&mut broadcaster_peers_lock, peer, info);
                 }
             } // Release lock before await
 
+            save_peer_cache(&broadcaster_clone);
+
             time::sleep(sync_interval).await;
         }
     });
 
-    // Start the continuous receive task
-    let user_interface_clone = user_interface.clone();
+    // Periodically evict peers we haven't heard from in a while
+    let broadcaster_clone = user_interface.broadcaster.clone();
+    let receiver_arc = user_interface.receiver.clone();
     task::spawn(async move {
-        continuous_receive_task(&user_interface_clone).await;
+        let max_age = time::Duration::from_secs(PEER_EXPIRY_SECS);
+        loop {
+            time::sleep(time::Duration::from_secs(PEER_REAPER_INTERVAL_SECS)).await;
+            receiver_arc.lock().unwrap().prune_stale_peers(max_age);
+            broadcaster_clone.prune_stale_peers(max_age);
+        }
     });
 
     // Handle graceful shutdown with Ctrl+C
-    let shutdown = Arc::new(tokio::sync::Notify::new());
-    let shutdown_clone = shutdown.clone();
+    let interrupted = Arc::new(tokio::sync::Notify::new());
+    let interrupted_clone = interrupted.clone();
 
     tokio::spawn(async move {
         if let Err(e) = signal::ctrl_c().await {
-            eprintln!("Failed to listen for Ctrl+C: {}", e);
+            log(Level::Error, &format!("Failed to listen for Ctrl+C: {}", e));
             return;
         }
-        shutdown_clone.notify_one();
+        interrupted_clone.notify_one();
     });
 
-    // Start continuous broadcast (this runs on the main thread)
-    let broadcast_task =
-        tokio::spawn(async move { continuous_broadcast_task(&user_interface).await });
+    // Kept for the shutdown routine, the original moves into the broadcast task
+    let shutdown_ui = user_interface.clone();
+
+    // Headless mode reads stdin and prints to stdout in place of the terminal UI's receive
+    // and input loops
+    let broadcast_task = if headless {
+        tokio::spawn(async move { headless::run(&user_interface).await })
+    } else {
+        // Start the continuous receive task
+        let user_interface_clone = user_interface.clone();
+        task::spawn(async move {
+            continuous_receive_task(&user_interface_clone).await;
+        });
+
+        // Start continuous broadcast (this runs on the main thread)
+        tokio::spawn(async move { continuous_broadcast_task(&user_interface).await })
+    };
 
     // Wait for either the broadcast task to complete or Ctrl+C
     tokio::select! {
         result = broadcast_task => {
             if let Err(e) = result {
-                eprintln!("Broadcast task failed: {:?}", e);
+                log(Level::Error, &format!("Broadcast task failed: {:?}", e));
             }
         }
-        _ = shutdown.notified() => {
-            println!("\nShutting down gracefully...");
-            // Restore terminal properly
-            let _ = GraphicsEngine::restore_terminal();
-            // Force exit with a small delay to allow terminal to reset
-            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-            std::process::exit(0);
-        }
+        _ = interrupted.notified() => {}
     }
 
-    // Make sure the terminal is properly restored
-    let _ = GraphicsEngine::restore_terminal();
-
-    // Force the process to exit completely
-    std::process::exit(0);
-
-    // This is unreachable, but needed for type correctness
-    #[allow(unreachable_code)]
-    Ok(())
+    shutdown(&shutdown_ui).await
 }
 
 // Helper functions
 async fn continuous_receive_task(ui: &UserInterface) {
-    let receiver = ui.receiver.clone();
     let graphics_engine = ui.graphics_engine.clone();
+    let Some(mut events) = ui.receiver.lock().unwrap().take_events() else {
+        log(Level::Error, "Network events are already being consumed");
+        return;
+    };
 
-    loop {
-        // Try to get a message from the queue
-        let message = {
-            let receiver_lock = receiver.lock().unwrap();
-            receiver_lock.get_queue_message()
-        };
+    // Pending acks are checked for timeouts on this interval
+    let mut expiry = time::interval(time::Duration::from_secs(1));
+    let mut reorder = ReorderBuffer::new(time::Duration::from_millis(REORDER_WINDOW_MS));
 
-        if let Some(message) = message {
-            // Add message to graphics engine
-            {
-                let mut engine = graphics_engine.lock().unwrap();
-                engine.add_message(&message);
-                let _ = engine.print_all_messages(false);
+    loop {
+        // Held messages wake the loop when they're due, with none held this branch is off
+        let next_release = reorder.next_release();
+        let release_at = time::Instant::from_std(next_release.unwrap_or_else(Instant::now));
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(NetworkEvent::Message(message)) => {
+                    reorder.push(message, Instant::now());
+                }
+                Ok(NetworkEvent::Typing) => refresh_typing(ui),
+                Ok(NetworkEvent::PeerStatus { name, away }) => {
+                    if away {
+                        ui.show_system_message(&format!("{} is away", name));
+                    } else {
+                        ui.show_system_message(&format!("{} is back", name));
+                    }
+                }
+                Ok(NetworkEvent::PeerJoined(name)) => {
+                    ui.show_toast(&format!("{} joined the chat", name));
+                }
+                Ok(NetworkEvent::PeerLeft(addr, name)) => {
+                    // The broadcaster has its own copy of the peer list
+                    ui.broadcaster.forget_peer(addr, &name);
+                    ui.show_toast(&format!("{} left the chat", name));
+                    refresh_typing(ui);
+                }
+                Ok(NetworkEvent::HistoryRequest { from, count }) => {
+                    ui.answer_history_request(from, count).await;
+                }
+                Ok(NetworkEvent::History(message)) => {
+                    let mut engine = graphics_engine.lock().unwrap();
+                    if engine.merge_history(&message) {
+                        let _ = engine.print_all_messages(false);
+                    }
+                }
+                Ok(NetworkEvent::Edited { id, node, content }) => {
+                    let mut engine = graphics_engine.lock().unwrap();
+                    if engine.edit_message(id, Some(&node), &content) {
+                        let _ = engine.print_all_messages(false);
+                    }
+                }
+                Ok(NetworkEvent::Deleted { id, node }) => {
+                    let mut engine = graphics_engine.lock().unwrap();
+                    if engine.delete_message(id, Some(&node)) {
+                        let _ = engine.print_all_messages(false);
+                    }
+                }
+                Ok(NetworkEvent::Reacted { id, node, emoji }) => {
+                    let mut engine = graphics_engine.lock().unwrap();
+                    if engine.react(id, &emoji, &node) {
+                        let _ = engine.print_all_messages(false);
+                    }
+                }
+                Ok(NetworkEvent::Ack { id, from }) => {
+                    // Only the first ack for a message we're waiting on counts
+                    if ui.broadcaster.confirm_delivery(id, from) {
+                        ui.set_delivery(&[id], Delivery::Delivered);
+                    }
+                }
+                Ok(NetworkEvent::Pong { from, sent }) => {
+                    if let Some(name) = ui.broadcaster.answer_ping(from, sent) {
+                        let now = chrono::Utc::now().timestamp_millis();
+                        ui.show_system_message(&format!(
+                            "Pong from {} ({}): {} ms",
+                            name,
+                            from,
+                            ping::round_trip(sent, now).as_millis()
+                        ));
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    debug_log(&format!("UI fell behind, dropped {} events", skipped));
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = time::sleep_until(release_at), if next_release.is_some() => {
+                let messages = reorder.take_ready(Instant::now());
+                {
+                    let mut engine = graphics_engine.lock().unwrap();
+                    let mut mentioned = false;
+                    for message in &messages {
+                        mentioned |= engine.add_message(message);
+                    }
+                    if mentioned {
+                        let _ = engine.notify_mention();
+                    }
+                    let _ = engine.print_all_messages(false);
+                }
+                for message in &messages {
+                    ui.log_message(message);
+                }
+            }
+            _ = expiry.tick() => {
+                let failed = ui.broadcaster.retransmit_pending();
+                ui.set_delivery(&failed, Delivery::Undelivered);
+                for name in ui
+                    .broadcaster
+                    .expire_pings(time::Duration::from_secs(PING_TIMEOUT_SECS))
+                {
+                    ui.show_system_message(&format!(
+                        "No pong from {} within {}s",
+                        name, PING_TIMEOUT_SECS
+                    ));
+                }
+                refresh_typing(ui);
+                refresh_dimming(ui);
             }
         }
+    }
+}
 
-        // Small delay to prevent CPU thrashing
-        time::sleep(time::Duration::from_millis(10)).await;
+// Redraws the messages once another one is old enough to be dimmed
+fn refresh_dimming(ui: &UserInterface) {
+    let mut engine = ui.graphics_engine.lock().unwrap();
+    if engine.refresh_dimming() {
+        let _ = engine.print_all_messages(false);
+    }
+}
+
+// Shows who is typing in the status bar, dropping peers whose indicator has expired
+fn refresh_typing(ui: &UserInterface) {
+    let names = ui.receiver.lock().unwrap().typing_peers();
+    let mut engine = ui.graphics_engine.lock().unwrap();
+    if engine.set_typing_status(typing::describe_typing(&names)) {
+        let _ = engine.print_status_bar();
     }
 }
 
@@ -215,18 +673,22 @@ async fn continuous_broadcast_task(ui: &UserInterface) -> std::io::Result<()> {
 
         // Get input character by character
         loop {
+            let previous_input = input.clone();
             let (input_complete, should_exit) = {
                 let mut engine = engine.lock().unwrap();
                 engine.read_input(&mut input)?
             };
+            if input != previous_input {
+                ui.update_typing(&input).await;
+            }
+            ui.update_away().await;
             if should_exit {
                 // User pressed Ctrl+Q or Ctrl+C or Esc
-                // Restore terminal properly
-                GraphicsEngine::restore_terminal()?;
-                // Force exit the process to ensure all threads are terminated
-                std::process::exit(0);
+                shutdown(ui).await;
             }
             if input_complete {
+                // Sending ends our typing, as does submitting an empty line
+                ui.update_typing("").await;
                 break;
             }
         }
@@ -237,75 +699,149 @@ async fn continuous_broadcast_task(ui: &UserInterface) -> std::io::Result<()> {
             engine.print_input_prompt()?;
         }
 
-        // Broadcast message
-        let message = Message::new(
-            input.clone(), // Clone so we can use it again
-            ui.username.clone(),
-            constants::OUTBOUND_MESSAGE_REPORTED_IP.to_string(),
-        );
+        // Commands are handled locally and never broadcast
+        match ui.handle_command(&input).await {
+            CommandResult::Handled => continue,
+            CommandResult::Quit => shutdown(ui).await,
+            CommandResult::NotCommand => {}
+        }
+
+        ui.send_chat(input).await;
+    }
+}
+
+// The one way out once the UI is running: /quit, Ctrl+Q, Ctrl+C, Esc and a failed input loop
+// all end here. The cleanup steps are in shutdown::run.
+async fn shutdown(ui: &UserInterface) -> ! {
+    shutdown::run_within(ui, time::Duration::from_millis(SHUTDOWN_TIMEOUT_MS), || {
+        std::process::exit(1)
+    })
+    .await;
+
+    if !ui.headless {
+        println!("Left the chat. Goodbye!");
+    }
+    // Background tasks never finish on their own, so exit rather than return
+    std::process::exit(0);
+}
+
+impl shutdown::Cleanup for UserInterface {
+    fn restore_terminal(&self) {
+        if !self.headless {
+            if let Err(e) = GraphicsEngine::restore_terminal() {
+                log(Level::Warn, &format!("Failed to restore terminal: {}", e));
+            }
+        }
+    }
 
-        // Also add this message to our own display
+    fn terminal_restorer(&self) -> impl FnOnce() + Send + 'static {
+        let headless = self.headless;
+        move || {
+            if !headless {
+                let _ = GraphicsEngine::restore_terminal();
+            }
+        }
+    }
+
+    fn save_state(&self) {
+        save_peer_cache(&self.broadcaster);
+        self.save_history();
+        self.save_muted();
+    }
+
+    async fn drain_send_queue(&self) {
+        let drain_timeout = time::Duration::from_millis(SEND_QUEUE_DRAIN_MS);
+        if time::timeout(drain_timeout, self.send_queue.wait_until_empty())
+            .await
+            .is_err()
         {
-            let mut engine = ui.graphics_engine.lock().unwrap();
-            // Create a local message to show in our UI
-            let local_message = Message::new(
-                input,
-                ui.username.clone(), // Use just the username, our display logic handles the YOU part
-                "local".to_string(),
+            log(
+                Level::Warn,
+                &format!(
+                    "Gave up on {} messages still waiting to be sent",
+                    self.send_queue.outstanding()
+                ),
             );
-            engine.add_message(&local_message);
-            let _ = engine.print_all_messages(false);
         }
+    }
+
+    fn flush_log(&self) {
+        self.flush_session_log();
+    }
+
+    async fn announce_leaving(&self) {
+        let leave_timeout = time::Duration::from_millis(LEAVE_ANNOUNCE_TIMEOUT_MS);
+        match time::timeout(leave_timeout, self.broadcaster.announce_leaving()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log(
+                Level::Warn,
+                &format!("Failed to tell peers we're leaving: {}", e),
+            ),
+            Err(_) => log(Level::Warn, "Timed out telling peers we're leaving"),
+        }
+    }
+}
 
-        if let Err(e) = ui.broadcaster.broadcast_message(message).await {
-            eprintln!("Failed to broadcast message: {}", e);
+fn save_peer_cache(broadcaster: &Broadcaster) {
+    if let Some(path) = storage::peer_cache_path() {
+        if let Err(e) = broadcaster.save_peers(&path) {
+            log(Level::Warn, &format!("Failed to save peer cache: {}", e));
         }
     }
 }
 
-async fn show_intro(chat_port: u16, discovery_port: u16) {
-    if constants::DO_BULLSHIT_INTRO {
-        // Cyberpunk-style intro sequence
-        time::sleep(time::Duration::from_millis(1000)).await;
-        println!(
-            "RECEIVER    >>> ONLINE!                   LISTENING ON:    DISCOVERY:{} | CHAT:{}",
-            discovery_port, chat_port
-        );
-        time::sleep(time::Duration::from_millis(20)).await;
-        println!(
-            "BROADCASTER >>> ONLINE!                   BROADCASTING ON: DISCOVERY:{} | CHAT:{}",
-            discovery_port, chat_port
-        );
-        time::sleep(time::Duration::from_millis(500)).await;
-        println!("setting up auxillery networking systems...");
-        time::sleep(time::Duration::from_millis(12)).await;
-        println!("launching threads...");
-        time::sleep(time::Duration::from_millis(5)).await;
-        println!("jacking in...");
-        time::sleep(time::Duration::from_millis(2)).await;
-        println!("breaking the cyber ice...");
-        time::sleep(time::Duration::from_millis(172)).await;
-        println!("contacting chatgpt to fix compilation errors...");
-        time::sleep(time::Duration::from_millis(7)).await;
-        println!("hol up mom said dinner is ready brb...");
-        time::sleep(time::Duration::from_millis(165)).await;
-        println!("ok im back...");
-        time::sleep(time::Duration::from_millis(1)).await;
-        println!("chatgpt unable to fix all errors, contacting gemini...");
-        time::sleep(time::Duration::from_millis(2)).await;
-        println!("connecting to imperial vox channels...");
-        time::sleep(time::Duration::from_millis(3)).await;
-        println!("requesting ip from adeptus mechanicus router...");
-        time::sleep(time::Duration::from_millis(3)).await;
-        println!("negotiating connection terms with NetWatch...");
-        time::sleep(time::Duration::from_millis(186)).await;
-        println!("determining used device type: Cyberdeck...");
-        time::sleep(time::Duration::from_millis(2)).await;
-        println!("turning on styalized neon japanese advertisement in a filthy back alley...");
-        time::sleep(time::Duration::from_millis(100)).await;
-        println!("{}", constants::ONLINE_ASCII_ART);
-        time::sleep(time::Duration::from_millis(1200)).await;
+// Sleeps between intro lines, skipped entirely with --fast-intro
+async fn intro_pause(millis: u64, mode: IntroMode) {
+    if mode == IntroMode::Full {
+        time::sleep(time::Duration::from_millis(millis)).await;
+    }
+}
+
+async fn show_intro(chat_port: u16, discovery_port: u16, mode: IntroMode) {
+    if mode == IntroMode::Off {
+        return;
     }
+
+    // Cyberpunk-style intro sequence
+    intro_pause(1000, mode).await;
+    println!(
+        "RECEIVER    >>> ONLINE!                   LISTENING ON:    DISCOVERY:{} | CHAT:{}",
+        discovery_port, chat_port
+    );
+    intro_pause(20, mode).await;
+    println!(
+        "BROADCASTER >>> ONLINE!                   BROADCASTING ON: DISCOVERY:{} | CHAT:{}",
+        discovery_port, chat_port
+    );
+    intro_pause(500, mode).await;
+    println!("setting up auxillery networking systems...");
+    intro_pause(12, mode).await;
+    println!("launching threads...");
+    intro_pause(5, mode).await;
+    println!("jacking in...");
+    intro_pause(2, mode).await;
+    println!("breaking the cyber ice...");
+    intro_pause(172, mode).await;
+    println!("contacting chatgpt to fix compilation errors...");
+    intro_pause(7, mode).await;
+    println!("hol up mom said dinner is ready brb...");
+    intro_pause(165, mode).await;
+    println!("ok im back...");
+    intro_pause(1, mode).await;
+    println!("chatgpt unable to fix all errors, contacting gemini...");
+    intro_pause(2, mode).await;
+    println!("connecting to imperial vox channels...");
+    intro_pause(3, mode).await;
+    println!("requesting ip from adeptus mechanicus router...");
+    intro_pause(3, mode).await;
+    println!("negotiating connection terms with NetWatch...");
+    intro_pause(186, mode).await;
+    println!("determining used device type: Cyberdeck...");
+    intro_pause(2, mode).await;
+    println!("turning on styalized neon japanese advertisement in a filthy back alley...");
+    intro_pause(100, mode).await;
+    println!("{}", constants::ONLINE_ASCII_ART);
+    intro_pause(1200, mode).await;
 }
 
 // This struct ensures that terminal is restored on program exit
@@ -314,7 +850,36 @@ struct CleanupGuard;
 impl Drop for CleanupGuard {
     fn drop(&mut self) {
         if let Err(e) = GraphicsEngine::restore_terminal() {
-            eprintln!("Failed to restore terminal: {}", e);
+            log(Level::Warn, &format!("Failed to restore terminal: {}", e));
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[tokio::test]
+    async fn disabled_intro_returns_immediately() {
+        let start = Instant::now();
+        show_intro(2223, 2224, IntroMode::Off).await;
+        intro_pause(1000, IntroMode::Fast).await;
+        // The full intro pauses for seconds
+        assert!(start.elapsed() < time::Duration::from_millis(100));
+    }
+
+    #[test]
+    fn intro_mode_follows_the_flags() {
+        let mut config = Config {
+            do_intro: false,
+            fast_intro: true,
+            ..Config::default()
+        };
+        assert_eq!(config.intro_mode(), IntroMode::Off);
+        config.do_intro = true;
+        assert_eq!(config.intro_mode(), IntroMode::Fast);
+        config.fast_intro = false;
+        assert_eq!(config.intro_mode(), IntroMode::Full);
+    }
+}
@@ -0,0 +1,182 @@
+// mDNS (RFC 6762) and DNS-SD (RFC 6763) discovery, for networks where broadcast doesn't
+// get through. Each instance advertises a _subnetvox._udp service named after its user,
+// with the chat port in its SRV record and its node id in a TXT record. The protocol
+// itself is left to the mdns-sd crate.
+
+use crate::constants::{MDNS_SERVICE_TYPE, UNKNOWN_PEER_NAME};
+use mdns_sd::{Error, IfKind, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::net::IpAddr;
+use std::thread;
+use tokio::sync::oneshot;
+
+// DNS labels are limited to 63 bytes
+const MAX_LABEL_LEN: usize = 63;
+// The TXT key holding the node id
+const NODE_PROPERTY: &str = "node";
+
+// The mdns-sd daemon, shut down when dropped. It runs on a thread of its own that outlives
+// a dropped ServiceDaemon, so a listener cancelled by a reconnect would otherwise leave it
+// running, with our instance still advertised.
+pub struct Daemon {
+    daemon: ServiceDaemon,
+    // Full name of the instance we advertise, withdrawn before shutting down
+    published: Option<String>,
+}
+
+impl Daemon {
+    // With a bind address, only its interface is used
+    pub fn start(bind_address: Option<IpAddr>) -> Result<Self, Error> {
+        let daemon = Self {
+            daemon: ServiceDaemon::new()?,
+            published: None,
+        };
+        if let Some(address) = bind_address {
+            daemon.daemon.disable_interface(IfKind::All)?;
+            daemon.daemon.enable_interface(IfKind::Addr(address))?;
+        }
+        Ok(daemon)
+    }
+
+    // Calls found with every instance of our service that resolves. The daemon's channel
+    // only has a blocking receive, so this runs on a thread of its own, which ends once the
+    // daemon shuts down. The returned receiver resolves when it has.
+    pub fn browse(
+        &self,
+        mut found: impl FnMut(ServiceInfo) + Send + 'static,
+    ) -> Result<oneshot::Receiver<()>, Error> {
+        let events = self.daemon.browse(MDNS_SERVICE_TYPE)?;
+        let (ended, on_end) = oneshot::channel();
+        thread::Builder::new()
+            .name("mdns-browse".to_string())
+            .spawn(move || {
+                while let Ok(event) = events.recv() {
+                    if let ServiceEvent::ServiceResolved(service) = event {
+                        found(service);
+                    }
+                }
+                let _ = ended.send(());
+            })
+            .map_err(|e| Error::Msg(format!("failed to start browsing: {}", e)))?;
+        Ok(on_end)
+    }
+
+    // Advertises the service in place of the one published before
+    pub fn publish(&mut self, service: ServiceInfo) -> Result<(), Error> {
+        if let Some(fullname) = self.published.take() {
+            let _ = self.daemon.unregister(&fullname);
+        }
+        let fullname = service.get_fullname().to_string();
+        self.daemon.register(service)?;
+        self.published = Some(fullname);
+        Ok(())
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        // Commands run in order, so the goodbye goes out before the daemon stops
+        if let Some(fullname) = self.published.take() {
+            let _ = self.daemon.unregister(&fullname);
+        }
+        let _ = self.daemon.shutdown();
+    }
+}
+
+// The service record we publish. Our addresses are filled in by the daemon for every
+// interface it runs on.
+pub fn service_info(username: &str, node_id: &str, port: u16) -> Result<ServiceInfo, Error> {
+    // An empty label would end the DNS name early
+    let username = if username.is_empty() {
+        UNKNOWN_PEER_NAME
+    } else {
+        username
+    };
+    let host_name = format!("{}.local.", node_id);
+
+    ServiceInfo::new(
+        MDNS_SERVICE_TYPE,
+        &truncate_label(username),
+        &host_name,
+        "",
+        port,
+        &[(NODE_PROPERTY, node_id)][..],
+    )
+    .map(ServiceInfo::enable_addr_auto)
+}
+
+// The username a found service was published under
+pub fn instance_name(service: &ServiceInfo) -> &str {
+    let fullname = service.get_fullname();
+    fullname
+        .strip_suffix(service.get_type())
+        .and_then(|instance| instance.strip_suffix('.'))
+        .unwrap_or(fullname)
+}
+
+pub fn node_id(service: &ServiceInfo) -> Option<&str> {
+    service.get_property_val_str(NODE_PROPERTY)
+}
+
+// Longer usernames are cut at a character boundary
+fn truncate_label(label: &str) -> String {
+    let mut end = label.len().min(MAX_LABEL_LEN);
+    while !label.is_char_boundary(end) {
+        end -= 1;
+    }
+    label[..end].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time;
+
+    #[test]
+    fn published_service_has_our_port_and_name() {
+        let service = service_info("alice", "a1b2", 2223).unwrap();
+        assert_eq!(service.get_port(), 2223);
+        assert_eq!(service.get_type(), MDNS_SERVICE_TYPE);
+        assert_eq!(service.get_hostname(), "a1b2.local.");
+        assert_eq!(instance_name(&service), "alice");
+        assert_eq!(node_id(&service), Some("a1b2"));
+    }
+
+    #[test]
+    fn instance_names_fit_a_dns_label() {
+        let unnamed = service_info("", "a1b2", 2223).unwrap();
+        assert_eq!(instance_name(&unnamed), UNKNOWN_PEER_NAME);
+
+        let long = service_info(&"é".repeat(40), "a1b2", 2223).unwrap();
+        assert_eq!(instance_name(&long), "é".repeat(31));
+    }
+
+    #[tokio::test]
+    async fn dropping_the_daemon_ends_browsing() {
+        let mut daemon = Daemon::start(None).unwrap();
+        let browse = daemon.browse(|_| {}).unwrap();
+        daemon
+            .publish(service_info("alice", "a1b2", 2223).unwrap())
+            .unwrap();
+
+        // A future owning the daemon, cancelled the way the supervisor cancels a listener
+        let owner = async move {
+            let _daemon = daemon;
+            std::future::pending::<()>().await
+        };
+        assert!(time::timeout(Duration::from_millis(50), owner)
+            .await
+            .is_err());
+
+        time::timeout(Duration::from_secs(5), browse)
+            .await
+            .expect("the browse thread outlived the daemon")
+            .unwrap();
+    }
+
+    #[test]
+    fn dotted_usernames_keep_their_dots() {
+        let service = service_info("alice.b", "a1b2", 2223).unwrap();
+        assert_eq!(instance_name(&service), "alice.b");
+    }
+}
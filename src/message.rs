@@ -1,21 +1,82 @@
-use crate::constants::FIELD_SPLITTER;
+use crate::constants::{
+    FIELD_ESCAPE, FIELD_SPLITTER, LEGACY_FIELD_SPLITTER, MSG_TYPE_ACTION, MSG_TYPE_CHAT,
+    MSG_TYPE_DM, SHORT_ID_DIGITS,
+};
+use crate::crypto::{encrypt_content, sign_content, Envelope};
+use crate::random::random_u64;
 
 #[derive(Clone, Debug)]
 pub struct Message {
+    id: u64,
+    // Unix time in milliseconds when the original sender created the message
+    timestamp: i64,
     content: String,
     sender_name: String,
+    // The sender's IP on received messages, "local" on our own echoes, and our node ID on
+    // the ones we send, where the field tells receivers who sent it
     sender_ip: String,
+    // Set for direct messages meant for a single peer. Empty when the peer's name is unknown.
+    recipient: Option<String>,
+    // Set for /me messages, whose content describes what the sender is doing
+    action: bool,
+    // The node ID the sender sent on received messages, which only it can edit or delete
+    // them with. Not part of the wire format, that's sender_ip.
+    sender_node: Option<String>,
 }
 
 impl Message {
     pub fn new(content: String, sender_name: String, sender_ip: String) -> Self {
         Self {
+            id: random_u64(),
+            timestamp: chrono::Utc::now().timestamp_millis(),
             content,
             sender_name,
             sender_ip,
+            recipient: None,
+            action: false,
+            sender_node: None,
         }
     }
 
+    // Turns this into a direct message for a single peer
+    pub fn with_recipient(mut self, recipient: String) -> Self {
+        self.recipient = Some(recipient);
+        self
+    }
+
+    // Turns this into a /me action message
+    pub fn into_action(mut self) -> Self {
+        self.action = true;
+        self
+    }
+
+    // Remembers the node ID a received message was sent from
+    pub fn with_sender_node(mut self, node: String) -> Self {
+        self.sender_node = Some(node);
+        self
+    }
+
+    // Keeps the ID assigned by the original sender
+    pub fn with_id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    // Keeps the creation time reported by the original sender
+    pub fn with_timestamp(mut self, timestamp: i64) -> Self {
+        self.timestamp = timestamp;
+        self
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // Milliseconds since the Unix epoch
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
     pub fn content(&self) -> &str {
         &self.content
     }
@@ -28,16 +89,272 @@ impl Message {
         &self.sender_ip
     }
 
+    pub fn recipient(&self) -> Option<&str> {
+        self.recipient.as_deref()
+    }
+
+    pub fn is_action(&self) -> bool {
+        self.action
+    }
+
+    pub fn sender_node(&self) -> Option<&str> {
+        self.sender_node.as_deref()
+    }
+
+    pub fn msg_type(&self) -> &'static str {
+        if self.recipient.is_some() {
+            MSG_TYPE_DM
+        } else if self.action {
+            MSG_TYPE_ACTION
+        } else {
+            MSG_TYPE_CHAT
+        }
+    }
+
     pub fn encode_for_broadcast(&self) -> String {
+        let id = self.id.to_string();
+        let timestamp = self.timestamp.to_string();
+        let mut fields = vec![
+            self.sender_name.as_str(),
+            self.sender_ip.as_str(),
+            id.as_str(),
+            timestamp.as_str(),
+        ];
+        if let Some(recipient) = &self.recipient {
+            fields.push(recipient);
+        }
+        // Sealed and tagged with the shared key when encryption is on
+        let envelope = Envelope {
+            msg_type: self.msg_type(),
+            id: self.id,
+            node: &self.sender_ip,
+            name: &self.sender_name,
+            timestamp: self.timestamp,
+            recipient: self.recipient.as_deref(),
+        };
+        let content = sign_content(&envelope, &encrypt_content(&self.content));
+        fields.push(&content);
+
+        join_fields(&fields)
+    }
+
+    // The full packet as sent on the wire, starting with the message type
+    pub fn encode_packet(&self) -> String {
         format!(
-            "{}{}{}{}{}",
-            self.sender_name, FIELD_SPLITTER, self.sender_ip, FIELD_SPLITTER, self.content
+            "{}{}{}",
+            self.msg_type(),
+            FIELD_SPLITTER,
+            self.encode_for_broadcast()
         )
     }
 }
 
+// The start of an ID in hex, as shown next to our messages. Any prefix of the full hex ID
+// picks a message out for /edit and /delete.
+pub fn short_id(id: u64) -> String {
+    let mut hex = format!("{:016x}", id);
+    hex.truncate(SHORT_ID_DIGITS);
+    hex
+}
+
+// Why a username can't be used, None when it's fine. It's checked as it will be used, with
+// the surrounding whitespace trimmed. Fields are escaped on the wire, but older peers split
+// on every LEGACY_FIELD_SPLITTER, so a name containing one would garble every packet we
+// send them.
+pub fn username_error(name: &str) -> Option<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Some("Usernames can't be empty".to_string());
+    }
+    [FIELD_SPLITTER, LEGACY_FIELD_SPLITTER]
+        .into_iter()
+        .find(|splitter| name.contains(splitter))
+        .map(|splitter| {
+            format!(
+                "Usernames can't contain {:?}, it separates the fields of every packet",
+                splitter
+            )
+        })
+}
+
+// Prefixes every escape character and field splitter so the field can't be split apart
+pub fn escape_field(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    let mut rest = field;
+
+    while let Some(c) = rest.chars().next() {
+        if c == FIELD_ESCAPE {
+            escaped.push(FIELD_ESCAPE);
+            escaped.push(c);
+            rest = &rest[c.len_utf8()..];
+        } else if rest.starts_with(FIELD_SPLITTER) {
+            escaped.push(FIELD_ESCAPE);
+            escaped.push_str(FIELD_SPLITTER);
+            rest = &rest[FIELD_SPLITTER.len()..];
+        } else {
+            escaped.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    escaped
+}
+
+// Escapes each field and joins them with the field splitter
+pub fn join_fields(fields: &[&str]) -> String {
+    fields
+        .iter()
+        .map(|field| escape_field(field))
+        .collect::<Vec<_>>()
+        .join(FIELD_SPLITTER)
+}
+
+// The splitter a packet was joined with, LEGACY_FIELD_SPLITTER for one from an older peer
+pub fn splitter_of(data: &str) -> &'static str {
+    if data.contains(FIELD_SPLITTER) {
+        FIELD_SPLITTER
+    } else {
+        LEGACY_FIELD_SPLITTER
+    }
+}
+
+// Splits on unescaped field splitters and unescapes each resulting field
+pub fn split_fields(data: &str) -> Vec<String> {
+    let splitter = splitter_of(data);
+    let mut fields = Vec::new();
+    let mut current = String::new();
+    let mut rest = data;
+
+    while let Some(c) = rest.chars().next() {
+        if c == FIELD_ESCAPE {
+            rest = &rest[c.len_utf8()..];
+            if rest.starts_with(splitter) {
+                current.push_str(splitter);
+                rest = &rest[splitter.len()..];
+            } else if let Some(next) = rest.chars().next() {
+                current.push(next);
+                rest = &rest[next.len_utf8()..];
+            } else {
+                // A trailing escape has nothing to escape, keep it literally
+                current.push(c);
+            }
+        } else if rest.starts_with(splitter) {
+            fields.push(std::mem::take(&mut current));
+            rest = &rest[splitter.len()..];
+        } else {
+            current.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+
+    fields.push(current);
+    fields
+}
+
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.encode_for_broadcast())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::Receiver;
+
+    fn round_trip(content: &str, name: &str) -> (String, String) {
+        let message = Message::new(content.to_string(), name.to_string(), "node-a".to_string());
+        let parsed = Receiver::parse_message(message.encode_packet().as_bytes()).unwrap();
+        (parsed.content, parsed.sender_name)
+    }
+
+    #[test]
+    fn splitters_in_fields_survive() {
+        assert_eq!(
+            round_trip("a ~ b ~ c", "~al~ice~"),
+            ("a ~ b ~ c".to_string(), "~al~ice~".to_string())
+        );
+        assert_eq!(
+            round_trip("", "alice"),
+            (String::new(), "alice".to_string())
+        );
+        // Control characters never reach the screen, but they don't split the content
+        let content = format!("a{}b", FIELD_SPLITTER);
+        assert_eq!(round_trip(&content, "alice").0, "ab");
+    }
+
+    #[test]
+    fn escapes_in_fields_survive() {
+        for field in [
+            "\\",
+            "\\\u{1f}",
+            "a\\\\\u{1f}b",
+            "ends with \\",
+            "\u{1f}\u{1f}",
+            "\\~",
+            "",
+        ] {
+            let fields = split_fields(&join_fields(&[field, "next"]));
+            assert_eq!(fields, vec![field.to_string(), "next".to_string()]);
+        }
+    }
+
+    #[test]
+    fn escaped_splitters_stay_in_their_field() {
+        assert_eq!(escape_field("a\u{1f}b\\c~"), "a\\\u{1f}b\\\\c~");
+        assert_eq!(split_fields("a\\\u{1f}b\u{1f}c"), vec!["a\u{1f}b", "c"]);
+    }
+
+    #[test]
+    fn packets_from_older_peers_split_on_the_legacy_splitter() {
+        assert_eq!(split_fields("CHAT~bob~hi"), vec!["CHAT", "bob", "hi"]);
+        assert_eq!(split_fields("a\\~b~c"), vec!["a~b", "c"]);
+        let legacy = Receiver::parse_message(b"CHAT~bob~node-b~a ~ b").unwrap();
+        assert_eq!(legacy.sender_name, "bob");
+        assert_eq!(legacy.content, "a ~ b");
+    }
+
+    #[test]
+    fn usernames_that_would_break_framing_are_rejected() {
+        let name = format!("al{}ice", FIELD_SPLITTER);
+        let error = username_error(&name).unwrap();
+        assert!(
+            error.contains(&format!("{:?}", FIELD_SPLITTER)),
+            "{}",
+            error
+        );
+        assert!(username_error("").is_some());
+        assert!(username_error("  \n").is_some());
+        // Older peers would split the name apart
+        assert!(username_error(" al~ice ").unwrap().contains("\"~\""));
+        assert_eq!(username_error("alice"), None);
+        assert_eq!(username_error(" alice "), None);
+        assert_eq!(username_error("Zoë 🦀"), None);
+    }
+
+    #[test]
+    fn plain_text_is_joined_and_split_unchanged() {
+        assert!(FIELD_SPLITTER.chars().all(char::is_control));
+        let fields = ["CHAT", "alice", "node-a", "lunch at noon? ~ 🍜"];
+        let joined = join_fields(&fields);
+        // Nothing to escape, so the splitter is the only thing added
+        assert_eq!(joined, fields.join(FIELD_SPLITTER));
+        assert_eq!(split_fields(&joined), fields);
+    }
+
+    #[test]
+    fn messages_get_distinct_ids() {
+        let first = Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string());
+        let second = Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string());
+        assert_ne!(first.id(), second.id());
+    }
+
+    #[test]
+    fn id_and_timestamp_survive_the_wire() {
+        let message = Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string())
+            .with_timestamp(1_714_566_615_123);
+        let parsed = Receiver::parse_message(message.encode_packet().as_bytes()).unwrap();
+        assert_eq!(parsed.id, Some(message.id()));
+        assert_eq!(parsed.timestamp, Some(1_714_566_615_123));
+    }
+}
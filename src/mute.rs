@@ -0,0 +1,102 @@
+// Usernames whose messages are hidden, set with /mute and /unmute. Names are matched
+// ignoring case. Optionally saved between sessions, one name per line.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Default)]
+pub struct MutedUsers {
+    // Lowercased
+    names: BTreeSet<String>,
+}
+
+impl MutedUsers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns false when the name was already muted
+    pub fn mute(&mut self, name: &str) -> bool {
+        self.names.insert(name.to_lowercase())
+    }
+
+    // Returns false when the name wasn't muted
+    pub fn unmute(&mut self, name: &str) -> bool {
+        self.names.remove(&name.to_lowercase())
+    }
+
+    pub fn is_muted(&self, name: &str) -> bool {
+        !self.names.is_empty() && self.names.contains(&name.to_lowercase())
+    }
+
+    // Sorted, lowercased
+    pub fn names(&self) -> Vec<String> {
+        self.names.iter().cloned().collect()
+    }
+
+    // Reads a saved list. A missing file gives an empty one.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut muted = Self::new();
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                for name in contents
+                    .lines()
+                    .map(str::trim)
+                    .filter(|name| !name.is_empty())
+                {
+                    muted.mute(name);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(muted)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = String::new();
+        for name in &self.names {
+            contents.push_str(name);
+            contents.push('\n');
+        }
+        fs::write(path, contents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_match_ignoring_case() {
+        let mut muted = MutedUsers::new();
+        assert!(!muted.is_muted("eve"));
+        assert!(muted.mute("Eve"));
+        assert!(!muted.mute("EVE"));
+        assert!(muted.is_muted("eve"));
+        assert!(!muted.is_muted("evelyn"));
+
+        assert!(muted.unmute("eVe"));
+        assert!(!muted.unmute("eve"));
+        assert!(!muted.is_muted("Eve"));
+    }
+
+    #[test]
+    fn saved_names_load_back() {
+        let path = std::env::temp_dir().join(format!("reticulum-muted-{}", std::process::id()));
+        let mut muted = MutedUsers::new();
+        muted.mute("Mallory");
+        muted.mute("eve");
+        muted.save(&path).unwrap();
+
+        let loaded = MutedUsers::load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.names(), ["eve", "mallory"]);
+        assert!(MutedUsers::load(&path).unwrap().names().is_empty());
+    }
+}
@@ -1,25 +1,562 @@
+use crate::access::AccessList;
+use crate::away;
+use crate::backoff::DiscoveryBackoff;
+use crate::codec::{self, DecodeError};
+use crate::config::Config;
 use crate::constants::{
-    BROADCAST_ADDR, DISCOVERY_PORT, FIELD_SPLITTER, MSG_TYPE_CHAT, MSG_TYPE_DISCOVERY,
-    MSG_TYPE_DISCOVERY_RESPONSE, RECV_BUFFER_SIZE, TAILSCALE_MULTICAST,
+    DEDUP_WINDOW, DISCOVERY_INTERVAL_SECS, FRAGMENT_TIMEOUT_SECS, HEARTBEAT_INTERVAL_SECS,
+    HISTORY_REQUEST_INTERVAL_SECS, HISTORY_SENDER, LEGACY_DEDUP_WINDOW_MS, MAX_CONTENT_CHARS,
+    MAX_MESSAGE_SIZE, MAX_USERNAME_CHARS, MSG_TYPE_ACK, MSG_TYPE_ACTION, MSG_TYPE_CHAT,
+    MSG_TYPE_DELETE, MSG_TYPE_DISCOVERY, MSG_TYPE_DISCOVERY_RESPONSE, MSG_TYPE_DM, MSG_TYPE_EDIT,
+    MSG_TYPE_HEARTBEAT, MSG_TYPE_HISTORY_REQ, MSG_TYPE_HISTORY_RESP, MSG_TYPE_LEAVE, MSG_TYPE_PING,
+    MSG_TYPE_PONG, MSG_TYPE_REACTION, MSG_TYPE_STATUS, MSG_TYPE_TYPING, PROTOCOL_VERSION,
+    TCP_CONNECT_TIMEOUT_MS, TCP_IDLE_TIMEOUT_SECS, TYPING_EXPIRY_SECS, UNKNOWN_PEER_NAME,
 };
-use crate::message::Message;
-use socket2::{Domain, Protocol, Socket, Type};
-use std::collections::{HashSet, VecDeque};
+use crate::crypto::{
+    decrypt_content, encrypt_content, is_encryption_enabled, sign_content, verify_content, Envelope,
+};
+use crate::debug_logger::{debug_log, log, Level};
+use crate::dedup::{RecentContent, RecentIds};
+use crate::delivery::DeliveryTracker;
+use crate::fragment::{self, fragment_bytes, fragment_packet, Fragment, Reassembler};
+use crate::history_sync::RequestLimiter;
+use crate::mdns;
+use crate::message::{join_fields, split_fields, splitter_of, Message};
+use crate::ping::PendingPings;
+use crate::protocol::{
+    parse_protocol_version, supports_authentication, supports_binary, supports_compression,
+    version_mismatch,
+};
+use crate::random::random_u64;
+use crate::rate_limit::RateLimiter;
+use crate::reactions::is_valid_reaction;
+use crate::stats;
+use crate::supervisor::{RecvErrors, Restarts};
+use crate::tcp;
+use crate::text::sanitize_remote;
+use crate::throttle::{SendThrottle, Throttled};
+use crate::transport::Transport;
+use crate::typing::TypingPeers;
+use crate::udp::{with_port, DualSocket};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
 use std::io;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
-use tokio::net::UdpSocket;
-use tokio::sync::mpsc::{self, Receiver as MpscReceiver, Sender as MpscSender};
-use tokio::time::sleep;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::{self, Receiver as EventReceiver, Sender as EventSender};
+use tokio::time;
+
+// What the listeners hand to the UI
+#[derive(Clone, Debug)]
+pub enum NetworkEvent {
+    Message(Message),
+    // A peer confirmed receiving the message with this ID
+    Ack {
+        id: u64,
+        from: IpAddr,
+    },
+    // A peer started or stopped typing
+    Typing,
+    // A peer answered a ping, echoing the time we sent it
+    Pong {
+        from: IpAddr,
+        sent: i64,
+    },
+    // A peer went away or came back
+    PeerStatus {
+        name: String,
+        away: bool,
+    },
+    // A peer we didn't know showed up, by name
+    PeerJoined(String),
+    // A peer quit, with the address it announced that from and its name
+    PeerLeft(SocketAddr, String),
+    // A peer changed or removed one of its messages. Only messages that came from the
+    // same node should be touched.
+    Edited {
+        id: u64,
+        node: String,
+        content: String,
+    },
+    Deleted {
+        id: u64,
+        node: String,
+    },
+    // A peer reacted to a message, which may be anyone's
+    Reacted {
+        id: u64,
+        node: String,
+        emoji: String,
+    },
+    // A peer asked for our most recent messages
+    HistoryRequest {
+        from: SocketAddr,
+        count: usize,
+    },
+    // One of the earlier messages a peer sent back when we asked, not seen before
+    History(Message),
+}
+
+// What we know about a peer, keyed by its address in the peer list
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    // Last username learned from the peer, "Unknown" until it tells us
+    pub name: String,
+    pub last_seen: Instant,
+    // Wire format version from the peer's discovery packets, None until one arrives
+    pub protocol_version: Option<u32>,
+    // The peer announced it's away after a while without input
+    pub away: bool,
+    // The node ID the peer sends with its packets, None until one arrives. One node heard
+    // from several addresses is kept as a single entry.
+    pub node: Option<String>,
+}
+
+impl PeerInfo {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            last_seen: Instant::now(),
+            protocol_version: None,
+            away: false,
+            node: None,
+        }
+    }
+}
+
+pub type PeerList = Arc<Mutex<HashMap<SocketAddr, PeerInfo>>>;
+
+// Longer than any node ID, or the IP address older peers put in the node field
+const MAX_NODE_FIELD_CHARS: usize = 45;
+
+// A random ID for this run of the client, sent in every packet so we can tell our own
+// packets apart when the broadcast address hands them back
+pub fn new_node_id() -> String {
+    format!("{:016x}", random_u64())
+}
+
+// Drops peers that haven't been heard from within max_age, returning how many were removed
+fn prune_peers(peers: &PeerList, max_age: Duration) -> usize {
+    let mut peers = peers.lock().unwrap();
+    let before = peers.len();
+    peers.retain(|_, info| info.last_seen.elapsed() <= max_age);
+    before - peers.len()
+}
+
+// Refreshes a peer's last-seen time and records its latest name, returning true if it's new.
+// An unknown name never replaces one we already learned. With the sender's node ID, entries
+// for the same node under other addresses are merged into this one, see absorb_node.
+fn record_peer(
+    peers: &mut HashMap<SocketAddr, PeerInfo>,
+    addr: SocketAddr,
+    name: &str,
+    node: Option<&str>,
+) -> bool {
+    let node = node.filter(|node| !node.is_empty());
+    let merged = node.is_some_and(|node| absorb_node(peers, addr, node));
+
+    let is_new = match peers.get_mut(&addr) {
+        Some(info) => {
+            info.last_seen = Instant::now();
+            if !name.is_empty() && name != UNKNOWN_PEER_NAME {
+                info.name = name.to_string();
+            }
+            false
+        }
+        None => {
+            let name = if name.is_empty() {
+                UNKNOWN_PEER_NAME
+            } else {
+                name
+            };
+            peers.insert(addr, PeerInfo::new(name));
+            true
+        }
+    };
+    if let (Some(node), Some(info)) = (node, peers.get_mut(&addr)) {
+        info.node = Some(node.to_string());
+    }
+    is_new && !merged
+}
+
+// Moves the entries for the node under other addresses onto addr, which the node was just
+// heard from. A peer reaches us from its discovery socket, its chat sockets and again after
+// restarting on another port, and each would otherwise be a peer of its own that messages
+// are sent to twice. Entries on the same IP that never learned a node, like ones added by
+// /connect or mDNS, are taken in too. Returns whether there were any.
+fn absorb_node(peers: &mut HashMap<SocketAddr, PeerInfo>, addr: SocketAddr, node: &str) -> bool {
+    let others: Vec<SocketAddr> = peers
+        .iter()
+        .filter(|(known, info)| {
+            **known != addr
+                && match &info.node {
+                    Some(known_node) => known_node == node,
+                    None => known.ip() == addr.ip(),
+                }
+        })
+        .map(|(known, _)| *known)
+        .collect();
+
+    for other in &others {
+        if let Some(info) = peers.remove(other) {
+            merge_peer(peers, addr, info);
+        }
+    }
+    !others.is_empty()
+}
+
+// Folds what's known about a peer into the entry at addr, keeping the most recent state.
+// Also used to bring the receiver's peers over to the broadcaster's list.
+pub fn merge_peer(peers: &mut HashMap<SocketAddr, PeerInfo>, addr: SocketAddr, info: PeerInfo) {
+    if let Some(node) = &info.node {
+        absorb_node(peers, addr, node);
+    }
+    let entry = peers.entry(addr).or_insert_with(|| info.clone());
+    if info.last_seen > entry.last_seen {
+        entry.last_seen = info.last_seen;
+        entry.away = info.away;
+        if info.name != UNKNOWN_PEER_NAME {
+            entry.name = info.name;
+        }
+    }
+    entry.protocol_version = entry.protocol_version.or(info.protocol_version);
+    entry.node = entry.node.take().or(info.node);
+}
+
+// Refreshes every entry for the heartbeat's sender. Heartbeats come from a fresh port each
+// time, so they match known peers by IP and only add an entry for a host we haven't seen.
+fn touch_peer(
+    peers: &mut HashMap<SocketAddr, PeerInfo>,
+    addr: SocketAddr,
+    name: &str,
+    node: &str,
+) -> bool {
+    let mut found = false;
+    for (_, info) in peers
+        .iter_mut()
+        .filter(|(known, _)| known.ip() == addr.ip())
+    {
+        info.last_seen = Instant::now();
+        if !name.is_empty() && name != UNKNOWN_PEER_NAME {
+            info.name = name.to_string();
+        }
+        found = true;
+    }
+
+    !found && record_peer(peers, addr, name, Some(node))
+}
+
+// Marks every entry for the host away or back, returning whether any of them changed.
+// Hosts we don't know yet are left for discovery to add.
+fn set_away(peers: &mut HashMap<SocketAddr, PeerInfo>, ip: IpAddr, away: bool) -> bool {
+    let mut changed = false;
+    for (_, info) in peers.iter_mut().filter(|(known, _)| known.ip() == ip) {
+        changed |= info.away != away;
+        info.away = away;
+    }
+    changed
+}
+
+// Removes the entries for a peer that's leaving, returning how many there were. Entries for
+// its IP under another name belong to someone else on the same host and are kept.
+fn forget_peer(peers: &mut HashMap<SocketAddr, PeerInfo>, addr: SocketAddr, name: &str) -> usize {
+    let before = peers.len();
+    peers.retain(|known, info| {
+        known.ip() != addr.ip() || (info.name != name && info.name != UNKNOWN_PEER_NAME)
+    });
+    before - peers.len()
+}
+
+// Records the version on every entry for the sender's IP, since discovery packets come from
+// a different port than its chat messages. Returns true if the version is new for the host.
+fn record_protocol_version(
+    peers: &mut HashMap<SocketAddr, PeerInfo>,
+    addr: SocketAddr,
+    version: u32,
+) -> bool {
+    let mut changed = false;
+    for (_, info) in peers
+        .iter_mut()
+        .filter(|(known, _)| known.ip() == addr.ip())
+    {
+        changed |= info.protocol_version != Some(version);
+        info.protocol_version = Some(version);
+    }
+    changed
+}
+
+// The peer's version if we've learned it, looked up by address and then by IP
+fn protocol_version_of(peers: &PeerList, addr: SocketAddr) -> Option<u32> {
+    let peers = peers.lock().unwrap();
+    peers
+        .get(&addr)
+        .and_then(|info| info.protocol_version)
+        .or_else(|| {
+            peers
+                .iter()
+                .filter(|(known, _)| known.ip() == addr.ip())
+                .find_map(|(_, info)| info.protocol_version)
+        })
+}
+
+// The fields every discovery packet carries
+fn discovery_packet(msg_type: &str, username: &str, node_id: &str) -> String {
+    join_fields(&[msg_type, username, node_id, &PROTOCOL_VERSION.to_string()])
+}
+
+// A packet laid out like a chat message, naming an earlier message by its ID. The content
+// is sealed and tagged like chat content.
+fn about_packet(msg_type: &str, username: &str, node_id: &str, id: u64, content: &str) -> String {
+    let timestamp = chrono::Utc::now().timestamp_millis();
+    let envelope = Envelope {
+        msg_type,
+        id,
+        node: node_id,
+        name: username,
+        timestamp,
+        recipient: None,
+    };
+    let content = sign_content(&envelope, &encrypt_content(content));
+    join_fields(&[
+        msg_type,
+        username,
+        node_id,
+        &id.to_string(),
+        &timestamp.to_string(),
+        &content,
+    ])
+}
+
+// A small packet about ourselves, like a ping or a typing update, whose content is sealed
+// and tagged too. It has no ID or timestamp of its own, so the tag covers them as zero.
+fn notice_packet(msg_type: &str, username: &str, node_id: &str, content: &str) -> String {
+    let envelope = Envelope {
+        msg_type,
+        id: 0,
+        node: node_id,
+        name: username,
+        timestamp: 0,
+        recipient: None,
+    };
+    let content = sign_content(&envelope, &encrypt_content(content));
+    join_fields(&[msg_type, username, node_id, &content])
+}
+
+// One earlier message sent in answer to a history request. It keeps its own ID, name and
+// timestamp, and is tagged under our node ID since we're the one vouching for it.
+fn history_packet(node_id: &str, message: &Message) -> String {
+    let envelope = Envelope {
+        msg_type: MSG_TYPE_HISTORY_RESP,
+        id: message.id(),
+        node: node_id,
+        name: message.sender_name(),
+        timestamp: message.timestamp(),
+        recipient: None,
+    };
+    let content = sign_content(&envelope, &encrypt_content(message.content()));
+    join_fields(&[
+        MSG_TYPE_HISTORY_RESP,
+        message.sender_name(),
+        node_id,
+        &message.id().to_string(),
+        &message.timestamp().to_string(),
+        &content,
+    ])
+}
+
+// Message types that carry something to show in the chat
+fn is_chat_type(msg_type: &str) -> bool {
+    msg_type == MSG_TYPE_CHAT || msg_type == MSG_TYPE_DM || msg_type == MSG_TYPE_ACTION
+}
+
+// Message types whose content must carry our tag when a passphrase is set, which is every
+// type on the chat port. Acks, deletions and notices are among them, or anyone could mark
+// messages delivered, remove them, or ask for and fake our history.
+fn is_authenticated_type(msg_type: &str) -> bool {
+    is_chat_type(msg_type)
+        || matches!(
+            msg_type,
+            MSG_TYPE_EDIT
+                | MSG_TYPE_REACTION
+                | MSG_TYPE_HISTORY_REQ
+                | MSG_TYPE_HISTORY_RESP
+                | MSG_TYPE_DELETE
+                | MSG_TYPE_ACK
+                | MSG_TYPE_TYPING
+                | MSG_TYPE_STATUS
+                | MSG_TYPE_PING
+                | MSG_TYPE_PONG
+        )
+}
+
+// Message types sent to the discovery port
+fn is_discovery_type(msg_type: &str) -> bool {
+    matches!(
+        msg_type,
+        MSG_TYPE_DISCOVERY | MSG_TYPE_DISCOVERY_RESPONSE | MSG_TYPE_HEARTBEAT | MSG_TYPE_LEAVE
+    )
+}
 
-type MessageQueue = Arc<Mutex<VecDeque<Message>>>;
-type PeerList = Arc<Mutex<HashSet<SocketAddr>>>;
+// Fewest fields a text packet of the type can have, None for types we don't know
+fn min_field_count(msg_type: &str) -> Option<usize> {
+    match msg_type {
+        // Legacy chat packets are type, name, ip, content
+        MSG_TYPE_CHAT | MSG_TYPE_ACTION => Some(4),
+        MSG_TYPE_DM => Some(7),
+        MSG_TYPE_EDIT | MSG_TYPE_REACTION | MSG_TYPE_HISTORY_RESP => Some(6),
+        MSG_TYPE_ACK | MSG_TYPE_DELETE | MSG_TYPE_HISTORY_REQ | MSG_TYPE_TYPING
+        | MSG_TYPE_STATUS | MSG_TYPE_PING | MSG_TYPE_PONG => Some(4),
+        // Peers from before versioning leave out the version
+        _ if is_discovery_type(msg_type) => Some(3),
+        _ => None,
+    }
+}
+
+// Binds a listening port and joins the configured multicast groups, turning the common
+// failures into an explanation of what to do
+fn bind_listener(
+    port: u16,
+    config: &Config,
+    purpose: &str,
+    option: &str,
+) -> Result<DualSocket, NetworkError> {
+    DualSocket::bind_multicast(
+        port,
+        config.bind_address,
+        Some(config.multicast_group_v4),
+        Some(config.multicast_group_v6),
+    )
+    .map_err(|e| listen_error(e, port, purpose, option))
+}
+
+// A socket on any free port, for sending, on the bind address when there is one
+fn bind_sender(bind_address: Option<IpAddr>) -> Result<DualSocket, NetworkError> {
+    DualSocket::bind(0, bind_address).map_err(|e| {
+        NetworkError::Bind(io::Error::new(
+            e.kind(),
+            format!("Could not open a socket to send from: {}", e),
+        ))
+    })
+}
+
+fn listen_error(e: io::Error, port: u16, purpose: &str, option: &str) -> NetworkError {
+    let reason = match e.kind() {
+        io::ErrorKind::AddrInUse => format!(
+            "the port is already in use, perhaps by another instance. \
+                 Choose a free one with {} <PORT>",
+            option
+        ),
+        io::ErrorKind::PermissionDenied => format!(
+            "permission denied. Ports below 1024 usually need elevated privileges, \
+                 choose another with {} <PORT>",
+            option
+        ),
+        _ => e.to_string(),
+    };
+    NetworkError::Bind(io::Error::new(
+        e.kind(),
+        format!(
+            "Could not listen for {} on port {}: {}",
+            purpose, port, reason
+        ),
+    ))
+}
+
+// Address and last-known username of every peer, sorted by name
+fn peer_names(peers: &PeerList) -> Vec<(SocketAddr, String)> {
+    let mut names: Vec<(SocketAddr, String)> = peers
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(addr, info)| (*addr, info.name.clone()))
+        .collect();
+    names.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(&b.0)));
+    names
+}
+
+// Datagrams carrying the message, in the binary format or the text one older peers read.
+// Compressing, which needs the binary format, happens before fragmenting.
+fn message_packets(message: &Message, binary: bool, compress: bool) -> io::Result<Vec<Vec<u8>>> {
+    if !binary {
+        return text_packets(&message.encode_packet(), message.id(), false);
+    }
+    let packet = codec::encode(message);
+    let packet = compress
+        .then(|| codec::compress_packet(&packet))
+        .flatten()
+        .unwrap_or(packet);
+    fragment_bytes(&packet, message.id()).ok_or_else(oversized)
+}
+
+// Datagrams carrying a text packet, like a history entry or an edit, compressed when asked
+// and fragmented like chat messages when it's too big for one
+fn text_packets(packet: &str, id: u64, compress: bool) -> io::Result<Vec<Vec<u8>>> {
+    let packets = match compress
+        .then(|| codec::compress_packet(packet.as_bytes()))
+        .flatten()
+    {
+        Some(packet) => fragment_bytes(&packet, id),
+        None => fragment_packet(packet, id)
+            .map(|packets| packets.into_iter().map(String::into_bytes).collect()),
+    };
+    packets.ok_or_else(oversized)
+}
+
+// The mDNS daemon couldn't start or take our service, which retrying won't fix
+fn mdns_error(e: mdns_sd::Error) -> NetworkError {
+    NetworkError::Bind(io::Error::other(e))
+}
+
+fn oversized() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("message is larger than {} bytes", MAX_MESSAGE_SIZE),
+    )
+}
+
+async fn send_packets<T: Transport>(
+    socket: &T,
+    packets: &[Vec<u8>],
+    target: SocketAddr,
+) -> io::Result<()> {
+    for packet in packets {
+        let sent = socket.send_to(packet, target).await?;
+        stats::record_bytes_sent(sent);
+    }
+    Ok(())
+}
 
 pub struct Broadcaster {
     peers: PeerList,
     chat_port: u16,
+    discovery_port: u16,
+    // Messages go to known peers over TCP first when set, UDP is the fallback
+    tcp: bool,
+    tcp_port: u16,
+    broadcast_address: Ipv4Addr,
+    multicast_group_v4: Ipv4Addr,
+    multicast_group_v6: Ipv6Addr,
+    // Second octets of the 100.x.y.2 addresses swept for Tailscale peers
+    tailscale_sweep: RangeInclusive<u8>,
+    // Our sockets are bound to this address alone when set
+    bind_address: Option<IpAddr>,
     username: Arc<Mutex<String>>,
+    node_id: String,
+    // Sent messages still waiting for an ack
+    pending: Arc<Mutex<DeliveryTracker>>,
+    // Pings still waiting for a pong
+    pings: Arc<Mutex<PendingPings>>,
+    // Spaces out the messages we broadcast
+    throttle: Arc<Mutex<SendThrottle>>,
+    // Who we answered history requests from lately
+    history_requests: Arc<Mutex<RequestLimiter>>,
+    // Copied by discovery_service, which does the backing off
+    discovery_backoff: DiscoveryBackoff,
 }
 
 impl Clone for Broadcaster {
@@ -27,17 +564,56 @@ impl Clone for Broadcaster {
         Self {
             peers: self.peers.clone(),
             chat_port: self.chat_port,
+            discovery_port: self.discovery_port,
+            tcp: self.tcp,
+            tcp_port: self.tcp_port,
+            broadcast_address: self.broadcast_address,
+            multicast_group_v4: self.multicast_group_v4,
+            multicast_group_v6: self.multicast_group_v6,
+            tailscale_sweep: self.tailscale_sweep.clone(),
+            bind_address: self.bind_address,
             username: self.username.clone(),
+            node_id: self.node_id.clone(),
+            pending: self.pending.clone(),
+            pings: self.pings.clone(),
+            throttle: self.throttle.clone(),
+            history_requests: self.history_requests.clone(),
+            discovery_backoff: self.discovery_backoff.clone(),
         }
     }
 }
 
 impl Broadcaster {
-    pub fn new(chat_port: u16, username: String) -> Self {
+    pub fn new(config: &Config, username: String, node_id: String) -> Self {
         Self {
-            peers: Arc::new(Mutex::new(HashSet::new())),
-            chat_port,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            chat_port: config.chat_port,
+            discovery_port: config.discovery_port,
+            tcp: config.tcp,
+            tcp_port: config.tcp_port,
+            broadcast_address: config.broadcast_address,
+            multicast_group_v4: config.multicast_group_v4,
+            multicast_group_v6: config.multicast_group_v6,
+            bind_address: config.bind_address,
+            tailscale_sweep: config.tailscale_sweep_first..=config.tailscale_sweep_last,
             username: Arc::new(Mutex::new(username)),
+            node_id,
+            pending: Arc::new(Mutex::new(DeliveryTracker::new(
+                config.max_retransmits,
+                Duration::from_millis(config.retransmit_delay_ms),
+            ))),
+            pings: Arc::new(Mutex::new(PendingPings::new())),
+            throttle: Arc::new(Mutex::new(SendThrottle::new(Duration::from_millis(
+                config.min_send_interval_ms,
+            )))),
+            history_requests: Arc::new(Mutex::new(RequestLimiter::new(Duration::from_secs(
+                HISTORY_REQUEST_INTERVAL_SECS,
+            )))),
+            discovery_backoff: DiscoveryBackoff::new(
+                Duration::from_secs(config.discovery_min_interval_secs),
+                Duration::from_secs(config.discovery_interval_secs),
+                Duration::from_secs(config.discovery_max_interval_secs),
+            ),
         }
     }
 
@@ -47,391 +623,2854 @@ impl Broadcaster {
         *username = new_username;
     }
 
+    // Goes in the node field of the messages we send
+    pub fn node_id(&self) -> &str {
+        &self.node_id
+    }
+
+    // Replaces the peer list, e.g. with one restored from the peer cache
+    pub fn with_peers(mut self, peers: PeerList) -> Self {
+        self.peers = peers;
+        self
+    }
+
     pub fn get_peers(&self) -> PeerList {
         self.peers.clone()
     }
 
-    pub async fn discover_peers(&self) -> io::Result<()> {
-        // Create a socket for discovery
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        socket.set_broadcast(true)?;
-        socket.set_reuse_address(true)?;
+    pub fn prune_stale_peers(&self, max_age: Duration) -> usize {
+        prune_peers(&self.peers, max_age)
+    }
 
-        // Bind to any available port
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-        socket.bind(&addr.into())?;
+    pub fn peer_protocol_version(&self, addr: SocketAddr) -> Option<u32> {
+        protocol_version_of(&self.peers, addr)
+    }
 
-        // Convert to tokio UDP socket
-        let discovery_socket = UdpSocket::from_std(socket.into())?;
+    // Drops a peer that announced it's leaving, see forget_peer
+    pub fn forget_peer(&self, addr: SocketAddr, name: &str) -> usize {
+        forget_peer(&mut self.peers.lock().unwrap(), addr, name)
+    }
 
-        // Send discovery broadcast
-        let username = self.username.lock().unwrap().clone();
-        let discovery_msg = format!(
-            "{}{}{}{}None",
-            MSG_TYPE_DISCOVERY, FIELD_SPLITTER, username, FIELD_SPLITTER
-        );
+    // Starts waiting for acks for a message we sent, from the one peer it went to or from
+    // every known peer when it was broadcast
+    pub fn track_pending(&self, message: &Message, to: Option<SocketAddr>) {
+        let peers = match to {
+            Some(peer) => vec![peer],
+            None => {
+                let mut peers: Vec<SocketAddr> = Vec::new();
+                for peer in self.peers.lock().unwrap().keys() {
+                    if !peers.iter().any(|known| known.ip() == peer.ip()) {
+                        peers.push(*peer);
+                    }
+                }
+                peers
+            }
+        };
+        self.pending
+            .lock()
+            .unwrap()
+            .track(message.clone(), peers, Instant::now());
+    }
+
+    // Returns true if the ack is the first one for a message we're waiting on
+    pub fn confirm_delivery(&self, id: u64, from: IpAddr) -> bool {
+        self.pending.lock().unwrap().acknowledge(id, from)
+    }
+
+    // Stops waiting for acks for a message that couldn't be sent
+    pub fn forget_pending(&self, id: u64) {
+        self.pending.lock().unwrap().forget(id);
+    }
 
-        // Broadcast to local subnet
-        let broadcast_addr =
-            SocketAddr::new(BROADCAST_ADDR.parse::<IpAddr>().unwrap(), DISCOVERY_PORT);
+    // When a message with this text may be broadcast, see SendThrottle
+    pub fn schedule_send(&self, text: &str) -> Throttled {
+        self.throttle.lock().unwrap().schedule(text, Instant::now())
+    }
+
+    // Sends messages again to the peers that haven't acked them yet, in the background.
+    // Returns the IDs of the messages given up on without a single ack.
+    pub fn retransmit_pending(&self) -> Vec<u64> {
+        let update = self.pending.lock().unwrap().poll(Instant::now());
+        for (message, peers) in update.retransmit {
+            let broadcaster = self.clone();
+            tokio::spawn(async move {
+                for peer in peers {
+                    debug_log(&format!(
+                        "Resending message {} to {}",
+                        message.id(),
+                        peer.ip()
+                    ));
+                    if let Err(e) = broadcaster.send_to_peer(&message, peer).await {
+                        debug_log(&format!("Failed to resend to {}: {}", peer.ip(), e));
+                    }
+                }
+            });
+        }
+        update.failed
+    }
 
-        // Send to local broadcast
-        let _ = discovery_socket
-            .send_to(discovery_msg.as_bytes(), broadcast_addr)
-            .await;
+    // Writes the known peer addresses as a JSON array of strings
+    pub fn save_peers(&self, path: &Path) -> io::Result<()> {
+        let mut addrs: Vec<SocketAddr> = self.peers.lock().unwrap().keys().copied().collect();
+        addrs.sort();
 
-        // Also try Tailscale subnet broadcast address
-        if let Ok(tailscale_addr) = TAILSCALE_MULTICAST.parse::<IpAddr>() {
-            let tailscale_broadcast = SocketAddr::new(tailscale_addr, DISCOVERY_PORT);
-            let _ = discovery_socket
-                .send_to(discovery_msg.as_bytes(), tailscale_broadcast)
-                .await;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
         }
+        fs::write(path, format!("{}\n", serde_json::to_string(&addrs)?))
+    }
+
+    // Loads a peer list saved by save_peers. A missing or corrupt file gives an empty list.
+    pub fn load_peers(path: &Path) -> PeerList {
+        let peers = fs::read_to_string(path)
+            .ok()
+            .and_then(|contents| serde_json::from_str::<Vec<SocketAddr>>(&contents).ok())
+            .unwrap_or_default();
+
+        // Restored peers get a fresh last-seen time so they survive until the next expiry pass
+        Arc::new(Mutex::new(
+            peers
+                .into_iter()
+                .map(|addr| (addr, PeerInfo::new(UNKNOWN_PEER_NAME)))
+                .collect(),
+        ))
+    }
 
+    pub async fn discover_peers(&self) -> Result<(), NetworkError> {
+        // Create a socket for discovery, bound to any available port
+        let discovery_socket = bind_sender(self.bind_address)?;
+        self.send_discovery_from(&discovery_socket).await;
         Ok(())
     }
 
-    // This runs discovery periodically
-    pub async fn discovery_service(broadcaster: Arc<Broadcaster>) -> io::Result<()> {
-        loop {
-            if let Err(e) = broadcaster.discover_peers().await {
-                eprintln!("Peer discovery error: {}", e);
-            }
+    // Broadcasts a discovery request from the given socket, which is where the responses
+    // come back to
+    pub async fn send_discovery_from(&self, socket: &DualSocket) {
+        let username = self.username.lock().unwrap().clone();
+        let discovery_msg = discovery_packet(MSG_TYPE_DISCOVERY, &username, &self.node_id);
 
-            // Run discovery more frequently (every 15 seconds)
-            // This helps with more reliable peer discovery
-            sleep(Duration::from_secs(15)).await;
+        for target in self.group_targets(self.discovery_port) {
+            let _ = socket.send_to(discovery_msg.as_bytes(), target).await;
         }
     }
 
-    pub async fn broadcast_message(&self, message: Message) -> io::Result<()> {
-        // Create a socket for sending message
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        socket.set_broadcast(true)?;
-        socket.set_reuse_address(true)?;
+    // Local broadcast address plus the multicast group of each family
+    fn group_targets(&self, port: u16) -> Vec<SocketAddr> {
+        vec![
+            SocketAddr::new(IpAddr::V4(self.broadcast_address), port),
+            SocketAddr::new(IpAddr::V4(self.multicast_group_v4), port),
+            SocketAddr::new(IpAddr::V6(self.multicast_group_v6), port),
+        ]
+    }
 
-        // Bind to any available port
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
-        socket.bind(&addr.into())?;
+    // Announces our presence to the subnet and to every known peer. It's a single small
+    // datagram per target, sent on the discovery port.
+    pub async fn send_heartbeat(&self) -> Result<(), NetworkError> {
+        let username = self.username.lock().unwrap().clone();
+        let heartbeat = discovery_packet(MSG_TYPE_HEARTBEAT, &username, &self.node_id);
+        self.send_to_subnet_and_peers(&heartbeat).await
+    }
 
-        // Convert to tokio UDP socket
-        let udp_socket = UdpSocket::from_std(socket.into())?;
+    // Tells the subnet and every known peer that we're quitting
+    pub async fn announce_leaving(&self) -> Result<(), NetworkError> {
+        let username = self.username.lock().unwrap().clone();
+        let leave = discovery_packet(MSG_TYPE_LEAVE, &username, &self.node_id);
+        self.send_to_subnet_and_peers(&leave).await
+    }
 
-        // Format message with type
-        let encoded_message = format!(
-            "{}{}{}",
-            MSG_TYPE_CHAT,
-            FIELD_SPLITTER,
-            message.encode_for_broadcast()
+    // Sends a packet to the discovery port of the local network and of each known peer
+    async fn send_to_subnet_and_peers(&self, packet: &str) -> Result<(), NetworkError> {
+        let socket = bind_sender(self.bind_address)?;
+        let mut targets = self.group_targets(self.discovery_port);
+        let mut peer_ips: Vec<IpAddr> = self
+            .peers
+            .lock()
+            .unwrap()
+            .keys()
+            .map(SocketAddr::ip)
+            .collect();
+        peer_ips.sort();
+        peer_ips.dedup();
+        targets.extend(
+            peer_ips
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, self.discovery_port)),
         );
 
-        let peers = self.peers.lock().unwrap().clone();
+        for target in targets {
+            let _ = socket.send_to(packet.as_bytes(), target).await;
+        }
 
-        // Always send to known peers if we have any
-        if !peers.is_empty() {
-            // Send to each known peer
-            for peer_addr in &peers {
-                let target_addr = SocketAddr::new(peer_addr.ip(), self.chat_port);
-
-                match udp_socket
-                    .send_to(encoded_message.as_bytes(), target_addr)
-                    .await
-                {
-                    Ok(_) => {}
-                    Err(e) => {
-                        eprintln!("Failed to send to {}: {}", target_addr, e);
+        Ok(())
+    }
+
+    // Sends discovery requests on the backoff schedule, plus heartbeats at a fixed rate. A
+    // reconnect sends a request right away and starts backing off over again, as the peers
+    // on a new network haven't heard from us yet.
+    pub async fn discovery_service(
+        broadcaster: Arc<Broadcaster>,
+        restarts: Restarts,
+    ) -> Result<(), NetworkError> {
+        let mut backoff = broadcaster.discovery_backoff.clone();
+        let mut heartbeat = time::interval(Duration::from_secs(HEARTBEAT_INTERVAL_SECS));
+        // The first request goes out right away
+        let next_discovery = time::sleep(Duration::ZERO);
+        tokio::pin!(next_discovery);
+
+        loop {
+            tokio::select! {
+                _ = &mut next_discovery => {
+                    if let Err(e) = broadcaster.discover_peers().await {
+                        log(Level::Warn, &format!("Peer discovery error: {}", e));
+                    }
+                    let interval = backoff.next_interval(broadcaster.has_peers());
+                    debug_log(&format!("Next discovery in {:.1}s", interval.as_secs_f64()));
+                    next_discovery.as_mut().reset(time::Instant::now() + interval);
+                }
+                _ = heartbeat.tick() => {
+                    if let Err(e) = broadcaster.send_heartbeat().await {
+                        debug_log(&format!("Heartbeat error: {}", e));
                     }
+                    // Start looking hard again as soon as everyone is gone
+                    if backoff.lost_peers(broadcaster.has_peers()) {
+                        let interval = backoff.next_interval(false);
+                        next_discovery.as_mut().reset(time::Instant::now() + interval);
+                    }
+                }
+                _ = restarts.requested() => {
+                    backoff = broadcaster.discovery_backoff.clone();
+                    next_discovery.as_mut().reset(time::Instant::now());
                 }
             }
         }
+    }
 
-        // Always try local broadcast (will work on local networks)
-        let broadcast_addr =
-            SocketAddr::new(BROADCAST_ADDR.parse::<IpAddr>().unwrap(), self.chat_port);
-        let _ = udp_socket
-            .send_to(encoded_message.as_bytes(), broadcast_addr)
-            .await;
+    fn has_peers(&self) -> bool {
+        !self.peers.lock().unwrap().is_empty()
+    }
 
-        // Try to send to all Tailscale IPs in the 100.x.y.z range
-        // This is a brute force approach but will work for small networks
-        println!("[DEBUG] Broadcasting to Tailscale network...");
-        let mut tailscale_sent = 0;
-        let mut tailscale_errors = 0;
-        for a in 64..128 {
-            // Typical Tailscale range
-            for b in 0..255 {
-                let tailscale_ip = format!("100.{}.{}.2", a, b);
-                if let Ok(ts_addr) = tailscale_ip.parse::<IpAddr>() {
-                    let target = SocketAddr::new(ts_addr, self.chat_port);
-                    match udp_socket.send_to(encoded_message.as_bytes(), target).await {
-                        Ok(_) => tailscale_sent += 1,
-                        Err(_) => tailscale_errors += 1,
-                    }
-                }
-            }
+    // Finds a known peer by IP address (optionally with port) or by username
+    pub fn resolve_peer(&self, target: &str) -> Option<(SocketAddr, PeerInfo)> {
+        let peers = self.peers.lock().unwrap();
+
+        if let Ok(addr) = target.parse::<SocketAddr>() {
+            return peers.get(&addr).map(|info| (addr, info.clone()));
         }
-        println!(
-            "[DEBUG] Tailscale broadcast complete: sent to {} addresses, {} errors",
-            tailscale_sent, tailscale_errors
+
+        let found = match target.parse::<IpAddr>() {
+            Ok(ip) => peers.iter().find(|(addr, _)| addr.ip() == ip),
+            Err(_) => peers
+                .iter()
+                .filter(|(_, info)| info.name.eq_ignore_ascii_case(target))
+                .max_by_key(|(_, info)| info.last_seen),
+        };
+
+        found.map(|(addr, info)| (*addr, info.clone()))
+    }
+
+    // Adds a peer by address, for networks where discovery can't find it, and sends it a
+    // discovery request so it adds us too. The port defaults to the chat port. Returns
+    // whether the peer was new.
+    pub async fn connect(&self, ip: IpAddr, port: Option<u16>) -> Result<bool, NetworkError> {
+        let addr = SocketAddr::new(ip, port.unwrap_or(self.chat_port));
+        let socket = bind_sender(self.bind_address)?;
+        let username = self.username.lock().unwrap().clone();
+        let request = discovery_packet(MSG_TYPE_DISCOVERY, &username, &self.node_id);
+        socket
+            .send_to(request.as_bytes(), with_port(addr, self.discovery_port))
+            .await?;
+
+        Ok(record_peer(
+            &mut self.peers.lock().unwrap(),
+            addr,
+            UNKNOWN_PEER_NAME,
+            None,
+        ))
+    }
+
+    // Sends a ping to the peer's chat port, stamped with the time so its pong gives the
+    // round trip
+    pub async fn send_ping(&self, peer: SocketAddr, name: &str) -> Result<(), NetworkError> {
+        let socket = bind_sender(self.bind_address)?;
+        let username = self.username.lock().unwrap().clone();
+        let sent_ms = chrono::Utc::now().timestamp_millis();
+        let packet = notice_packet(
+            MSG_TYPE_PING,
+            &username,
+            &self.node_id,
+            &sent_ms.to_string(),
         );
 
+        self.pings.lock().unwrap().track(peer.ip(), name, sent_ms);
+        let sent = socket
+            .send_to(packet.as_bytes(), with_port(peer, self.chat_port))
+            .await?;
+        stats::record_bytes_sent(sent);
         Ok(())
     }
-}
 
-pub struct Receiver {
-    message_queue: MessageQueue,
-    message_sender: MpscSender<Message>,
-    message_receiver: Option<MpscReceiver<Message>>,
-    peers: PeerList,
-    username: Arc<Mutex<String>>,
-}
+    // The name of the peer whose ping this pong answers, None if it doesn't answer one
+    pub fn answer_ping(&self, from: IpAddr, sent_ms: i64) -> Option<String> {
+        self.pings.lock().unwrap().answer(from, sent_ms)
+    }
 
-impl Receiver {
-    pub fn new(_chat_port: u16, username: String) -> Self {
-        let message_queue = Arc::new(Mutex::new(VecDeque::new()));
-        let (tx, rx) = mpsc::channel(100);
+    // Stops waiting for pongs to pings older than max_age, returning the peers' names
+    pub fn expire_pings(&self, max_age: Duration) -> Vec<String> {
+        self.pings
+            .lock()
+            .unwrap()
+            .expire(chrono::Utc::now().timestamp_millis(), max_age)
+    }
 
-        Self {
-            message_queue,
-            message_sender: tx,
-            message_receiver: Some(rx),
-            peers: Arc::new(Mutex::new(HashSet::new())),
-            username: Arc::new(Mutex::new(username)),
-        }
+    // Tells known peers and the local network whether we're composing a message
+    pub async fn send_typing(&self, typing: bool) -> Result<(), NetworkError> {
+        let state = if typing { "1" } else { "0" };
+        self.send_presence(MSG_TYPE_TYPING, state).await
     }
 
-    pub fn get_peers(&self) -> PeerList {
-        self.peers.clone()
+    // Tells known peers and the local network whether we've gone away
+    pub async fn send_status(&self, away: bool) -> Result<(), NetworkError> {
+        self.send_presence(MSG_TYPE_STATUS, away::status_content(away))
+            .await
     }
 
-    #[allow(dead_code)]
-    pub fn update_username(&self, new_username: String) {
-        let mut username = self.username.lock().unwrap();
-        *username = new_username;
+    // Replaces the content of one of our messages for everyone
+    pub async fn send_edit(&self, id: u64, content: &str) -> Result<(), NetworkError> {
+        self.send_about_message(MSG_TYPE_EDIT, id, content).await
     }
 
-    pub fn parse_message(udp_data: &str) -> (String, String, String, String) {
-        if udp_data.is_empty() {
-            return (
-                String::new(),
-                "Unknown".to_string(),
-                "".to_string(),
-                "Unknown".to_string(),
-            );
-        }
+    // Reacts to a message with an emoji for everyone
+    pub async fn send_reaction(&self, id: u64, emoji: &str) -> Result<(), NetworkError> {
+        self.send_about_message(MSG_TYPE_REACTION, id, emoji).await
+    }
+
+    // Sends a packet laid out like a chat message, naming an earlier message by its ID
+    async fn send_about_message(
+        &self,
+        msg_type: &str,
+        id: u64,
+        content: &str,
+    ) -> Result<(), NetworkError> {
+        let username = self.username.lock().unwrap().clone();
+        let packet = about_packet(msg_type, &username, &self.node_id, id, content);
+        self.send_to_chat_port(&packet).await
+    }
 
-        // Split by the field splitter
-        let parts: Vec<&str> = udp_data.split(FIELD_SPLITTER).collect();
+    // Asks a peer for its most recent messages
+    pub async fn request_history(
+        &self,
+        peer: SocketAddr,
+        count: usize,
+    ) -> Result<(), NetworkError> {
+        let username = self.username.lock().unwrap().clone();
+        let count = count.to_string();
+        let packet = notice_packet(MSG_TYPE_HISTORY_REQ, &username, &self.node_id, &count);
+        let socket = bind_sender(self.bind_address)?;
+        socket
+            .send_to(packet.as_bytes(), with_port(peer, self.chat_port))
+            .await?;
+        Ok(())
+    }
 
-        if parts.len() < 2 {
-            return (
-                String::new(),
-                "Unknown".to_string(),
-                udp_data.to_string(),
-                "Unknown".to_string(),
-            );
-        }
+    // Whether to answer a history request from this address, see RequestLimiter
+    pub fn allow_history_request(&self, from: IpAddr) -> bool {
+        self.history_requests
+            .lock()
+            .unwrap()
+            .allow(from, Instant::now())
+    }
 
-        let msg_type = parts[0].to_string();
+    // Sends a batch of earlier messages to the peer that asked for them
+    pub async fn send_history(
+        &self,
+        peer: SocketAddr,
+        messages: &[Message],
+    ) -> Result<(), NetworkError> {
+        let socket = bind_sender(self.bind_address)?;
+        self.send_history_over(&socket, peer, messages).await
+    }
 
-        if parts.len() < 4 {
-            return (
-                msg_type,
-                "Unknown".to_string(),
-                parts[1..].join(FIELD_SPLITTER),
-                "Unknown".to_string(),
-            );
+    // Sends the history through the given transport, each message on its own and split into
+    // fragments when it's too big for one datagram
+    pub async fn send_history_over<T: Transport>(
+        &self,
+        socket: &T,
+        peer: SocketAddr,
+        messages: &[Message],
+    ) -> Result<(), NetworkError> {
+        let target = with_port(peer, self.chat_port);
+        for message in messages {
+            let packet = history_packet(&self.node_id, message);
+            let packets = text_packets(&packet, message.id(), self.takes_compressed(peer))?;
+            send_packets(socket, &packets, target).await?;
         }
+        Ok(())
+    }
 
-        // For a standard chat message: MSG_TYPE, name, ip, content
-        (
-            msg_type,
-            parts[1].to_string(),            // sender name
-            parts[3..].join(FIELD_SPLITTER), // message content
-            parts[2].to_string(),            // sender IP
-        )
+    // Removes one of our messages for everyone. The ID is the content too, as peers from
+    // before deletions were tagged read it from there.
+    pub async fn send_delete(&self, id: u64) -> Result<(), NetworkError> {
+        self.send_about_message(MSG_TYPE_DELETE, id, &id.to_string())
+            .await
     }
 
-    pub async fn handle_discovery(
-        &self,
-        socket: &UdpSocket,
-        src: SocketAddr,
-        data: &str,
-    ) -> io::Result<()> {
-        let (msg_type, sender_name, _content, _) = Self::parse_message(data);
+    // Sends a small notice about ourselves to the chat port
+    async fn send_presence(&self, msg_type: &str, content: &str) -> Result<(), NetworkError> {
+        let username = self.username.lock().unwrap().clone();
+        let packet = notice_packet(msg_type, &username, &self.node_id, content);
+        self.send_to_chat_port(&packet).await
+    }
 
-        match msg_type.as_str() {
-            MSG_TYPE_DISCOVERY => {
-                // Someone is looking for peers, respond with our presence
-                println!(
-                    "[DEBUG] Received discovery request from {} ({})",
-                    sender_name,
-                    src.ip()
-                );
-                let username = self.username.lock().unwrap().clone();
-                let response = format!(
-                    "{}{}{}{}None",
-                    MSG_TYPE_DISCOVERY_RESPONSE, FIELD_SPLITTER, username, FIELD_SPLITTER
-                );
-                println!("[DEBUG] Sending discovery response to {}", src);
-                socket.send_to(response.as_bytes(), src).await?;
+    // Sends a text packet to the local network and every known peer, in fragments when it's
+    // too big for one datagram, like a long edit. Unlike chat messages this skips the
+    // Tailscale sweep, it's not worth that much traffic.
+    async fn send_to_chat_port(&self, packet: &str) -> Result<(), NetworkError> {
+        // Edits share their message's ID, so the fragments get one of their own
+        let packets = text_packets(packet, random_u64(), false)?;
+        let socket = bind_sender(self.bind_address)?;
+
+        let mut targets = self.group_targets(self.chat_port);
+        targets.extend(
+            self.peers
+                .lock()
+                .unwrap()
+                .keys()
+                .map(|peer| with_port(*peer, self.chat_port)),
+        );
 
-                // Add this peer to our list
-                let mut peers = self.peers.lock().unwrap();
-                let is_new = peers.insert(src);
-                let peer_count = peers.len();
-                if is_new {
-                    println!(
-                        "[DEBUG] Added new peer: {} ({}). Total peers: {}",
-                        sender_name,
-                        src.ip(),
-                        peer_count
-                    );
-                }
-            }
-            MSG_TYPE_DISCOVERY_RESPONSE => {
-                // Someone responded to our discovery request, add them to peers
-                let mut peers = self.peers.lock().unwrap();
-                let is_new = peers.insert(src);
-                let peer_count = peers.len();
-                println!(
-                    "[DEBUG] Discovered peer: {} ({}). New: {}. Total peers: {}",
-                    sender_name,
-                    src.ip(),
-                    is_new,
-                    peer_count
-                );
-            }
-            _ => {
-                println!("[DEBUG] Received unknown message type: {}", msg_type);
-            } // Log unknown message types
+        for target in targets {
+            let _ = send_packets(&socket, &packets, target).await;
         }
 
         Ok(())
     }
 
-    pub async fn listen_for_discovery(&self, discovery_port: u16) -> io::Result<()> {
-        // Setup a socket with proper configuration using socket2
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        socket.set_reuse_address(true)?;
-        socket.set_broadcast(true)?;
-
-        // Enabling re-use port for better results across platforms
-        #[cfg(not(windows))]
-        socket.set_reuse_port(true)?;
-
-        // Bind to the discovery port
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), discovery_port);
-        socket.bind(&addr.into())?;
+    // Sends the whole message to the peer's TCP port in one frame
+    pub async fn send_tcp(&self, peer: SocketAddr, message: &Message) -> Result<(), NetworkError> {
+        let target_addr = with_port(peer, self.tcp_port);
+        let timeout = Duration::from_millis(TCP_CONNECT_TIMEOUT_MS);
+        let mut stream = tcp::connect(target_addr, timeout).await?;
+
+        let packet = if self.speaks_binary(peer) {
+            codec::encode(message)
+        } else {
+            message.encode_packet().into_bytes()
+        };
+        let packet = self.compressed_for(peer, packet);
+        tcp::write_frame(&mut stream, &packet).await?;
+        stats::record_bytes_sent(packet.len());
+        Ok(())
+    }
 
-        // Convert to std socket
-        let std_socket = socket.into();
+    fn speaks_binary(&self, peer: SocketAddr) -> bool {
+        supports_binary(protocol_version_of(&self.peers, peer))
+    }
 
-        // Convert to tokio UDP socket
-        let udp_socket = UdpSocket::from_std(std_socket)?;
+    fn takes_compressed(&self, peer: SocketAddr) -> bool {
+        supports_compression(protocol_version_of(&self.peers, peer))
+    }
 
-        // Join multicast group if possible (for Tailscale compatibility)
-        if let Ok(IpAddr::V4(multicast_v4)) = TAILSCALE_MULTICAST.parse::<IpAddr>() {
-            // Try to join multicast group, ignore errors since this is just for better discovery
-            let _ = udp_socket.join_multicast_v4(multicast_v4, Ipv4Addr::UNSPECIFIED);
+    // The packet compressed when the peer understands it and it's worth compressing
+    fn compressed_for(&self, peer: SocketAddr, packet: Vec<u8>) -> Vec<u8> {
+        if !self.takes_compressed(peer) {
+            return packet;
         }
+        codec::compress_packet(&packet).unwrap_or(packet)
+    }
 
-        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
-
-        // Continuously listen for discovery messages
-        loop {
-            let (size, src) = udp_socket.recv_from(&mut buf).await?;
-            let data = String::from_utf8_lossy(&buf[..size]).to_string();
-
-            if let Err(e) = self.handle_discovery(&udp_socket, src, &data).await {
-                eprintln!("Error handling discovery: {}", e);
+    // Tries TCP when it's enabled, reporting whether the message went out that way
+    async fn try_send_tcp(&self, peer: SocketAddr, message: &Message) -> bool {
+        if !self.tcp {
+            return false;
+        }
+        match self.send_tcp(peer, message).await {
+            Ok(()) => true,
+            Err(e) => {
+                debug_log(&format!("Falling back to UDP for {}: {}", peer.ip(), e));
+                false
             }
         }
     }
 
-    pub async fn listen_for_messages(&mut self, chat_port: u16) -> io::Result<()> {
-        // Setup a socket with proper configuration using socket2
-        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
-        socket.set_reuse_address(true)?;
-        socket.set_broadcast(true)?;
-
-        // Enabling re-use port for better results across platforms
-        #[cfg(not(windows))]
-        socket.set_reuse_port(true)?;
+    // Sends a message to a single peer instead of the whole subnet
+    pub async fn send_direct(
+        &self,
+        message: Message,
+        peer: SocketAddr,
+    ) -> Result<(), NetworkError> {
+        self.send_to_peer(&message, peer).await?;
+        stats::record_message_sent();
+        Ok(())
+    }
 
-        // Bind to the chat port
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), chat_port);
-        socket.bind(&addr.into())?;
+    // Sends over TCP when it's on and the peer takes the connection, over UDP otherwise
+    async fn send_to_peer(&self, message: &Message, peer: SocketAddr) -> Result<(), NetworkError> {
+        if self.try_send_tcp(peer, message).await {
+            return Ok(());
+        }
 
-        // Convert to std socket
-        let std_socket = socket.into();
+        // Bind to any available port
+        let udp_socket = bind_sender(self.bind_address)?;
+
+        let packets = message_packets(
+            message,
+            self.speaks_binary(peer),
+            self.takes_compressed(peer),
+        )?;
+        let target_addr = with_port(peer, self.chat_port);
+        send_packets(&udp_socket, &packets, target_addr).await?;
+        Ok(())
+    }
 
-        // Convert to tokio UDP socket
-        let udp_socket = UdpSocket::from_std(std_socket)?;
+    // A socket for sending messages, bound to any available port
+    pub fn bind_sender(&self) -> Result<DualSocket, NetworkError> {
+        bind_sender(self.bind_address)
+    }
 
-        // Join multicast group if possible (for Tailscale compatibility)
-        if let Ok(IpAddr::V4(multicast_v4)) = TAILSCALE_MULTICAST.parse::<IpAddr>() {
-            // Try to join multicast group, ignore errors since this is just for better discovery
-            let _ = udp_socket.join_multicast_v4(multicast_v4, Ipv4Addr::UNSPECIFIED);
-        }
+    // Sends a message to everyone through the given transport. TCP is still tried first for
+    // known peers when it's enabled. Fails only when not a single address could be sent to.
+    pub async fn broadcast_message_over<T: Transport>(
+        &self,
+        udp_socket: &T,
+        message: Message,
+    ) -> Result<(), NetworkError> {
+        // Encode in both formats, split into fragments if it's too big for one datagram
+        let binary_packets = message_packets(&message, true, false)?;
+        let text_packets = message_packets(&message, false, false)?;
+        let compressed_packets = message_packets(&message, true, true)?;
+        // The broadcast and multicast groups get both, since we don't know who's there.
+        // Peers that understand the binary format drop the second copy as a duplicate.
+        let packets = [binary_packets.as_slice(), text_packets.as_slice()].concat();
+        stats::record_message_sent();
 
-        let mut buf = vec![0u8; RECV_BUFFER_SIZE];
-        let mut rx = self.message_receiver.take().unwrap();
+        let peers = self.peers.lock().unwrap().clone();
+        let mut sent_any = false;
+        let mut last_error = None;
 
-        // Spawn a task to process messages from the channel and put them in the queue
-        let queue = self.message_queue.clone();
-        tokio::spawn(async move {
-            while let Some(message) = rx.recv().await {
-                let mut queue = queue.lock().unwrap();
-                queue.push_back(message);
+        // Always send to known peers if we have any
+        if !peers.is_empty() {
+            // Send to each known peer
+            for peer_addr in peers.keys() {
+                if self.try_send_tcp(*peer_addr, &message).await {
+                    sent_any = true;
+                    continue;
+                }
+                let target_addr = with_port(*peer_addr, self.chat_port);
+                let packets = if self.takes_compressed(*peer_addr) {
+                    &compressed_packets
+                } else if self.speaks_binary(*peer_addr) {
+                    &binary_packets
+                } else {
+                    &text_packets
+                };
+
+                match send_packets(udp_socket, packets, target_addr).await {
+                    Ok(_) => sent_any = true,
+                    Err(e) => {
+                        debug_log(&format!("Failed to send to {}: {}", target_addr, e));
+                        last_error = Some(e);
+                    }
+                }
+            }
+        }
+
+        // Always try local broadcast and multicast (will work on local networks)
+        for target in self.group_targets(self.chat_port) {
+            match send_packets(udp_socket, &packets, target).await {
+                Ok(_) => sent_any = true,
+                Err(e) => last_error = Some(e),
             }
-        });
+        }
 
-        // Continuously listen for message UDP packets
+        // Try to send to all Tailscale IPs in the 100.x.y.z range
+        // This is a brute force approach but will work for small networks. Every peer reads
+        // the text format, so only that is sent to keep the sweep from doubling.
+        if udp_socket.has_v4() {
+            debug_log("Broadcasting to Tailscale network...");
+            let mut tailscale_sent = 0;
+            let mut tailscale_errors = 0;
+            for a in self.tailscale_sweep.clone() {
+                for b in 0..255 {
+                    let target =
+                        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(100, a, b, 2)), self.chat_port);
+                    match send_packets(udp_socket, &text_packets, target).await {
+                        Ok(_) => tailscale_sent += 1,
+                        Err(_) => tailscale_errors += 1,
+                    }
+                }
+            }
+            debug_log(&format!(
+                "Tailscale broadcast complete: sent to {} addresses, {} errors",
+                tailscale_sent, tailscale_errors
+            ));
+            sent_any |= tailscale_sent > 0;
+        }
+
+        match last_error {
+            Some(e) if !sent_any => Err(NetworkError::Send(e)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Why a received packet didn't give a ParsedMessage
+#[derive(Debug)]
+pub enum ParseError {
+    // Well-formed, but sent to the other port, like discovery traffic on the chat port
+    NotChat(String),
+    // Invalid UTF-8, too few fields or an unknown type
+    Malformed(String),
+    // Chat content sealed with a key other than ours
+    Undecryptable,
+    // Chat content whose authentication tag is missing or doesn't match the sender
+    Unauthenticated,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NotChat(msg_type) => write!(f, "unexpected {} packet", msg_type),
+            ParseError::Malformed(reason) => write!(f, "malformed packet: {}", reason),
+            ParseError::Undecryptable => write!(f, "content doesn't decrypt with our key"),
+            ParseError::Unauthenticated => write!(f, "missing or invalid authentication tag"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// A failure in the networking layer, by the step that failed
+#[derive(Debug)]
+pub enum NetworkError {
+    // A socket couldn't be opened or a port claimed, which retrying won't fix
+    Bind(io::Error),
+    Send(io::Error),
+    Recv(io::Error),
+    // A text packet that didn't give a ParsedMessage
+    Parse(ParseError),
+    // A binary packet that didn't decode
+    Decode(DecodeError),
+}
+
+impl NetworkError {
+    // Whether the listener that hit this should stay down rather than be restarted
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, NetworkError::Bind(_))
+    }
+
+    // A message that failed the authentication tag check, counted apart from the others
+    fn is_unauthenticated(&self) -> bool {
+        matches!(
+            self,
+            NetworkError::Parse(ParseError::Unauthenticated)
+                | NetworkError::Decode(DecodeError::Unauthenticated)
+        )
+    }
+
+    // Packets that aren't for us or don't decrypt are expected on a busy network, anything
+    // else counts towards the malformed packets in /stats
+    fn is_malformed(&self) -> bool {
+        matches!(
+            self,
+            NetworkError::Parse(ParseError::Malformed(_))
+                | NetworkError::Decode(
+                    DecodeError::NotBinary
+                        | DecodeError::UnsupportedVersion(_)
+                        | DecodeError::UnknownKind(_)
+                        | DecodeError::Truncated
+                        | DecodeError::TrailingBytes
+                        | DecodeError::InvalidUtf8
+                        | DecodeError::BadCompression
+                )
+        )
+    }
+}
+
+impl fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Bind errors already say what couldn't be bound, see listen_error
+            NetworkError::Bind(e) => write!(f, "{}", e),
+            NetworkError::Send(e) => write!(f, "send failed: {}", e),
+            NetworkError::Recv(e) => write!(f, "receive failed: {}", e),
+            NetworkError::Parse(e) => write!(f, "{}", e),
+            NetworkError::Decode(e) => write!(f, "undecodable packet: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetworkError::Bind(e) | NetworkError::Send(e) | NetworkError::Recv(e) => Some(e),
+            NetworkError::Parse(e) => Some(e),
+            NetworkError::Decode(e) => Some(e),
+        }
+    }
+}
+
+// Most I/O past binding is sending, so `?` on a send needs no mapping. Binds and receives
+// are mapped explicitly.
+impl From<io::Error> for NetworkError {
+    fn from(e: io::Error) -> Self {
+        NetworkError::Send(e)
+    }
+}
+
+impl From<ParseError> for NetworkError {
+    fn from(e: ParseError) -> Self {
+        NetworkError::Parse(e)
+    }
+}
+
+impl From<DecodeError> for NetworkError {
+    fn from(e: DecodeError) -> Self {
+        NetworkError::Decode(e)
+    }
+}
+
+// Fields extracted from a received packet
+pub struct ParsedMessage {
+    pub msg_type: String,
+    pub sender_name: String,
+    // The sender's node ID. Older peers put a placeholder IP address or "None" here.
+    pub sender_node: String,
+    pub content: String,
+    // Missing for packets from legacy peers
+    pub id: Option<u64>,
+    pub timestamp: Option<i64>,
+    // Only present on direct messages
+    pub recipient: Option<String>,
+}
+
+pub struct Receiver {
+    // Shared by every clone, so events sent through any of them reach the same consumer
+    // Bounded, so a UI that falls behind loses the oldest events instead of stalling the
+    // listeners
+    event_sender: EventSender<NetworkEvent>,
+    // Taken once by the UI, which awaits events as they arrive
+    event_receiver: Arc<Mutex<Option<EventReceiver<NetworkEvent>>>>,
+    peers: PeerList,
+    username: Arc<Mutex<String>>,
+    // Packets carrying this node ID are our own
+    node_id: String,
+    seen_ids: Arc<Mutex<RecentIds>>,
+    // Catches copies of messages that came without an ID
+    seen_content: Arc<Mutex<RecentContent>>,
+    typing: Arc<Mutex<TypingPeers>>,
+    chat_port: u16,
+    // Our sockets are bound to this address alone when set
+    bind_address: Option<IpAddr>,
+    // Shared by the UDP and TCP listeners so a peer can't double its allowance by using both
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    // Sources outside the allowlist or inside the denylist are dropped before parsing
+    access: AccessList,
+    // Longest packet the UDP listeners accept, see recv_buffer
+    recv_buffer_size: usize,
+}
+
+impl Receiver {
+    pub fn new(config: &Config, username: String, node_id: String) -> Self {
+        let (tx, rx) = broadcast::channel(config.event_queue_size.max(1));
+
+        Self {
+            event_sender: tx,
+            event_receiver: Arc::new(Mutex::new(Some(rx))),
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            username: Arc::new(Mutex::new(username)),
+            node_id,
+            seen_ids: Arc::new(Mutex::new(RecentIds::new(DEDUP_WINDOW))),
+            seen_content: Arc::new(Mutex::new(RecentContent::new(Duration::from_millis(
+                LEGACY_DEDUP_WINDOW_MS,
+            )))),
+            typing: Arc::new(Mutex::new(TypingPeers::new(Duration::from_secs(
+                TYPING_EXPIRY_SECS,
+            )))),
+            chat_port: config.chat_port,
+            bind_address: config.bind_address,
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::new(config.max_messages_per_sec))),
+            access: AccessList::new(config.allowlist.clone(), config.denylist.clone()),
+            recv_buffer_size: config.recv_buffer_size,
+        }
+    }
+
+    // One byte longer than recv_buffer_size, so a datagram that didn't fit fills it and
+    // can be told apart from one that fit exactly
+    fn recv_buffer(&self) -> Vec<u8> {
+        vec![0u8; self.recv_buffer_size + 1]
+    }
+
+    fn is_truncated(&self, size: usize) -> bool {
+        size > self.recv_buffer_size
+    }
+
+    // Whether packets from the address are dropped by the allowlist or denylist. The
+    // sender isn't told, it just never hears back.
+    fn is_blocked(&self, ip: IpAddr) -> bool {
+        if self.access.permits(ip) {
+            return false;
+        }
+        stats::record_blocked();
+        debug_log(&format!("Dropping packet from blocked address {}", ip));
+        true
+    }
+
+    pub fn get_peers(&self) -> PeerList {
+        self.peers.clone()
+    }
+
+    pub fn prune_stale_peers(&self, max_age: Duration) -> usize {
+        prune_peers(&self.peers, max_age)
+    }
+
+    pub fn get_peer_names(&self) -> Vec<(SocketAddr, String)> {
+        peer_names(&self.peers)
+    }
+
+    pub fn peer_protocol_version(&self, addr: SocketAddr) -> Option<u32> {
+        protocol_version_of(&self.peers, addr)
+    }
+
+    // Whether the peer's host announced it's away
+    pub fn peer_is_away(&self, addr: SocketAddr) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|(known, info)| known.ip() == addr.ip() && info.away)
+    }
+
+    #[allow(dead_code)]
+    pub fn update_username(&self, new_username: String) {
+        let mut username = self.username.lock().unwrap();
+        *username = new_username;
+    }
+
+    // Remembers the ID of a message we sent, so copies of it that loop back are ignored
+    pub fn mark_seen(&self, id: u64) {
+        self.seen_ids.lock().unwrap().insert(id);
+    }
+
+    // Names of the peers typing right now
+    pub fn typing_peers(&self) -> Vec<String> {
+        self.typing.lock().unwrap().active()
+    }
+
+    // The stream of received messages and acks. Only the first caller gets it.
+    pub fn take_events(&self) -> Option<EventReceiver<NetworkEvent>> {
+        self.event_receiver.lock().unwrap().take()
+    }
+
+    // Parses a chat port packet, with every text field made safe to display. Packets come
+    // in the binary format, or as text from older peers.
+    pub fn parse_message(packet: &[u8]) -> Result<ParsedMessage, NetworkError> {
+        if codec::is_compressed(packet) {
+            return Self::parse_message(&codec::decompress_packet(packet)?);
+        }
+        if !codec::is_binary(packet) {
+            let data = std::str::from_utf8(packet)
+                .map_err(|_| ParseError::Malformed("invalid UTF-8".to_string()))?;
+            let parsed = Self::parse_fields(data)?;
+            if is_discovery_type(&parsed.msg_type) {
+                return Err(ParseError::NotChat(parsed.msg_type).into());
+            }
+            return Ok(Self::sanitize(parsed));
+        }
+
+        let message = codec::decode(packet)?;
+        Ok(Self::sanitize(ParsedMessage {
+            msg_type: message.msg_type().to_string(),
+            sender_name: message.sender_name().to_string(),
+            sender_node: message.sender_ip().to_string(),
+            content: message.content().to_string(),
+            id: Some(message.id()),
+            timestamp: Some(message.timestamp()),
+            recipient: message.recipient().map(str::to_string),
+        }))
+    }
+
+    // Parses a discovery port packet, which is always text
+    pub fn parse_discovery(packet: &[u8]) -> Result<ParsedMessage, ParseError> {
+        let data = std::str::from_utf8(packet)
+            .map_err(|_| ParseError::Malformed("invalid UTF-8".to_string()))?;
+        let parsed = Self::parse_fields(data)?;
+        if !is_discovery_type(&parsed.msg_type) {
+            return Err(ParseError::NotChat(parsed.msg_type));
+        }
+        Ok(Self::sanitize(parsed))
+    }
+
+    // These all end up on the terminal, which a peer mustn't be able to control
+    fn sanitize(mut parsed: ParsedMessage) -> ParsedMessage {
+        parsed.sender_name = sanitize_remote(&parsed.sender_name, MAX_USERNAME_CHARS)
+            .trim()
+            .to_string();
+        if parsed.sender_name.is_empty() {
+            parsed.sender_name = UNKNOWN_PEER_NAME.to_string();
+        }
+        parsed.sender_node = sanitize_remote(&parsed.sender_node, MAX_NODE_FIELD_CHARS);
+        parsed.content = sanitize_remote(&parsed.content, MAX_CONTENT_CHARS);
+        parsed.recipient = parsed
+            .recipient
+            .map(|recipient| sanitize_remote(&recipient, MAX_USERNAME_CHARS));
+
+        parsed
+    }
+
+    fn parse_fields(data: &str) -> Result<ParsedMessage, ParseError> {
+        // Split by the field splitter, honoring escaped splitters inside fields
+        let parts = split_fields(data);
+        let msg_type = parts[0].clone();
+
+        let min_fields = min_field_count(&msg_type)
+            .ok_or_else(|| ParseError::Malformed(format!("unknown message type {:?}", msg_type)))?;
+        if parts.len() < min_fields {
+            return Err(ParseError::Malformed(format!(
+                "{} packet with {} fields, expected at least {}",
+                msg_type,
+                parts.len(),
+                min_fields
+            )));
+        }
+
+        let mut parsed = ParsedMessage {
+            msg_type,
+            sender_name: parts[1].clone(),
+            sender_node: parts[2].clone(),
+            content: String::new(),
+            id: None,
+            timestamp: None,
+            recipient: None,
+        };
+
+        // Current chat messages are MSG_TYPE, name, ip, id, timestamp, content.
+        // Legacy peers send only MSG_TYPE, name, ip, content.
+        let id = parts.get(3).and_then(|id| id.parse::<u64>().ok());
+        let timestamp = parts.get(4).and_then(|ts| ts.parse::<i64>().ok());
+        // Direct messages add the recipient's name before the content.
+        let content_start = if parsed.msg_type == MSG_TYPE_DM { 6 } else { 5 };
+        match (id, timestamp) {
+            (Some(id), Some(timestamp)) if parts.len() > content_start => {
+                parsed.id = Some(id);
+                parsed.timestamp = Some(timestamp);
+                if parsed.msg_type == MSG_TYPE_DM {
+                    parsed.recipient = Some(parts[5].clone());
+                }
+                parsed.content = parts[content_start..].join(splitter_of(data));
+            }
+            _ => {
+                // Extra splitters in the content come from legacy peers that don't escape
+                parsed.content = parts[3..].join(splitter_of(data));
+            }
+        }
+
+        // Content that isn't tagged and sealed with our key is untrusted
+        if is_authenticated_type(&parsed.msg_type) {
+            let envelope = Envelope {
+                msg_type: &parsed.msg_type,
+                id: parsed.id.unwrap_or_default(),
+                node: &parsed.sender_node,
+                name: &parsed.sender_name,
+                timestamp: parsed.timestamp.unwrap_or_default(),
+                recipient: parsed.recipient.as_deref(),
+            };
+            let content =
+                verify_content(&envelope, &parsed.content).ok_or(ParseError::Unauthenticated)?;
+            parsed.content = decrypt_content(content).ok_or(ParseError::Undecryptable)?;
+        }
+
+        Ok(parsed)
+    }
+
+    pub async fn handle_discovery(
+        &self,
+        socket: &DualSocket,
+        src: SocketAddr,
+        packet: &[u8],
+    ) -> Result<(), NetworkError> {
+        if self.is_blocked(src.ip()) {
+            return Ok(());
+        }
+        let ParsedMessage {
+            msg_type,
+            sender_name,
+            sender_node,
+            content,
+            ..
+        } = match Self::parse_discovery(packet) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                debug_log(&format!("Ignoring discovery packet from {}: {}", src, e));
+                return Ok(());
+            }
+        };
+        // Our own discovery packets come back through the broadcast address and multicast
+        // loopback, and we aren't our own peer
+        if sender_node == self.node_id {
+            return Ok(());
+        }
+        let version = parse_protocol_version(&content);
+
+        match msg_type.as_str() {
+            MSG_TYPE_DISCOVERY => {
+                // Someone is looking for peers, respond with our presence
+                debug_log(&format!(
+                    "Received discovery request from {} ({})",
+                    sender_name,
+                    src.ip()
+                ));
+                let username = self.username.lock().unwrap().clone();
+                let response =
+                    discovery_packet(MSG_TYPE_DISCOVERY_RESPONSE, &username, &self.node_id);
+                debug_log(&format!("Sending discovery response to {}", src));
+                socket.send_to(response.as_bytes(), src).await?;
+
+                // Add this peer to our list
+                let mut peers = self.peers.lock().unwrap();
+                let is_new = record_peer(&mut peers, src, &sender_name, Some(&sender_node));
+                let peer_count = peers.len();
+                if is_new {
+                    debug_log(&format!(
+                        "Added new peer: {} ({}). Total peers: {}",
+                        sender_name,
+                        src.ip(),
+                        peer_count
+                    ));
+                    self.send_event(NetworkEvent::PeerJoined(sender_name.clone()));
+                }
+            }
+            MSG_TYPE_DISCOVERY_RESPONSE => {
+                // Someone responded to our discovery request, add them to peers
+                let mut peers = self.peers.lock().unwrap();
+                let is_new = record_peer(&mut peers, src, &sender_name, Some(&sender_node));
+                let peer_count = peers.len();
+                debug_log(&format!(
+                    "Discovered peer: {} ({}). New: {}. Total peers: {}",
+                    sender_name,
+                    src.ip(),
+                    is_new,
+                    peer_count
+                ));
+                if is_new {
+                    self.send_event(NetworkEvent::PeerJoined(sender_name.clone()));
+                }
+            }
+            MSG_TYPE_LEAVE => {
+                // Chat and discovery entries for the host share its IP but not its port
+                let removed = forget_peer(&mut self.peers.lock().unwrap(), src, &sender_name);
+                self.typing.lock().unwrap().set(&sender_name, false);
+                debug_log(&format!(
+                    "Peer {} ({}) left, removed {} entries",
+                    sender_name,
+                    src.ip(),
+                    removed
+                ));
+                self.send_event(NetworkEvent::PeerLeft(src, sender_name));
+                return Ok(());
+            }
+            MSG_TYPE_HEARTBEAT => {
+                // A peer is still around, keep it from expiring
+                let is_new = touch_peer(
+                    &mut self.peers.lock().unwrap(),
+                    src,
+                    &sender_name,
+                    &sender_node,
+                );
+                if is_new {
+                    debug_log(&format!(
+                        "Added peer from heartbeat: {} ({})",
+                        sender_name,
+                        src.ip()
+                    ));
+                    self.send_event(NetworkEvent::PeerJoined(sender_name.clone()));
+                }
+            }
+            _ => {
+                debug_log(&format!("Received unknown message type: {}", msg_type));
+                return Ok(());
+            } // Log unknown message types
+        }
+
+        let changed = record_protocol_version(&mut self.peers.lock().unwrap(), src, version);
+        if changed {
+            if let Some(mismatch) = version_mismatch(version) {
+                debug_log(&format!(
+                    "Peer {} ({}) uses {}",
+                    sender_name,
+                    src.ip(),
+                    mismatch
+                ));
+            }
+            // Its untagged messages fail the check, which would look like the peer is silent
+            if is_encryption_enabled() && !supports_authentication(version) {
+                log(
+                    Level::Warn,
+                    &format!(
+                        "Peer {} ({}) uses protocol v{}, its messages are dropped while a \
+                         passphrase is set",
+                        sender_name,
+                        src.ip(),
+                        version
+                    ),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // Binds the discovery port on IPv4 and IPv6 and joins the multicast groups. Done before
+    // the UI starts so a failure can be reported on a normal terminal.
+    pub fn bind_discovery(config: &Config) -> Result<DualSocket, NetworkError> {
+        bind_listener(
+            config.discovery_port,
+            config,
+            "peer discovery",
+            "--discovery-port",
+        )
+    }
+
+    pub fn bind_chat(config: &Config) -> Result<DualSocket, NetworkError> {
+        bind_listener(config.chat_port, config, "chat messages", "--chat-port")
+    }
+
+    pub fn bind_tcp(
+        tcp_port: u16,
+        bind_address: Option<IpAddr>,
+    ) -> Result<TcpListener, NetworkError> {
+        tcp::bind_listener(tcp_port, bind_address)
+            .map_err(|e| listen_error(e, tcp_port, "chat messages over TCP", "--tcp-port"))
+    }
+
+    pub async fn listen_for_discovery(&self, udp_socket: DualSocket) -> Result<(), NetworkError> {
+        let mut buf = self.recv_buffer();
+        let mut errors = RecvErrors::new("discovery");
+
+        // Continuously listen for discovery messages
         loop {
-            let (size, src) = udp_socket.recv_from(&mut buf).await?;
-            let data = String::from_utf8_lossy(&buf[..size]).to_string();
+            let (size, src) = match udp_socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    errors.record(e).map_err(NetworkError::Recv)?;
+                    continue;
+                }
+            };
+            errors.reset();
+            if self.is_truncated(size) {
+                debug_log(&format!("Dropping truncated discovery packet from {}", src));
+                continue;
+            }
+
+            if let Err(e) = self.handle_discovery(&udp_socket, src, &buf[..size]).await {
+                log(Level::Warn, &format!("Error handling discovery: {}", e));
+            }
+        }
+    }
+
+    // Advertises us over mDNS and adds the instances it finds to the peer list. Runs next to
+    // the broadcast discovery, for networks that drop broadcasts but pass multicast DNS.
+    pub async fn mdns_service(&self) -> Result<(), NetworkError> {
+        // Shut down when this returns or is cancelled
+        let mut daemon = mdns::Daemon::start(self.bind_address).map_err(mdns_error)?;
+        let receiver = self.clone();
+        let mut browse = daemon
+            .browse(move |service| receiver.found_over_mdns(&service))
+            .map_err(mdns_error)?;
+
+        // Republished under the new name when the username changes
+        let mut published: Option<String> = None;
+        let mut check = time::interval(Duration::from_secs(DISCOVERY_INTERVAL_SECS));
+
+        loop {
+            tokio::select! {
+                _ = check.tick() => {
+                    let username = self.username.lock().unwrap().clone();
+                    if published.as_ref() == Some(&username) {
+                        continue;
+                    }
+                    let service = mdns::service_info(&username, &self.node_id, self.chat_port)
+                        .map_err(mdns_error)?;
+                    daemon.publish(service).map_err(mdns_error)?;
+                    published = Some(username);
+                }
+                _ = &mut browse => {
+                    return Err(NetworkError::Recv(io::Error::other("mDNS browsing stopped")));
+                }
+            }
+        }
+    }
+
+    fn found_over_mdns(&self, service: &mdns_sd::ServiceInfo) {
+        let node = mdns::node_id(service);
+        if node == Some(self.node_id.as_str()) {
+            return;
+        }
+        // One address is enough, IPv4 first as link-local IPv6 ones need a scope to reach
+        let Some(ip) = service
+            .get_addresses()
+            .iter()
+            .filter(|ip| !self.is_blocked(**ip))
+            .min_by_key(|ip| ip.is_ipv6())
+        else {
+            return;
+        };
+
+        let addr = SocketAddr::new(*ip, service.get_port());
+        let name = sanitize_remote(mdns::instance_name(service), MAX_USERNAME_CHARS);
+        let mut peers = self.peers.lock().unwrap();
+        if record_peer(&mut peers, addr, name.trim(), node) {
+            debug_log(&format!("Found peer over mDNS: {} ({})", name, addr));
+        }
+    }
 
-            let (msg_type, name, content, _) = Self::parse_message(&data);
+    // Takes any transport, a DualSocket bound by bind_chat in the app
+    pub async fn listen_for_messages<T: Transport>(
+        &self,
+        udp_socket: T,
+    ) -> Result<(), NetworkError> {
+        let mut buf = self.recv_buffer();
+        let mut reassembler = Reassembler::new();
+        let mut errors = RecvErrors::new("chat");
 
-            // Skip the message if it's not a chat message
-            if msg_type != MSG_TYPE_CHAT {
+        // Continuously listen for message UDP packets
+        loop {
+            let (size, src) = match udp_socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(e) => {
+                    errors.record(e).map_err(NetworkError::Recv)?;
+                    continue;
+                }
+            };
+            errors.reset();
+            stats::record_bytes_received(size);
+            if self.is_truncated(size) {
+                stats::record_truncated();
+                debug_log(&format!(
+                    "Dropping packet from {} longer than the {} byte receive buffer",
+                    src, self.recv_buffer_size
+                ));
                 continue;
             }
+            stats::record_packet_size(size);
+            let packet = &buf[..size];
+
+            // Fragments are held back until the whole message has arrived
+            let packet = if fragment::is_fragment(packet) {
+                let dropped = reassembler.expire(Duration::from_secs(FRAGMENT_TIMEOUT_SECS));
+                if dropped > 0 {
+                    debug_log(&format!(
+                        "Dropped {} incomplete fragmented messages",
+                        dropped
+                    ));
+                }
+                match Fragment::parse(packet).and_then(|fragment| reassembler.insert(fragment)) {
+                    Some(packet) => packet,
+                    None => continue,
+                }
+            } else {
+                packet.to_vec()
+            };
+
+            self.handle_packet(&packet, src, &udp_socket).await;
+        }
+    }
+
+    // Accepts TCP connections from peers and reads their packets until they hang up. Acks
+    // still go back over UDP, like for packets that arrived that way.
+    pub async fn listen_for_tcp(&self, listener: TcpListener) -> Result<(), NetworkError> {
+        let ack_socket = Arc::new(bind_sender(self.bind_address)?);
+        // Accepting fails for one connection at a time too, like when we're out of file
+        // descriptors for a moment
+        let mut errors = RecvErrors::new("TCP");
+
+        loop {
+            let (stream, src) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    errors.record(e).map_err(NetworkError::Recv)?;
+                    continue;
+                }
+            };
+            errors.reset();
+            let receiver = self.clone();
+            let ack_socket = ack_socket.clone();
+            tokio::spawn(async move {
+                if let Err(e) = receiver.read_tcp_packets(stream, src, &ack_socket).await {
+                    debug_log(&format!("TCP connection from {} failed: {}", src, e));
+                }
+            });
+        }
+    }
+
+    async fn read_tcp_packets(
+        &self,
+        mut stream: TcpStream,
+        src: SocketAddr,
+        ack_socket: &DualSocket,
+    ) -> io::Result<()> {
+        let idle_timeout = Duration::from_secs(TCP_IDLE_TIMEOUT_SECS);
+        loop {
+            let packet = match time::timeout(idle_timeout, tcp::read_frame(&mut stream)).await {
+                Ok(Ok(Some(packet))) => packet,
+                Ok(Ok(None)) | Err(_) => return Ok(()),
+                Ok(Err(e)) => return Err(e),
+            };
+            stats::record_bytes_received(packet.len());
+            self.handle_packet(&packet, src, ack_socket).await;
+        }
+    }
+
+    // Handles one complete chat port packet, whichever transport it came over
+    async fn handle_packet<T: Transport>(&self, packet: &[u8], src: SocketAddr, reply_socket: &T) {
+        if self.is_blocked(src.ip()) {
+            return;
+        }
+        let parsed = match Self::parse_message(packet) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                if e.is_malformed() {
+                    stats::record_malformed();
+                }
+                if e.is_unauthenticated() {
+                    stats::record_unauthenticated();
+                }
+                debug_log(&format!("Dropping packet from {}: {}", src.ip(), e));
+                return;
+            }
+        };
+
+        // Broadcasts reach us too, and we already show our own messages
+        if parsed.sender_node == self.node_id {
+            return;
+        }
+
+        // Acks carry the ID of one of our messages as their content
+        if parsed.msg_type == MSG_TYPE_ACK {
+            if let Ok(id) = parsed.content.parse::<u64>() {
+                self.send_event(NetworkEvent::Ack { id, from: src.ip() });
+            }
+            record_peer(
+                &mut self.peers.lock().unwrap(),
+                src,
+                &parsed.sender_name,
+                Some(&parsed.sender_node),
+            );
+            return;
+        }
+
+        // Pongs echo the time of one of our pings
+        if parsed.msg_type == MSG_TYPE_PONG {
+            if let Ok(sent) = parsed.content.parse::<i64>() {
+                self.send_event(NetworkEvent::Pong {
+                    from: src.ip(),
+                    sent,
+                });
+            }
+            record_peer(
+                &mut self.peers.lock().unwrap(),
+                src,
+                &parsed.sender_name,
+                Some(&parsed.sender_node),
+            );
+            return;
+        }
+
+        // A peer flooding us is dropped without an ack so it doesn't wedge the UI
+        if !self.rate_limiter.lock().unwrap().allow(src.ip()) {
+            debug_log(&format!("Rate limiting messages from {}", src.ip()));
+            return;
+        }
+
+        // Pings are answered right away with their timestamp, nothing else is echoed
+        if parsed.msg_type == MSG_TYPE_PING {
+            if parsed.content.parse::<i64>().is_ok() {
+                let username = self.username.lock().unwrap().clone();
+                let pong = notice_packet(MSG_TYPE_PONG, &username, &self.node_id, &parsed.content);
+                let sender = with_port(src, self.chat_port);
+                match reply_socket.send_to(pong.as_bytes(), sender).await {
+                    Ok(sent) => stats::record_bytes_sent(sent),
+                    Err(e) => debug_log(&format!("Failed to send pong to {}: {}", sender, e)),
+                }
+            }
+            return;
+        }
+
+        // Typing updates carry "1" or "0" as their content
+        if parsed.msg_type == MSG_TYPE_TYPING {
+            let typing = parsed.content == "1";
+            self.typing.lock().unwrap().set(&parsed.sender_name, typing);
+            self.send_event(NetworkEvent::Typing);
+            return;
+        }
+
+        // Away and back announcements mark every entry for the sender's host
+        if parsed.msg_type == MSG_TYPE_STATUS {
+            if let Some(away) = away::parse_status(&parsed.content) {
+                if set_away(&mut self.peers.lock().unwrap(), src.ip(), away) {
+                    self.send_event(NetworkEvent::PeerStatus {
+                        name: parsed.sender_name,
+                        away,
+                    });
+                }
+            }
+            return;
+        }
+
+        if parsed.msg_type == MSG_TYPE_HISTORY_REQ {
+            if let Ok(count) = parsed.content.parse::<usize>() {
+                self.send_event(NetworkEvent::HistoryRequest { from: src, count });
+            }
+            return;
+        }
+        // Earlier messages keep their ID, so ones we already have are dropped
+        if parsed.msg_type == MSG_TYPE_HISTORY_RESP {
+            if let (Some(id), Some(timestamp)) = (parsed.id, parsed.timestamp) {
+                if self.seen_ids.lock().unwrap().insert(id) {
+                    let message = Message::new(
+                        parsed.content,
+                        parsed.sender_name,
+                        HISTORY_SENDER.to_string(),
+                    )
+                    .with_id(id)
+                    .with_timestamp(timestamp);
+                    self.send_event(NetworkEvent::History(message));
+                }
+            }
+            return;
+        }
+
+        // Edits and deletions name an earlier message by ID, not one of their own
+        if parsed.msg_type == MSG_TYPE_EDIT {
+            if let Some(id) = parsed.id {
+                self.send_event(NetworkEvent::Edited {
+                    id,
+                    node: parsed.sender_node,
+                    content: parsed.content,
+                });
+            }
+            return;
+        }
+        if parsed.msg_type == MSG_TYPE_DELETE {
+            if let Ok(id) = parsed.content.parse::<u64>() {
+                self.send_event(NetworkEvent::Deleted {
+                    id,
+                    node: parsed.sender_node,
+                });
+            }
+            return;
+        }
+        if parsed.msg_type == MSG_TYPE_REACTION {
+            if let Some(id) = parsed.id.filter(|_| is_valid_reaction(&parsed.content)) {
+                self.send_event(NetworkEvent::Reacted {
+                    id,
+                    node: parsed.sender_node,
+                    emoji: parsed.content,
+                });
+            }
+            return;
+        }
 
-            // Use the actual source IP address (from Tailscale or local network)
-            let sender_ip = src.ip().to_string();
+        // Direct messages can still reach us via broadcast, drop the ones for someone else
+        if let Some(recipient) = &parsed.recipient {
+            let username = self.username.lock().unwrap().clone();
+            if !recipient.is_empty() && !recipient.eq_ignore_ascii_case(&username) {
+                return;
+            }
+        }
 
-            // Create a new message and add it to our queue
-            let message = Message::new(content, name, sender_ip);
+        // The same packet can arrive several times via broadcast, peers and Tailscale. Copies
+        // are acked too, a resent message means the sender missed our first ack.
+        if let Some(id) = parsed.id {
+            let duplicate = !self.seen_ids.lock().unwrap().insert(id);
+
+            // Let the sender know the message arrived
+            // The ID is the content too, as peers from before acks were tagged read it there
+            let username = self.username.lock().unwrap().clone();
+            let ack = about_packet(MSG_TYPE_ACK, &username, &self.node_id, id, &id.to_string());
+            let sender = with_port(src, self.chat_port);
+            match reply_socket.send_to(ack.as_bytes(), sender).await {
+                Ok(sent) => stats::record_bytes_sent(sent),
+                Err(e) => debug_log(&format!("Failed to send ack to {}: {}", sender, e)),
+            }
 
-            if let Err(e) = self.message_sender.send(message).await {
-                eprintln!("Failed to add message to queue: {}", e);
+            if duplicate {
+                stats::record_duplicate();
+                return;
+            }
+        } else {
+            let first_copy = self.seen_content.lock().unwrap().insert(
+                &parsed.sender_node,
+                &parsed.sender_name,
+                &parsed.content,
+                Instant::now(),
+            );
+            if !first_copy {
+                stats::record_duplicate();
+                return;
             }
+        }
 
-            // Add this peer to our known peers list
-            self.peers.lock().unwrap().insert(src);
+        // Use the actual source IP address (from Tailscale or local network)
+        let sender_ip = src.ip().to_string();
+
+        // Create a new message and add it to our queue. Legacy packets keep the
+        // ID and timestamp generated here on receipt.
+        let sender_name = parsed.sender_name.clone();
+        let sender_node = parsed.sender_node.clone();
+        let mut message = Message::new(parsed.content, parsed.sender_name, sender_ip)
+            .with_sender_node(parsed.sender_node);
+        if let Some(id) = parsed.id {
+            message = message.with_id(id);
+        }
+        if let Some(timestamp) = parsed.timestamp {
+            message = message.with_timestamp(timestamp);
+        }
+        if let Some(recipient) = parsed.recipient {
+            message = message.with_recipient(recipient);
+        }
+        if parsed.msg_type == MSG_TYPE_ACTION {
+            message = message.into_action();
         }
+
+        // Sending a message ends the sender's typing
+        self.typing.lock().unwrap().set(&sender_name, false);
+        stats::record_message_received();
+        self.send_event(NetworkEvent::Message(message));
+
+        // Add this peer to our known peers list and refresh its last-seen time
+        record_peer(
+            &mut self.peers.lock().unwrap(),
+            src,
+            &sender_name,
+            Some(&sender_node),
+        );
     }
 
-    pub fn get_queue_message(&self) -> Option<Message> {
-        let mut queue = self.message_queue.lock().unwrap();
-        queue.pop_front()
+    fn send_event(&self, event: NetworkEvent) {
+        if let Err(e) = self.event_sender.send(event) {
+            debug_log(&format!("Dropping event, nobody is listening: {:?}", e.0));
+        }
     }
 }
 
 impl Clone for Receiver {
     fn clone(&self) -> Self {
-        // Create a new channel
-        let (tx, rx) = mpsc::channel(100);
-
         Self {
-            message_queue: self.message_queue.clone(),
-            message_sender: tx,
-            message_receiver: Some(rx),
+            event_sender: self.event_sender.clone(),
+            event_receiver: self.event_receiver.clone(),
             peers: self.peers.clone(),
             username: self.username.clone(),
+            node_id: self.node_id.clone(),
+            seen_ids: self.seen_ids.clone(),
+            seen_content: self.seen_content.clone(),
+            typing: self.typing.clone(),
+            chat_port: self.chat_port,
+            bind_address: self.bind_address,
+            rate_limiter: self.rate_limiter.clone(),
+            access: self.access.clone(),
+            recv_buffer_size: self.recv_buffer_size,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::{MAX_CONSECUTIVE_RECV_ERRORS, MIN_RECV_BUFFER_SIZE};
+    use crate::supervisor::{supervise, Restarts};
+    use crate::transport::{MemoryNetwork, MemoryTransport};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn addr(ip: &str, port: u16) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), port)
+    }
+
+    // A broadcaster on 10.0.0.1 and a receiver listening on 10.0.0.2, both on the default
+    // chat port of one in-memory network
+    fn loopback() -> (
+        Broadcaster,
+        MemoryTransport,
+        Receiver,
+        EventReceiver<NetworkEvent>,
+    ) {
+        let config = Config::default();
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let events = receiver.take_events().unwrap();
+        let listener = receiver.clone();
+        let socket = network.bind(addr("10.0.0.2", config.chat_port));
+        tokio::spawn(async move { listener.listen_for_messages(socket).await });
+        (broadcaster, sender, receiver, events)
+    }
+
+    async fn next_message(events: &mut EventReceiver<NetworkEvent>) -> Option<Message> {
+        loop {
+            let event = time::timeout(Duration::from_millis(300), events.recv()).await;
+            match event {
+                Ok(Ok(NetworkEvent::Message(message))) => return Some(message),
+                Ok(Ok(_)) => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn broadcasts_reach_the_receiver_once() {
+        let (broadcaster, sender, _receiver, mut events) = loopback();
+        let message = Message::new(
+            "hello ~ bob".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        let id = message.id();
+        broadcaster
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+
+        // Every group address gets the message in both formats, only one copy gets through
+        let received = next_message(&mut events).await.unwrap();
+        assert_eq!(received.id(), id);
+        assert_eq!(received.content(), "hello ~ bob");
+        assert_eq!(received.sender_name(), "alice");
+        assert_eq!(received.sender_node(), Some("node-a"));
+        assert!(next_message(&mut events).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn a_broadcast_reaches_every_peer_on_the_network() {
+        let config = Config::default();
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let mut queues = Vec::new();
+        for n in 2..5 {
+            let receiver = Receiver::new(&config, format!("peer{}", n), format!("node-{}", n));
+            queues.push(receiver.take_events().unwrap());
+            let socket = network.bind(addr(&format!("10.0.0.{}", n), config.chat_port));
+            tokio::spawn(async move { receiver.listen_for_messages(socket).await });
+        }
+
+        let message = Message::new(
+            "all of you".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        let id = message.id();
+        broadcaster
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+        for events in &mut queues {
+            let received = next_message(events).await.unwrap();
+            assert_eq!(received.id(), id);
+            assert_eq!(received.content(), "all of you");
+            assert!(next_message(events).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn received_messages_are_acked() {
+        let (broadcaster, sender, _receiver, _events) = loopback();
+        let message = Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string());
+        let id = message.id();
+        broadcaster
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+
+        let mut buf = vec![0u8; 2048];
+        let (size, from) = sender.recv_from(&mut buf).await.unwrap();
+        assert_eq!(from, addr("10.0.0.2", Config::default().chat_port));
+        let ack = Receiver::parse_message(&buf[..size]).unwrap();
+        assert_eq!(ack.msg_type, MSG_TYPE_ACK);
+        assert_eq!(ack.sender_node, "node-b");
+        assert_eq!(ack.content, id.to_string());
+    }
+
+    #[tokio::test]
+    async fn traffic_is_counted() {
+        let (broadcaster, sender, _receiver, mut events) = loopback();
+        let before = stats::snapshot();
+        let message = Message::new(
+            "count me".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        broadcaster
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+        assert!(next_message(&mut events).await.is_some());
+
+        // Other tests count too, so only the growth is certain
+        let after = stats::snapshot();
+        assert!(after.messages_sent > before.messages_sent);
+        assert!(after.messages_received > before.messages_received);
+        assert!(after.bytes_sent > before.bytes_sent);
+        assert!(after.bytes_received > before.bytes_received);
+        // The copies sent to every group address after the first are duplicates
+        assert!(after.duplicates_dropped > before.duplicates_dropped);
+    }
+
+    #[tokio::test]
+    async fn messages_arrive_over_a_tcp_connection() {
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+        let listener = Receiver::bind_tcp(0, Some(localhost)).unwrap();
+        let config = Config {
+            tcp: true,
+            tcp_port: listener.local_addr().unwrap().port(),
+            ..Config::default()
+        };
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+        tokio::spawn(async move { receiver.listen_for_tcp(listener).await });
+
+        let message = Message::new(
+            "over ~ tcp".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        let id = message.id();
+        broadcaster
+            .send_tcp(SocketAddr::new(localhost, config.chat_port), &message)
+            .await
+            .unwrap();
+
+        let received = next_message(&mut events).await.unwrap();
+        assert_eq!(received.id(), id);
+        assert_eq!(received.content(), "over ~ tcp");
+        assert_eq!(received.sender_name(), "alice");
+    }
+
+    #[tokio::test]
+    async fn receive_errors_do_not_stop_the_listener() {
+        let config = Config::default();
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let listening_on = addr("10.0.0.2", config.chat_port);
+        let socket = network.bind(listening_on);
+        for _ in 1..MAX_CONSECUTIVE_RECV_ERRORS {
+            network.fail_next_recv(listening_on, io::ErrorKind::ConnectionRefused);
+        }
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+        let listener = tokio::spawn(async move { receiver.listen_for_messages(socket).await });
+
+        let message = Message::new(
+            "still here".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        broadcaster
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+        let received = next_message(&mut events).await.unwrap();
+        assert_eq!(received.content(), "still here");
+        assert!(!listener.is_finished());
+
+        // A socket that does nothing but fail is given up on, for the supervisor to replace
+        for _ in 0..MAX_CONSECUTIVE_RECV_ERRORS {
+            network.fail_next_recv(listening_on, io::ErrorKind::ConnectionRefused);
+        }
+        let result = time::timeout(Duration::from_millis(300), listener)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(result, Err(NetworkError::Recv(_))));
+    }
+
+    #[tokio::test]
+    async fn pings_are_answered_with_their_timestamp() {
+        let (_, sender, _receiver, _events) = loopback();
+        let ping = join_fields(&[MSG_TYPE_PING, "alice", "node-a", "1700000000123"]);
+        let parsed = Receiver::parse_message(ping.as_bytes()).unwrap();
+        assert_eq!(parsed.msg_type, MSG_TYPE_PING);
+        assert_eq!(parsed.content, "1700000000123");
+
+        let target = addr("10.0.0.2", Config::default().chat_port);
+        sender.send_to(ping.as_bytes(), target).await.unwrap();
+        let mut buf = vec![0u8; 2048];
+        let (size, from) = time::timeout(Duration::from_millis(300), sender.recv_from(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from, target);
+        let pong = Receiver::parse_message(&buf[..size]).unwrap();
+        assert_eq!(pong.msg_type, MSG_TYPE_PONG);
+        assert_eq!(pong.sender_name, "bob");
+        assert_eq!(pong.content, "1700000000123");
+    }
+
+    #[tokio::test]
+    async fn away_and_back_mark_the_peer() {
+        let (_, sender, receiver, mut events) = loopback();
+        let peer = addr("10.0.0.1", Config::default().chat_port);
+        receiver
+            .get_peers()
+            .lock()
+            .unwrap()
+            .insert(peer, PeerInfo::new("alice"));
+        let target = addr("10.0.0.2", Config::default().chat_port);
+
+        for away in [true, false] {
+            let packet = join_fields(&[
+                MSG_TYPE_STATUS,
+                "alice",
+                "node-a",
+                away::status_content(away),
+            ]);
+            let parsed = Receiver::parse_message(packet.as_bytes()).unwrap();
+            assert_eq!(parsed.msg_type, MSG_TYPE_STATUS);
+            sender.send_to(packet.as_bytes(), target).await.unwrap();
+
+            let event = time::timeout(Duration::from_millis(300), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(
+                matches!(event, NetworkEvent::PeerStatus { ref name, away: now } if name == "alice" && now == away)
+            );
+            assert_eq!(receiver.get_peers().lock().unwrap()[&peer].away, away);
+        }
+    }
+
+    #[tokio::test]
+    async fn our_own_messages_are_ignored() {
+        let (_, sender, _receiver, mut events) = loopback();
+        let config = Config::default();
+        // Sent under the receiver's own node ID, as when a broadcast loops back to us
+        let ours = Broadcaster::new(&config, "bob".to_string(), "node-b".to_string());
+        let message = Message::new("echo".to_string(), "bob".to_string(), "node-b".to_string());
+        ours.broadcast_message_over(&sender, message).await.unwrap();
+        assert!(next_message(&mut events).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn only_our_node_id_marks_a_message_as_ours() {
+        let (_, sender, _receiver, mut events) = loopback();
+        let config = Config::default();
+        // Someone else who happens to use our name still gets through
+        let namesake = Broadcaster::new(&config, "bob".to_string(), "node-c".to_string());
+        let message = Message::new("hi".to_string(), "bob".to_string(), "node-c".to_string());
+        namesake
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+        let received = next_message(&mut events).await.unwrap();
+        assert_eq!(received.sender_node(), Some("node-c"));
+    }
+
+    #[test]
+    fn node_ids_differ_between_runs() {
+        let ids: std::collections::HashSet<String> = (0..100).map(|_| new_node_id()).collect();
+        assert_eq!(ids.len(), 100);
+        assert!(ids.iter().all(|id| id.len() <= MAX_NODE_FIELD_CHARS));
+    }
+
+    #[test]
+    fn about_packets_carry_the_id_as_content() {
+        for msg_type in [MSG_TYPE_DELETE, MSG_TYPE_ACK] {
+            let packet = about_packet(msg_type, "alice", "node-a", 1234, "1234");
+            let parsed = Receiver::parse_message(packet.as_bytes()).unwrap();
+            assert_eq!(parsed.msg_type, msg_type);
+            assert_eq!(parsed.sender_name, "alice");
+            assert_eq!(parsed.sender_node, "node-a");
+            assert_eq!(parsed.id, Some(1234));
+            assert_eq!(parsed.content, "1234");
+        }
+    }
+
+    #[test]
+    fn escape_sequences_from_peers_are_neutralized() {
+        let message = Message::new(
+            "\x1b[31mred\r\x1b[H".to_string(),
+            "\x1b[2Jmallory".to_string(),
+            "node-m".to_string(),
+        );
+        let parsed = Receiver::parse_message(message.encode_packet().as_bytes()).unwrap();
+        assert_eq!(parsed.sender_name, "[2Jmallory");
+        assert_eq!(parsed.content, "[31mred [H");
+
+        // A name that is nothing but controls still shows as someone
+        let packet = discovery_packet(MSG_TYPE_DISCOVERY, "\x1b\x07", "node-m");
+        let parsed = Receiver::parse_discovery(packet.as_bytes()).unwrap();
+        assert_eq!(parsed.sender_name, UNKNOWN_PEER_NAME);
+    }
+
+    #[test]
+    fn malformed_packets_are_told_apart_from_other_traffic() {
+        let malformed = |packet: &[u8]| {
+            matches!(
+                Receiver::parse_message(packet),
+                Err(NetworkError::Parse(ParseError::Malformed(_)))
+            )
+        };
+        assert!(malformed(b"CHAT~alice~node-a~\xff\xfe hi"));
+        assert!(malformed(b"CHAT~alice"));
+        assert!(malformed(b"SHOUT~alice~node-a~hi"));
+        assert!(malformed(b""));
+
+        let discovery = discovery_packet(MSG_TYPE_DISCOVERY, "alice", "node-a");
+        assert!(matches!(
+            Receiver::parse_message(discovery.as_bytes()),
+            Err(NetworkError::Parse(ParseError::NotChat(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn malformed_packets_are_counted_not_shown() {
+        let (_, sender, _receiver, mut events) = loopback();
+        let before = stats::snapshot();
+        let target = addr("10.0.0.2", Config::default().chat_port);
+        for packet in [&b"CHAT~alice~node-a~\xc3("[..], b"CHAT~alice"] {
+            sender.send_to(packet, target).await.unwrap();
+        }
+
+        assert!(next_message(&mut events).await.is_none());
+        assert!(stats::snapshot().malformed_dropped >= before.malformed_dropped + 2);
+    }
+
+    #[test]
+    fn untagged_acks_and_deletions_still_parse() {
+        // The layout peers used before acks and deletions were tagged
+        for msg_type in [MSG_TYPE_DELETE, MSG_TYPE_ACK] {
+            let packet = join_fields(&[msg_type, "alice", "node-a", "1234"]);
+            let parsed = Receiver::parse_message(packet.as_bytes()).unwrap();
+            assert_eq!(parsed.id, None);
+            assert_eq!(parsed.content, "1234");
+        }
+    }
+
+    #[test]
+    fn every_chat_port_type_needs_a_tag() {
+        for msg_type in [
+            MSG_TYPE_CHAT,
+            MSG_TYPE_DM,
+            MSG_TYPE_ACTION,
+            MSG_TYPE_EDIT,
+            MSG_TYPE_REACTION,
+            MSG_TYPE_DELETE,
+            MSG_TYPE_ACK,
+            MSG_TYPE_HISTORY_REQ,
+            MSG_TYPE_HISTORY_RESP,
+            MSG_TYPE_TYPING,
+            MSG_TYPE_STATUS,
+            MSG_TYPE_PING,
+            MSG_TYPE_PONG,
+        ] {
+            assert!(is_authenticated_type(msg_type), "{}", msg_type);
+        }
+        assert!(!is_authenticated_type(MSG_TYPE_DISCOVERY));
+    }
+
+    #[tokio::test]
+    async fn connecting_adds_the_peer_on_the_chat_port_by_default() {
+        let config = Config {
+            bind_address: Some("127.0.0.1".parse().unwrap()),
+            ..Config::default()
+        };
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+
+        assert!(broadcaster.connect(localhost, None).await.unwrap());
+        assert!(broadcaster.connect(localhost, Some(4000)).await.unwrap());
+        assert!(!broadcaster.connect(localhost, None).await.unwrap());
+        let peers = broadcaster.get_peers();
+        let peers = peers.lock().unwrap();
+        assert!(peers.contains_key(&SocketAddr::new(localhost, config.chat_port)));
+        assert!(peers.contains_key(&SocketAddr::new(localhost, 4000)));
+    }
+
+    #[test]
+    fn stale_peers_are_pruned() {
+        let broadcaster = Broadcaster::new(&Config::default(), "alice".into(), "node-a".into());
+        let mut stale = PeerInfo::new("bob");
+        stale.last_seen = Instant::now() - Duration::from_secs(120);
+        {
+            let peers = broadcaster.get_peers();
+            let mut peers = peers.lock().unwrap();
+            peers.insert(addr("10.0.0.2", 2223), stale);
+            peers.insert(addr("10.0.0.3", 2223), PeerInfo::new("carol"));
+        }
+
+        assert_eq!(broadcaster.prune_stale_peers(Duration::from_secs(60)), 1);
+        let peers = broadcaster.get_peers();
+        let peers = peers.lock().unwrap();
+        assert!(!peers.contains_key(&addr("10.0.0.2", 2223)));
+        assert!(peers.contains_key(&addr("10.0.0.3", 2223)));
+    }
+
+    #[tokio::test]
+    async fn the_same_packet_twice_is_delivered_once() {
+        let (_broadcaster, sender, _receiver, mut events) = loopback();
+        let message = Message::new(
+            "hello".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        let packet = message.encode_packet();
+        let target = addr("10.0.0.2", Config::default().chat_port);
+        sender.send_to(packet.as_bytes(), target).await.unwrap();
+        sender.send_to(packet.as_bytes(), target).await.unwrap();
+
+        assert_eq!(next_message(&mut events).await.unwrap().content(), "hello");
+        assert!(next_message(&mut events).await.is_none());
+    }
+
+    #[test]
+    fn saved_peers_load_back_the_same() {
+        let path = std::env::temp_dir()
+            .join(format!("reticulum-{}", std::process::id()))
+            .join("peers.json");
+        let broadcaster = Broadcaster::new(&Config::default(), "alice".into(), "node-a".into());
+        let saved = [
+            addr("10.0.0.2", 2223),
+            addr("10.0.0.3", 4000),
+            addr("fd7a:115c:a1e0::1", 2223),
+        ];
+        for peer in saved {
+            broadcaster
+                .get_peers()
+                .lock()
+                .unwrap()
+                .insert(peer, PeerInfo::new("bob"));
+        }
+
+        broadcaster.save_peers(&path).unwrap();
+        let loaded = Broadcaster::load_peers(&path);
+        let _ = fs::remove_dir_all(path.parent().unwrap());
+
+        let mut loaded: Vec<SocketAddr> = loaded.lock().unwrap().keys().copied().collect();
+        loaded.sort();
+        assert_eq!(loaded, saved);
+    }
+
+    #[test]
+    fn missing_or_corrupt_peer_files_load_empty() {
+        let dir = std::env::temp_dir().join(format!("reticulum-corrupt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("peers.json"), "[\"10.0.0.2:2223\", oops").unwrap();
+
+        assert!(Broadcaster::load_peers(&dir.join("peers.json"))
+            .lock()
+            .unwrap()
+            .is_empty());
+        assert!(Broadcaster::load_peers(&dir.join("missing.json"))
+            .lock()
+            .unwrap()
+            .is_empty());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn escaped_peer_addresses_load() {
+        let dir = std::env::temp_dir().join(format!("reticulum-escaped-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("peers.json"),
+            "[ \"10.0.0.2:2223\",\n  \"\\u0031\\u0030.0.0.3:4000\" ]",
+        )
+        .unwrap();
+
+        let loaded = Broadcaster::load_peers(&dir.join("peers.json"));
+        let _ = fs::remove_dir_all(&dir);
+        let mut loaded: Vec<SocketAddr> = loaded.lock().unwrap().keys().copied().collect();
+        loaded.sort();
+        assert_eq!(loaded, [addr("10.0.0.2", 2223), addr("10.0.0.3", 4000)]);
+    }
+
+    #[test]
+    fn direct_messages_carry_their_recipient() {
+        let message = Message::new(
+            "psst".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        )
+        .with_recipient("bob".to_string());
+        let parsed = Receiver::parse_message(message.encode_packet().as_bytes()).unwrap();
+        assert_eq!(parsed.msg_type, MSG_TYPE_DM);
+        assert_eq!(parsed.recipient.as_deref(), Some("bob"));
+        assert_eq!(parsed.content, "psst");
+    }
+
+    #[tokio::test]
+    async fn direct_messages_for_others_are_ignored() {
+        let (_broadcaster, sender, _receiver, mut events) = loopback();
+        let target = addr("10.0.0.2", Config::default().chat_port);
+        for (recipient, content) in [
+            ("carol", "not for bob"),
+            ("BOB", "for bob"),
+            ("", "unnamed"),
+        ] {
+            let message = Message::new(
+                content.to_string(),
+                "alice".to_string(),
+                "node-a".to_string(),
+            )
+            .with_recipient(recipient.to_string());
+            sender
+                .send_to(message.encode_packet().as_bytes(), target)
+                .await
+                .unwrap();
+        }
+
+        // An empty recipient is a peer whose name the sender didn't know yet
+        assert_eq!(
+            next_message(&mut events).await.unwrap().content(),
+            "for bob"
+        );
+        assert_eq!(
+            next_message(&mut events).await.unwrap().content(),
+            "unnamed"
+        );
+        assert!(next_message(&mut events).await.is_none());
+    }
+
+    #[test]
+    fn direct_message_targets_resolve_by_name_or_address() {
+        let broadcaster = Broadcaster::new(&Config::default(), "alice".into(), "node-a".into());
+        let bob = addr("10.0.0.2", 2223);
+        broadcaster
+            .get_peers()
+            .lock()
+            .unwrap()
+            .insert(bob, PeerInfo::new("bob"));
+
+        assert_eq!(
+            broadcaster.resolve_peer("Bob").map(|(addr, _)| addr),
+            Some(bob)
+        );
+        assert_eq!(
+            broadcaster.resolve_peer("10.0.0.2").map(|(addr, _)| addr),
+            Some(bob)
+        );
+        assert_eq!(
+            broadcaster
+                .resolve_peer("10.0.0.2:2223")
+                .map(|(addr, _)| addr),
+            Some(bob)
+        );
+        // Peers we don't know can't be messaged
+        assert!(broadcaster.resolve_peer("carol").is_none());
+        assert!(broadcaster.resolve_peer("10.0.0.9").is_none());
+        assert!(broadcaster.resolve_peer("10.0.0.2:4000").is_none());
+    }
+
+    #[test]
+    fn rediscovery_updates_the_name_in_place() {
+        let mut peers = HashMap::new();
+        let bob = addr("10.0.0.2", 2224);
+        assert!(record_peer(&mut peers, bob, "bob", None));
+        assert!(!record_peer(&mut peers, bob, "bobby", None));
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[&bob].name, "bobby");
+
+        // A name we don't know yet never replaces one we learned
+        assert!(!record_peer(&mut peers, bob, UNKNOWN_PEER_NAME, None));
+        assert!(!record_peer(&mut peers, bob, "", None));
+        assert_eq!(peers[&bob].name, "bobby");
+    }
+
+    #[test]
+    fn one_node_on_several_ports_is_one_peer() {
+        let mut peers = HashMap::new();
+        let discovery = addr("10.0.0.2", 2224);
+        let chat = addr("10.0.0.2", 2223);
+        let restarted = addr("10.0.0.2", 41000);
+        assert!(record_peer(&mut peers, discovery, "bob", Some("node-b")));
+        assert!(!record_peer(&mut peers, chat, "bob", Some("node-b")));
+        assert!(!record_peer(&mut peers, restarted, "bob", Some("node-b")));
+
+        // Kept under the address it was heard from last
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[&restarted].node.as_deref(), Some("node-b"));
+
+        // Another node on the same host is a peer of its own
+        assert!(record_peer(&mut peers, chat, "carol", Some("node-c")));
+        assert_eq!(peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn repeated_discovery_responses_collapse_to_one_peer() {
+        let receiver = Receiver::new(
+            &Config::default(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        );
+        let mut events = receiver.take_events().unwrap();
+        let socket = DualSocket::bind(0, Some(IpAddr::V4(Ipv4Addr::LOCALHOST))).unwrap();
+        let response = discovery_packet(MSG_TYPE_DISCOVERY_RESPONSE, "bob", "node-b");
+
+        for port in [2224, 2224, 50123] {
+            receiver
+                .handle_discovery(&socket, addr("10.0.0.2", port), response.as_bytes())
+                .await
+                .unwrap();
+        }
+
+        let peers = receiver.get_peers();
+        let peers = peers.lock().unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers.values().next().unwrap().name, "bob");
+        // Announced as joining once
+        assert!(matches!(events.try_recv(), Ok(NetworkEvent::PeerJoined(name)) if name == "bob"));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn messages_arrive_over_ipv6() {
+        let config = Config::default();
+        let network = MemoryNetwork::default();
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+        let socket = network.bind(addr("fd7a:115c:a1e0::2", config.chat_port));
+        tokio::spawn(async move { receiver.listen_for_messages(socket).await });
+
+        let sender = network.bind(addr("fd7a:115c:a1e0::1", 40000));
+        let message = Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string());
+        let target: SocketAddr = format!("[fd7a:115c:a1e0::2]:{}", config.chat_port)
+            .parse()
+            .unwrap();
+        sender
+            .send_to(message.encode_packet().as_bytes(), target)
+            .await
+            .unwrap();
+
+        let received = next_message(&mut events).await.unwrap();
+        assert_eq!(received.content(), "hi");
+        // Shown without brackets or port, like IPv4 senders
+        assert_eq!(received.sender_ip(), "fd7a:115c:a1e0::1");
+    }
+
+    #[test]
+    fn ipv6_peers_resolve_with_and_without_a_port() {
+        let broadcaster = Broadcaster::new(&Config::default(), "alice".into(), "node-a".into());
+        let bob = addr("fd7a:115c:a1e0::2", 2223);
+        broadcaster
+            .get_peers()
+            .lock()
+            .unwrap()
+            .insert(bob, PeerInfo::new("bob"));
+
+        assert_eq!(bob.to_string(), "[fd7a:115c:a1e0::2]:2223");
+        for target in [
+            "fd7a:115c:a1e0::2",
+            "[fd7a:115c:a1e0::2]:2223",
+            "FD7A:115C:A1E0::2",
+        ] {
+            assert_eq!(
+                broadcaster.resolve_peer(target).map(|(addr, _)| addr),
+                Some(bob)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn large_messages_arrive_in_one_piece() {
+        let (broadcaster, sender, _receiver, mut events) = loopback();
+        // 21 KB of CJK characters, under MAX_CONTENT_CHARS and varied enough not to
+        // compress below the fragment size
+        let content: String = (0..7000u32)
+            .map(|i| char::from_u32(0x4e00 + i.wrapping_mul(2_654_435_761) % 0x5000).unwrap())
+            .collect();
+        assert!(content.len() > 20 * 1024);
+        let message = Message::new(content.clone(), "alice".to_string(), "node-a".to_string());
+        broadcaster
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+
+        assert_eq!(next_message(&mut events).await.unwrap().content(), content);
+        assert!(next_message(&mut events).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn clones_share_one_event_queue() {
+        let receiver = Receiver::new(&Config::default(), "bob".to_string(), "node-b".to_string());
+        let clone = receiver.clone();
+        let mut events = receiver.take_events().unwrap();
+        // Only one consumer gets the events, whichever handle it asks through
+        assert!(clone.take_events().is_none());
+
+        let message = Message::new(
+            "hi".to_string(),
+            "alice".to_string(),
+            "10.0.0.1".to_string(),
+        );
+        clone.send_event(NetworkEvent::Message(message.clone()));
+        clone.clone().send_event(NetworkEvent::Typing);
+
+        assert_eq!(next_message(&mut events).await.unwrap().id(), message.id());
+        assert!(matches!(events.try_recv(), Ok(NetworkEvent::Typing)));
+    }
+
+    #[tokio::test]
+    async fn events_wake_a_waiting_consumer() {
+        let receiver = Receiver::new(&Config::default(), "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+        let consumer = tokio::spawn(async move { events.recv().await });
+
+        // The consumer is parked on the channel, not looping
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(!consumer.is_finished());
+
+        receiver.send_event(NetworkEvent::Typing);
+        let event = time::timeout(Duration::from_millis(100), consumer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(event, Ok(NetworkEvent::Typing)));
+    }
+
+    #[test]
+    fn heartbeats_round_trip() {
+        let packet = discovery_packet(MSG_TYPE_HEARTBEAT, "alice", "node-a");
+        let parsed = Receiver::parse_discovery(packet.as_bytes()).unwrap();
+        assert_eq!(parsed.msg_type, MSG_TYPE_HEARTBEAT);
+        assert_eq!(parsed.sender_name, "alice");
+        assert_eq!(parsed.sender_node, "node-a");
+        // Not chat, so the chat port turns it away
+        assert!(Receiver::parse_message(packet.as_bytes()).is_err());
+    }
+
+    #[test]
+    fn leaves_round_trip() {
+        let packet = discovery_packet(MSG_TYPE_LEAVE, "alice", "node-a");
+        let parsed = Receiver::parse_discovery(packet.as_bytes()).unwrap();
+        assert_eq!(parsed.msg_type, MSG_TYPE_LEAVE);
+        assert_eq!(parsed.sender_name, "alice");
+        assert_eq!(parsed.sender_node, "node-a");
+    }
+
+    #[test]
+    fn leaving_removes_every_entry_for_the_host() {
+        let mut peers = HashMap::new();
+        peers.insert(addr("10.0.0.2", 2223), PeerInfo::new("bob"));
+        peers.insert(addr("10.0.0.2", 2224), PeerInfo::new(UNKNOWN_PEER_NAME));
+        // Another user behind the same address stays
+        peers.insert(addr("10.0.0.2", 3223), PeerInfo::new("carol"));
+        peers.insert(addr("10.0.0.3", 2223), PeerInfo::new("bob"));
+
+        // The leave comes from the discovery port, not the chat port
+        assert_eq!(forget_peer(&mut peers, addr("10.0.0.2", 50123), "bob"), 2);
+        assert_eq!(peers.len(), 2);
+        assert!(peers.contains_key(&addr("10.0.0.2", 3223)));
+        assert!(peers.contains_key(&addr("10.0.0.3", 2223)));
+    }
+
+    #[test]
+    fn heartbeats_refresh_last_seen() {
+        let mut peers = HashMap::new();
+        let chat = addr("10.0.0.2", 2223);
+        let discovery = addr("10.0.0.2", 2224);
+        let an_hour_ago = Instant::now() - Duration::from_secs(3600);
+        for peer in [chat, discovery] {
+            let mut info = PeerInfo::new("bob");
+            info.last_seen = an_hour_ago;
+            peers.insert(peer, info);
+        }
+
+        // Heartbeats come from a fresh port, matched by IP
+        assert!(!touch_peer(
+            &mut peers,
+            addr("10.0.0.2", 50123),
+            "bob",
+            "node-b"
+        ));
+        assert_eq!(peers.len(), 2);
+        assert!(peers[&chat].last_seen > an_hour_ago);
+        assert!(peers[&discovery].last_seen > an_hour_ago);
+
+        assert!(touch_peer(
+            &mut peers,
+            addr("10.0.0.3", 50123),
+            "carol",
+            "node-c"
+        ));
+        assert_eq!(peers.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn a_flooding_peer_is_rate_limited() {
+        let (_broadcaster, sender, _receiver, mut events) = loopback();
+        let config = Config::default();
+        let target = addr("10.0.0.2", config.chat_port);
+        for i in 0..100 {
+            let message = Message::new(i.to_string(), "alice".to_string(), "node-a".to_string());
+            sender
+                .send_to(message.encode_packet().as_bytes(), target)
+                .await
+                .unwrap();
+        }
+
+        let mut received = 0;
+        while next_message(&mut events).await.is_some() {
+            received += 1;
+        }
+        // A slow test run can let a token or two refill while the flood is handled
+        let burst = config.max_messages_per_sec as usize;
+        assert!(
+            (burst..burst + 5).contains(&received),
+            "{} got through",
+            received
+        );
+    }
+
+    #[test]
+    fn network_errors_keep_their_kind_and_cause() {
+        use std::error::Error;
+
+        let refused = || io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        let send: NetworkError = refused().into();
+        assert!(
+            matches!(&send, NetworkError::Send(e) if e.kind() == io::ErrorKind::ConnectionRefused)
+        );
+        assert_eq!(send.to_string(), "send failed: refused");
+        assert!(!send.is_fatal());
+
+        let recv = NetworkError::Recv(refused());
+        assert_eq!(recv.to_string(), "receive failed: refused");
+        assert!(!recv.is_fatal());
+        assert!(NetworkError::Bind(refused()).is_fatal());
+
+        let parse: NetworkError = ParseError::Malformed("too short".to_string()).into();
+        assert!(matches!(
+            parse,
+            NetworkError::Parse(ParseError::Malformed(_))
+        ));
+        assert!(parse.is_malformed());
+        assert!(parse.source().is_some());
+
+        let decode: NetworkError = DecodeError::Unauthenticated.into();
+        assert!(matches!(
+            decode,
+            NetworkError::Decode(DecodeError::Unauthenticated)
+        ));
+        assert!(decode.is_unauthenticated());
+        assert!(!decode.is_malformed());
+        // Someone else's traffic isn't malformed
+        assert!(
+            !NetworkError::from(ParseError::NotChat(MSG_TYPE_HEARTBEAT.to_string())).is_malformed()
+        );
+    }
+
+    // Every clone of the receiver shares its peer list
+    fn receiver_clones(receiver: &Receiver) -> usize {
+        Arc::strong_count(&receiver.peers)
+    }
+
+    // Waits for clones held by threads that are winding down to be dropped
+    async fn settle_clones(receiver: &Receiver, expected: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while receiver_clones(receiver) != expected && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(receiver_clones(receiver), expected);
+    }
+
+    #[tokio::test]
+    async fn a_cancelled_mdns_service_stops_browsing() {
+        let receiver = Receiver::new(&Config::default(), "bob".to_string(), "node-b".to_string());
+        let alone = receiver_clones(&receiver);
+
+        let cancelled = time::timeout(Duration::from_millis(100), receiver.mdns_service()).await;
+        assert!(
+            cancelled.is_err(),
+            "mDNS stopped by itself: {:?}",
+            cancelled
+        );
+
+        // The browse thread holds a clone of the receiver until it ends
+        settle_clones(&receiver, alone).await;
+    }
+
+    #[tokio::test]
+    async fn reconnects_keep_a_single_mdns_browser() {
+        let receiver = Receiver::new(&Config::default(), "bob".to_string(), "node-b".to_string());
+        let alone = receiver_clones(&receiver);
+
+        let restarts = Restarts::new();
+        let supervisor = {
+            let (receiver, restarts) = (receiver.clone(), restarts.clone());
+            tokio::spawn(async move {
+                let start = || {
+                    let receiver = receiver.clone();
+                    async move { receiver.mdns_service().await }
+                };
+                supervise("mDNS discovery", start, &restarts, |_| {}).await
+            })
+        };
+        time::sleep(Duration::from_millis(100)).await;
+        let running = receiver_clones(&receiver);
+
+        // Each reconnect replaces the daemon and its browse thread rather than adding one
+        for _ in 0..3 {
+            restarts.restart_all();
+            time::sleep(Duration::from_millis(50)).await;
+        }
+        settle_clones(&receiver, running).await;
+
+        supervisor.abort();
+        settle_clones(&receiver, alone).await;
+    }
+
+    #[tokio::test]
+    async fn an_occupied_port_explains_itself() {
+        // A port that was free a moment ago, then held by another instance's listener
+        let free = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
+        let config = Config {
+            chat_port: free.local_addr().unwrap().port(),
+            ..Config::default()
+        };
+        drop(free);
+        let _first = Receiver::bind_chat(&config).unwrap();
+
+        let error = Receiver::bind_chat(&config).err().unwrap();
+        assert!(error.is_fatal());
+        let text = error.to_string();
+        assert!(
+            text.contains(&format!("on port {}", config.chat_port)),
+            "{}",
+            text
+        );
+        assert!(text.contains("already in use"), "{}", text);
+        assert!(text.contains("--chat-port"), "{}", text);
+    }
+
+    #[test]
+    fn privileged_ports_explain_themselves() {
+        let error = listen_error(
+            io::Error::from(io::ErrorKind::PermissionDenied),
+            80,
+            "peer discovery",
+            "--discovery-port",
+        );
+        assert_eq!(
+            error.to_string(),
+            "Could not listen for peer discovery on port 80: permission denied. Ports below \
+             1024 usually need elevated privileges, choose another with --discovery-port <PORT>"
+        );
+    }
+
+    #[test]
+    fn actions_round_trip() {
+        let message = Message::new(
+            "waves".to_string(),
+            "alice".to_string(),
+            "node-a".to_string(),
+        )
+        .into_action();
+        let packet = message.encode_packet();
+        assert!(packet.starts_with(&format!(
+            "{}{}",
+            MSG_TYPE_ACTION,
+            crate::constants::FIELD_SPLITTER
+        )));
+        let parsed = Receiver::parse_message(packet.as_bytes()).unwrap();
+        assert_eq!(parsed.msg_type, MSG_TYPE_ACTION);
+        assert_eq!(parsed.content, "waves");
+    }
+
+    #[test]
+    fn discovery_carries_our_protocol_version() {
+        let packet = discovery_packet(MSG_TYPE_DISCOVERY, "alice", "node-a");
+        let parsed = Receiver::parse_discovery(packet.as_bytes()).unwrap();
+        assert_eq!(parse_protocol_version(&parsed.content), PROTOCOL_VERSION);
+    }
+
+    #[tokio::test]
+    async fn history_requests_carry_the_count() {
+        let (_, sender, _receiver, mut events) = loopback();
+        let packet = join_fields(&[MSG_TYPE_HISTORY_REQ, "alice", "node-a", "20"]);
+        let target = addr("10.0.0.2", Config::default().chat_port);
+        sender.send_to(packet.as_bytes(), target).await.unwrap();
+
+        let event = time::timeout(Duration::from_millis(300), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            event,
+            NetworkEvent::HistoryRequest { from, count: 20 } if from.ip() == addr("10.0.0.1", 0).ip()
+        ));
+    }
+
+    #[tokio::test]
+    async fn history_arrives_once_with_its_original_id_and_time() {
+        let (_, sender, _receiver, mut events) = loopback();
+        let earlier = Message::new(
+            "earlier".to_string(),
+            "carol".to_string(),
+            "node-c".to_string(),
+        )
+        .with_timestamp(1_700_000_000_000);
+        let packet = history_packet("node-a", &earlier);
+        let target = addr("10.0.0.2", Config::default().chat_port);
+
+        // Asking two peers can bring back the same message twice
+        for _ in 0..2 {
+            sender.send_to(packet.as_bytes(), target).await.unwrap();
+        }
+
+        let event = time::timeout(Duration::from_millis(300), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let NetworkEvent::History(message) = event else {
+            panic!("expected history");
+        };
+        assert_eq!(message.id(), earlier.id());
+        assert_eq!(message.timestamp(), earlier.timestamp());
+        assert_eq!(message.sender_name(), "carol");
+        assert_eq!(message.content(), "earlier");
+        assert!(time::timeout(Duration::from_millis(300), events.recv())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn long_history_is_fragmented_to_fit_the_receive_buffer() {
+        let config = Config {
+            recv_buffer_size: MIN_RECV_BUFFER_SIZE,
+            ..Config::default()
+        };
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+        let socket = network.bind(addr("10.0.0.2", config.chat_port));
+        tokio::spawn(async move { receiver.listen_for_messages(socket).await });
+
+        // Three bytes a character, far more than one datagram the receiver can take
+        let content = "漢".repeat(2000);
+        let earlier = Message::new(content.clone(), "carol".to_string(), "node-c".to_string());
+        let id = earlier.id();
+        broadcaster
+            .send_history_over(&sender, addr("10.0.0.2", 0), &[earlier])
+            .await
+            .unwrap();
+
+        let event = time::timeout(Duration::from_millis(300), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let NetworkEvent::History(message) = event else {
+            panic!("expected history");
+        };
+        assert_eq!(message.id(), id);
+        assert_eq!(message.content(), content);
+    }
+
+    #[tokio::test]
+    async fn long_edits_are_fragmented_to_fit_the_receive_buffer() {
+        let config = Config {
+            recv_buffer_size: MIN_RECV_BUFFER_SIZE,
+            ..Config::default()
+        };
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+        let socket = network.bind(addr("10.0.0.2", config.chat_port));
+        tokio::spawn(async move { receiver.listen_for_messages(socket).await });
+
+        let content = "🦀".repeat(2000);
+        let packet = about_packet(MSG_TYPE_EDIT, "alice", "node-a", 42, &content);
+        let packets = text_packets(&packet, 7, false).unwrap();
+        assert!(packets.len() > 1);
+        send_packets(&sender, &packets, addr("10.0.0.2", config.chat_port))
+            .await
+            .unwrap();
+
+        let event = time::timeout(Duration::from_millis(300), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let NetworkEvent::Edited {
+            id,
+            content: edited,
+            ..
+        } = event
+        else {
+            panic!("expected an edit");
+        };
+        assert_eq!(id, 42);
+        assert_eq!(edited, content);
+    }
+
+    #[tokio::test]
+    async fn blocked_sources_are_dropped_and_counted() {
+        let config = Config {
+            denylist: vec!["10.0.0.1".parse().unwrap()],
+            ..Config::default()
+        };
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+        let socket = network.bind(addr("10.0.0.2", config.chat_port));
+        tokio::spawn(async move { receiver.listen_for_messages(socket).await });
+
+        let before = stats::snapshot();
+        let message = Message::new("hi".to_string(), "alice".to_string(), "node-a".to_string());
+        broadcaster
+            .broadcast_message_over(&sender, message)
+            .await
+            .unwrap();
+
+        assert!(next_message(&mut events).await.is_none());
+        assert!(stats::snapshot().blocked_dropped > before.blocked_dropped);
+    }
+
+    #[tokio::test]
+    async fn a_reconnect_rebinds_the_listener_and_keeps_receiving() {
+        let config = Config::default();
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let broadcaster = Broadcaster::new(&config, "alice".to_string(), "node-a".to_string());
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        let mut events = receiver.take_events().unwrap();
+
+        let binds = Arc::new(AtomicUsize::new(0));
+        let restarts = Restarts::new();
+        let listener = {
+            let (network, binds, restarts) = (network.clone(), binds.clone(), restarts.clone());
+            tokio::spawn(async move {
+                let start = || {
+                    binds.fetch_add(1, Ordering::SeqCst);
+                    let socket = network.bind(addr("10.0.0.2", config.chat_port));
+                    let receiver = receiver.clone();
+                    async move { receiver.listen_for_messages(socket).await }
+                };
+                supervise("Listener", start, &restarts, |_| {}).await
+            })
+        };
+
+        let say =
+            |text: &str| Message::new(text.to_string(), "alice".to_string(), "node-a".to_string());
+        // Nothing gets through until the listener has bound its socket
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(binds.load(Ordering::SeqCst), 1);
+        broadcaster
+            .broadcast_message_over(&sender, say("before"))
+            .await
+            .unwrap();
+        assert_eq!(next_message(&mut events).await.unwrap().content(), "before");
+
+        restarts.restart_all();
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(binds.load(Ordering::SeqCst), 2);
+
+        // The fresh socket is the one that gets messages now
+        broadcaster
+            .broadcast_message_over(&sender, say("after"))
+            .await
+            .unwrap();
+        assert_eq!(next_message(&mut events).await.unwrap().content(), "after");
+        listener.abort();
+    }
+
+    #[tokio::test]
+    async fn packets_longer_than_the_buffer_are_counted_as_truncated() {
+        let config = Config {
+            recv_buffer_size: MIN_RECV_BUFFER_SIZE,
+            ..Config::default()
+        };
+        let network = MemoryNetwork::default();
+        let sender = network.bind(addr("10.0.0.1", config.chat_port));
+        let receiver = Receiver::new(&config, "bob".to_string(), "node-b".to_string());
+        assert_eq!(receiver.recv_buffer().len(), MIN_RECV_BUFFER_SIZE + 1);
+        let mut events = receiver.take_events().unwrap();
+        let socket = network.bind(addr("10.0.0.2", config.chat_port));
+        tokio::spawn(async move { receiver.listen_for_messages(socket).await });
+
+        let before = stats::snapshot();
+        let target = addr("10.0.0.2", config.chat_port);
+        let fits = Message::new("a".repeat(1000), "alice".to_string(), "node-a".to_string());
+        sender
+            .send_to(fits.encode_packet().as_bytes(), target)
+            .await
+            .unwrap();
+        assert_eq!(next_message(&mut events).await.unwrap().id(), fits.id());
+        assert!(stats::snapshot().largest_packet >= fits.encode_packet().len() as u64);
+
+        let too_long = Message::new("a".repeat(5000), "alice".to_string(), "node-a".to_string());
+        sender
+            .send_to(too_long.encode_packet().as_bytes(), target)
+            .await
+            .unwrap();
+        assert!(next_message(&mut events).await.is_none());
+        assert!(stats::snapshot().truncated_dropped > before.truncated_dropped);
+    }
+
+    #[tokio::test]
+    async fn messages_tagged_under_a_passphrase_we_lack_are_dropped() {
+        // No test sets the process-wide passphrase, so the tag is made with a cipher of
+        // our own, as a peer with a passphrase would
+        let (_, sender, _receiver, mut events) = loopback();
+        let cipher = crate::crypto::Cipher::with_iterations("hunter2", 2);
+        let timestamp = chrono::Utc::now().timestamp_millis();
+        let envelope = Envelope {
+            msg_type: MSG_TYPE_CHAT,
+            id: 42,
+            node: "node-a",
+            name: "alice",
+            timestamp,
+            recipient: None,
+        };
+        let packet = join_fields(&[
+            MSG_TYPE_CHAT,
+            "alice",
+            "node-a",
+            "42",
+            &timestamp.to_string(),
+            &cipher.sign(&envelope, "trust me"),
+        ]);
+
+        let before = stats::snapshot();
+        let target = addr("10.0.0.2", Config::default().chat_port);
+        sender.send_to(packet.as_bytes(), target).await.unwrap();
+        assert!(next_message(&mut events).await.is_none());
+        assert!(stats::snapshot().unauthenticated_dropped > before.unauthenticated_dropped);
+    }
+
+    #[test]
+    fn notices_tagged_under_a_passphrase_we_lack_are_rejected() {
+        let cipher = crate::crypto::Cipher::with_iterations("hunter2", 2);
+        for (msg_type, content) in [
+            (MSG_TYPE_TYPING, "1"),
+            (MSG_TYPE_STATUS, "away"),
+            (MSG_TYPE_PING, "1700000000123"),
+            (MSG_TYPE_HISTORY_REQ, "20"),
+        ] {
+            let envelope = Envelope {
+                msg_type,
+                id: 0,
+                node: "node-a",
+                name: "alice",
+                timestamp: 0,
+                recipient: None,
+            };
+            let packet = join_fields(&[
+                msg_type,
+                "alice",
+                "node-a",
+                &cipher.sign(&envelope, content),
+            ]);
+            let parsed = Receiver::parse_message(packet.as_bytes());
+            assert!(
+                matches!(parsed, Err(ref e) if e.is_unauthenticated()),
+                "{}",
+                msg_type
+            );
         }
     }
 }
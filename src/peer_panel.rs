@@ -0,0 +1,180 @@
+// The live peer list shown at the right of the message area, toggled with Ctrl+P
+
+use crate::constants::{
+    MIN_MESSAGE_AREA_WIDTH, PEER_PANEL_WIDTH, PEER_STALE_SECS, UNKNOWN_PEER_NAME,
+};
+use crate::networking::PeerList;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+// A host as listed in the panel
+pub struct PanelPeer {
+    pub name: String,
+    pub ip: IpAddr,
+    pub last_seen: Instant,
+    pub away: bool,
+}
+
+// Splits the terminal width into the message area and the panel. The panel gets no columns
+// when it's closed or when it would leave the messages too narrow.
+pub fn split_width(width: usize, panel_open: bool) -> (usize, usize) {
+    if panel_open && width >= PEER_PANEL_WIDTH + MIN_MESSAGE_AREA_WIDTH {
+        (width - PEER_PANEL_WIDTH, PEER_PANEL_WIDTH)
+    } else {
+        (width, 0)
+    }
+}
+
+// One entry per host, sorted by name. A peer heard from several ports is listed once, with
+// the first name we learned for it and the most recent time we heard from any of them.
+pub fn panel_peers(peers: &PeerList) -> Vec<PanelPeer> {
+    let mut by_ip: HashMap<IpAddr, PanelPeer> = HashMap::new();
+    for (addr, info) in peers.lock().unwrap().iter() {
+        let peer = by_ip.entry(addr.ip()).or_insert_with(|| PanelPeer {
+            name: info.name.clone(),
+            ip: addr.ip(),
+            last_seen: info.last_seen,
+            away: false,
+        });
+        if peer.name == UNKNOWN_PEER_NAME {
+            peer.name = info.name.clone();
+        }
+        peer.last_seen = peer.last_seen.max(info.last_seen);
+        peer.away |= info.away;
+    }
+
+    let mut peers: Vec<PanelPeer> = by_ip.into_values().collect();
+    peers.sort_by(|a, b| a.name.cmp(&b.name).then(a.ip.cmp(&b.ip)));
+    peers
+}
+
+// How the network looks from here, shown in the status bar
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    // Some peer was heard from within PEER_STALE_SECS
+    Good,
+    // We still have peers, but none of them has been heard from lately
+    Stale,
+    // No peers at all
+    Alone,
+}
+
+impl Health {
+    pub fn glyph(self) -> &'static str {
+        match self {
+            Health::Good => "🟢",
+            Health::Stale => "🟡",
+            Health::Alone => "🔴",
+        }
+    }
+}
+
+pub fn health(peers: &[PanelPeer], now: Instant) -> Health {
+    let stale_after = Duration::from_secs(PEER_STALE_SECS);
+    if peers.is_empty() {
+        Health::Alone
+    } else if peers
+        .iter()
+        .any(|peer| now.saturating_duration_since(peer.last_seen) <= stale_after)
+    {
+        Health::Good
+    } else {
+        Health::Stale
+    }
+}
+
+// The status bar segment, like "🟢 3 peers"
+pub fn health_summary(peers: &[PanelPeer], now: Instant) -> String {
+    let noun = if peers.len() == 1 { "peer" } else { "peers" };
+    format!("{} {} {}", health(peers, now).glyph(), peers.len(), noun)
+}
+
+// How long ago, in the largest whole unit
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0 => "just now".to_string(),
+        1..=59 => format!("{}s ago", secs),
+        60..=3599 => format!("{}m ago", secs / 60),
+        _ => format!("{}h ago", secs / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::networking::PeerInfo;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn the_panel_only_opens_when_there_is_room() {
+        let narrowest = PEER_PANEL_WIDTH + MIN_MESSAGE_AREA_WIDTH;
+        assert_eq!(
+            split_width(120, true),
+            (120 - PEER_PANEL_WIDTH, PEER_PANEL_WIDTH)
+        );
+        assert_eq!(
+            split_width(narrowest, true),
+            (MIN_MESSAGE_AREA_WIDTH, PEER_PANEL_WIDTH)
+        );
+        assert_eq!(split_width(narrowest - 1, true), (narrowest - 1, 0));
+        assert_eq!(split_width(0, true), (0, 0));
+        assert_eq!(split_width(120, false), (120, 0));
+    }
+
+    #[test]
+    fn hosts_are_listed_once_by_name() {
+        let addr = |text: &str| text.parse::<SocketAddr>().unwrap();
+        let mut away = PeerInfo::new("bob");
+        away.away = true;
+        let peers: PeerList = Arc::new(Mutex::new(HashMap::from([
+            (addr("10.0.0.3:2223"), PeerInfo::new(UNKNOWN_PEER_NAME)),
+            (addr("10.0.0.3:2224"), away),
+            (addr("10.0.0.2:2223"), PeerInfo::new("alice")),
+        ])));
+
+        let listed = panel_peers(&peers);
+        let names: Vec<&str> = listed.iter().map(|peer| peer.name.as_str()).collect();
+        assert_eq!(names, ["alice", "bob"]);
+        assert!(listed[1].away);
+        assert_eq!(health(&listed, Instant::now()), Health::Good);
+        assert_eq!(health_summary(&[], Instant::now()), "🔴 0 peers");
+    }
+
+    #[test]
+    fn ages_use_the_largest_whole_unit() {
+        assert_eq!(format_age(Duration::from_millis(900)), "just now");
+        assert_eq!(format_age(Duration::from_secs(59)), "59s ago");
+        assert_eq!(format_age(Duration::from_secs(61)), "1m ago");
+        assert_eq!(format_age(Duration::from_secs(7300)), "2h ago");
+    }
+
+    #[test]
+    fn health_follows_the_freshest_peer() {
+        let now = Instant::now();
+        let seen = |secs_ago: u64| PanelPeer {
+            name: "bob".to_string(),
+            ip: "10.0.0.2".parse().unwrap(),
+            last_seen: now - Duration::from_secs(secs_ago),
+            away: false,
+        };
+
+        assert_eq!(health(&[], now), Health::Alone);
+        assert_eq!(health(&[seen(0)], now), Health::Good);
+        assert_eq!(health(&[seen(PEER_STALE_SECS)], now), Health::Good);
+        assert_eq!(health(&[seen(PEER_STALE_SECS + 1)], now), Health::Stale);
+        // One recent peer is enough
+        assert_eq!(
+            health(&[seen(PEER_STALE_SECS * 3), seen(1)], now),
+            Health::Good
+        );
+
+        assert_eq!(health_summary(&[seen(1)], now), "🟢 1 peer");
+        assert_eq!(
+            health_summary(&[seen(PEER_STALE_SECS + 5), seen(PEER_STALE_SECS + 9)], now),
+            "🟡 2 peers"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+// Round trips measured with /ping. A PING is MSG_TYPE_PING, name, node, then the time it
+// was sent in milliseconds on our clock. The peer echoes that time back unchanged in a
+// PONG, so the round trip doesn't depend on the peer's clock being right.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+// Pings still waiting for their pong, one per peer host
+pub struct PendingPings {
+    // Peer to its name and when we pinged it
+    pending: HashMap<IpAddr, (String, i64)>,
+}
+
+impl PendingPings {
+    pub fn new() -> Self {
+        Self {
+            pending: HashMap::new(),
+        }
+    }
+
+    // Pinging a peer again replaces the earlier ping
+    pub fn track(&mut self, peer: IpAddr, name: &str, sent_ms: i64) {
+        self.pending.insert(peer, (name.to_string(), sent_ms));
+    }
+
+    // Takes the ping a pong answers, returning the peer's name. Pongs for pings we didn't
+    // send, already answered or timed out give None.
+    pub fn answer(&mut self, peer: IpAddr, sent_ms: i64) -> Option<String> {
+        match self.pending.get(&peer) {
+            Some((_, pending_ms)) if *pending_ms == sent_ms => {
+                self.pending.remove(&peer).map(|(name, _)| name)
+            }
+            _ => None,
+        }
+    }
+
+    // Stops waiting for pings sent more than timeout before now, returning the peers' names
+    pub fn expire(&mut self, now_ms: i64, timeout: Duration) -> Vec<String> {
+        let timeout_ms = i64::try_from(timeout.as_millis()).unwrap_or(i64::MAX);
+        let mut expired = Vec::new();
+        self.pending.retain(|_, (name, sent_ms)| {
+            let waiting = now_ms.saturating_sub(*sent_ms) <= timeout_ms;
+            if !waiting {
+                expired.push(name.clone());
+            }
+            waiting
+        });
+        expired.sort();
+        expired
+    }
+}
+
+// Time between sending a ping and its pong arriving. Zero if the clock went backwards.
+pub fn round_trip(sent_ms: i64, received_ms: i64) -> Duration {
+    Duration::from_millis(u64::try_from(received_ms.saturating_sub(sent_ms)).unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn round_trips_come_from_our_own_clock() {
+        assert_eq!(round_trip(1_000, 1_042), Duration::from_millis(42));
+        assert_eq!(round_trip(1_000, 1_000), Duration::ZERO);
+        assert_eq!(round_trip(1_000, 900), Duration::ZERO);
+    }
+
+    #[test]
+    fn only_the_latest_ping_is_answered() {
+        let mut pings = PendingPings::new();
+        pings.track(ip("10.0.0.2"), "bob", 1_000);
+        pings.track(ip("10.0.0.2"), "bob", 2_000);
+        assert_eq!(pings.answer(ip("10.0.0.2"), 1_000), None);
+        assert_eq!(pings.answer(ip("10.0.0.3"), 2_000), None);
+        assert_eq!(pings.answer(ip("10.0.0.2"), 2_000).as_deref(), Some("bob"));
+        // Each ping is answered once
+        assert_eq!(pings.answer(ip("10.0.0.2"), 2_000), None);
+    }
+
+    #[test]
+    fn unanswered_pings_time_out() {
+        let mut pings = PendingPings::new();
+        pings.track(ip("10.0.0.2"), "bob", 1_000);
+        pings.track(ip("10.0.0.3"), "alice", 4_000);
+        pings.track(ip("10.0.0.4"), "carol", 1_500);
+
+        let timeout = Duration::from_secs(5);
+        assert!(pings.expire(6_000, timeout).is_empty());
+        assert_eq!(pings.expire(6_600, timeout), ["bob", "carol"]);
+        assert_eq!(
+            pings.answer(ip("10.0.0.3"), 4_000).as_deref(),
+            Some("alice")
+        );
+    }
+}
@@ -0,0 +1,83 @@
+// Wire format versions. DISCOVER, DISCOVER_RESPONSE, HEARTBEAT and LEAVE packets carry the
+// sender's PROTOCOL_VERSION after its name and node ID, so each side knows what the other
+// understands. Peers from before versioning leave the field out.
+
+use crate::constants::{
+    AUTH_PROTOCOL_VERSION, BINARY_PROTOCOL_VERSION, COMPRESSION_PROTOCOL_VERSION,
+    LEGACY_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use std::cmp::Ordering;
+
+// The version announced in a discovery packet's content. Anything that isn't a version
+// number comes from a peer that predates versioning.
+pub fn parse_protocol_version(field: &str) -> u32 {
+    match field.trim().parse::<u32>() {
+        Ok(version) if version >= LEGACY_PROTOCOL_VERSION => version,
+        _ => LEGACY_PROTOCOL_VERSION,
+    }
+}
+
+// Whether chat packets for the peer can use the binary format. Peers whose version we
+// haven't learned yet get the text format, which everyone understands.
+pub fn supports_binary(peer_version: Option<u32>) -> bool {
+    peer_version.is_some_and(|version| version >= BINARY_PROTOCOL_VERSION)
+}
+
+// Whether packets for the peer can be compressed, see codec::compress_packet
+pub fn supports_compression(peer_version: Option<u32>) -> bool {
+    peer_version.is_some_and(|version| version >= COMPRESSION_PROTOCOL_VERSION)
+}
+
+// Whether the peer tags its messages when a passphrase is set, see crypto::sign_content
+pub fn supports_authentication(peer_version: u32) -> bool {
+    peer_version >= AUTH_PROTOCOL_VERSION
+}
+
+// Describes how a peer's version differs from ours, None when they match
+pub fn version_mismatch(peer_version: u32) -> Option<String> {
+    match peer_version.cmp(&PROTOCOL_VERSION) {
+        Ordering::Equal => None,
+        Ordering::Less => Some(format!(
+            "older protocol v{} (we use v{}), newer features may not reach it",
+            peer_version, PROTOCOL_VERSION
+        )),
+        Ordering::Greater => Some(format!(
+            "newer protocol v{} (we use v{}), consider upgrading",
+            peer_version, PROTOCOL_VERSION
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versions_parse_with_a_legacy_fallback() {
+        assert_eq!(parse_protocol_version("5"), 5);
+        assert_eq!(parse_protocol_version(" 12 "), 12);
+        assert_eq!(parse_protocol_version(""), LEGACY_PROTOCOL_VERSION);
+        assert_eq!(parse_protocol_version("hello"), LEGACY_PROTOCOL_VERSION);
+        assert_eq!(parse_protocol_version("0"), LEGACY_PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn mismatches_are_described_either_way() {
+        assert_eq!(version_mismatch(PROTOCOL_VERSION), None);
+        let older = version_mismatch(PROTOCOL_VERSION - 1).unwrap();
+        assert!(older.starts_with("older protocol"), "{}", older);
+        let newer = version_mismatch(PROTOCOL_VERSION + 1).unwrap();
+        assert!(newer.starts_with("newer protocol"), "{}", newer);
+    }
+
+    #[test]
+    fn features_follow_the_peer_version() {
+        assert!(!supports_binary(None));
+        assert!(!supports_binary(Some(BINARY_PROTOCOL_VERSION - 1)));
+        assert!(supports_binary(Some(BINARY_PROTOCOL_VERSION)));
+        assert!(!supports_compression(None));
+        assert!(supports_compression(Some(PROTOCOL_VERSION)));
+        assert!(!supports_authentication(LEGACY_PROTOCOL_VERSION));
+        assert!(supports_authentication(AUTH_PROTOCOL_VERSION));
+    }
+}
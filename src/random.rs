@@ -0,0 +1,27 @@
+// Lightweight randomness for identifiers, without pulling in an extra crate
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// RandomState is seeded from the OS, so hashing a counter and the clock gives unpredictable values
+pub fn random_u64() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    hasher.write_u128(nanos);
+    hasher.finish()
+}
+
+// Uniform in [0, 1), for jitter
+pub fn random_unit() -> f64 {
+    // The top 53 bits fill an f64's mantissa exactly
+    (random_u64() >> 11) as f64 / (1u64 << 53) as f64
+}
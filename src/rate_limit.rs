@@ -0,0 +1,105 @@
+// Per-source token buckets, so one noisy peer can't drown out everyone else
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+// Buckets beyond this many are pruned of sources that have gone quiet
+const MAX_TRACKED_SOURCES: usize = 1024;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+pub struct RateLimiter {
+    // Tokens added per second, which is also the most a source can send in a burst
+    rate: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(per_second: u32) -> Self {
+        Self {
+            rate: f64::from(per_second.max(1)),
+            buckets: HashMap::new(),
+        }
+    }
+
+    // Takes a token for the source, returning false if it's over its limit
+    pub fn allow(&mut self, source: IpAddr) -> bool {
+        self.allow_at(source, Instant::now())
+    }
+
+    pub fn allow_at(&mut self, source: IpAddr, now: Instant) -> bool {
+        if self.buckets.len() >= MAX_TRACKED_SOURCES && !self.buckets.contains_key(&source) {
+            self.prune(now);
+        }
+
+        let rate = self.rate;
+        let bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: rate,
+            updated: now,
+        });
+
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    // Forgets sources whose buckets have refilled, since they'd start full anyway
+    fn prune(&mut self, now: Instant) {
+        let rate = self.rate;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate < rate
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn ip(text: &str) -> IpAddr {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn a_flood_gets_only_the_burst_through() {
+        let mut limiter = RateLimiter::new(20);
+        let now = Instant::now();
+        let allowed = (0..100)
+            .filter(|_| limiter.allow_at(ip("10.0.0.2"), now))
+            .count();
+        assert_eq!(allowed, 20);
+        // Everyone else still gets through
+        assert!(limiter.allow_at(ip("10.0.0.3"), now));
+    }
+
+    #[test]
+    fn buckets_refill_at_the_rate() {
+        let mut limiter = RateLimiter::new(10);
+        let start = Instant::now();
+        while limiter.allow_at(ip("10.0.0.2"), start) {}
+
+        let later = start + Duration::from_millis(250);
+        let allowed = (0..10)
+            .filter(|_| limiter.allow_at(ip("10.0.0.2"), later))
+            .count();
+        assert_eq!(allowed, 2);
+        // Never more than a full bucket, however long the source was quiet
+        let much_later = start + Duration::from_secs(3600);
+        let allowed = (0..100)
+            .filter(|_| limiter.allow_at(ip("10.0.0.2"), much_later))
+            .count();
+        assert_eq!(allowed, 10);
+    }
+}
@@ -0,0 +1,88 @@
+// Emoji reactions to messages, added with /react and shown on a line under the message
+
+use crate::constants::MAX_REACTION_CHARS;
+
+#[derive(Clone, Debug, Default)]
+pub struct Reactions {
+    // Emoji and who reacted with it, LOCAL_REACTOR or the node ID of a peer, in the order
+    // they arrived
+    entries: Vec<(String, String)>,
+}
+
+impl Reactions {
+    // Returns false when the reactor already reacted with this emoji, which doesn't count
+    // twice
+    pub fn add(&mut self, emoji: &str, reactor: &str) -> bool {
+        if self
+            .entries
+            .iter()
+            .any(|(known, from)| known == emoji && from == reactor)
+        {
+            return false;
+        }
+        self.entries.push((emoji.to_string(), reactor.to_string()));
+        true
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // Each emoji with how many reacted with it, in the order they first appeared
+    pub fn counts(&self) -> Vec<(&str, usize)> {
+        let mut counts: Vec<(&str, usize)> = Vec::new();
+        for (emoji, _) in &self.entries {
+            match counts.iter_mut().find(|(known, _)| known == emoji) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((emoji, 1)),
+            }
+        }
+        counts
+    }
+}
+
+// The line under a message, like "👍 x3  🎉 x1"
+pub fn format_reactions(counts: &[(&str, usize)]) -> String {
+    counts
+        .iter()
+        .map(|(emoji, count)| format!("{} x{}", emoji, count))
+        .collect::<Vec<_>>()
+        .join("  ")
+}
+
+// A reaction is a single short token, so it can't be used to post a message under
+// someone else's
+pub fn is_valid_reaction(text: &str) -> bool {
+    !text.is_empty()
+        && text.chars().count() <= MAX_REACTION_CHARS
+        && !text.chars().any(char::is_whitespace)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reactions_are_counted_per_emoji() {
+        let mut reactions = Reactions::default();
+        assert!(reactions.is_empty());
+        assert!(reactions.add("👍", "node-b"));
+        assert!(reactions.add("🎉", "node-b"));
+        assert!(reactions.add("👍", "node-c"));
+        assert!(reactions.add("👍", "local"));
+        // The same person reacting again doesn't count twice
+        assert!(!reactions.add("👍", "node-b"));
+
+        assert_eq!(reactions.counts(), vec![("👍", 3), ("🎉", 1)]);
+        assert_eq!(format_reactions(&reactions.counts()), "👍 x3  🎉 x1");
+    }
+
+    #[test]
+    fn reactions_are_single_short_tokens() {
+        assert!(is_valid_reaction("👍"));
+        assert!(is_valid_reaction("+1"));
+        assert!(!is_valid_reaction(""));
+        assert!(!is_valid_reaction("nice one"));
+        assert!(!is_valid_reaction(&"x".repeat(MAX_REACTION_CHARS + 1)));
+    }
+}
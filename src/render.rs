@@ -0,0 +1,488 @@
+// Turns messages into lines of text for the message area. Nothing here touches the
+// terminal, the engine draws what comes out.
+
+use crate::console_graphics::{Delivery, DisplayMessage};
+use crate::constants::UNKNOWN_PEER_NAME;
+use crate::message::short_id;
+use crate::reactions::format_reactions;
+use crate::text::{ellipsize, find_ignore_case, wrap_ranges};
+use crate::theme::Theme;
+use crate::timestamp::format_timestamp;
+use chrono::{DateTime, Local};
+use crossterm::style::Color;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+
+// A formatted line of text plus the byte ranges of it that are drawn in color, and the ones
+// highlighted as search matches
+#[derive(Clone, Debug, Default)]
+pub struct StyledLine {
+    pub text: String,
+    pub colors: Vec<(Range<usize>, Color)>,
+    pub highlights: Vec<Range<usize>>,
+}
+
+impl StyledLine {
+    pub fn push(&mut self, text: &str) {
+        self.text.push_str(text);
+    }
+
+    pub fn push_colored(&mut self, text: &str, color: Color) {
+        let start = self.text.len();
+        self.text.push_str(text);
+        self.colors.push((start..self.text.len(), color));
+    }
+
+    // Highlights matches of the search term in the text from start onwards
+    pub fn highlight_matches(&mut self, start: usize, term: &str) {
+        self.highlights.extend(
+            find_ignore_case(&self.text[start..], term)
+                .into_iter()
+                .map(|found| start + found.start..start + found.end),
+        );
+    }
+
+    // Cuts out a byte range of the line, keeping the colors and highlights that overlap it
+    pub fn slice(&self, range: Range<usize>) -> StyledLine {
+        let clip = |styled: &Range<usize>| {
+            let start = styled.start.max(range.start);
+            let end = styled.end.min(range.end);
+            (start < end).then(|| start - range.start..end - range.start)
+        };
+
+        StyledLine {
+            text: self.text[range.clone()].to_string(),
+            colors: self
+                .colors
+                .iter()
+                .filter_map(|(colored, color)| clip(colored).map(|clipped| (clipped, *color)))
+                .collect(),
+            highlights: self.highlights.iter().filter_map(clip).collect(),
+        }
+    }
+
+    // Draws the whole line in one color, search highlights stay on top
+    pub fn recolor(&mut self, color: Color) {
+        self.colors = vec![(0..self.text.len(), color)];
+    }
+
+    // Splits the line into runs that share a color and highlighting
+    pub fn segments(&self) -> Vec<(&str, Option<Color>, bool)> {
+        let mut boundaries = vec![0, self.text.len()];
+        for range in self
+            .colors
+            .iter()
+            .map(|(range, _)| range)
+            .chain(&self.highlights)
+        {
+            boundaries.push(range.start);
+            boundaries.push(range.end);
+        }
+        boundaries.sort_unstable();
+        boundaries.dedup();
+
+        boundaries
+            .windows(2)
+            .map(|bounds| {
+                let (start, end) = (bounds[0], bounds[1]);
+                let color = self
+                    .colors
+                    .iter()
+                    .find(|(range, _)| range.contains(&start))
+                    .map(|(_, color)| *color);
+                let highlighted = self.highlights.iter().any(|range| range.contains(&start));
+                (&self.text[start..end], color, highlighted)
+            })
+            .collect()
+    }
+}
+
+// The settings messages are formatted with, borrowed from the engine
+pub struct MessageFormat<'a> {
+    pub theme: &'a Theme,
+    pub time_format: &'a str,
+    // Longer sender names are cut short
+    pub max_name_width: usize,
+    // Messages older than this are dimmed, never when None
+    pub dim_after: Option<Duration>,
+    pub now: DateTime<Local>,
+    // Put the sender's IP in front of remote messages
+    pub show_ip: bool,
+    // Names of the peers heard from each IP, for messages that came without a usable name.
+    // Only filled in when show_ip is off.
+    pub peer_names: HashMap<String, String>,
+}
+
+// How a message is drawn going by its age
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AgeStyle {
+    Recent,
+    Dimmed,
+}
+
+// Messages from the future, after a clock change or from a peer with a fast clock, count
+// as recent
+pub fn age_style(
+    timestamp: &DateTime<Local>,
+    now: &DateTime<Local>,
+    dim_after: Option<Duration>,
+) -> AgeStyle {
+    let age = now.signed_duration_since(*timestamp).to_std().ok();
+    match (age, dim_after) {
+        (Some(age), Some(dim_after)) if age >= dim_after => AgeStyle::Dimmed,
+        _ => AgeStyle::Recent,
+    }
+}
+
+impl MessageFormat<'_> {
+    // The name a remote message is shown under. Without the IP in front, a message from an
+    // unnamed sender goes by the name its peer is known by, or by the IP when there's none.
+    fn sender_name<'a>(&'a self, message: &'a DisplayMessage) -> &'a str {
+        let known = |name: &str| !name.is_empty() && name != UNKNOWN_PEER_NAME;
+        if self.show_ip || known(&message.sender_name) {
+            return &message.sender_name;
+        }
+        self.peer_names
+            .get(&message.sender_ip)
+            .filter(|name| known(name))
+            .unwrap_or(&message.sender_ip)
+    }
+
+    fn age_style(&self, message: &DisplayMessage) -> AgeStyle {
+        age_style(&message.timestamp, &self.now, self.dim_after)
+    }
+
+    // Matches of the search term in the message's content are highlighted. Old messages
+    // only change color, so they wrap the same as when they were new.
+    pub fn format_message(&self, message: &DisplayMessage, search: Option<&str>) -> StyledLine {
+        let mut line = self.format_bright(message, search);
+        if self.age_style(message) == AgeStyle::Dimmed {
+            line.recolor(self.theme.old_message);
+        }
+        line
+    }
+
+    fn format_bright(&self, message: &DisplayMessage, search: Option<&str>) -> StyledLine {
+        let timestamp = format_timestamp(&message.timestamp, self.time_format, message.starts_day);
+        let mut line = StyledLine::default();
+
+        if message.is_system {
+            line.push_colored(
+                &format!("[{}] *** {}", timestamp, message.content),
+                self.theme.system_message,
+            );
+            return line;
+        }
+
+        line.push(&format!("[{}] ", timestamp));
+        match (&message.recipient, message.is_local) {
+            (Some(recipient), true) => line.push(&format!("[DM to {}] ", recipient)),
+            (Some(_), false) => line.push("[DM] "),
+            (None, _) => {}
+        }
+
+        // The full name is kept in the message, only the shown one is cut short
+        let name = ellipsize(self.sender_name(message), self.max_name_width);
+
+        // Actions read as a sentence, drawn entirely in the sender's color
+        let content_start;
+        if message.is_action {
+            let color = if message.is_local {
+                self.theme.local_message
+            } else {
+                self.theme.name_color(&message.sender_name)
+            };
+            line.push_colored(&format!("* {} ", name), color);
+            content_start = line.text.len();
+            if message.is_mention {
+                line.push_colored(&message.content, self.theme.mention);
+            } else {
+                line.push_colored(&message.content, color);
+            }
+        } else if message.is_local {
+            line.push_colored(&format!("YOU >>> {}", name), self.theme.local_message);
+            line.push(": ");
+            content_start = line.text.len();
+            line.push(&message.content);
+        } else {
+            if self.show_ip {
+                line.push(&format!("{} >>> ", message.sender_ip));
+            }
+            line.push_colored(&name, self.theme.name_color(&message.sender_name));
+            line.push(": ");
+            content_start = line.text.len();
+            if message.is_mention {
+                line.push_colored(&message.content, self.theme.mention);
+            } else {
+                line.push(&message.content);
+            }
+        }
+        if let Some(term) = search {
+            line.highlight_matches(content_start, term);
+        }
+        if message.edited {
+            line.push_colored(" (edited)", self.theme.system_message);
+        }
+
+        match message.delivery {
+            Delivery::Delivered => line.push_colored(" ✓", self.theme.delivered),
+            Delivery::Undelivered => line.push_colored(" ✗", self.theme.system_message),
+            Delivery::Failed => line.push_colored(" ✗ not sent", self.theme.system_message),
+            Delivery::Queued => line.push_colored(" …", self.theme.system_message),
+            Delivery::NotTracked | Delivery::Pending => {}
+        }
+        // Messages carry their ID, for /react, and for /edit and /delete on ours
+        if message.id != 0 {
+            line.push_colored(
+                &format!(" #{}", short_id(message.id)),
+                self.theme.system_message,
+            );
+        }
+
+        line
+    }
+
+    // A message wrapped to the width, followed by its reactions when it has any
+    pub fn wrapped_message(
+        &self,
+        message: &DisplayMessage,
+        width: usize,
+        search: Option<&str>,
+    ) -> Vec<StyledLine> {
+        let mut lines = vec![self.format_message(message, search)];
+        if !message.reactions.is_empty() {
+            let mut reactions = StyledLine::default();
+            let color = match self.age_style(message) {
+                AgeStyle::Recent => self.theme.system_message,
+                AgeStyle::Dimmed => self.theme.old_message,
+            };
+            reactions.push_colored(
+                &format!("    {}", format_reactions(&message.reactions.counts())),
+                color,
+            );
+            lines.push(reactions);
+        }
+
+        lines
+            .into_iter()
+            .flat_map(|line| {
+                wrap_ranges(&line.text, width)
+                    .into_iter()
+                    .map(move |range| line.slice(range))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+}
+
+// The range of lines shown in rows rows when scrolled up by the offset, which is clamped so
+// the view never goes past the oldest line
+pub fn visible_range(total_lines: usize, scroll_offset: usize, rows: usize) -> Range<usize> {
+    let last = total_lines - clamp_scroll_offset(scroll_offset, total_lines, rows);
+    last.saturating_sub(rows)..last
+}
+
+// The view can't scroll further up than showing the oldest line at the top
+pub fn clamp_scroll_offset(offset: usize, total_lines: usize, visible_rows: usize) -> usize {
+    offset.min(total_lines.saturating_sub(visible_rows))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+    use chrono::TimeZone;
+
+    fn at_noon() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, 12, 30, 15).unwrap()
+    }
+
+    fn format(theme: &Theme) -> MessageFormat<'_> {
+        MessageFormat {
+            theme,
+            time_format: "%H:%M:%S",
+            max_name_width: 16,
+            dim_after: None,
+            now: at_noon(),
+            show_ip: false,
+            peer_names: HashMap::new(),
+        }
+    }
+
+    // Pinned to a known time and without an ID, so the output is the same every run
+    fn display(content: &str, name: &str, ip: &str) -> DisplayMessage {
+        let message = Message::new(content.to_string(), name.to_string(), ip.to_string());
+        let mut display = DisplayMessage::from_message(&message);
+        display.timestamp = at_noon();
+        display.id = 0;
+        display
+    }
+
+    fn texts(lines: &[StyledLine]) -> Vec<&str> {
+        lines.iter().map(|line| line.text.as_str()).collect()
+    }
+
+    #[test]
+    fn local_message() {
+        let theme = Theme::default();
+        let mut message = display("hi all", "alice", "local");
+        message.delivery = Delivery::Delivered;
+
+        let line = format(&theme).format_message(&message, None);
+        assert_eq!(line.text, "[12:30:15] YOU >>> alice: hi all ✓");
+        assert_eq!(line.colors[0], (11..24, theme.local_message));
+    }
+
+    #[test]
+    fn remote_message() {
+        let theme = Theme::default();
+        let message = display("hello", "bob", "10.0.0.2");
+
+        let line = format(&theme).format_message(&message, None);
+        assert_eq!(line.text, "[12:30:15] bob: hello");
+        assert_eq!(line.colors, vec![(11..14, theme.name_color("bob"))]);
+
+        let mut with_ip = format(&theme);
+        with_ip.show_ip = true;
+        let line = with_ip.format_message(&message, None);
+        assert_eq!(line.text, "[12:30:15] 10.0.0.2 >>> bob: hello");
+    }
+
+    #[test]
+    fn long_message_wraps_at_word_boundaries() {
+        let theme = Theme::default();
+        let message = display(
+            "the quick brown fox jumps over the lazy dog",
+            "bob",
+            "10.0.0.2",
+        );
+
+        let lines = format(&theme).wrapped_message(&message, 24, None);
+        assert_eq!(
+            texts(&lines),
+            vec![
+                "[12:30:15] bob: the",
+                "quick brown fox jumps",
+                "over the lazy dog",
+            ]
+        );
+        // The name keeps its color on the first line only
+        assert_eq!(lines[0].colors, vec![(11..14, theme.name_color("bob"))]);
+        assert!(lines[1].colors.is_empty());
+    }
+
+    #[test]
+    fn scroll_offset_stops_at_the_oldest_line() {
+        assert_eq!(clamp_scroll_offset(5, 20, 10), 5);
+        assert_eq!(clamp_scroll_offset(10, 20, 10), 10);
+        assert_eq!(clamp_scroll_offset(50, 20, 10), 10);
+        // Everything fits, so there's nothing to scroll
+        assert_eq!(clamp_scroll_offset(3, 4, 10), 0);
+        assert_eq!(clamp_scroll_offset(3, 0, 10), 0);
+    }
+
+    #[test]
+    fn visible_range_follows_the_offset() {
+        assert_eq!(visible_range(20, 0, 10), 10..20);
+        assert_eq!(visible_range(20, 4, 10), 6..16);
+        assert_eq!(visible_range(20, 50, 10), 0..10);
+        assert_eq!(visible_range(4, 2, 10), 0..4);
+        assert_eq!(visible_range(0, 0, 10), 0..0);
+    }
+
+    #[test]
+    fn actions_read_as_a_sentence() {
+        let theme = Theme::default();
+        let mut remote = display("waves", "bob", "10.0.0.2");
+        remote.is_action = true;
+        let line = format(&theme).format_message(&remote, None);
+        assert_eq!(line.text, "[12:30:15] * bob waves");
+        // Drawn entirely in the sender's color
+        let color = theme.name_color("bob");
+        assert_eq!(line.colors, vec![(11..17, color), (17..22, color)]);
+
+        let mut local = display("waves back", "alice", "local");
+        local.is_action = true;
+        local.delivery = Delivery::Pending;
+        let line = format(&theme).format_message(&local, None);
+        assert_eq!(line.text, "[12:30:15] * alice waves back");
+        assert!(line
+            .colors
+            .iter()
+            .all(|(_, color)| *color == theme.local_message));
+    }
+
+    #[test]
+    fn reactions_go_on_a_line_under_the_message() {
+        let theme = Theme::default();
+        let mut message = display("lunch?", "bob", "10.0.0.2");
+        message.reactions.add("👍", "node-a");
+        message.reactions.add("🍜", "node-c");
+        message.reactions.add("👍", "local");
+
+        let lines = format(&theme).wrapped_message(&message, 80, None);
+        assert_eq!(
+            texts(&lines),
+            vec!["[12:30:15] bob: lunch?", "    👍 x2  🍜 x1"]
+        );
+        assert_eq!(lines[1].colors[0].1, theme.system_message);
+    }
+
+    #[test]
+    fn long_names_are_cut_to_the_name_width() {
+        let theme = Theme::default();
+        let name = format!("{}{}", "x".repeat(30), "名前".repeat(5));
+        assert_eq!(name.chars().count(), 40);
+        let message = display("hi", &name, "10.0.0.2");
+
+        let line = format(&theme).format_message(&message, None);
+        let shown = format!("{}…", "x".repeat(15));
+        assert_eq!(line.text, format!("[12:30:15] {}: hi", shown));
+        // Colored by columns of the shown name, and the full name is still stored
+        assert_eq!(line.colors[0].0, 11..11 + shown.len());
+        assert_eq!(message.sender_name, name);
+
+        let name = format!("{}{}", "名前".repeat(10), "x".repeat(20));
+        let message = display("hi", &name, "10.0.0.2");
+        let line = format(&theme).format_message(&message, None);
+        assert_eq!(line.text, "[12:30:15] 名前名前名前名…: hi");
+        assert_eq!(crate::text::str_width("名前名前名前名…"), 15);
+    }
+
+    #[test]
+    fn messages_dim_once_they_are_old_enough() {
+        let now = at_noon();
+        let ago = |secs: i64| now - chrono::Duration::seconds(secs);
+        let hour = Some(Duration::from_secs(3600));
+
+        assert_eq!(age_style(&now, &now, hour), AgeStyle::Recent);
+        assert_eq!(age_style(&ago(3599), &now, hour), AgeStyle::Recent);
+        assert_eq!(age_style(&ago(3600), &now, hour), AgeStyle::Dimmed);
+        assert_eq!(age_style(&ago(86_400), &now, hour), AgeStyle::Dimmed);
+        // From a fast clock
+        assert_eq!(age_style(&ago(-60), &now, hour), AgeStyle::Recent);
+        // Without dim_after nothing is dimmed
+        assert_eq!(age_style(&ago(86_400), &now, None), AgeStyle::Recent);
+    }
+
+    #[test]
+    fn dimming_only_changes_the_color() {
+        let theme = Theme::default();
+        let mut message = display("the quick brown fox jumps over", "bob", "10.0.0.2");
+        let bright = format(&theme).wrapped_message(&message, 24, None);
+
+        message.timestamp = at_noon() - chrono::Duration::hours(2);
+        let mut dimming = format(&theme);
+        dimming.dim_after = Some(Duration::from_secs(3600));
+        let dimmed = dimming.wrapped_message(&message, 24, None);
+
+        // The same lines, all in the old message color
+        assert_eq!(texts(&dimmed).len(), bright.len());
+        assert!(texts(&dimmed)[0].starts_with("[10:30:15] bob:"));
+        assert_eq!(
+            dimmed[0].colors,
+            vec![(0..dimmed[0].text.len(), theme.old_message)]
+        );
+    }
+}
@@ -0,0 +1,107 @@
+// Holds received messages for a moment so ones that arrive slightly out of order, over
+// different routes or transports, are shown in the order they were sent. Ordering uses the
+// sender's timestamp, so it can only fix what happens within the hold window.
+
+use crate::message::Message;
+use std::time::{Duration, Instant};
+
+pub struct ReorderBuffer {
+    window: Duration,
+    // Messages with the time they arrived, in arrival order
+    pending: Vec<(Instant, Message)>,
+}
+
+impl ReorderBuffer {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, message: Message, now: Instant) {
+        self.pending.push((now, message));
+    }
+
+    // When the longest-held message is due to be shown
+    pub fn next_release(&self) -> Option<Instant> {
+        self.pending
+            .first()
+            .map(|(arrived, _)| *arrived + self.window)
+    }
+
+    // Messages held for the whole window, oldest timestamp first. Anything still held that
+    // was sent before the newest of them comes along, since it has to be shown ahead of it.
+    pub fn take_ready(&mut self, now: Instant) -> Vec<Message> {
+        let Some(newest) = self
+            .pending
+            .iter()
+            .filter(|(arrived, _)| now.duration_since(*arrived) >= self.window)
+            .map(|(_, message)| message.timestamp())
+            .max()
+        else {
+            return Vec::new();
+        };
+
+        let (mut ready, held): (Vec<_>, Vec<_>) =
+            self.pending.drain(..).partition(|(arrived, message)| {
+                now.duration_since(*arrived) >= self.window || message.timestamp() <= newest
+            });
+        self.pending = held;
+
+        // Stable, so messages with the same timestamp keep their arrival order
+        ready.sort_by_key(|(_, message)| message.timestamp());
+        ready.into_iter().map(|(_, message)| message).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(300);
+
+    fn sent_at(timestamp: i64) -> Message {
+        Message::new(
+            format!("sent at {}", timestamp),
+            "bob".to_string(),
+            "10.0.0.2".to_string(),
+        )
+        .with_timestamp(timestamp)
+    }
+
+    fn timestamps(messages: &[Message]) -> Vec<i64> {
+        messages.iter().map(Message::timestamp).collect()
+    }
+
+    #[test]
+    fn late_arrivals_are_put_back_in_order() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let start = Instant::now();
+        for (delay, timestamp) in [(0, 3000), (50, 1000), (100, 2000), (100, 2000)] {
+            buffer.push(sent_at(timestamp), start + Duration::from_millis(delay));
+        }
+
+        assert_eq!(buffer.next_release(), Some(start + WINDOW));
+        assert!(buffer
+            .take_ready(start + Duration::from_millis(299))
+            .is_empty());
+        // The first arrival is due, and everything sent before it comes along
+        let ready = buffer.take_ready(start + WINDOW);
+        assert_eq!(timestamps(&ready), [1000, 2000, 2000, 3000]);
+        assert_eq!(buffer.next_release(), None);
+    }
+
+    #[test]
+    fn newer_messages_wait_out_their_own_window() {
+        let mut buffer = ReorderBuffer::new(WINDOW);
+        let start = Instant::now();
+        buffer.push(sent_at(1000), start);
+        buffer.push(sent_at(5000), start + Duration::from_millis(200));
+
+        assert_eq!(timestamps(&buffer.take_ready(start + WINDOW)), [1000]);
+        let second = start + Duration::from_millis(200) + WINDOW;
+        assert_eq!(buffer.next_release(), Some(second));
+        assert_eq!(timestamps(&buffer.take_ready(second)), [5000]);
+    }
+}
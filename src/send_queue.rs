@@ -0,0 +1,82 @@
+// Messages waiting to be sent. The input loop hands them over and goes straight back to
+// reading keys, while a sender task puts them on the network one at a time, in order.
+
+use crate::message::Message;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{mpsc, Notify};
+
+pub struct Outgoing {
+    pub message: Message,
+    // The peer a direct message goes to, None to broadcast
+    pub to: Option<SocketAddr>,
+    // When the send throttle lets it go out
+    pub not_before: Instant,
+}
+
+#[derive(Clone)]
+pub struct SendQueue {
+    sender: mpsc::UnboundedSender<Outgoing>,
+    // Taken once, by the sender task
+    receiver: Arc<Mutex<Option<mpsc::UnboundedReceiver<Outgoing>>>>,
+    // Messages queued or being sent
+    outstanding: Arc<AtomicUsize>,
+    emptied: Arc<Notify>,
+}
+
+impl Default for SendQueue {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Self {
+            sender,
+            receiver: Arc::new(Mutex::new(Some(receiver))),
+            outstanding: Arc::new(AtomicUsize::new(0)),
+            emptied: Arc::new(Notify::new()),
+        }
+    }
+}
+
+impl SendQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Never waits. Returns false when nothing is left to take messages off the queue.
+    pub fn enqueue(&self, outgoing: Outgoing) -> bool {
+        self.outstanding.fetch_add(1, Ordering::SeqCst);
+        if self.sender.send(outgoing).is_err() {
+            self.finished();
+            return false;
+        }
+        true
+    }
+
+    pub fn take_receiver(&self) -> Option<mpsc::UnboundedReceiver<Outgoing>> {
+        self.receiver.lock().unwrap().take()
+    }
+
+    // Called by the sender task once a message has been dealt with, sent or not
+    pub fn finished(&self) {
+        if self.outstanding.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.emptied.notify_waiters();
+        }
+    }
+
+    pub fn outstanding(&self) -> usize {
+        self.outstanding.load(Ordering::SeqCst)
+    }
+
+    // Resolves once every message enqueued so far has been dealt with
+    pub async fn wait_until_empty(&self) {
+        loop {
+            // Created before checking, so a notification in between isn't missed
+            let emptied = self.emptied.notified();
+            if self.outstanding() == 0 {
+                return;
+            }
+            emptied.await;
+        }
+    }
+}
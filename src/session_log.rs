@@ -0,0 +1,322 @@
+// Opt-in log of every sent and received message, one JSON object per line.
+// Written with --log and read back with --replay so a restarted session keeps its context.
+
+use crate::message::Message;
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct SessionLog {
+    file: Arc<Mutex<File>>,
+    // Set after the first write failure so the UI only reports it once
+    error_reported: Arc<AtomicBool>,
+}
+
+impl SessionLog {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+            error_reported: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn append(&self, message: &Message) -> io::Result<()> {
+        let line = format!("{}\n", serialize_message(message));
+        self.file.lock().unwrap().write_all(line.as_bytes())
+    }
+
+    // Makes sure everything appended so far is on disk
+    pub fn flush(&self) -> io::Result<()> {
+        self.file.lock().unwrap().sync_data()
+    }
+
+    // True only for the first failure, so repeated write errors don't flood the UI
+    pub fn should_report_error(&self) -> bool {
+        !self.error_reported.swap(true, Ordering::SeqCst)
+    }
+}
+
+// The last `count` messages in the log, oldest first. Lines that don't parse are skipped.
+pub fn load_recent(path: &Path, count: usize) -> io::Result<Vec<Message>> {
+    let contents = fs::read_to_string(path)?;
+    let mut recent = VecDeque::with_capacity(count);
+
+    for message in contents.lines().filter_map(parse_message) {
+        if recent.len() == count {
+            recent.pop_front();
+        }
+        recent.push_back(message);
+    }
+
+    Ok(recent.into())
+}
+
+pub fn serialize_message(message: &Message) -> String {
+    let mut fields = vec![
+        format!("\"timestamp\":{}", message.timestamp()),
+        format!("\"id\":{}", message.id()),
+        format!("\"sender\":{}", json_string(message.sender_name())),
+        format!("\"ip\":{}", json_string(message.sender_ip())),
+    ];
+    if let Some(recipient) = message.recipient() {
+        fields.push(format!("\"recipient\":{}", json_string(recipient)));
+    }
+    if message.is_action() {
+        fields.push("\"action\":true".to_string());
+    }
+    fields.push(format!("\"content\":{}", json_string(message.content())));
+
+    format!("{{{}}}", fields.join(","))
+}
+
+pub fn parse_message(line: &str) -> Option<Message> {
+    let fields = parse_object(line)?;
+    let get = |key: &str| {
+        fields
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.clone())
+    };
+    let number = |key: &str| get(key).and_then(|value| value.into_number());
+    let text = |key: &str| get(key).and_then(|value| value.into_string());
+
+    let mut message = Message::new(text("content")?, text("sender")?, text("ip")?)
+        .with_id(number("id")?.parse().ok()?)
+        .with_timestamp(number("timestamp")?.parse().ok()?);
+    if let Some(recipient) = text("recipient") {
+        message = message.with_recipient(recipient);
+    }
+    if matches!(get("action"), Some(JsonValue::Boolean(true))) {
+        message = message.into_action();
+    }
+
+    Some(message)
+}
+
+fn json_string(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len() + 2);
+    escaped.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if u32::from(c) < 0x20 => escaped.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[derive(Clone)]
+enum JsonValue {
+    String(String),
+    // Kept as text so large IDs don't lose precision
+    Number(String),
+    Boolean(bool),
+}
+
+impl JsonValue {
+    fn into_number(self) -> Option<String> {
+        match self {
+            JsonValue::Number(number) => Some(number),
+            _ => None,
+        }
+    }
+
+    fn into_string(self) -> Option<String> {
+        match self {
+            JsonValue::String(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+// Parses a flat JSON object whose values are strings, integers or booleans, as written by
+// serialize_message
+fn parse_object(line: &str) -> Option<Vec<(String, JsonValue)>> {
+    let mut chars = line.trim().chars().peekable();
+    let mut fields = Vec::new();
+
+    if chars.next()? != '{' {
+        return None;
+    }
+
+    loop {
+        skip_whitespace(&mut chars);
+        match chars.peek()? {
+            '}' if fields.is_empty() => {
+                chars.next();
+                break;
+            }
+            '"' => {}
+            _ => return None,
+        }
+
+        let key = parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+
+        let value = if chars.peek() == Some(&'"') {
+            JsonValue::String(parse_string(&mut chars)?)
+        } else if chars.peek().is_some_and(char::is_ascii_alphabetic) {
+            let mut word = String::new();
+            while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+                word.push(*c);
+                chars.next();
+            }
+            match word.as_str() {
+                "true" => JsonValue::Boolean(true),
+                "false" => JsonValue::Boolean(false),
+                _ => return None,
+            }
+        } else {
+            let mut number = String::new();
+            while let Some(c) = chars.peek().filter(|c| c.is_ascii_digit() || **c == '-') {
+                number.push(*c);
+                chars.next();
+            }
+            if number.is_empty() {
+                return None;
+            }
+            JsonValue::Number(number)
+        };
+        fields.push((key, value));
+
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => break,
+            _ => return None,
+        }
+    }
+
+    skip_whitespace(&mut chars);
+    chars.next().is_none().then_some(fields)
+}
+
+fn skip_whitespace(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(text),
+            '\\' => match chars.next()? {
+                '"' => text.push('"'),
+                '\\' => text.push('\\'),
+                '/' => text.push('/'),
+                'n' => text.push('\n'),
+                'r' => text.push('\r'),
+                't' => text.push('\t'),
+                'b' => text.push('\u{8}'),
+                'f' => text.push('\u{c}'),
+                'u' => {
+                    let mut code = parse_hex4(chars)?;
+                    // Characters outside the BMP arrive as a surrogate pair
+                    if (0xD800..0xDC00).contains(&code) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex4(chars)?;
+                        if !(0xDC00..0xE000).contains(&low) {
+                            return None;
+                        }
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                    }
+                    text.push(char::from_u32(code)?);
+                }
+                _ => return None,
+            },
+            c => text.push(c),
+        }
+    }
+}
+
+fn parse_hex4(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<u32> {
+    let digits: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+    u32::from_str_radix(&digits, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        Message::new(
+            content.to_string(),
+            "alice".to_string(),
+            "10.0.0.1".to_string(),
+        )
+        .with_id(u64::MAX - 1)
+        .with_timestamp(1_714_566_615_123)
+    }
+
+    #[test]
+    fn a_line_is_one_json_object() {
+        assert_eq!(
+            serialize_message(&message("say \"hi\"\n\\")),
+            "{\"timestamp\":1714566615123,\"id\":18446744073709551614,\"sender\":\"alice\",\
+             \"ip\":\"10.0.0.1\",\"content\":\"say \\\"hi\\\"\\n\\\\\"}"
+        );
+    }
+
+    #[test]
+    fn lines_parse_back_into_messages() {
+        for original in [
+            message("plain"),
+            message("quotes \" and \\ and \u{1} and ✓ and\ttabs"),
+            message("psst").with_recipient("bob".to_string()),
+            message("waves").into_action(),
+        ] {
+            let parsed = parse_message(&serialize_message(&original)).unwrap();
+            // IDs above 2^53 survive, JSON numbers aren't read as floats
+            assert_eq!(parsed.id(), original.id());
+            assert_eq!(parsed.timestamp(), original.timestamp());
+            assert_eq!(parsed.sender_name(), original.sender_name());
+            assert_eq!(parsed.sender_ip(), original.sender_ip());
+            assert_eq!(parsed.content(), original.content());
+            assert_eq!(parsed.recipient(), original.recipient());
+            assert_eq!(parsed.is_action(), original.is_action());
+        }
+    }
+
+    #[test]
+    fn broken_lines_are_rejected() {
+        assert!(parse_message("").is_none());
+        assert!(parse_message("not json").is_none());
+        assert!(parse_message("{\"timestamp\":1,\"id\":2,\"sender\":\"a\"}").is_none());
+        let line = serialize_message(&message("cut off"));
+        assert!(parse_message(&line[..line.len() - 1]).is_none());
+        // Written by hand, with spacing and escapes serialize_message doesn't use
+        let by_hand =
+            r#" { "timestamp" : 5, "id" : 6, "sender" : "a", "ip" : "b", "content" : "é\/" } "#;
+        assert_eq!(parse_message(by_hand).unwrap().content(), "é/");
+    }
+}
@@ -0,0 +1,145 @@
+// Leaving the chat. /quit, Ctrl+Q, Ctrl+C, Esc and a failed input loop all run the same
+// cleanup steps in the same order. The steps are a trait so the order can be checked
+// without a terminal or a network.
+
+use crate::watchdog;
+use std::future::Future;
+use std::time::Duration;
+
+pub trait Cleanup {
+    // First, so anything the later steps print is readable
+    fn restore_terminal(&self);
+
+    // The same restore for the watchdog to run if a later step hangs. It runs on its own
+    // thread, so it can't borrow the cleanup.
+    fn terminal_restorer(&self) -> impl FnOnce() + Send + 'static;
+
+    // The peer cache, input history and mute list
+    fn save_state(&self);
+
+    // Gives messages still in the send queue a moment to go out
+    fn drain_send_queue(&self) -> impl Future<Output = ()>;
+
+    fn flush_log(&self);
+
+    // Best effort, peers that miss it drop us once we stop sending heartbeats
+    fn announce_leaving(&self) -> impl Future<Output = ()>;
+}
+
+pub async fn run(cleanup: &impl Cleanup) {
+    cleanup.restore_terminal();
+    cleanup.save_state();
+    cleanup.drain_send_queue().await;
+    cleanup.flush_log();
+    cleanup.announce_leaving().await;
+}
+
+// Runs the steps with a deadline. Past it the watchdog restores the terminal and calls
+// exit, which is expected to end the process.
+pub async fn run_within(
+    cleanup: &impl Cleanup,
+    timeout: Duration,
+    exit: impl FnOnce() + Send + 'static,
+) {
+    let restore = cleanup.terminal_restorer();
+    watchdog::arm(timeout, move || {
+        restore();
+        eprintln!(
+            "Cleanup took longer than {}ms, exiting anyway",
+            timeout.as_millis()
+        );
+        exit();
+    });
+    run(cleanup).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct Recorder {
+        steps: Arc<Mutex<Vec<&'static str>>>,
+        // Makes save_state block until this gets a message
+        hang: Option<Mutex<mpsc::Receiver<()>>>,
+    }
+
+    impl Recorder {
+        fn record(&self, step: &'static str) {
+            self.steps.lock().unwrap().push(step);
+        }
+    }
+
+    impl Cleanup for Recorder {
+        fn restore_terminal(&self) {
+            self.record("restore terminal");
+        }
+
+        fn terminal_restorer(&self) -> impl FnOnce() + Send + 'static {
+            let steps = self.steps.clone();
+            move || steps.lock().unwrap().push("watchdog restore")
+        }
+
+        fn save_state(&self) {
+            if let Some(hang) = &self.hang {
+                // Blocks the runtime's only thread, so nothing but the watchdog can end it
+                let released = hang.lock().unwrap().recv_timeout(Duration::from_secs(5));
+                assert!(released.is_ok(), "the watchdog never fired");
+            }
+            self.record("save state");
+        }
+
+        async fn drain_send_queue(&self) {
+            tokio::task::yield_now().await;
+            self.record("drain send queue");
+        }
+
+        fn flush_log(&self) {
+            self.record("flush log");
+        }
+
+        async fn announce_leaving(&self) {
+            tokio::task::yield_now().await;
+            self.record("announce leaving");
+        }
+    }
+
+    #[tokio::test]
+    async fn cleanup_steps_run_in_order() {
+        let recorder = Recorder::default();
+        run(&recorder).await;
+        assert_eq!(
+            *recorder.steps.lock().unwrap(),
+            vec![
+                "restore terminal",
+                "save state",
+                "drain send queue",
+                "flush log",
+                "announce leaving",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn a_hung_cleanup_still_gets_the_terminal_restored() {
+        // Exiting is what ends the hang in the app, here it releases the hung step
+        let (exit, release) = mpsc::channel();
+        let recorder = Recorder {
+            hang: Some(Mutex::new(release)),
+            ..Recorder::default()
+        };
+
+        run_within(&recorder, Duration::from_millis(50), move || {
+            let _ = exit.send(());
+        })
+        .await;
+
+        let steps = recorder.steps.lock().unwrap();
+        assert_eq!(
+            steps[..3],
+            ["restore terminal", "watchdog restore", "save state"]
+        );
+    }
+}
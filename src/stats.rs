@@ -0,0 +1,139 @@
+// Process-wide counters of chat traffic, shown by /stats. Only the chat port is counted,
+// discovery and heartbeats are left out, except for packets dropped by the allowlist and
+// denylist.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static MESSAGES_SENT: AtomicU64 = AtomicU64::new(0);
+static MESSAGES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static BYTES_SENT: AtomicU64 = AtomicU64::new(0);
+static BYTES_RECEIVED: AtomicU64 = AtomicU64::new(0);
+static DUPLICATES_DROPPED: AtomicU64 = AtomicU64::new(0);
+static MALFORMED_DROPPED: AtomicU64 = AtomicU64::new(0);
+static BLOCKED_DROPPED: AtomicU64 = AtomicU64::new(0);
+static TRUNCATED_DROPPED: AtomicU64 = AtomicU64::new(0);
+static UNAUTHENTICATED_DROPPED: AtomicU64 = AtomicU64::new(0);
+static LARGEST_PACKET: AtomicU64 = AtomicU64::new(0);
+
+// Upper bounds of the packet size buckets, with one more bucket for anything larger
+pub const PACKET_SIZE_BUCKETS: [u64; 4] = [256, 1024, 4096, 16384];
+static PACKET_SIZES: [AtomicU64; PACKET_SIZE_BUCKETS.len() + 1] =
+    [const { AtomicU64::new(0) }; PACKET_SIZE_BUCKETS.len() + 1];
+
+// A message of ours was handed to the network, however many datagrams that took
+pub fn record_message_sent() {
+    MESSAGES_SENT.fetch_add(1, Ordering::Relaxed);
+}
+
+// A new message from a peer reached the UI
+pub fn record_message_received() {
+    MESSAGES_RECEIVED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn record_bytes_sent(bytes: usize) {
+    BYTES_SENT.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+pub fn record_bytes_received(bytes: usize) {
+    BYTES_RECEIVED.fetch_add(bytes as u64, Ordering::Relaxed);
+}
+
+// The size of a received datagram, for tuning recv_buffer_size
+pub fn record_packet_size(bytes: usize) {
+    let bytes = bytes as u64;
+    let bucket = PACKET_SIZE_BUCKETS
+        .iter()
+        .position(|limit| bytes <= *limit)
+        .unwrap_or(PACKET_SIZE_BUCKETS.len());
+    PACKET_SIZES[bucket].fetch_add(1, Ordering::Relaxed);
+    LARGEST_PACKET.fetch_max(bytes, Ordering::Relaxed);
+}
+
+// A datagram longer than the receive buffer, which arrived cut short
+pub fn record_truncated() {
+    TRUNCATED_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// Another copy of a message we'd already seen
+pub fn record_duplicate() {
+    DUPLICATES_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// A packet that was corrupt or made no sense, dropped instead of shown
+pub fn record_malformed() {
+    MALFORMED_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// A message whose authentication tag was missing or didn't match, which could be someone
+// without the passphrase posing as a peer
+pub fn record_unauthenticated() {
+    UNAUTHENTICATED_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+// A packet from an address the allowlist or denylist keeps out, on any port
+pub fn record_blocked() {
+    BLOCKED_DROPPED.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub messages_sent: u64,
+    pub messages_received: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duplicates_dropped: u64,
+    pub malformed_dropped: u64,
+    pub blocked_dropped: u64,
+    pub truncated_dropped: u64,
+    pub unauthenticated_dropped: u64,
+    pub largest_packet: u64,
+    // Received packets per bucket of PACKET_SIZE_BUCKETS
+    pub packet_sizes: [u64; PACKET_SIZE_BUCKETS.len() + 1],
+}
+
+pub fn snapshot() -> Stats {
+    Stats {
+        messages_sent: MESSAGES_SENT.load(Ordering::Relaxed),
+        messages_received: MESSAGES_RECEIVED.load(Ordering::Relaxed),
+        bytes_sent: BYTES_SENT.load(Ordering::Relaxed),
+        bytes_received: BYTES_RECEIVED.load(Ordering::Relaxed),
+        duplicates_dropped: DUPLICATES_DROPPED.load(Ordering::Relaxed),
+        malformed_dropped: MALFORMED_DROPPED.load(Ordering::Relaxed),
+        blocked_dropped: BLOCKED_DROPPED.load(Ordering::Relaxed),
+        truncated_dropped: TRUNCATED_DROPPED.load(Ordering::Relaxed),
+        unauthenticated_dropped: UNAUTHENTICATED_DROPPED.load(Ordering::Relaxed),
+        largest_packet: LARGEST_PACKET.load(Ordering::Relaxed),
+        packet_sizes: PACKET_SIZES
+            .each_ref()
+            .map(|count| count.load(Ordering::Relaxed)),
+    }
+}
+
+// Received packets by size, like "≤256 B: 12, ≤1.0 KiB: 3, ..., larger: 0"
+pub fn format_packet_sizes(counts: &[u64]) -> String {
+    counts
+        .iter()
+        .enumerate()
+        .map(|(bucket, count)| match PACKET_SIZE_BUCKETS.get(bucket) {
+            Some(limit) => format!("≤{}: {}", format_bytes(*limit), count),
+            None => format!("larger: {}", count),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// Byte counts in the largest unit that keeps them at or above 1
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
@@ -0,0 +1,50 @@
+// Locations of files persisted between sessions
+
+use std::path::PathBuf;
+
+const APP_DIR_NAME: &str = "subnetvox";
+const PEER_CACHE_FILE: &str = "peers.json";
+const CONFIG_FILE: &str = "config.toml";
+const HISTORY_FILE: &str = "history";
+const MUTED_FILE: &str = "muted";
+
+// ~/.config/subnetvox, or None when no home directory can be determined
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir).join(APP_DIR_NAME));
+    }
+
+    std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .map(|home| PathBuf::from(home).join(".config").join(APP_DIR_NAME))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+pub fn peer_cache_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(PEER_CACHE_FILE))
+}
+
+pub fn history_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(HISTORY_FILE))
+}
+
+pub fn muted_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(MUTED_FILE))
+}
+
+// Expands a leading ~ to the home directory, as a shell would for paths typed in the UI.
+// Left as is when no home directory can be determined.
+pub fn expand_home(path: &str) -> PathBuf {
+    let rest = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => rest,
+        _ => return PathBuf::from(path),
+    };
+
+    match std::env::var_os("HOME").or_else(|| std::env::var_os("USERPROFILE")) {
+        Some(home) => PathBuf::from(home).join(rest.trim_start_matches(['/', '\\'])),
+        None => PathBuf::from(path),
+    }
+}
@@ -0,0 +1,254 @@
+// Keeps the listener tasks running. A listener that fails is started again after a delay
+// that doubles with each failure in a row, unless the failure is one that retrying won't
+// fix, like the port being taken by another program. /reconnect restarts every listener
+// at once, with fresh sockets on whatever interfaces there are by then.
+
+use crate::constants::{
+    LISTENER_RESTART_MAX_SECS, LISTENER_RESTART_MIN_SECS, MAX_CONSECUTIVE_RECV_ERRORS,
+};
+use crate::debug_logger::debug_log;
+use crate::networking::NetworkError;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::futures::Notified;
+use tokio::sync::Notify;
+use tokio::time;
+
+// Tells the supervised listeners to drop their sockets and start over, shared by everything
+// that has to start over on a reconnect
+#[derive(Clone, Default)]
+pub struct Restarts(Arc<Notify>);
+
+impl Restarts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn restart_all(&self) {
+        self.0.notify_waiters();
+    }
+
+    // Resolves at the next restart_all after this is called, even if not polled until later
+    pub fn requested(&self) -> Notified<'_> {
+        self.0.notified()
+    }
+}
+
+// Runs the task from `start` until it stops for good. `start` is called again for every
+// restart, so it should bind a fresh socket each time. Restarts and the final stop are
+// passed to `report`. A restart from `restarts` cancels the running task first, and brings
+// back one that had stopped for good.
+// Cancelling drops the task wherever it's waiting, so whatever it holds has to be released
+// on drop, as sockets are. Anything with a cleanup step of its own, like the mDNS daemon,
+// needs a guard that runs it in Drop. The task is dropped before the next start.
+pub async fn supervise<F, Fut>(name: &str, mut start: F, restarts: &Restarts, report: impl Fn(&str))
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<(), NetworkError>>,
+{
+    let min_delay = Duration::from_secs(LISTENER_RESTART_MIN_SECS);
+    let max_delay = Duration::from_secs(LISTENER_RESTART_MAX_SECS);
+    let mut delay = min_delay;
+
+    loop {
+        let restart = restarts.requested();
+        let started = Instant::now();
+        // Dropping the task closes its socket before start binds the next one
+        let result = tokio::select! {
+            result = start() => result,
+            _ = restart => {
+                debug_log(&format!("{} restarting to reconnect", name));
+                delay = min_delay;
+                continue;
+            }
+        };
+        let e = match result {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+
+        if e.is_fatal() {
+            report(&format!(
+                "{} stopped: {}. Use /reconnect to try again.",
+                name, e
+            ));
+            restarts.requested().await;
+            delay = min_delay;
+            continue;
+        }
+
+        // A listener that ran for a while before failing starts over from the short delay
+        if started.elapsed() >= max_delay {
+            delay = min_delay;
+        }
+        report(&format!(
+            "{} failed: {}, restarting in {}s",
+            name,
+            e,
+            delay.as_secs()
+        ));
+        let restart = restarts.requested();
+        tokio::select! {
+            _ = time::sleep(delay) => delay = delay.saturating_mul(2).min(max_delay),
+            // A reconnect doesn't wait out the delay
+            _ = restart => delay = min_delay,
+        }
+    }
+}
+
+// Tracks receive errors on a listening socket. One failed receive, like an ICMP error
+// reported on a UDP socket, says nothing about the next, so it's skipped. A socket that
+// keeps failing is given up on so the supervisor can bind a fresh one.
+pub struct RecvErrors {
+    name: &'static str,
+    consecutive: u32,
+}
+
+impl RecvErrors {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            consecutive: 0,
+        }
+    }
+
+    // Call after every successful receive
+    pub fn reset(&mut self) {
+        self.consecutive = 0;
+    }
+
+    // Returns the error once too many have come in a row
+    pub fn record(&mut self, e: io::Error) -> io::Result<()> {
+        self.consecutive += 1;
+        debug_log(&format!(
+            "Receive error on the {} socket ({} in a row): {}",
+            self.name, self.consecutive, e
+        ));
+        if self.consecutive >= MAX_CONSECUTIVE_RECV_ERRORS {
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Mutex;
+
+    fn recv_error() -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionReset, "reset")
+    }
+
+    #[test]
+    fn only_a_run_of_errors_gives_up() {
+        let mut errors = RecvErrors::new("test");
+        for _ in 1..MAX_CONSECUTIVE_RECV_ERRORS {
+            assert!(errors.record(recv_error()).is_ok());
+        }
+        errors.reset();
+        for _ in 1..MAX_CONSECUTIVE_RECV_ERRORS {
+            assert!(errors.record(recv_error()).is_ok());
+        }
+        assert!(errors.record(recv_error()).is_err());
+    }
+
+    #[tokio::test]
+    async fn a_failed_listener_is_started_again() {
+        let starts = AtomicU32::new(0);
+        let reports = Mutex::new(Vec::new());
+        supervise(
+            "Listener",
+            || async {
+                match starts.fetch_add(1, Ordering::Relaxed) {
+                    0 => Err(NetworkError::Recv(recv_error())),
+                    _ => Ok(()),
+                }
+            },
+            &Restarts::new(),
+            |report| reports.lock().unwrap().push(report.to_string()),
+        )
+        .await;
+
+        assert_eq!(starts.load(Ordering::Relaxed), 2);
+        let reports = reports.into_inner().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert!(reports[0].contains("restarting in 1s"));
+    }
+
+    // Stands in for something a listener owns that has to be cleaned up, like a daemon
+    struct Resource(Arc<AtomicU32>);
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_reconnect_cleans_up_before_starting_again() {
+        let starts = AtomicU32::new(0);
+        let cleanups = Arc::new(AtomicU32::new(0));
+        let restarts = Restarts::new();
+        let supervisor = supervise(
+            "Listener",
+            || {
+                // Every earlier run is cleaned up by the time the next one starts
+                let started = starts.fetch_add(1, Ordering::SeqCst);
+                assert_eq!(cleanups.load(Ordering::SeqCst), started);
+                let resource = Resource(cleanups.clone());
+                async move {
+                    let _resource = resource;
+                    std::future::pending::<Result<(), NetworkError>>().await
+                }
+            },
+            &restarts,
+            |_| {},
+        );
+        tokio::pin!(supervisor);
+
+        for _ in 0..3 {
+            let running = time::timeout(Duration::from_millis(20), &mut supervisor).await;
+            assert!(running.is_err());
+            restarts.restart_all();
+        }
+        let running = time::timeout(Duration::from_millis(20), &mut supervisor).await;
+        assert!(running.is_err());
+        assert_eq!(starts.load(Ordering::SeqCst), 4);
+        assert_eq!(cleanups.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn a_fatal_failure_waits_for_a_reconnect() {
+        let starts = AtomicU32::new(0);
+        let restarts = Restarts::new();
+        let supervisor = supervise(
+            "Listener",
+            || async {
+                match starts.fetch_add(1, Ordering::Relaxed) {
+                    0 => Err(NetworkError::Bind(io::Error::from(
+                        io::ErrorKind::AddrInUse,
+                    ))),
+                    _ => Ok(()),
+                }
+            },
+            &restarts,
+            |_| {},
+        );
+        tokio::pin!(supervisor);
+
+        // Stopped for good, not retrying on its own
+        let waited = time::timeout(Duration::from_millis(100), &mut supervisor).await;
+        assert!(waited.is_err());
+        assert_eq!(starts.load(Ordering::Relaxed), 1);
+
+        restarts.restart_all();
+        time::timeout(Duration::from_millis(100), supervisor)
+            .await
+            .unwrap();
+        assert_eq!(starts.load(Ordering::Relaxed), 2);
+    }
+}
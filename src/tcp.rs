@@ -0,0 +1,150 @@
+// Optional message delivery over TCP, for networks that drop or throttle UDP. Discovery
+// stays on UDP, TCP only carries chat packets to peers we already know.
+// A connection carries frames of a 4-byte big-endian length followed by one packet, encoded
+// exactly as it would be for UDP, so the receiver handles packets from both the same way.
+
+use crate::constants::MAX_MESSAGE_SIZE;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time;
+
+// Listens on both address families with one dual-stack socket where the OS allows it,
+// IPv4 only otherwise. A bind address limits it to that one address.
+pub fn bind_listener(port: u16, bind_address: Option<IpAddr>) -> io::Result<TcpListener> {
+    let socket = match bind_address {
+        Some(ip) => {
+            let addr = SocketAddr::new(ip, port);
+            bind_socket(Domain::for_address(addr), addr)
+        }
+        None => bind_dual_stack(port).or_else(|_| {
+            bind_socket(
+                Domain::IPV4,
+                SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+            )
+        }),
+    }?;
+    socket.listen(128)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_dual_stack(port: u16) -> io::Result<Socket> {
+    let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+    socket.set_only_v6(false)?;
+    bind(
+        socket,
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+    )
+}
+
+fn bind_socket(domain: Domain, addr: SocketAddr) -> io::Result<Socket> {
+    bind(
+        Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?,
+        addr,
+    )
+}
+
+fn bind(socket: Socket, addr: SocketAddr) -> io::Result<Socket> {
+    // Tokio expects non-blocking sockets
+    socket.set_nonblocking(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    Ok(socket)
+}
+
+// Opens a connection, giving up after the timeout so an unreachable peer can't hold up
+// the send
+pub async fn connect(addr: SocketAddr, timeout: Duration) -> io::Result<TcpStream> {
+    match time::timeout(timeout, TcpStream::connect(addr)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("connecting to {} timed out", addr),
+        )),
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, packet: &[u8]) -> io::Result<()> {
+    if packet.len() > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("packet is too large to send: {} bytes", packet.len()),
+        ));
+    }
+
+    writer
+        .write_all(&(packet.len() as u32).to_be_bytes())
+        .await?;
+    writer.write_all(packet).await?;
+    writer.flush().await
+}
+
+// Reads the next packet. Returns None when the other side closes the connection between
+// frames, and an error for one cut off midway or claiming to be over MAX_MESSAGE_SIZE.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    match reader.read_exact(&mut length).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+
+    let length = u32::from_be_bytes(length) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame is too large: {} bytes", length),
+        ));
+    }
+
+    let mut packet = vec![0u8; length];
+    reader.read_exact(&mut packet).await?;
+    Ok(Some(packet))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn frames_come_back_whole_and_in_order() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            write_frame(&mut client, b"CHAT~first").await.unwrap();
+            write_frame(&mut client, b"").await.unwrap();
+            write_frame(&mut client, &[b'x'; 300]).await.unwrap();
+        });
+
+        assert_eq!(
+            read_frame(&mut server).await.unwrap().unwrap(),
+            b"CHAT~first"
+        );
+        assert_eq!(read_frame(&mut server).await.unwrap().unwrap(), b"");
+        assert_eq!(read_frame(&mut server).await.unwrap().unwrap(), [b'x'; 300]);
+        writer.await.unwrap();
+        // The writer is gone, which ends the connection between frames
+        assert!(read_frame(&mut server).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn oversized_or_cut_off_frames_are_errors() {
+        let (mut client, mut server) = tokio::io::duplex(64);
+        let too_long = vec![0u8; MAX_MESSAGE_SIZE + 1];
+        assert!(write_frame(&mut client, &too_long).await.is_err());
+
+        client
+            .write_all(&((MAX_MESSAGE_SIZE + 1) as u32).to_be_bytes())
+            .await
+            .unwrap();
+        assert!(read_frame(&mut server).await.is_err());
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        client.write_all(&[0, 0, 0, 10]).await.unwrap();
+        client.write_all(b"short").await.unwrap();
+        drop(client);
+        assert!(read_frame(&mut server).await.is_err());
+    }
+}
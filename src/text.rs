@@ -0,0 +1,323 @@
+// Unicode-aware text measurement for the terminal UI.
+// Widths follow the usual terminal conventions: wide East Asian and emoji characters take
+// two columns, combining marks and joiners take none.
+
+use std::borrow::Cow;
+use std::ops::Range;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+// Characters that reorder the text around them, so a message could read differently than
+// it was written
+const BIDI_CONTROL_RANGES: &[(u32, u32)] = &[(0x202A, 0x202E), (0x2066, 0x2069)];
+
+fn in_ranges(c: char, ranges: &[(u32, u32)]) -> bool {
+    let code = c as u32;
+    ranges
+        .binary_search_by(|&(start, end)| {
+            if end < code {
+                std::cmp::Ordering::Less
+            } else if start > code {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
+
+// Number of terminal columns a string occupies
+pub fn str_width(text: &str) -> usize {
+    text.width()
+}
+
+// Longest prefix of text that fits in max_width columns, never splitting a character
+pub fn truncate_to_width(text: &str, max_width: usize) -> &str {
+    let mut width = 0;
+
+    for (index, grapheme) in text.grapheme_indices(true) {
+        width += grapheme.width();
+        if width > max_width {
+            return &text[..index];
+        }
+    }
+
+    text
+}
+
+// Cuts text to fit in max_width columns, ending it with an ellipsis when anything was cut
+pub fn ellipsize(text: &str, max_width: usize) -> Cow<'_, str> {
+    if str_width(text) <= max_width {
+        return Cow::Borrowed(text);
+    }
+    // Not even the ellipsis fits
+    if max_width == 0 {
+        return Cow::Borrowed("");
+    }
+    let kept = truncate_to_width(text, max_width.saturating_sub(1));
+    Cow::Owned(format!("{}…", kept))
+}
+
+// Greedy word wrap into lines of at most width columns, returning the byte range of each
+// line within text so callers can carry styling over to the wrapped lines. Words wider
+// than a whole line are broken at character boundaries.
+pub fn wrap_ranges(text: &str, width: usize) -> Vec<Range<usize>> {
+    let width = width.max(1);
+    let mut lines = Vec::new();
+    let mut line_start = 0;
+    let mut line_end = 0;
+    let mut line_width = 0;
+    let mut line_empty = true;
+    let mut word_start = 0;
+
+    for word in text.split(' ') {
+        let word_end = word_start + word.len();
+        let word_width = str_width(word);
+        let separator = usize::from(!line_empty);
+
+        if line_width + separator + word_width <= width {
+            if line_empty {
+                line_start = word_start;
+            }
+            line_end = word_end;
+            line_width += separator + word_width;
+            line_empty = false;
+        } else {
+            if !line_empty {
+                lines.push(line_start..line_end);
+            }
+
+            // Split words that can't fit on a line of their own
+            let mut start = word_start;
+            while str_width(&text[start..word_end]) > width {
+                let rest = &text[start..word_end];
+                let mut head_len = truncate_to_width(rest, width).len();
+                if head_len == 0 {
+                    // A single character wider than the line still has to go somewhere
+                    head_len = rest.chars().next().map_or(0, char::len_utf8);
+                }
+                lines.push(start..start + head_len);
+                start += head_len;
+            }
+
+            line_start = start;
+            line_end = word_end;
+            line_width = str_width(&text[start..word_end]);
+            line_empty = start == word_end;
+        }
+
+        // Skip past the space that separated this word from the next
+        word_start = word_end + 1;
+    }
+
+    if !line_empty || lines.is_empty() {
+        lines.push(line_start..line_end);
+    }
+
+    lines
+}
+
+// Byte ranges of the non-overlapping matches of needle in haystack, ignoring case
+pub fn find_ignore_case(haystack: &str, needle: &str) -> Vec<Range<usize>> {
+    let mut matches = Vec::new();
+    if needle.is_empty() {
+        return matches;
+    }
+
+    let mut start = 0;
+    while start < haystack.len() {
+        match match_end_ignore_case(&haystack[start..], needle) {
+            Some(length) => {
+                matches.push(start..start + length);
+                start += length;
+            }
+            None => {
+                start += haystack[start..].chars().next().map_or(1, char::len_utf8);
+            }
+        }
+    }
+
+    matches
+}
+
+// Whether the text contains the name as a whole word, ignoring case, so "bob" is mentioned
+// by "hi bob!" but not by "bobcat"
+pub fn mentions(text: &str, name: &str) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+
+    find_ignore_case(text, name).into_iter().any(|found| {
+        let before = text[..found.start].chars().next_back();
+        let after = text[found.end..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+// Makes text from the network safe to print in the raw-mode terminal. Control characters,
+// which include the escape that starts terminal sequences, are dropped along with bidi
+// overrides. Line breaks and tabs become spaces so the text stays on its line, and anything
+// beyond max_chars is cut off and marked with an ellipsis.
+pub fn sanitize_remote(text: &str, max_chars: usize) -> String {
+    let mut sanitized = String::with_capacity(text.len().min(max_chars));
+    let mut kept = 0;
+
+    for c in text.chars() {
+        let c = match c {
+            '\n' | '\r' | '\t' => ' ',
+            c if c.is_control() || in_ranges(c, BIDI_CONTROL_RANGES) => continue,
+            c => c,
+        };
+        if kept == max_chars {
+            sanitized.pop();
+            sanitized.push('…');
+            break;
+        }
+        sanitized.push(c);
+        kept += 1;
+    }
+
+    sanitized
+}
+
+// Length in bytes of the prefix of text matching needle, if it starts with it
+fn match_end_ignore_case(text: &str, needle: &str) -> Option<usize> {
+    let mut text_chars = text.char_indices();
+    for expected in needle.chars() {
+        let (_, actual) = text_chars.next()?;
+        if !actual.to_lowercase().eq(expected.to_lowercase()) {
+            return None;
+        }
+    }
+    Some(text_chars.next().map_or(text.len(), |(index, _)| index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STATUS: &str = " 🕒 12:30:15 | 📅 2024-05-01 | 📺 80x24 | ⌨️  F1: Help ";
+
+    #[test]
+    fn wide_characters_take_two_columns() {
+        assert_eq!(str_width("abc"), 3);
+        assert_eq!(str_width("🕒"), 2);
+        assert_eq!(str_width("日本"), 4);
+        // The keyboard is narrow on its own, the variation selector makes it an emoji
+        assert_eq!(str_width("⌨"), 1);
+        assert_eq!(str_width("⌨️"), 2);
+        assert_eq!(str_width("e\u{301}"), 1);
+        assert_eq!(str_width(STATUS), 55);
+    }
+
+    #[test]
+    fn narrow_widths_never_split_a_character() {
+        for width in 0..=str_width(STATUS) + 1 {
+            let cut = truncate_to_width(STATUS, width);
+            assert!(STATUS.starts_with(cut));
+            assert!(str_width(cut) <= width);
+            // Only a wide character that didn't fit can leave a column unused
+            assert!(str_width(cut) + 1 >= width.min(str_width(STATUS)));
+        }
+        assert_eq!(truncate_to_width(STATUS, 2), " ");
+        assert_eq!(truncate_to_width(STATUS, 3), " 🕒");
+        assert_eq!(truncate_to_width("⌨️ x", 2), "⌨️");
+        assert_eq!(truncate_to_width("⌨️ x", 1), "");
+    }
+
+    fn wrap(text: &str, width: usize) -> Vec<&str> {
+        wrap_ranges(text, width)
+            .into_iter()
+            .map(|range| &text[range])
+            .collect()
+    }
+
+    #[test]
+    fn wraps_at_spaces() {
+        assert_eq!(wrap("hello world", 20), vec!["hello world"]);
+        assert_eq!(wrap("hello world", 11), vec!["hello world"]);
+        assert_eq!(wrap("hello world", 10), vec!["hello", "world"]);
+        assert_eq!(wrap("a b c d", 3), vec!["a b", "c d"]);
+    }
+
+    #[test]
+    fn long_words_are_broken() {
+        assert_eq!(wrap("abcdefgh", 3), vec!["abc", "def", "gh"]);
+        assert_eq!(wrap("hi abcdefgh", 4), vec!["hi", "abcd", "efgh"]);
+        // Wide characters are never split, even when half of one would fit
+        assert_eq!(wrap("日本語", 3), vec!["日", "本", "語"]);
+        assert_eq!(wrap("日", 1), vec!["日"]);
+    }
+
+    #[test]
+    fn every_line_fits() {
+        let text = "the quick 🦊 jumps over the lazy 🐕, twice as wide as it looks";
+        // From two columns up, as narrower lines can't hold the emoji
+        for width in 2..40 {
+            for line in wrap(text, width) {
+                assert!(str_width(line) <= width, "{:?} at {}", line, width);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_text_is_one_empty_line() {
+        assert_eq!(wrap("", 10), vec![""]);
+        assert_eq!(wrap("abc", 0), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn finds_every_match_ignoring_case() {
+        assert_eq!(
+            find_ignore_case("Rust and rust, RUST", "rust"),
+            vec![0..4, 9..13, 15..19]
+        );
+        assert_eq!(find_ignore_case("aaaa", "aa"), vec![0..2, 2..4]);
+        assert_eq!(find_ignore_case("héllo HÉLLO", "héllo"), vec![0..6, 7..13]);
+        assert!(find_ignore_case("hello", "").is_empty());
+        assert!(find_ignore_case("hello", "bye").is_empty());
+    }
+
+    #[test]
+    fn mentions_need_a_whole_word() {
+        assert!(mentions("hi bob!", "bob"));
+        assert!(mentions("BOB, lunch?", "bob"));
+        assert!(mentions("@bob", "bob"));
+        assert!(mentions("bobcat or bob", "bob"));
+        assert!(!mentions("bobcat", "bob"));
+        assert!(!mentions("kabob", "bob"));
+        assert!(!mentions("bob_2 is here", "bob"));
+        assert!(!mentions("hello", ""));
+    }
+
+    #[test]
+    fn remote_text_loses_terminal_controls() {
+        assert_eq!(sanitize_remote("\x1b[2Jmallory", 32), "[2Jmallory");
+        assert_eq!(sanitize_remote("a\rb\nc\td", 32), "a b c d");
+        assert_eq!(sanitize_remote("bell\x07\u{202e}olleh", 32), "bellolleh");
+        assert_eq!(sanitize_remote("héllo wörld", 32), "héllo wörld");
+    }
+
+    #[test]
+    fn remote_text_is_capped() {
+        assert_eq!(sanitize_remote("abcdef", 4), "abc…");
+        assert_eq!(sanitize_remote("abcd", 4), "abcd");
+        // Dropped characters don't count towards the limit
+        assert_eq!(sanitize_remote("\x1b\x1bab", 2), "ab");
+    }
+
+    #[test]
+    fn long_names_are_ellipsized_by_column() {
+        assert_eq!(ellipsize("alice", 16), "alice");
+        assert_eq!(
+            ellipsize(&"a".repeat(40), 16),
+            format!("{}…", "a".repeat(15))
+        );
+        // A wide character that would straddle the limit is left out whole
+        let wide = "日本語".repeat(10);
+        let shown = ellipsize(&wide, 16);
+        assert_eq!(shown, "日本語日本語日…");
+        assert_eq!(str_width(&shown), 15);
+        assert_eq!(ellipsize("ab", 1), "…");
+        assert_eq!(ellipsize("ab", 0), "");
+        assert_eq!(ellipsize("", 0), "");
+    }
+}
@@ -0,0 +1,296 @@
+// Colors used by the UI. A theme starts from one of the presets, picked with `theme = "name"`
+// in the config or --theme, and the config's [theme] section can override single colors.
+
+use crate::color_support::{downgrade, ColorSupport};
+use crossterm::style::Color;
+
+pub const DEFAULT_THEME: &str = "default";
+pub const THEME_NAMES: [&str; 4] = [DEFAULT_THEME, "cyberpunk", "mono", "light"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Theme {
+    pub status_bar_background: Color,
+    pub status_bar_foreground: Color,
+    pub prompt: Color,
+    // Our own name and actions
+    pub local_message: Color,
+    pub system_message: Color,
+    // Messages older than the dim_after_secs setting
+    pub old_message: Color,
+    pub delivered: Color,
+    // Content of messages that mention our name
+    pub mention: Color,
+    // Commands listed by tab completion
+    pub completion: Color,
+    pub search_highlight_background: Color,
+    pub search_highlight_foreground: Color,
+    // Colors assigned to remote sender names, picked by hashing the name. Never empty.
+    pub name_palette: Vec<Color>,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            status_bar_background: Color::DarkBlue,
+            status_bar_foreground: Color::White,
+            prompt: Color::Reset,
+            local_message: Color::White,
+            system_message: Color::DarkGrey,
+            old_message: Color::DarkGrey,
+            delivered: Color::Green,
+            mention: Color::Red,
+            completion: Color::Yellow,
+            search_highlight_background: Color::Yellow,
+            search_highlight_foreground: Color::Black,
+            name_palette: vec![
+                Color::Cyan,
+                Color::Green,
+                Color::Magenta,
+                Color::Yellow,
+                Color::Blue,
+                Color::Red,
+                Color::DarkCyan,
+                Color::DarkGreen,
+                Color::DarkMagenta,
+                Color::DarkYellow,
+            ],
+        }
+    }
+}
+
+impl Theme {
+    // One of the presets in THEME_NAMES, matched ignoring case
+    pub fn named(name: &str) -> Option<Theme> {
+        let theme = match name.to_lowercase().as_str() {
+            DEFAULT_THEME => Theme::default(),
+            "cyberpunk" => Theme {
+                status_bar_background: Color::Magenta,
+                status_bar_foreground: Color::Black,
+                prompt: Color::Cyan,
+                local_message: Color::Cyan,
+                system_message: Color::DarkMagenta,
+                old_message: Color::DarkBlue,
+                delivered: Color::Cyan,
+                mention: Color::Yellow,
+                completion: Color::Magenta,
+                search_highlight_background: Color::Cyan,
+                search_highlight_foreground: Color::Black,
+                name_palette: vec![
+                    Color::Magenta,
+                    Color::Cyan,
+                    Color::Yellow,
+                    Color::Green,
+                    Color::Red,
+                    Color::Blue,
+                ],
+            },
+            // For terminals without color, or people who'd rather not have it
+            "mono" => Theme {
+                status_bar_background: Color::White,
+                status_bar_foreground: Color::Black,
+                prompt: Color::Reset,
+                local_message: Color::White,
+                system_message: Color::Grey,
+                old_message: Color::DarkGrey,
+                delivered: Color::Reset,
+                mention: Color::White,
+                completion: Color::White,
+                search_highlight_background: Color::White,
+                search_highlight_foreground: Color::Black,
+                name_palette: vec![Color::Reset],
+            },
+            // Dark colors that stay readable on a light background
+            "light" => Theme {
+                status_bar_background: Color::DarkBlue,
+                status_bar_foreground: Color::White,
+                prompt: Color::DarkBlue,
+                local_message: Color::Black,
+                system_message: Color::DarkGrey,
+                old_message: Color::Grey,
+                delivered: Color::DarkGreen,
+                mention: Color::DarkRed,
+                completion: Color::DarkMagenta,
+                search_highlight_background: Color::DarkYellow,
+                search_highlight_foreground: Color::White,
+                name_palette: vec![
+                    Color::DarkBlue,
+                    Color::DarkGreen,
+                    Color::DarkMagenta,
+                    Color::DarkRed,
+                    Color::DarkCyan,
+                    Color::DarkYellow,
+                ],
+            },
+            _ => return None,
+        };
+        Some(theme)
+    }
+
+    // The preset, or the default theme when there's no preset by that name
+    pub fn named_or_default(name: &str) -> Theme {
+        Theme::named(name).unwrap_or_default()
+    }
+
+    // Sets one color from the config's [theme] section, returning an error message for
+    // unknown keys and colors
+    pub fn set_color(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if key == "name_palette" {
+            let palette = value
+                .split(',')
+                .map(|name| parse_color(name.trim()))
+                .collect::<Result<Vec<Color>, String>>()?;
+            if palette.is_empty() {
+                return Err("name_palette needs at least one color".to_string());
+            }
+            self.name_palette = palette;
+            return Ok(());
+        }
+
+        let color = parse_color(value)?;
+        let field = match key {
+            "status_bar_background" => &mut self.status_bar_background,
+            "status_bar_foreground" => &mut self.status_bar_foreground,
+            "prompt" => &mut self.prompt,
+            "local_message" => &mut self.local_message,
+            "system_message" => &mut self.system_message,
+            "old_message" => &mut self.old_message,
+            "delivered" => &mut self.delivered,
+            "mention" => &mut self.mention,
+            "completion" => &mut self.completion,
+            "search_highlight_background" => &mut self.search_highlight_background,
+            "search_highlight_foreground" => &mut self.search_highlight_foreground,
+            _ => return Err(format!("unknown theme color: {}", key)),
+        };
+        *field = color;
+        Ok(())
+    }
+
+    // The theme with every color mapped to one the terminal can show
+    pub fn downgraded(&self, support: ColorSupport) -> Theme {
+        let color = |color| downgrade(color, support);
+        Theme {
+            status_bar_background: color(self.status_bar_background),
+            status_bar_foreground: color(self.status_bar_foreground),
+            prompt: color(self.prompt),
+            local_message: color(self.local_message),
+            system_message: color(self.system_message),
+            old_message: color(self.old_message),
+            delivered: color(self.delivered),
+            mention: color(self.mention),
+            completion: color(self.completion),
+            search_highlight_background: color(self.search_highlight_background),
+            search_highlight_foreground: color(self.search_highlight_foreground),
+            name_palette: self.name_palette.iter().copied().map(color).collect(),
+        }
+    }
+
+    // Hashes the name (FNV-1a) so a user keeps the same color for the whole session
+    pub fn name_color(&self, name: &str) -> Color {
+        let mut hash: u32 = 0x811c9dc5;
+        for byte in name.bytes() {
+            hash ^= u32::from(byte);
+            hash = hash.wrapping_mul(0x01000193);
+        }
+        self.name_palette
+            .get(hash as usize % self.name_palette.len().max(1))
+            .copied()
+            .unwrap_or(Color::Reset)
+    }
+}
+
+// Color names as crossterm spells them ("dark_blue"), plus "reset" for the terminal's own,
+// "#rrggbb" for 24-bit colors and 0 to 255 for the 256-color palette
+fn parse_color(name: &str) -> Result<Color, String> {
+    if name.eq_ignore_ascii_case("reset") {
+        return Ok(Color::Reset);
+    }
+    if let Some(hex) = name.strip_prefix('#') {
+        let channel = |i: usize| {
+            hex.get(i..i + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+        };
+        if let (6, Some(r), Some(g), Some(b)) = (hex.len(), channel(0), channel(2), channel(4)) {
+            return Ok(Color::Rgb { r, g, b });
+        }
+    }
+    if let Ok(value) = name.parse::<u8>() {
+        return Ok(Color::AnsiValue(value));
+    }
+    Color::try_from(name).map_err(|_| format!("unknown color: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_name_always_gets_the_same_color() {
+        let theme = Theme::default();
+        assert_eq!(
+            theme.name_color("alice"),
+            Theme::default().name_color("alice")
+        );
+        assert_eq!(theme.name_color("alice"), theme.name_color("alice"));
+        // FNV-1a of an empty name is the offset basis, 2166136261, which is index 1
+        assert_eq!(theme.name_color(""), theme.name_palette[1]);
+    }
+
+    #[test]
+    fn names_spread_over_the_palette() {
+        let theme = Theme::default();
+        let mut used: Vec<Color> = Vec::new();
+        for name in [
+            "alice", "bob", "carol", "dave", "eve", "frank", "grace", "heidi",
+        ] {
+            let color = theme.name_color(name);
+            assert!(theme.name_palette.contains(&color));
+            if !used.contains(&color) {
+                used.push(color);
+            }
+        }
+        assert!(used.len() > 1);
+
+        let single = Theme {
+            name_palette: vec![Color::Cyan],
+            ..Theme::default()
+        };
+        assert_eq!(single.name_color("bob"), Color::Cyan);
+    }
+
+    #[test]
+    fn an_unknown_theme_falls_back_to_the_default() {
+        assert_eq!(Theme::named("solarized"), None);
+        assert_eq!(Theme::named_or_default("solarized"), Theme::default());
+        assert_eq!(Theme::named_or_default(""), Theme::default());
+    }
+
+    #[test]
+    fn every_preset_is_found_ignoring_case() {
+        for name in THEME_NAMES {
+            assert!(Theme::named(name).is_some(), "{}", name);
+            assert!(Theme::named(&name.to_uppercase()).is_some(), "{}", name);
+        }
+        assert_ne!(Theme::named_or_default("mono"), Theme::default());
+    }
+
+    #[test]
+    fn colors_are_set_from_the_config() {
+        let mut theme = Theme::default();
+        theme.set_color("prompt", "#ff8000").unwrap();
+        assert_eq!(
+            theme.prompt,
+            Color::Rgb {
+                r: 255,
+                g: 128,
+                b: 0
+            }
+        );
+        theme.set_color("mention", "208").unwrap();
+        assert_eq!(theme.mention, Color::AnsiValue(208));
+        theme.set_color("name_palette", "red, dark_blue").unwrap();
+        assert_eq!(theme.name_palette, vec![Color::Red, Color::DarkBlue]);
+
+        assert!(theme.set_color("prompt", "not-a-color").is_err());
+        assert!(theme.set_color("border", "red").is_err());
+    }
+}
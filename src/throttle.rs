@@ -0,0 +1,112 @@
+// Spacing between our own broadcasts, so holding Enter or pasting many lines can't flood a
+// slow link. Sends that come too fast wait for the next free slot, and the same text sent
+// again within the interval is taken to be an accidental double send and dropped.
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Throttled {
+    // Free to go out right away
+    Now,
+    // Has to wait this long for its slot
+    After(Duration),
+    // A repeat of the previous send, not worth sending
+    Repeat,
+}
+
+pub struct SendThrottle {
+    min_interval: Duration,
+    // Slot and text of the latest send let through, which may still be waiting
+    last: Option<(Instant, String)>,
+}
+
+impl SendThrottle {
+    // An interval of zero lets everything through
+    pub fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last: None,
+        }
+    }
+
+    // Decides when a send of text may go out and reserves that slot for it
+    pub fn schedule(&mut self, text: &str, now: Instant) -> Throttled {
+        if let Some((slot, last_text)) = &self.last {
+            let next_slot = *slot + self.min_interval;
+            if now < next_slot {
+                if last_text == text {
+                    return Throttled::Repeat;
+                }
+                self.last = Some((next_slot, text.to_string()));
+                return Throttled::After(next_slot - now);
+            }
+        }
+
+        self.last = Some((now, text.to_string()));
+        Throttled::Now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn one_send_per_interval_goes_out_right_away() {
+        let mut throttle = SendThrottle::new(INTERVAL);
+        let start = Instant::now();
+        assert_eq!(throttle.schedule("one", start), Throttled::Now);
+        assert_eq!(throttle.schedule("two", start + INTERVAL), Throttled::Now);
+        assert_eq!(
+            throttle.schedule("three", start + INTERVAL * 3),
+            Throttled::Now
+        );
+    }
+
+    #[test]
+    fn sends_that_come_too_fast_are_deferred_to_the_next_slot() {
+        let mut throttle = SendThrottle::new(INTERVAL);
+        let start = Instant::now();
+        assert_eq!(throttle.schedule("one", start), Throttled::Now);
+
+        // Each one waits for the slot after the one before it
+        let soon = start + Duration::from_millis(50);
+        assert_eq!(
+            throttle.schedule("two", soon),
+            Throttled::After(Duration::from_millis(150))
+        );
+        assert_eq!(
+            throttle.schedule("three", soon),
+            Throttled::After(Duration::from_millis(350))
+        );
+
+        // Once the queue has drained, sends go straight out again
+        assert_eq!(
+            throttle.schedule("four", start + INTERVAL * 3),
+            Throttled::Now
+        );
+    }
+
+    #[test]
+    fn a_double_send_is_dropped() {
+        let mut throttle = SendThrottle::new(INTERVAL);
+        let start = Instant::now();
+        assert_eq!(throttle.schedule("hi", start), Throttled::Now);
+        assert_eq!(
+            throttle.schedule("hi", start + Duration::from_millis(10)),
+            Throttled::Repeat
+        );
+        // Saying it again later is deliberate
+        assert_eq!(throttle.schedule("hi", start + INTERVAL), Throttled::Now);
+    }
+
+    #[test]
+    fn a_zero_interval_lets_everything_through() {
+        let mut throttle = SendThrottle::new(Duration::ZERO);
+        let now = Instant::now();
+        assert_eq!(throttle.schedule("hi", now), Throttled::Now);
+        assert_eq!(throttle.schedule("hi", now), Throttled::Now);
+    }
+}
@@ -0,0 +1,68 @@
+// Message timestamps, shown in the strftime-style format from the config's time_format.
+// See chrono's format::strftime documentation for the specifiers.
+
+use crate::constants::DEFAULT_TIME_FORMAT;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use std::fmt::Write;
+
+// Shown ahead of the time on the first message of a new day
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+// Whether every specifier in the format is one chrono knows
+pub fn is_valid_format(format: &str) -> bool {
+    !format.is_empty() && StrftimeItems::new(format).all(|item| item != Item::Error)
+}
+
+// Falls back to DEFAULT_TIME_FORMAT instead of failing on a bad format
+pub fn format_timestamp(timestamp: &DateTime<Local>, format: &str, with_date: bool) -> String {
+    let mut formatted = String::new();
+    if with_date {
+        let _ = write!(formatted, "{} ", timestamp.format(DATE_FORMAT));
+    }
+
+    let start = formatted.len();
+    if !is_valid_format(format) || write!(formatted, "{}", timestamp.format(format)).is_err() {
+        formatted.truncate(start);
+        let _ = write!(formatted, "{}", timestamp.format(DEFAULT_TIME_FORMAT));
+    }
+    formatted
+}
+
+// Whether the two times fall on different local days
+pub fn is_new_day(previous: &DateTime<Local>, current: &DateTime<Local>) -> bool {
+    previous.date_naive() != current.date_naive()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn at(day: u32, hour: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 3, day, hour, 5, 9).unwrap()
+    }
+
+    #[test]
+    fn invalid_formats_fall_back_to_the_default() {
+        for format in ["", "%Q", "%H:%", "%Ez"] {
+            assert!(!is_valid_format(format), "{:?} passed", format);
+            assert_eq!(format_timestamp(&at(1, 14), format, false), "14:05:09");
+        }
+        assert_eq!(format_timestamp(&at(1, 14), "%I:%M %p", false), "02:05 PM");
+    }
+
+    #[test]
+    fn the_date_goes_ahead_of_the_time() {
+        assert_eq!(
+            format_timestamp(&at(2, 0), "%H:%M", true),
+            "2024-03-02 00:05"
+        );
+        assert_eq!(
+            format_timestamp(&at(2, 0), "%Q", true),
+            "2024-03-02 00:05:09"
+        );
+        assert!(is_new_day(&at(1, 23), &at(2, 0)));
+        assert!(!is_new_day(&at(2, 0), &at(2, 23)));
+    }
+}
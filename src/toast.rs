@@ -0,0 +1,79 @@
+// Short-lived notices, like a failed send or someone joining, shown on their own line above
+// the status bar instead of in the message list. A newer toast replaces the one shown, and
+// each clears itself once its time is up.
+
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Default)]
+pub struct Toast {
+    current: Option<(String, Instant)>,
+}
+
+impl Toast {
+    pub fn show(&mut self, text: &str, duration: Duration, now: Instant) {
+        self.current = Some((text.to_string(), now + duration));
+    }
+
+    // The text to show, None once it has run out
+    pub fn text(&self, now: Instant) -> Option<&str> {
+        self.current
+            .as_ref()
+            .filter(|(_, until)| now < *until)
+            .map(|(text, _)| text.as_str())
+    }
+
+    // Whether the toast line is taken, which it stays until the next expire call
+    pub fn is_shown(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // Drops a toast that has run out, returning true if one was cleared and the line
+    // needs repainting
+    pub fn expire(&mut self, now: Instant) -> bool {
+        if self.is_shown() && self.text(now).is_none() {
+            self.current = None;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_toast_clears_once_its_time_is_up() {
+        let mut toast = Toast::default();
+        let start = Instant::now();
+        assert!(!toast.expire(start));
+
+        toast.show("alice joined", Duration::from_secs(3), start);
+        assert_eq!(toast.text(start), Some("alice joined"));
+        assert!(!toast.expire(start + Duration::from_secs(2)));
+        assert!(toast.is_shown());
+
+        // Run out but still on screen until expired
+        assert_eq!(toast.text(start + Duration::from_secs(3)), None);
+        assert!(toast.is_shown());
+        assert!(toast.expire(start + Duration::from_secs(3)));
+        assert!(!toast.is_shown());
+        assert!(!toast.expire(start + Duration::from_secs(4)));
+    }
+
+    #[test]
+    fn a_newer_toast_replaces_the_shown_one() {
+        let mut toast = Toast::default();
+        let start = Instant::now();
+        toast.show("Failed to broadcast", Duration::from_secs(1), start);
+        toast.show(
+            "bob left",
+            Duration::from_secs(5),
+            start + Duration::from_millis(500),
+        );
+
+        let later = start + Duration::from_secs(2);
+        assert_eq!(toast.text(later), Some("bob left"));
+        assert!(!toast.expire(later));
+    }
+}
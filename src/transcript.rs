@@ -0,0 +1,100 @@
+// Plain text transcript of the messages on screen, written by /save
+
+use crate::console_graphics::DisplayMessage;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+// One line per message, oldest first
+pub fn format_transcript(messages: &[DisplayMessage]) -> String {
+    messages
+        .iter()
+        .map(|message| format!("{}\n", format_line(message)))
+        .collect()
+}
+
+// Also how headless mode prints received messages
+pub fn format_line(message: &DisplayMessage) -> String {
+    let time = message.timestamp.format(TIMESTAMP_FORMAT);
+    if message.is_system {
+        return format!("{} -- {}", time, message.content);
+    }
+
+    // Our own messages have no address worth recording
+    let mut sender = message.sender_name.clone();
+    if !message.is_local {
+        sender.push_str(&format!(" ({})", message.sender_ip));
+    }
+    if let Some(recipient) = &message.recipient {
+        sender.push_str(&format!(" -> {}", recipient));
+    }
+
+    let edited = if message.edited { " (edited)" } else { "" };
+    if message.is_action {
+        format!("{} * {} {}{}", time, sender, message.content, edited)
+    } else {
+        format!("{} {}: {}{}", time, sender, message.content, edited)
+    }
+}
+
+// Overwrites the file if it exists
+pub fn save(path: &Path, messages: &[DisplayMessage]) -> io::Result<()> {
+    fs::write(path, format_transcript(messages))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::Message;
+    use chrono::{Local, TimeZone};
+
+    fn at_noon(mut message: DisplayMessage) -> DisplayMessage {
+        message.timestamp = Local.with_ymd_and_hms(2024, 3, 1, 12, 0, 5).unwrap();
+        message
+    }
+
+    fn received(content: &str) -> DisplayMessage {
+        at_noon(DisplayMessage::from_message(&Message::new(
+            content.to_string(),
+            "bob".to_string(),
+            "10.0.0.2".to_string(),
+        )))
+    }
+
+    #[test]
+    fn transcripts_have_a_line_per_message() {
+        let mut edited = received("typo fixed");
+        edited.edited = true;
+        let direct = at_noon(DisplayMessage::from_message(
+            &Message::new("psst".to_string(), "alice".to_string(), "local".to_string())
+                .with_recipient("bob".to_string()),
+        ));
+        let action = at_noon(DisplayMessage::from_message(
+            &Message::new(
+                "waves".to_string(),
+                "bob".to_string(),
+                "10.0.0.2".to_string(),
+            )
+            .into_action(),
+        ));
+        let messages = [
+            at_noon(DisplayMessage::system("bob joined")),
+            received("hello"),
+            edited,
+            direct,
+            action,
+        ];
+
+        assert_eq!(
+            format_transcript(&messages),
+            "2024-03-01 12:00:05 -- bob joined\n\
+             2024-03-01 12:00:05 bob (10.0.0.2): hello\n\
+             2024-03-01 12:00:05 bob (10.0.0.2): typo fixed (edited)\n\
+             2024-03-01 12:00:05 alice -> bob: psst\n\
+             2024-03-01 12:00:05 * bob (10.0.0.2) waves\n"
+        );
+        assert_eq!(format_transcript(&[]), "");
+    }
+}
@@ -0,0 +1,230 @@
+// What the chat traffic needs from a datagram socket. Sending and receiving chat messages
+// goes through this rather than DualSocket directly, so the socket can be swapped for
+// something else, like the in-memory MemoryTransport the tests exercise the networking code
+// with.
+
+use crate::udp::DualSocket;
+#[cfg(test)]
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+#[cfg(test)]
+use std::net::IpAddr;
+use std::net::SocketAddr;
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use tokio::sync::mpsc;
+
+pub trait Transport: Send + Sync {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    // Whether IPv4 targets can be reached. The Tailscale sweep is skipped when they can't.
+    fn has_v4(&self) -> bool;
+}
+
+impl Transport for DualSocket {
+    fn send_to(
+        &self,
+        buf: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send {
+        DualSocket::send_to(self, buf, target)
+    }
+
+    fn recv_from(
+        &self,
+        buf: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send {
+        DualSocket::recv_from(self, buf)
+    }
+
+    fn has_v4(&self) -> bool {
+        DualSocket::has_v4(self)
+    }
+}
+
+// An in-memory stand-in for DualSocket. Transports bound on the same MemoryNetwork reach
+// each other by address, and a datagram to a multicast or broadcast address goes to every
+// other transport on its port, like on a LAN. Datagrams to addresses nobody is bound to are
+// dropped without an error, as UDP would.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct MemoryNetwork {
+    endpoints: Arc<Mutex<HashMap<SocketAddr, Inbox>>>,
+}
+
+// A datagram and who sent it, or a simulated error
+#[cfg(test)]
+type Delivery = io::Result<(Vec<u8>, SocketAddr)>;
+
+#[cfg(test)]
+type Inbox = mpsc::UnboundedSender<Delivery>;
+
+#[cfg(test)]
+impl MemoryNetwork {
+    // Replaces whatever was bound to the address before
+    pub fn bind(&self, addr: SocketAddr) -> MemoryTransport {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.endpoints.lock().unwrap().insert(addr, sender);
+        MemoryTransport {
+            addr,
+            network: self.clone(),
+            inbox: Arc::new(tokio::sync::Mutex::new(receiver)),
+        }
+    }
+
+    // Makes the next receive on the address fail, like a socket error would
+    pub fn fail_next_recv(&self, addr: SocketAddr, kind: io::ErrorKind) {
+        if let Some(inbox) = self.endpoints.lock().unwrap().get(&addr) {
+            let _ = inbox.send(Err(io::Error::new(kind, "simulated receive error")));
+        }
+    }
+
+    fn deliver(&self, from: SocketAddr, target: SocketAddr, data: &[u8]) {
+        let is_group = match target.ip() {
+            IpAddr::V4(ip) => ip.is_multicast() || ip.is_broadcast(),
+            IpAddr::V6(ip) => ip.is_multicast(),
+        };
+        for (addr, inbox) in self.endpoints.lock().unwrap().iter() {
+            let reached = if is_group {
+                addr.port() == target.port() && *addr != from
+            } else {
+                *addr == target
+            };
+            if reached {
+                let _ = inbox.send(Ok((data.to_vec(), from)));
+            }
+        }
+    }
+}
+
+// Clones share the same inbox, like handles to one socket
+#[cfg(test)]
+#[derive(Clone)]
+pub struct MemoryTransport {
+    addr: SocketAddr,
+    network: MemoryNetwork,
+    inbox: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Delivery>>>,
+}
+
+#[cfg(test)]
+impl Transport for MemoryTransport {
+    async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.network.deliver(self.addr, target, buf);
+        Ok(buf.len())
+    }
+
+    // A datagram longer than the buffer is cut short, like recv_from on a UDP socket
+    async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (data, from) = self
+            .inbox
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotConnected))??;
+        let size = data.len().min(buf.len());
+        buf[..size].copy_from_slice(&data[..size]);
+        Ok((size, from))
+    }
+
+    // Keeps the Tailscale sweep out of tests
+    fn has_v4(&self) -> bool {
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(text: &str) -> SocketAddr {
+        text.parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn datagrams_reach_the_bound_address() {
+        let network = MemoryNetwork::default();
+        let alice = network.bind(addr("10.0.0.1:5000"));
+        let bob = network.bind(addr("10.0.0.2:5000"));
+
+        alice
+            .send_to(b"hello", addr("10.0.0.2:5000"))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let (size, from) = bob.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert_eq!(from, addr("10.0.0.1:5000"));
+    }
+
+    #[tokio::test]
+    async fn group_addresses_reach_everyone_else_on_the_port() {
+        let network = MemoryNetwork::default();
+        let alice = network.bind(addr("10.0.0.1:5000"));
+        let bob = network.bind(addr("10.0.0.2:5000"));
+        let other_port = network.bind(addr("10.0.0.3:6000"));
+
+        alice
+            .send_to(b"all", addr("255.255.255.255:5000"))
+            .await
+            .unwrap();
+        alice.send_to(b"v6", addr("[ff02::1]:5000")).await.unwrap();
+        alice
+            .send_to(b"nobody", addr("10.0.0.9:5000"))
+            .await
+            .unwrap();
+
+        let mut buf = [0u8; 16];
+        let (size, _) = bob.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"all");
+        let (size, _) = bob.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"v6");
+        for transport in [&alice, &other_port] {
+            assert!(transport.inbox.lock().await.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn long_datagrams_are_cut_to_the_buffer() {
+        let network = MemoryNetwork::default();
+        let alice = network.bind(addr("10.0.0.1:5000"));
+        let bob = network.bind(addr("10.0.0.2:5000"));
+
+        alice
+            .send_to(&[7u8; 32], addr("10.0.0.2:5000"))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 8];
+        let (size, _) = bob.recv_from(&mut buf).await.unwrap();
+        assert_eq!(size, 8);
+    }
+
+    #[tokio::test]
+    async fn simulated_errors_come_before_later_datagrams() {
+        let network = MemoryNetwork::default();
+        let alice = network.bind(addr("10.0.0.1:5000"));
+        let bob = network.bind(addr("10.0.0.2:5000"));
+
+        network.fail_next_recv(addr("10.0.0.2:5000"), io::ErrorKind::ConnectionReset);
+        alice
+            .send_to(b"after", addr("10.0.0.2:5000"))
+            .await
+            .unwrap();
+        let mut buf = [0u8; 16];
+        let error = bob.recv_from(&mut buf).await.unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionReset);
+        let (size, _) = bob.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"after");
+    }
+}
@@ -0,0 +1,136 @@
+// Typing indicators: when to tell peers we're typing, and who is typing right now.
+// A TYPING packet is MSG_TYPE_TYPING, name, ip, then "1" when typing starts or "0" when it
+// stops. Starts are repeated while typing continues, so a lost stop only lingers until the
+// indicator expires.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// Decides when our own typing state is worth a packet
+pub struct TypingNotifier {
+    // When we last announced that we're typing, None while we're not
+    last_start: Option<Instant>,
+    resend_after: Duration,
+}
+
+impl TypingNotifier {
+    pub fn new(resend_after: Duration) -> Self {
+        Self {
+            last_start: None,
+            resend_after,
+        }
+    }
+
+    // Called whenever the input line changes. Returns Some(true) when a start should be sent,
+    // Some(false) for a stop, and None when peers already know.
+    pub fn update(&mut self, input: &str) -> Option<bool> {
+        // Commands are never sent, so typing one isn't announced either
+        let composing = !input.trim().is_empty() && !input.starts_with('/');
+
+        match (composing, self.last_start) {
+            (true, Some(sent)) if sent.elapsed() < self.resend_after => None,
+            (true, _) => {
+                self.last_start = Some(Instant::now());
+                Some(true)
+            }
+            (false, Some(_)) => {
+                self.last_start = None;
+                Some(false)
+            }
+            (false, None) => None,
+        }
+    }
+}
+
+// Peers currently typing, each forgotten after a while without a fresh start
+pub struct TypingPeers {
+    started: HashMap<String, Instant>,
+    expiry: Duration,
+}
+
+impl TypingPeers {
+    pub fn new(expiry: Duration) -> Self {
+        Self {
+            started: HashMap::new(),
+            expiry,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, typing: bool) {
+        if typing {
+            self.started.insert(name.to_string(), Instant::now());
+        } else {
+            self.started.remove(name);
+        }
+    }
+
+    // Names of the peers still typing, sorted, after dropping the expired ones
+    pub fn active(&mut self) -> Vec<String> {
+        let expiry = self.expiry;
+        self.started
+            .retain(|_, started| started.elapsed() <= expiry);
+
+        let mut names: Vec<String> = self.started.keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+// Status text for the peers typing, empty when nobody is
+pub fn describe_typing(names: &[String]) -> String {
+    match names {
+        [] => String::new(),
+        [name] => format!("{} is typing…", name),
+        [first, second] => format!("{} and {} are typing…", first, second),
+        _ => "several people are typing…".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn typing_peers_expire_without_a_fresh_start() {
+        let mut peers = TypingPeers::new(Duration::from_millis(50));
+        peers.set("bob", true);
+        assert_eq!(peers.active(), vec!["bob".to_string()]);
+
+        std::thread::sleep(Duration::from_millis(80));
+        assert!(peers.active().is_empty());
+    }
+
+    #[test]
+    fn a_stop_clears_the_indicator_at_once() {
+        let mut peers = TypingPeers::new(Duration::from_secs(5));
+        peers.set("bob", true);
+        peers.set("alice", true);
+        peers.set("bob", false);
+        assert_eq!(peers.active(), vec!["alice".to_string()]);
+    }
+
+    #[test]
+    fn starts_are_resent_only_after_a_while() {
+        let mut notifier = TypingNotifier::new(Duration::from_secs(60));
+        assert_eq!(notifier.update("h"), Some(true));
+        assert_eq!(notifier.update("he"), None);
+        assert_eq!(notifier.update(""), Some(false));
+        assert_eq!(notifier.update(""), None);
+        assert_eq!(notifier.update("/quit"), None);
+    }
+
+    #[test]
+    fn describes_who_is_typing() {
+        let names = |list: &[&str]| list.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert_eq!(describe_typing(&[]), "");
+        assert_eq!(describe_typing(&names(&["bob"])), "bob is typing…");
+        assert_eq!(
+            describe_typing(&names(&["alice", "bob"])),
+            "alice and bob are typing…"
+        );
+        assert_eq!(
+            describe_typing(&names(&["a", "b", "c"])),
+            "several people are typing…"
+        );
+    }
+}
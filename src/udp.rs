@@ -0,0 +1,224 @@
+// UDP sockets covering both IPv4 and IPv6.
+// Each family gets its own socket so a host without one of them still works with the other.
+
+use crate::debug_logger::debug_log;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::future::pending;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+
+pub struct DualSocket {
+    v4: Option<UdpSocket>,
+    v6: Option<UdpSocket>,
+}
+
+// Same address with a different port. Unlike SocketAddr::new this keeps the IPv6 scope,
+// which link-local addresses need to be reachable.
+pub fn with_port(addr: SocketAddr, port: u16) -> SocketAddr {
+    let mut addr = addr;
+    addr.set_port(port);
+    addr
+}
+
+// Where a socket of the family is bound: the configured address when it's of that family,
+// the unspecified address when none is configured, and nowhere when the configured one is
+// of the other family
+fn bind_addr(domain: Domain, bind_address: Option<IpAddr>, port: u16) -> Option<SocketAddr> {
+    let ip = match (bind_address, domain == Domain::IPV6) {
+        (None, false) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (None, true) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        (Some(ip), v6) if ip.is_ipv6() == v6 => ip,
+        (Some(_), _) => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+// Ports aren't shared, so a second instance on one fails to bind instead of quietly
+// splitting the traffic with the first.
+fn bind_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let domain = Domain::for_address(addr);
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    // Tokio expects non-blocking sockets, a blocking read would stall the whole runtime
+    socket.set_nonblocking(true)?;
+
+    if domain == Domain::IPV6 {
+        // Keep the families apart, IPv4 traffic goes to the IPv4 socket
+        socket.set_only_v6(true)?;
+    } else {
+        socket.set_broadcast(true)?;
+    }
+    socket.bind(&addr.into())?;
+
+    // Our own multicast packets would otherwise come straight back to us
+    if domain == Domain::IPV6 {
+        socket.set_multicast_loop_v6(false)?;
+    } else {
+        socket.set_multicast_loop_v4(false)?;
+    }
+
+    UdpSocket::from_std(socket.into())
+}
+
+impl DualSocket {
+    // Binds a listening socket that joins the given groups, so packets sent to them are
+    // received
+    pub fn bind_multicast(
+        port: u16,
+        bind_address: Option<IpAddr>,
+        group_v4: Option<Ipv4Addr>,
+        group_v6: Option<Ipv6Addr>,
+    ) -> io::Result<Self> {
+        let socket = Self::bind(port, bind_address)?;
+
+        // Joined on the bind address's interface, or wherever the OS picks
+        let interface_v4 = match bind_address {
+            Some(IpAddr::V4(address)) => address,
+            _ => Ipv4Addr::UNSPECIFIED,
+        };
+        if let (Some(v4), Some(group)) = (&socket.v4, group_v4) {
+            if let Err(e) = v4.join_multicast_v4(group, interface_v4) {
+                debug_log(&format!("Could not join multicast group {}: {}", group, e));
+            }
+        }
+        if let (Some(v6), Some(group)) = (&socket.v6, group_v6) {
+            if let Err(e) = v6.join_multicast_v6(&group, 0) {
+                debug_log(&format!("Could not join multicast group {}: {}", group, e));
+            }
+        }
+
+        Ok(socket)
+    }
+
+    // Binds a socket per address family, skipping a family the OS doesn't support.
+    // Fails only when neither family can be bound. With a bind address only its family is
+    // bound, to that address alone.
+    pub fn bind(port: u16, bind_address: Option<IpAddr>) -> io::Result<Self> {
+        let bind = |domain| match bind_addr(domain, bind_address, port) {
+            Some(addr) => bind_socket(addr),
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                "the bind address is of the other family",
+            )),
+        };
+        let v4 = bind(Domain::IPV4);
+        let v6 = bind(Domain::IPV6);
+
+        match (v4, v6) {
+            // Report the failure of the family that was asked for
+            (Err(e4), Err(e6)) if bind_address.is_some_and(|ip| ip.is_ipv6()) => {
+                debug_log(&format!("IPv4 socket unavailable: {}", e4));
+                Err(e6)
+            }
+            (Err(e4), Err(e6)) => {
+                debug_log(&format!("IPv6 socket unavailable: {}", e6));
+                Err(e4)
+            }
+            (v4, v6) => {
+                if let Err(e) = &v4 {
+                    debug_log(&format!("IPv4 socket unavailable on port {}: {}", port, e));
+                }
+                if let Err(e) = &v6 {
+                    debug_log(&format!("IPv6 socket unavailable on port {}: {}", port, e));
+                }
+                Ok(Self {
+                    v4: v4.ok(),
+                    v6: v6.ok(),
+                })
+            }
+        }
+    }
+
+    pub fn has_v4(&self) -> bool {
+        self.v4.is_some()
+    }
+
+    // Sends on the socket matching the target's address family
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let socket = match target {
+            SocketAddr::V4(_) => self.v4.as_ref(),
+            SocketAddr::V6(_) => self.v6.as_ref(),
+        };
+
+        match socket {
+            Some(socket) => socket.send_to(buf, target).await,
+            None => Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no socket for the address family of {}", target),
+            )),
+        }
+    }
+
+    // Receives the next packet from whichever family has one first
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let socket = tokio::select! {
+                result = readable(&self.v4) => result?,
+                result = readable(&self.v6) => result?,
+            };
+
+            match socket.try_recv_from(buf) {
+                Ok(received) => return Ok(received),
+                // Readiness can be spurious, go back to waiting
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// Waits until the socket has data. A missing socket never becomes readable.
+async fn readable(socket: &Option<UdpSocket>) -> io::Result<&UdpSocket> {
+    match socket {
+        Some(socket) => {
+            socket.readable().await?;
+            Ok(socket)
+        }
+        None => pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddrV6;
+
+    #[test]
+    fn changing_the_port_keeps_the_ipv6_scope() {
+        let link_local = SocketAddr::V6(SocketAddrV6::new("fe80::1".parse().unwrap(), 2224, 0, 3));
+        let SocketAddr::V6(moved) = with_port(link_local, 2223) else {
+            panic!("not IPv6 anymore");
+        };
+        assert_eq!(moved.port(), 2223);
+        assert_eq!(moved.scope_id(), 3);
+        assert_eq!(with_port(link_local, 2223).to_string(), "[fe80::1%3]:2223");
+    }
+
+    #[test]
+    fn sockets_bind_to_the_configured_address() {
+        let lan: IpAddr = "192.168.1.20".parse().unwrap();
+        assert_eq!(
+            bind_addr(Domain::IPV4, Some(lan), 2223),
+            Some(SocketAddr::new(lan, 2223))
+        );
+        // Without one, every interface
+        assert_eq!(
+            bind_addr(Domain::IPV4, None, 2224),
+            Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 2224))
+        );
+        assert_eq!(
+            bind_addr(Domain::IPV6, None, 2224),
+            Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 2224))
+        );
+        // An IPv4 address leaves no IPv6 socket to bind
+        assert_eq!(bind_addr(Domain::IPV6, Some(lan), 2223), None);
+    }
+
+    #[tokio::test]
+    async fn a_bound_socket_is_only_on_that_address() {
+        let socket = DualSocket::bind(0, Some(IpAddr::V4(Ipv4Addr::LOCALHOST))).unwrap();
+        assert!(socket.v6.is_none());
+        let local = socket.v4.as_ref().unwrap().local_addr().unwrap();
+        assert_eq!(local.ip(), IpAddr::V4(Ipv4Addr::LOCALHOST));
+    }
+}
@@ -1,12 +1,53 @@
-use crate::console_graphics::GraphicsEngine;
-use crate::networking::{Broadcaster, Receiver};
+use crate::commands::{Command, CommandResult, COMMAND_DESCRIPTIONS};
+use crate::console_graphics::{Delivery, GraphicsEngine};
+use crate::constants::{
+    HISTORY_BATCH_MAX, HISTORY_SYNC_WAIT_SECS, LOCAL_REACTOR, MAX_REACTION_CHARS, PROTOCOL_VERSION,
+    TOAST_DURATION_MS, TYPING_RESEND_SECS, UNKNOWN_PEER_NAME,
+};
+use crate::debug_logger::{debug_log, log, Level};
+use crate::emoji::expand_shortcodes;
+use crate::help::format_entry;
+use crate::history_sync;
+use crate::message::Message;
+use crate::networking::{Broadcaster, NetworkError, Receiver};
+use crate::protocol::version_mismatch;
+use crate::reactions::is_valid_reaction;
+use crate::send_queue::{Outgoing, SendQueue};
+use crate::session_log::SessionLog;
+use crate::stats::{self, format_bytes, format_packet_sizes};
+use crate::storage;
+use crate::supervisor::Restarts;
+use crate::throttle::Throttled;
+use crate::transcript;
+use crate::transport::Transport;
+use crate::typing::TypingNotifier;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
 
 pub struct UserInterface {
     pub graphics_engine: Arc<Mutex<GraphicsEngine>>,
     pub receiver: Arc<Mutex<Receiver>>,
     pub broadcaster: Broadcaster,
     pub username: String,
+    // Set with --log to keep a record of the session's messages
+    pub session_log: Option<SessionLog>,
+    // Where the input history is saved at exit, set when the save_history setting is on
+    pub history_path: Option<PathBuf>,
+    // Where the muted users are saved at exit, set when the save_muted setting is on
+    pub muted_path: Option<PathBuf>,
+    // Set with --headless, where there's no terminal UI and notices go to stderr
+    pub headless: bool,
+    // Our messages wait here for the sender task, so typing never waits on the network
+    pub send_queue: SendQueue,
+    // Signalled by /reconnect to restart the listeners and discovery
+    pub restarts: Restarts,
+    // Expand shortcodes like :smile: in what we send
+    pub emoji_shortcodes: bool,
+    typing: Arc<Mutex<TypingNotifier>>,
 }
 
 impl Clone for UserInterface {
@@ -16,6 +57,14 @@ impl Clone for UserInterface {
             receiver: self.receiver.clone(),
             broadcaster: self.broadcaster.clone(),
             username: self.username.clone(),
+            session_log: self.session_log.clone(),
+            history_path: self.history_path.clone(),
+            muted_path: self.muted_path.clone(),
+            headless: self.headless,
+            send_queue: self.send_queue.clone(),
+            restarts: self.restarts.clone(),
+            emoji_shortcodes: self.emoji_shortcodes,
+            typing: self.typing.clone(),
         }
     }
 }
@@ -31,6 +80,876 @@ impl UserInterface {
             receiver: Arc::new(Mutex::new(receiver)),
             broadcaster,
             username: String::new(),
+            session_log: None,
+            history_path: None,
+            muted_path: None,
+            headless: false,
+            send_queue: SendQueue::new(),
+            restarts: Restarts::new(),
+            emoji_shortcodes: true,
+            typing: Arc::new(Mutex::new(TypingNotifier::new(Duration::from_secs(
+                TYPING_RESEND_SECS,
+            )))),
         }
     }
+
+    // Intercepts input starting with '/' so commands are never broadcast
+    pub async fn handle_command(&self, input: &str) -> CommandResult {
+        let command = match Command::parse(input) {
+            Some(command) => command,
+            None => return CommandResult::NotCommand,
+        };
+
+        match command {
+            Command::Help => {
+                if self.headless {
+                    self.show_system_message("Available commands:");
+                    for (name, description) in COMMAND_DESCRIPTIONS.iter() {
+                        self.show_system_message(&format_entry(name, description));
+                    }
+                } else {
+                    let mut engine = self.graphics_engine.lock().unwrap();
+                    if !engine.is_help_open() {
+                        engine.toggle_help();
+                    }
+                    let _ = engine.print_all_messages(false);
+                }
+            }
+            Command::Quit => return CommandResult::Quit,
+            Command::Clear => {
+                let mut engine = self.graphics_engine.lock().unwrap();
+                engine.clear_messages();
+                let _ = GraphicsEngine::clear_console();
+                let _ = engine.print_all_messages(true);
+                let _ = engine.print_status_bar();
+                let _ = engine.print_input_prompt();
+            }
+            Command::Users => {
+                let peers = self.receiver.lock().unwrap().get_peer_names();
+
+                if peers.is_empty() {
+                    self.show_system_message("No peers discovered yet");
+                } else {
+                    self.show_system_message(&format!("Known peers ({}):", peers.len()));
+                    let receiver = self.receiver.lock().unwrap().clone();
+                    for (addr, name) in peers {
+                        let name = if receiver.peer_is_away(addr) {
+                            format!("{} (away)", name)
+                        } else {
+                            name
+                        };
+                        // Only peers on another version get a note
+                        match receiver
+                            .peer_protocol_version(addr)
+                            .and_then(version_mismatch)
+                        {
+                            Some(mismatch) => self.show_system_message(&format!(
+                                "  {} ({}) - {}",
+                                name,
+                                addr.ip(),
+                                mismatch
+                            )),
+                            None => {
+                                self.show_system_message(&format!("  {} ({})", name, addr.ip()))
+                            }
+                        }
+                    }
+                }
+            }
+            Command::Ping(target) => self.ping(target.as_deref()).await,
+            Command::Stats => self.show_stats(),
+            Command::DirectMessage { target, text } => {
+                self.send_direct_message(&target, text).await;
+            }
+            Command::Action(text) => self.send_action(text).await,
+            Command::Search(term) => self.search(&term),
+            Command::Save(path) => self.save_transcript(&path),
+            Command::Mute(name) => self.mute(name.as_deref()),
+            Command::Unmute(name) => self.unmute(&name),
+            Command::DoNotDisturb(on) => self.do_not_disturb(on),
+            Command::ShowIp(on) => self.show_ip(on),
+            Command::Edit { id, text } => self.edit_message(&id, text).await,
+            Command::Delete(id) => self.delete_message(&id).await,
+            Command::React { id, emoji } => self.react(&id, &emoji).await,
+            Command::Reconnect => self.reconnect(),
+            Command::Connect { ip, port } => match self.broadcaster.connect(ip, port).await {
+                Ok(true) => self.show_system_message(&format!(
+                    "Added {} as a peer and sent it a discovery request",
+                    ip
+                )),
+                Ok(false) => self.show_system_message(&format!(
+                    "{} is already a peer, sent it a discovery request",
+                    ip
+                )),
+                Err(e) => self.show_system_message(&format!("Failed to connect to {}: {}", ip, e)),
+            },
+            Command::Invalid(usage) => self.show_system_message(&usage),
+            Command::Unknown(name) => {
+                self.show_system_message(&format!(
+                    "Unknown command: {} (type /help for a list)",
+                    name
+                ));
+            }
+        }
+
+        CommandResult::Handled
+    }
+
+    // Pings the named peer, or every known host. With nobody known yet there's no one to
+    // ping, so a discovery probe goes out instead.
+    async fn ping(&self, target: Option<&str>) {
+        let targets = match target {
+            Some(target) => match self.broadcaster.resolve_peer(target) {
+                Some((addr, info)) => vec![(addr, info.name)],
+                None => {
+                    self.show_system_message(&format!("Unknown peer: {} (see /users)", target));
+                    return;
+                }
+            },
+            None => {
+                // A host known on several ports is pinged once
+                let mut hosts = HashSet::new();
+                let mut peers = self.receiver.lock().unwrap().get_peer_names();
+                peers.retain(|(addr, _)| hosts.insert(addr.ip()));
+                peers
+            }
+        };
+
+        if targets.is_empty() {
+            match self.broadcaster.discover_peers().await {
+                Ok(()) => self.show_system_message("No peers known yet, sent a discovery probe"),
+                Err(e) => self.show_system_message(&format!("Discovery probe failed: {}", e)),
+            }
+            return;
+        }
+
+        for (addr, name) in targets {
+            match self.broadcaster.send_ping(addr, &name).await {
+                Ok(()) => self.show_system_message(&format!("Pinging {} ({})", name, addr.ip())),
+                Err(e) => {
+                    self.show_system_message(&format!("Failed to ping {}: {}", name, e));
+                }
+            }
+        }
+    }
+
+    async fn send_direct_message(&self, target: &str, text: String) {
+        let text = self.expand_emoji(text);
+        if self.too_long(&text) {
+            return;
+        }
+        let (peer, info) = match self.broadcaster.resolve_peer(target) {
+            Some(peer) => peer,
+            None => {
+                self.show_system_message(&format!("Unknown peer: {} (see /users)", target));
+                return;
+            }
+        };
+
+        // The recipient's name lets other receivers of a stray copy ignore it
+        let recipient = if info.name == UNKNOWN_PEER_NAME {
+            String::new()
+        } else {
+            info.name
+        };
+
+        let message = Message::new(
+            text.clone(),
+            self.username.clone(),
+            self.broadcaster.node_id().to_string(),
+        )
+        .with_recipient(recipient);
+
+        let local_message = {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            let local_message = Message::new(text, self.username.clone(), "local".to_string())
+                .with_id(message.id())
+                .with_recipient(target.to_string());
+            engine.add_message(&local_message);
+            let _ = engine.print_all_messages(false);
+            local_message
+        };
+        self.log_message(&local_message);
+        self.enqueue(message, Some(peer), Instant::now());
+
+        // Older clients may not know direct messages, or show them to everyone nearby
+        if let Some(mismatch) = self
+            .broadcaster
+            .peer_protocol_version(peer)
+            .filter(|version| *version < PROTOCOL_VERSION)
+            .and_then(version_mismatch)
+        {
+            self.show_system_message(&format!("{} uses an {}", target, mismatch));
+        }
+    }
+
+    fn show_ip(&self, on: Option<bool>) {
+        let on = {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            if let Some(on) = on {
+                engine.set_show_ip(on);
+                let _ = engine.print_all_messages(false);
+            }
+            engine.show_ip()
+        };
+
+        if on {
+            self.show_toast("Showing the IP address in front of each message");
+        } else {
+            self.show_toast("Showing senders by name only");
+        }
+    }
+
+    fn do_not_disturb(&self, on: Option<bool>) {
+        let on = {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            if let Some(on) = on {
+                engine.set_do_not_disturb(on);
+                let _ = engine.print_status_bar();
+            }
+            engine.do_not_disturb()
+        };
+
+        if on {
+            self.show_toast(
+                "Do not disturb is on: mentions are highlighted but don't ring the bell",
+            );
+        } else {
+            self.show_toast("Do not disturb is off");
+        }
+    }
+
+    // Peers on the old network are left to time out like any others that went quiet
+    fn reconnect(&self) {
+        self.restarts.restart_all();
+        self.show_toast("Reconnecting: binding fresh sockets and looking for peers");
+    }
+
+    fn show_stats(&self) {
+        let stats = stats::snapshot();
+        let peers = self.receiver.lock().unwrap().get_peer_names().len();
+
+        self.show_system_message("Network statistics:");
+        self.show_system_message(&format!(
+            "  Messages sent: {}, received: {}",
+            stats.messages_sent, stats.messages_received
+        ));
+        self.show_system_message(&format!(
+            "  Chat traffic sent: {}, received: {}",
+            format_bytes(stats.bytes_sent),
+            format_bytes(stats.bytes_received)
+        ));
+        self.show_system_message(&format!(
+            "  Duplicate copies dropped: {}",
+            stats.duplicates_dropped
+        ));
+        self.show_system_message(&format!(
+            "  Malformed packets dropped: {}",
+            stats.malformed_dropped
+        ));
+        self.show_system_message(&format!(
+            "  Blocked packets dropped: {}",
+            stats.blocked_dropped
+        ));
+        self.show_system_message(&format!(
+            "  Truncated packets dropped: {} (raise recv_buffer_size if this grows)",
+            stats.truncated_dropped
+        ));
+        self.show_system_message(&format!(
+            "  Unauthenticated messages dropped: {}",
+            stats.unauthenticated_dropped
+        ));
+        self.show_system_message(&format!(
+            "  Largest packet received: {}",
+            format_bytes(stats.largest_packet)
+        ));
+        self.show_system_message(&format!(
+            "  Packet sizes: {}",
+            format_packet_sizes(&stats.packet_sizes)
+        ));
+        self.show_system_message(&format!("  Known peers: {}", peers));
+    }
+
+    fn search(&self, term: &str) {
+        if term.is_empty() {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            engine.clear_search();
+            let _ = engine.print_all_messages(false);
+            return;
+        }
+
+        let found = self.graphics_engine.lock().unwrap().search(term);
+        match found {
+            Some((position, total)) => self.show_system_message(&format!(
+                "Match {} of {} for \"{}\" (repeat /search for older ones)",
+                position, total, term
+            )),
+            None => self.show_system_message(&format!("No messages match \"{}\"", term)),
+        }
+    }
+
+    fn mute(&self, name: Option<&str>) {
+        let Some(name) = name else {
+            let names = self.graphics_engine.lock().unwrap().muted().names();
+            if names.is_empty() {
+                self.show_system_message("Nobody is muted");
+            } else {
+                self.show_system_message(&format!("Muted: {}", names.join(", ")));
+            }
+            return;
+        };
+
+        if name.eq_ignore_ascii_case(&self.username) {
+            self.show_system_message("You can't mute yourself");
+            return;
+        }
+        if self.graphics_engine.lock().unwrap().muted_mut().mute(name) {
+            self.show_toast(&format!("Muted {}, /unmute to see them again", name));
+        } else {
+            self.show_toast(&format!("{} is already muted", name));
+        }
+    }
+
+    fn unmute(&self, name: &str) {
+        if self
+            .graphics_engine
+            .lock()
+            .unwrap()
+            .muted_mut()
+            .unmute(name)
+        {
+            self.show_toast(&format!("Unmuted {}", name));
+        } else {
+            self.show_toast(&format!("{} isn't muted", name));
+        }
+    }
+
+    // Picks out one of our messages by the start of its ID. Direct messages are left out,
+    // changes to them would be broadcast to everyone.
+    fn find_own_message(&self, id: &str) -> Option<u64> {
+        let found: Vec<(u64, bool)> = self
+            .graphics_engine
+            .lock()
+            .unwrap()
+            .own_messages_matching(id)
+            .iter()
+            .map(|message| (message.id, message.recipient.is_some()))
+            .collect();
+
+        match found.as_slice() {
+            [] => self.show_system_message(&format!("None of your messages has ID {}", id)),
+            [(_, true)] => self.show_system_message("Direct messages can't be edited or deleted"),
+            [(found, false)] => return Some(*found),
+            _ => self.show_system_message(&format!(
+                "ID {} matches {} of your messages, type more of it",
+                id,
+                found.len()
+            )),
+        }
+        None
+    }
+
+    async fn edit_message(&self, id: &str, text: String) {
+        let text = self.expand_emoji(text);
+        if self.too_long(&text) {
+            return;
+        }
+        let Some(id) = self.find_own_message(id) else {
+            return;
+        };
+
+        {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            engine.edit_message(id, None, &text);
+            let _ = engine.print_all_messages(false);
+        }
+        if let Err(e) = self.broadcaster.send_edit(id, &text).await {
+            self.show_toast(&format!("Failed to send the edit: {}", e));
+        }
+    }
+
+    async fn delete_message(&self, id: &str) {
+        let Some(id) = self.find_own_message(id) else {
+            return;
+        };
+
+        {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            engine.delete_message(id, None);
+            let _ = engine.print_all_messages(false);
+        }
+        if let Err(e) = self.broadcaster.send_delete(id).await {
+            self.show_toast(&format!("Failed to send the deletion: {}", e));
+        }
+    }
+
+    // Reactions are broadcast, so direct messages can't be reacted to
+    async fn react(&self, id: &str, emoji: &str) {
+        if !is_valid_reaction(emoji) {
+            self.show_system_message(&format!(
+                "A reaction is one emoji or word of up to {} characters",
+                MAX_REACTION_CHARS
+            ));
+            return;
+        }
+        let found: Vec<(u64, bool)> = self
+            .graphics_engine
+            .lock()
+            .unwrap()
+            .messages_matching(id)
+            .iter()
+            .map(|message| (message.id, message.recipient.is_some()))
+            .collect();
+        let id = match found.as_slice() {
+            [] => return self.show_system_message(&format!("No message has ID {}", id)),
+            [(_, true)] => return self.show_system_message("Direct messages can't be reacted to"),
+            [(found, false)] => *found,
+            _ => {
+                return self.show_system_message(&format!(
+                    "ID {} matches {} messages, type more of it",
+                    id,
+                    found.len()
+                ))
+            }
+        };
+
+        {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            if !engine.react(id, emoji, LOCAL_REACTOR) {
+                return self.show_system_message("You already reacted with that");
+            }
+            let _ = engine.print_all_messages(false);
+        }
+        if let Err(e) = self.broadcaster.send_reaction(id, emoji).await {
+            self.show_toast(&format!("Failed to send the reaction: {}", e));
+        }
+    }
+
+    // Waits for the first peer to turn up this session and asks it for its recent messages,
+    // giving up after a while alone
+    pub async fn request_history(&self, count: usize) {
+        for _ in 0..HISTORY_SYNC_WAIT_SECS {
+            let peer = self
+                .receiver
+                .lock()
+                .unwrap()
+                .get_peer_names()
+                .first()
+                .cloned();
+            if let Some((addr, name)) = peer {
+                debug_log(&format!(
+                    "Asking {} ({}) for recent messages",
+                    name,
+                    addr.ip()
+                ));
+                if let Err(e) = self.broadcaster.request_history(addr, count).await {
+                    debug_log(&format!("Failed to ask {} for history: {}", name, e));
+                }
+                return;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+
+    // Sends a peer that just joined our most recent messages, capped at HISTORY_BATCH_MAX
+    pub async fn answer_history_request(&self, from: SocketAddr, count: usize) {
+        if !self.broadcaster.allow_history_request(from.ip()) {
+            debug_log(&format!(
+                "Ignoring repeated history request from {}",
+                from.ip()
+            ));
+            return;
+        }
+        let batch = history_sync::batch(
+            self.graphics_engine.lock().unwrap().messages(),
+            count.min(HISTORY_BATCH_MAX),
+            self.broadcaster.node_id(),
+        );
+        if let Err(e) = self.broadcaster.send_history(from, &batch).await {
+            debug_log(&format!("Failed to send history to {}: {}", from.ip(), e));
+        }
+    }
+
+    fn save_transcript(&self, path: &str) {
+        let messages = self.graphics_engine.lock().unwrap().messages().to_vec();
+        let path = storage::expand_home(path);
+        match transcript::save(&path, &messages) {
+            Ok(()) => self.show_system_message(&format!(
+                "Saved {} messages to {}",
+                messages.len(),
+                path.display()
+            )),
+            Err(e) => {
+                self.show_system_message(&format!("Failed to save to {}: {}", path.display(), e))
+            }
+        }
+    }
+
+    async fn send_action(&self, text: String) {
+        let text = self.expand_emoji(text);
+        if self.too_long(&text) {
+            return;
+        }
+        let Some(not_before) = self.send_slot(&text) else {
+            return;
+        };
+        let message = Message::new(
+            text.clone(),
+            self.username.clone(),
+            self.broadcaster.node_id().to_string(),
+        )
+        .into_action();
+
+        let local_message = {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            let local_message = Message::new(text, self.username.clone(), "local".to_string())
+                .with_id(message.id())
+                .into_action();
+            engine.add_message(&local_message);
+            let _ = engine.print_all_messages(false);
+            local_message
+        };
+        self.log_message(&local_message);
+        self.enqueue(message, None, not_before);
+    }
+
+    // Lets peers know when we start or stop composing, given the current input line
+    pub async fn update_typing(&self, input: &str) {
+        let Some(typing) = self.typing.lock().unwrap().update(input) else {
+            return;
+        };
+
+        if let Err(e) = self.broadcaster.send_typing(typing).await {
+            debug_log(&format!("Failed to send typing update: {}", e));
+        }
+    }
+
+    // Tells peers when we go away after a while without input and when we're back
+    pub async fn update_away(&self) {
+        let Some(away) = self.graphics_engine.lock().unwrap().update_away() else {
+            return;
+        };
+
+        if let Err(e) = self.broadcaster.send_status(away).await {
+            debug_log(&format!("Failed to send away status: {}", e));
+        }
+    }
+
+    // Waits for acks for a message we're sending, to the one peer or to everyone, and
+    // ignores copies of it that loop back
+    pub fn track_outgoing(&self, message: &Message, to: Option<SocketAddr>) {
+        self.broadcaster.track_pending(message, to);
+        self.receiver.lock().unwrap().mark_seen(message.id());
+    }
+
+    // Appends a message to the session log, if there is one. A failing log only costs the
+    // record, so the first error is shown and the chat carries on.
+    pub fn log_message(&self, message: &Message) {
+        let Some(log) = &self.session_log else {
+            return;
+        };
+
+        if let Err(e) = log.append(message) {
+            if log.should_report_error() {
+                self.show_system_message(&format!("Failed to write session log: {}", e));
+            }
+        }
+    }
+
+    pub fn flush_session_log(&self) {
+        if let Some(session_log) = &self.session_log {
+            if let Err(e) = session_log.flush() {
+                log(Level::Warn, &format!("Failed to flush session log: {}", e));
+            }
+        }
+    }
+
+    pub fn save_history(&self) {
+        if let Some(path) = &self.history_path {
+            let engine = self.graphics_engine.lock().unwrap();
+            if let Err(e) = engine.input_history().save(path) {
+                log(
+                    Level::Warn,
+                    &format!("Failed to save input history {}: {}", path.display(), e),
+                );
+            }
+        }
+    }
+
+    pub fn save_muted(&self) {
+        if let Some(path) = &self.muted_path {
+            let engine = self.graphics_engine.lock().unwrap();
+            if let Err(e) = engine.muted().save(path) {
+                log(
+                    Level::Warn,
+                    &format!("Failed to save muted users {}: {}", path.display(), e),
+                );
+            }
+        }
+    }
+
+    // Typing stops at the length limit, but a line recalled from history saved under a
+    // higher limit can still be over it. Says so when refusing to send it.
+    pub fn too_long(&self, text: &str) -> bool {
+        let max = self.graphics_engine.lock().unwrap().max_message_length();
+        let length = text.chars().count();
+        if length <= max {
+            return false;
+        }
+        self.show_system_message(&format!(
+            "Message not sent: it is {} characters long, the limit is {}",
+            length, max
+        ));
+        true
+    }
+
+    // Done before a message is built, so our copy and the one sent are the same
+    fn expand_emoji(&self, text: String) -> String {
+        if !self.emoji_shortcodes {
+            return text;
+        }
+        expand_shortcodes(&text).into_owned()
+    }
+
+    // Broadcasts a chat message to everyone and shows our own copy of it
+    pub async fn send_chat(&self, text: String) {
+        // Enter on an empty line, or a blank line piped in, isn't worth a message
+        if text.trim().is_empty() {
+            return;
+        }
+        let text = self.expand_emoji(text);
+        if self.too_long(&text) {
+            return;
+        }
+        let Some(not_before) = self.send_slot(&text) else {
+            return;
+        };
+
+        let message = Message::new(
+            text.clone(),
+            self.username.clone(),
+            self.broadcaster.node_id().to_string(),
+        );
+
+        // Our display logic shows the local copy as ours
+        let local_message =
+            Message::new(text, self.username.clone(), "local".to_string()).with_id(message.id());
+        {
+            let mut engine = self.graphics_engine.lock().unwrap();
+            engine.add_message(&local_message);
+            let _ = engine.print_all_messages(false);
+        }
+        self.log_message(&local_message);
+        self.enqueue(message, None, not_before);
+    }
+
+    // When the throttle lets a broadcast of the text go out. None for an accidental repeat,
+    // which isn't sent.
+    fn send_slot(&self, text: &str) -> Option<Instant> {
+        match self.broadcaster.schedule_send(text) {
+            Throttled::Now => Some(Instant::now()),
+            Throttled::After(delay) => Some(Instant::now() + delay),
+            Throttled::Repeat => {
+                debug_log("Dropped a repeat of the message just sent");
+                None
+            }
+        }
+    }
+
+    // Hands a message we've already shown to the sender task
+    fn enqueue(&self, message: Message, to: Option<SocketAddr>, not_before: Instant) {
+        let id = message.id();
+        let outgoing = Outgoing {
+            message,
+            to,
+            not_before,
+        };
+        if !self.send_queue.enqueue(outgoing) {
+            self.set_delivery(&[id], Delivery::Failed);
+        }
+    }
+
+    // Takes messages off the send queue and puts them on the network in the order they were
+    // sent. A message waiting for its throttle slot holds up the ones behind it, with the
+    // status bar showing the wait.
+    pub async fn send_queued(&self, outgoing: UnboundedReceiver<Outgoing>) {
+        self.send_queued_over(outgoing, || self.broadcaster.bind_sender())
+            .await
+    }
+
+    // Broadcasts go out through a transport from bind, a fresh UDP socket each in the app
+    pub async fn send_queued_over<T, F>(&self, mut outgoing: UnboundedReceiver<Outgoing>, bind: F)
+    where
+        T: Transport,
+        F: Fn() -> Result<T, NetworkError>,
+    {
+        while let Some(Outgoing {
+            message,
+            to,
+            not_before,
+        }) = outgoing.recv().await
+        {
+            let wait = not_before.saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                let _ = self.graphics_engine.lock().unwrap().set_throttled(true);
+                tokio::time::sleep(wait).await;
+                let _ = self.graphics_engine.lock().unwrap().set_throttled(false);
+            }
+
+            let id = message.id();
+            self.track_outgoing(&message, to);
+            let sent = match to {
+                Some(peer) => self.broadcaster.send_direct(message, peer).await,
+                None => match bind() {
+                    Ok(socket) => {
+                        self.broadcaster
+                            .broadcast_message_over(&socket, message)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+            match sent {
+                Ok(()) => self.set_delivery(&[id], Delivery::Pending),
+                Err(e) => {
+                    self.broadcaster.forget_pending(id);
+                    self.set_delivery(&[id], Delivery::Failed);
+                    let what = if to.is_some() {
+                        "direct message"
+                    } else {
+                        "message"
+                    };
+                    self.show_toast(&format!("Failed to send {}: {}", what, e));
+                }
+            }
+            self.send_queue.finished();
+        }
+    }
+
+    // Updates the delivery marks on our messages, repainting if any are on screen
+    pub fn set_delivery(&self, ids: &[u64], delivery: Delivery) {
+        if ids.is_empty() {
+            return;
+        }
+
+        let mut engine = self.graphics_engine.lock().unwrap();
+        let mut changed = false;
+        for id in ids {
+            changed |= engine.set_delivery(*id, delivery);
+        }
+        if changed {
+            let _ = engine.print_all_messages(false);
+        }
+    }
+
+    pub fn show_system_message(&self, text: &str) {
+        if self.headless {
+            eprintln!("{}", text);
+            return;
+        }
+        let mut engine = self.graphics_engine.lock().unwrap();
+        engine.add_system_message(text);
+        let _ = engine.print_all_messages(false);
+    }
+
+    // For notices not worth keeping in the message list, which clear after a few seconds
+    pub fn show_toast(&self, text: &str) {
+        if self.headless {
+            eprintln!("{}", text);
+            return;
+        }
+        let _ = self
+            .graphics_engine
+            .lock()
+            .unwrap()
+            .show_toast(text, Duration::from_millis(TOAST_DURATION_MS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::transport::MemoryTransport;
+    use std::io;
+
+    fn alice() -> UserInterface {
+        let config = Config::default();
+        let mut graphics_engine = GraphicsEngine::new(&config);
+        graphics_engine.set_headless();
+        let mut ui = UserInterface::new(
+            Receiver::new(&config, "alice".to_string(), "node-a".to_string()),
+            Broadcaster::new(&config, "alice".to_string(), "node-a".to_string()),
+            graphics_engine,
+        );
+        ui.username = "alice".to_string();
+        ui.headless = true;
+        ui
+    }
+
+    fn deliveries(ui: &UserInterface) -> Vec<Delivery> {
+        let engine = ui.graphics_engine.lock().unwrap();
+        engine.messages().iter().map(|m| m.delivery).collect()
+    }
+
+    #[tokio::test]
+    async fn sending_only_queues_the_message() {
+        let ui = alice();
+        // Nothing is taking messages off the queue, so a send that waited would hang
+        tokio::time::timeout(Duration::from_millis(100), ui.send_chat("hi".to_string()))
+            .await
+            .unwrap();
+
+        assert_eq!(ui.send_queue.outstanding(), 1);
+        assert_eq!(deliveries(&ui), [Delivery::Queued]);
+    }
+
+    #[tokio::test]
+    async fn a_failed_send_marks_the_message_failed() {
+        let ui = alice();
+        let outgoing = ui.send_queue.take_receiver().unwrap();
+        let sender = ui.clone();
+        tokio::spawn(async move {
+            sender
+                .send_queued_over(outgoing, || {
+                    Err::<MemoryTransport, _>(NetworkError::Bind(io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        "no network",
+                    )))
+                })
+                .await
+        });
+
+        ui.send_chat("hi".to_string()).await;
+        tokio::time::timeout(Duration::from_secs(1), ui.send_queue.wait_until_empty())
+            .await
+            .unwrap();
+        assert_eq!(deliveries(&ui), [Delivery::Failed]);
+    }
+
+    #[test]
+    fn shortcodes_are_only_expanded_when_enabled() {
+        let mut ui = alice();
+        ui.emoji_shortcodes = true;
+        assert_eq!(ui.expand_emoji("see you :wave:".to_string()), "see you 👋");
+        ui.emoji_shortcodes = false;
+        assert_eq!(
+            ui.expand_emoji("see you :wave:".to_string()),
+            "see you :wave:"
+        );
+    }
+
+    #[tokio::test]
+    async fn edits_expand_shortcodes_like_new_messages() {
+        let mut ui = alice();
+        ui.emoji_shortcodes = true;
+        ui.send_chat("see you".to_string()).await;
+        let id = ui.graphics_engine.lock().unwrap().messages()[0].id;
+
+        ui.edit_message(&format!("{:016x}", id), "see you :wave:".to_string())
+            .await;
+        let engine = ui.graphics_engine.lock().unwrap();
+        assert_eq!(engine.messages()[0].content, "see you 👋");
+        assert!(engine.messages()[0].edited);
+    }
 }
@@ -0,0 +1,21 @@
+// A deadline for shutting down. The cleanup steps lock shared state and wait on the
+// network, and one that hangs would leave the app running with the terminal in raw mode.
+// The watchdog runs on its own thread, so it fires even when the hang has blocked every
+// runtime worker.
+
+use std::thread;
+use std::time::Duration;
+
+// Calls expire after the timeout, from another thread. There's no disarming it, expire is
+// expected to end the process and shutdown ends it first when all goes well.
+pub fn arm(timeout: Duration, expire: impl FnOnce() + Send + 'static) {
+    let spawned = thread::Builder::new()
+        .name("shutdown-watchdog".to_string())
+        .spawn(move || {
+            thread::sleep(timeout);
+            expire();
+        });
+    if let Err(e) = spawned {
+        eprintln!("Failed to start the shutdown watchdog: {}", e);
+    }
+}